@@ -1,46 +1,93 @@
 //! Types for blobs and collections of blobs
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
 use anyhow::{Context, Result};
-use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::util::Hash;
 
 /// A collection of blobs
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+///
+/// An entry can also refer to another collection, which makes it possible to represent a
+/// directory tree where each directory is its own collection.  Unchanged directories then
+/// have the same hash and are shared between collections.  See [`Blob::is_collection`].
+///
+/// Optionally a collection also contains [`Metadata`] of its entries.  This is serialised
+/// after all other fields and only if present, so collections without metadata are
+/// unchanged and older versions which do not know about it ignore it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Collection {
     /// Links to the blobs in this collection
     blobs: Vec<Blob>,
-    /// The total size of the raw_data referred to by all links
+    /// The total size of the raw_data referred to by all links, including the blobs of
+    /// nested collections
     total_blobs_size: u64,
+    /// Metadata of the entries, sorted by name
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    metadata: Vec<Metadata>,
 }
 
 impl Collection {
     pub(crate) fn new(blobs: Vec<Blob>, total_blobs_size: u64) -> anyhow::Result<Self> {
         let mut blobs = blobs;
+        if let Some(blob) = blobs
+            .iter()
+            .find(|blob| blob.kind == BlobKind::Blob && blob.name.ends_with('/'))
+        {
+            anyhow::bail!("blob name ends with a `/`: {}", blob.name);
+        }
         let n = blobs.len();
-        blobs.sort_by(|a, b| a.name.cmp(&b.name));
+        blobs.sort_by(|a, b| (&a.name, a.kind).cmp(&(&b.name, b.kind)));
         blobs.dedup_by(|a, b| a.name == b.name);
         anyhow::ensure!(n == blobs.len(), "duplicate blob names");
         Ok(Self {
             blobs,
             total_blobs_size,
+            metadata: Vec::new(),
         })
     }
 
+    /// Adds metadata of the entries to the collection.
+    pub(crate) fn with_metadata(mut self, metadata: Vec<Metadata>) -> Result<Self> {
+        let mut metadata = metadata;
+        let n = metadata.len();
+        metadata.sort_by(|a, b| a.name.cmp(&b.name));
+        metadata.dedup_by(|a, b| a.name == b.name);
+        anyhow::ensure!(n == metadata.len(), "duplicate metadata names");
+        self.metadata = metadata;
+        Ok(self)
+    }
+
     /// Deserialize a collection from a byte slice
     pub fn from_bytes(data: &[u8]) -> Result<Self> {
-        let c: Collection =
-            postcard::from_bytes(data).context("failed to serialize Collection data")?;
-        Ok(c)
+        let mut decoder = CollectionDecoder::default();
+        decoder.extend(data);
+        let mut blobs = Vec::new();
+        while let Some(blob) = decoder.next_blob()? {
+            blobs.push(blob);
+        }
+        let mut metadata = Vec::new();
+        while let Some(entry) = decoder.next_metadata()? {
+            metadata.push(entry);
+        }
+        let total_blobs_size = decoder.finish()?;
+        Ok(Self {
+            blobs,
+            total_blobs_size,
+            metadata,
+        })
     }
 
     /// Blobs in this collection
-    pub(crate) fn blobs(&self) -> &[Blob] {
+    pub fn blobs(&self) -> &[Blob] {
         &self.blobs
     }
 
-    /// Take ownership of the blobs in this collection
-    pub(crate) fn into_inner(self) -> Vec<Blob> {
-        self.blobs
+    /// Metadata of the entries in this collection, empty if it was created without it
+    pub fn metadata(&self) -> &[Metadata] {
+        &self.metadata
     }
 
     /// Total size of the raw data referred to by all blobs in this collection
@@ -54,12 +101,311 @@ impl Collection {
     }
 }
 
+/// Deserializes the encoded collection from bytes using [`Collection::from_bytes`].
+///
+/// The metadata is only present if the data does not end after the other fields, so the
+/// encoding is read as a whole, e.g. as produced by `postcard::to_stdvec`.
+impl<'de> Deserialize<'de> for Collection {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(CollectionVisitor)
+    }
+}
+
+struct CollectionVisitor;
+
+impl<'de> serde::de::Visitor<'de> for CollectionVisitor {
+    type Value = Collection;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an encoded collection")
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Collection::from_bytes(bytes).map_err(E::custom)
+    }
+}
+
+/// A blob entry of a collection
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub(crate) struct Blob {
+#[serde(from = "BlobEncoding", into = "BlobEncoding")]
+pub struct Blob {
     /// The name of this blob of data
     pub(crate) name: String,
     /// The hash of the blob of data
     pub(crate) hash: Hash,
+    /// Whether the entry refers to raw data or a nested collection
+    pub(crate) kind: BlobKind,
+}
+
+impl Blob {
+    /// The name of this blob of data
+    ///
+    /// For a nested collection this is the name of the directory it represents.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The hash of the blob of data
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    /// Whether the entry refers to raw data or a nested collection
+    pub fn kind(&self) -> BlobKind {
+        self.kind
+    }
+
+    /// Whether this entry refers to a nested collection instead of a blob
+    pub fn is_collection(&self) -> bool {
+        self.kind == BlobKind::Collection
+    }
+}
+
+/// The kind of a [`Blob`] entry of a collection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BlobKind {
+    /// The entry refers to raw data
+    Blob,
+    /// The entry refers to a nested collection
+    Collection,
+}
+
+/// The serialised form of a [`Blob`].
+///
+/// The name of a nested collection is followed by a `/`, so collections without nested
+/// collections are serialised like before they were supported.  [`Collection::new`] rejects
+/// other names ending with a `/`, so this is unambiguous.
+#[derive(Clone, Serialize, Deserialize)]
+struct BlobEncoding {
+    name: String,
+    hash: Hash,
+}
+
+impl From<Blob> for BlobEncoding {
+    fn from(blob: Blob) -> Self {
+        let name = match blob.kind {
+            BlobKind::Blob => blob.name,
+            BlobKind::Collection => format!("{}/", blob.name),
+        };
+        Self {
+            name,
+            hash: blob.hash,
+        }
+    }
+}
+
+impl From<BlobEncoding> for Blob {
+    fn from(blob: BlobEncoding) -> Self {
+        let BlobEncoding { mut name, hash } = blob;
+        let kind = if name.ends_with('/') {
+            name.pop();
+            BlobKind::Collection
+        } else {
+            BlobKind::Blob
+        };
+        Self { name, hash, kind }
+    }
+}
+
+/// Metadata of an entry of a [`Collection`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// The name of the entry
+    ///
+    /// For a file this is the name of its blob, otherwise the `/` separated path of the
+    /// directory or symlink.
+    pub name: String,
+    /// The kind of the entry
+    pub kind: EntryKind,
+    /// The unix permission bits
+    pub mode: Option<u32>,
+    /// The last modification time
+    pub mtime: Option<Timestamp>,
+}
+
+/// A point in time, as seconds and nanoseconds relative to the unix epoch.
+///
+/// Unlike [`SystemTime`] this can be serialised for times before the epoch as well.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Timestamp {
+    /// Whole seconds since the epoch, negative for times before it
+    pub secs: i64,
+    /// Nanoseconds to add to `secs`, always less than one second
+    pub nanos: u32,
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => Self {
+                secs: since.as_secs() as i64,
+                nanos: since.subsec_nanos(),
+            },
+            Err(err) => {
+                let before = err.duration();
+                let secs = -(before.as_secs() as i64);
+                match before.subsec_nanos() {
+                    0 => Self { secs, nanos: 0 },
+                    nanos => Self {
+                        secs: secs - 1,
+                        nanos: 1_000_000_000 - nanos,
+                    },
+                }
+            }
+        }
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(time: Timestamp) -> Self {
+        let nanos = Duration::from_nanos(time.nanos as u64);
+        if time.secs >= 0 {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(time.secs as u64) + nanos
+        } else {
+            SystemTime::UNIX_EPOCH - Duration::from_secs(time.secs.unsigned_abs()) + nanos
+        }
+    }
+}
+
+/// The kind of an entry of a collection, see [`Metadata`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    /// A file, whose data is a blob of the collection
+    File,
+    /// A directory, which might be empty
+    Directory,
+    /// A symbolic link
+    Symlink {
+        /// The path the link points to
+        target: String,
+    },
+}
+
+/// Incrementally decodes the blobs of a serialised [`Collection`].
+///
+/// The serialised collection can be passed in pieces of any size using
+/// [`CollectionDecoder::extend`], after which [`CollectionDecoder::next_blob`] returns all
+/// blobs whose entries are complete, followed by [`CollectionDecoder::next_metadata`] for
+/// the metadata.  Only the undecoded remainder is kept, so the entire collection never needs
+/// to be in memory.
+#[derive(Debug, Default)]
+pub(crate) struct CollectionDecoder {
+    /// Data which has not been decoded yet, starting at `pos`.
+    buffer: Vec<u8>,
+    pos: usize,
+    /// The number of blobs in the collection, once decoded.
+    num_blobs: Option<u64>,
+    /// The number of blobs which have been decoded.
+    decoded: u64,
+    /// The total size of the blobs, which follows the blobs.
+    total_blobs_size: Option<u64>,
+    /// The number of metadata entries, once decoded.
+    num_metadata: Option<u64>,
+    /// The number of metadata entries which have been decoded.
+    decoded_metadata: u64,
+}
+
+impl CollectionDecoder {
+    /// Adds the next piece of the serialised collection.
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.drain(..self.pos);
+        self.pos = 0;
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// The number of blobs in the collection, once the start of it is decoded.
+    pub fn num_blobs(&self) -> Option<u64> {
+        self.num_blobs
+    }
+
+    /// Returns the next blob if its entry is complete.
+    ///
+    /// Returns `None` if more data is needed or all blobs have been decoded.
+    pub fn next_blob(&mut self) -> Result<Option<Blob>> {
+        let num_blobs = match self.num_blobs {
+            Some(num_blobs) => num_blobs,
+            None => match self.take::<u64>()? {
+                Some(num_blobs) => {
+                    self.num_blobs = Some(num_blobs);
+                    num_blobs
+                }
+                None => return Ok(None),
+            },
+        };
+        if self.decoded < num_blobs {
+            let blob = self.take::<Blob>()?;
+            if blob.is_some() {
+                self.decoded += 1;
+            }
+            Ok(blob)
+        } else {
+            if self.total_blobs_size.is_none() {
+                self.total_blobs_size = self.take::<u64>()?;
+            }
+            Ok(None)
+        }
+    }
+
+    /// Returns the next metadata entry if it is complete.
+    ///
+    /// The metadata follows the blobs, so this returns `None` until all blobs are decoded
+    /// using [`CollectionDecoder::next_blob`].  It also returns `None` if more data is needed
+    /// or all metadata has been decoded.
+    pub fn next_metadata(&mut self) -> Result<Option<Metadata>> {
+        if self.total_blobs_size.is_none() {
+            return Ok(None);
+        }
+        let num_metadata = match self.num_metadata {
+            Some(num_metadata) => num_metadata,
+            None => match self.take::<u64>()? {
+                Some(num_metadata) => {
+                    self.num_metadata = Some(num_metadata);
+                    num_metadata
+                }
+                None => return Ok(None),
+            },
+        };
+        if self.decoded_metadata < num_metadata {
+            let metadata = self.take::<Metadata>()?;
+            if metadata.is_some() {
+                self.decoded_metadata += 1;
+            }
+            Ok(metadata)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Finishes decoding, returning the total size of the blobs in the collection.
+    ///
+    /// Fails if the collection is incomplete or followed by more data.
+    pub fn finish(mut self) -> Result<u64> {
+        while self.next_blob()?.is_some() {}
+        while self.next_metadata()?.is_some() {}
+        let total_blobs_size = self
+            .total_blobs_size
+            .context("incomplete Collection data")?;
+        if let Some(num_metadata) = self.num_metadata {
+            anyhow::ensure!(
+                self.decoded_metadata == num_metadata,
+                "incomplete Collection metadata"
+            );
+        }
+        anyhow::ensure!(self.pos == self.buffer.len(), "extra data after Collection");
+        Ok(total_blobs_size)
+    }
+
+    /// Decodes a value from the start of the buffer, if it is complete.
+    fn take<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        match postcard::take_from_bytes::<T>(&self.buffer[self.pos..]) {
+            Ok((value, rest)) => {
+                self.pos = self.buffer.len() - rest.len();
+                Ok(Some(value))
+            }
+            Err(postcard::Error::DeserializeUnexpectedEnd) => Ok(None),
+            Err(err) => Err(err).context("failed to deserialize Collection data"),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -75,6 +421,7 @@ mod tests {
             )
             .unwrap()
             .into(),
+            kind: BlobKind::Blob,
         };
 
         let mut buf = bytes::BytesMut::zeroed(1024);
@@ -82,4 +429,142 @@ mod tests {
         let deserialize_b: Blob = postcard::from_bytes(&buf).unwrap();
         assert_eq!(b, deserialize_b);
     }
+
+    #[test]
+    fn nested_blob_encoding() {
+        let hash: Hash = blake3::hash(b"dir").into();
+        let nested = Blob {
+            name: "dir".to_string(),
+            hash,
+            kind: BlobKind::Collection,
+        };
+        let data = postcard::to_stdvec(&nested).unwrap();
+        let plain = Blob {
+            name: "dir/".to_string(),
+            hash,
+            kind: BlobKind::Blob,
+        };
+        assert_eq!(
+            data,
+            postcard::to_stdvec(&BlobEncoding::from(plain.clone())).unwrap()
+        );
+        assert_eq!(postcard::from_bytes::<Blob>(&data).unwrap(), nested);
+
+        // A blob whose name ends with a `/` would be decoded as a nested collection.
+        assert!(Collection::new(vec![plain], 0).is_err());
+        assert!(Collection::new(vec![nested], 0).is_ok());
+    }
+
+    #[test]
+    fn collection_decoder() {
+        let blobs = (0..100)
+            .map(|i| Blob {
+                name: format!("blob-{i}"),
+                hash: blake3::hash(&[i]).into(),
+                kind: BlobKind::Blob,
+            })
+            .collect::<Vec<_>>();
+        let collection = Collection::new(blobs, 1234).unwrap();
+        let data = postcard::to_stdvec(&collection).unwrap();
+
+        for piece_size in [1, 7, 100, data.len()] {
+            let mut decoder = CollectionDecoder::default();
+            let mut decoded = Vec::new();
+            for piece in data.chunks(piece_size) {
+                decoder.extend(piece);
+                while let Some(blob) = decoder.next_blob().unwrap() {
+                    decoded.push(blob);
+                }
+            }
+            assert_eq!(decoder.num_blobs(), Some(100));
+            assert_eq!(decoder.finish().unwrap(), 1234);
+            assert_eq!(decoded, collection.blobs());
+        }
+
+        let mut decoder = CollectionDecoder::default();
+        decoder.extend(&data[..data.len() - 1]);
+        assert!(decoder.finish().is_err());
+
+        // An empty metadata section is accepted, but nothing can follow it.
+        let mut decoder = CollectionDecoder::default();
+        decoder.extend(&data);
+        decoder.extend(&[0]);
+        assert_eq!(decoder.finish().unwrap(), 1234);
+        decoder = CollectionDecoder::default();
+        decoder.extend(&data);
+        decoder.extend(&[0, 0]);
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn collection_metadata() {
+        let blobs = vec![Blob {
+            name: "bin/tool".to_string(),
+            hash: blake3::hash(b"tool").into(),
+            kind: BlobKind::Blob,
+        }];
+        let collection = Collection::new(blobs, 4).unwrap();
+        let plain = postcard::to_stdvec(&collection).unwrap();
+
+        let metadata = vec![
+            Metadata {
+                name: "bin/tool".to_string(),
+                kind: EntryKind::File,
+                mode: Some(0o755),
+                mtime: Some(Timestamp {
+                    secs: 1000,
+                    nanos: 0,
+                }),
+            },
+            Metadata {
+                name: "bin/link".to_string(),
+                kind: EntryKind::Symlink {
+                    target: "tool".to_string(),
+                },
+                mode: None,
+                mtime: None,
+            },
+            Metadata {
+                name: "empty".to_string(),
+                kind: EntryKind::Directory,
+                mode: Some(0o700),
+                mtime: None,
+            },
+        ];
+        let collection = collection.with_metadata(metadata).unwrap();
+        let data = postcard::to_stdvec(&collection).unwrap();
+        assert_eq!(data[..plain.len()], plain);
+        let decoded = Collection::from_bytes(&data).unwrap();
+        assert_eq!(decoded, collection);
+        let names: Vec<_> = decoded.metadata().iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["bin/link", "bin/tool", "empty"]);
+
+        // Collections without metadata are unchanged.
+        let decoded = Collection::from_bytes(&plain).unwrap();
+        assert!(decoded.metadata().is_empty());
+
+        // Deserialize takes the encoded collection as bytes.
+        let bytes = postcard::to_stdvec(&data).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<Collection>(&bytes).unwrap(),
+            collection
+        );
+    }
+
+    #[test]
+    fn timestamp() {
+        let epoch = SystemTime::UNIX_EPOCH;
+        for (time, secs, nanos) in [
+            (epoch, 0, 0),
+            (epoch + Duration::new(1000, 5), 1000, 5),
+            (epoch - Duration::from_secs(1000), -1000, 0),
+            (epoch - Duration::new(1000, 5), -1001, 999_999_995),
+        ] {
+            let timestamp = Timestamp::from(time);
+            assert_eq!(timestamp, Timestamp { secs, nanos });
+            assert_eq!(SystemTime::from(timestamp), time);
+            let data = postcard::to_stdvec(&timestamp).unwrap();
+            assert_eq!(postcard::from_bytes::<Timestamp>(&data).unwrap(), timestamp);
+        }
+    }
 }
@@ -3,15 +3,19 @@
 //! The main entry point is [`run`]. This function takes callbacks that will
 //! be invoked when blobs or collections are received. It is up to the caller
 //! to store the received data.
+use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::io;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::ops::Range;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use crate::blobs::Collection;
+use crate::blobs::{Blob, CollectionDecoder, Metadata};
 use crate::protocol::{
-    read_bao_encoded, read_lp, write_lp, AuthToken, Handshake, Request, Res, Response,
+    collection_page, collection_page_count, negotiated_version, read_lp, supported_alpns, write_lp,
+    Closed, Credential, ErrorCode, Handshake, HasRequest, Presence, RangeSpec, Req, Request,
+    RequestV1, Res, ResolveRequest, Response, TAG_VERSION,
 };
 use crate::provider::Ticket;
 use crate::subnet::{same_subnet_v4, same_subnet_v6};
@@ -19,17 +23,21 @@ use crate::tls::{self, Keypair, PeerId};
 use crate::IROH_BLOCK_SIZE;
 use anyhow::{anyhow, bail, Context, Result};
 use bao_tree::io::tokio::AsyncResponseDecoder;
+use bao_tree::ChunkNum;
 use bytes::BytesMut;
 use default_net::Interface;
 use futures::{Future, StreamExt};
-use postcard::experimental::max_size::MaxSize;
-use range_collections::RangeSet2;
+use range_collections::range_set::RangeSetRange;
+use range_collections::{RangeSet2, RangeSetRef};
 use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
 use tracing::{debug, debug_span, error};
 use tracing_futures::Instrument;
 
 pub use crate::util::Hash;
 
+/// The first protocol version which supports [`query`].
+const QUERY_VERSION: u64 = 3;
+
 /// Options for the client
 #[derive(Clone, Debug)]
 pub struct Options {
@@ -39,6 +47,11 @@ pub struct Options {
     pub peer_id: Option<PeerId>,
     /// Whether to log the SSL keys when `SSLKEYLOGFILE` environment variable is set.
     pub keylog: bool,
+    /// The keypair identifying the client to the provider
+    ///
+    /// If `None` a new keypair is generated for every connection, so the provider can not
+    /// recognise the client.  See [`crate::provider::Builder::allow_peer`].
+    pub keypair: Option<Arc<Keypair>>,
 }
 
 impl Default for Options {
@@ -47,20 +60,23 @@ impl Default for Options {
             addr: "127.0.0.1:4433".parse().unwrap(),
             peer_id: None,
             keylog: false,
+            keypair: None,
         }
     }
 }
 
 /// Create a quinn client endpoint
+///
+/// The client presents a certificate for `keypair`, from which the provider learns the
+/// [`PeerId`] of the client.
 pub fn make_client_endpoint(
     bind_addr: SocketAddr,
+    keypair: &Keypair,
     peer_id: Option<PeerId>,
     alpn_protocols: Vec<Vec<u8>>,
     keylog: bool,
 ) -> Result<quinn::Endpoint> {
-    let keypair = Keypair::generate();
-
-    let tls_client_config = tls::make_client_config(&keypair, peer_id, alpn_protocols, keylog)?;
+    let tls_client_config = tls::make_client_config(keypair, peer_id, alpn_protocols, keylog)?;
     let mut client_config = quinn::ClientConfig::new(Arc::new(tls_client_config));
     let mut endpoint = quinn::Endpoint::client(bind_addr)?;
     let mut transport_config = quinn::TransportConfig::default();
@@ -72,13 +88,17 @@ pub fn make_client_endpoint(
 }
 
 /// Establishes a QUIC connection to the provided peer.
-async fn dial_peer(opts: Options) -> Result<quinn::Connection> {
+pub(crate) async fn dial_peer(opts: Options) -> Result<quinn::Connection> {
     let bind_addr = match opts.addr.is_ipv6() {
         true => SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0).into(),
         false => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into(),
     };
+    let keypair = match opts.keypair {
+        Some(keypair) => keypair,
+        None => Arc::new(Keypair::generate()),
+    };
     let endpoint =
-        make_client_endpoint(bind_addr, opts.peer_id, vec![tls::P2P_ALPN.to_vec()], false)?;
+        make_client_endpoint(bind_addr, &keypair, opts.peer_id, supported_alpns(), false)?;
 
     debug!("connecting to {}", opts.addr);
     let connect = endpoint.connect(opts.addr, "localhost")?;
@@ -87,6 +107,35 @@ async fn dial_peer(opts: Options) -> Result<quinn::Connection> {
     Ok(connection)
 }
 
+/// A page of the blobs of a collection, passed to the `on_collection` callback of [`run`].
+///
+/// Large collections are transferred in several pages, so the entire collection never
+/// needs to be held in memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectionPage {
+    /// The hash of the collection
+    pub hash: Hash,
+    /// The path of the collection
+    ///
+    /// This is empty for the requested collection.  For a nested collection it is the
+    /// path of its entry, e.g. `dir/sub/`, which is also the prefix of the names passed to
+    /// `on_blob` for its blobs.
+    pub path: String,
+    /// The blobs of this page
+    pub blobs: Vec<Blob>,
+    /// The index of the first blob of this page in the collection
+    pub offset: u64,
+    /// The number of blobs in the entire collection
+    pub total_entries: u64,
+    /// The total size of the raw data referred to by all blobs in the collection
+    pub total_blobs_size: u64,
+    /// The metadata of the collection's entries which was completed by this page
+    ///
+    /// This is only present if the collection was created with metadata, and the names are
+    /// relative to [`CollectionPage::path`] like those of the blobs.
+    pub metadata: Vec<Metadata>,
+}
+
 /// Stats about the transfer.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Stats {
@@ -104,65 +153,323 @@ impl Stats {
     }
 }
 
+/// Error returned by [`run`], [`run_ticket`] and [`crate::push::run`].
+#[derive(thiserror::Error, Debug)]
+pub enum GetError {
+    /// The provider failed the request.
+    ///
+    /// This is either reported by the provider in its response, or by closing the
+    /// connection or resetting the stream with one of the [`ErrorCode`]s.
+    #[error("provider error ({code}): {message}")]
+    Provider {
+        /// Machine readable reason for the failure.
+        code: ErrorCode,
+        /// Human readable description of the failure.
+        message: String,
+    },
+    /// Any other error, e.g. a network failure, invalid data or an error returned by one of
+    /// the callbacks.
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl GetError {
+    /// The [`ErrorCode`] sent by the provider, if the provider failed the request.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            GetError::Provider { code, .. } => Some(*code),
+            GetError::Other(_) => None,
+        }
+    }
+
+    pub(crate) fn provider(code: ErrorCode, message: impl Into<String>) -> Self {
+        GetError::Provider {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for GetError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<GetError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        // Look for the error_code if the provider closed the connection or the stream.
+        for cause in err.chain() {
+            let cause = match cause.downcast_ref::<io::Error>().and_then(|e| e.get_ref()) {
+                Some(inner) => inner as &(dyn std::error::Error + 'static),
+                None => cause,
+            };
+            let (error_code, reason) = match closed_error_code(cause) {
+                Some(closed) => closed,
+                None => continue,
+            };
+            let code = Closed::try_from(error_code)
+                .ok()
+                .and_then(|closed| closed.error_code());
+            if let Some(code) = code {
+                return GetError::provider(code, reason);
+            }
+        }
+        GetError::Other(err)
+    }
+}
+
+/// Extracts the `error_code` and reason when the provider closed the connection or stream.
+fn closed_error_code(err: &(dyn std::error::Error + 'static)) -> Option<(quinn::VarInt, String)> {
+    let conn_err = if let Some(err) = err.downcast_ref::<quinn::ConnectionError>() {
+        err
+    } else if let Some(err) = err.downcast_ref::<quinn::ReadError>() {
+        match err {
+            quinn::ReadError::Reset(code) => return Some((*code, "stream reset".to_string())),
+            quinn::ReadError::ConnectionLost(err) => err,
+            _ => return None,
+        }
+    } else if let Some(err) = err.downcast_ref::<quinn::WriteError>() {
+        match err {
+            quinn::WriteError::Stopped(code) => return Some((*code, "stream stopped".to_string())),
+            quinn::WriteError::ConnectionLost(err) => err,
+            _ => return None,
+        }
+    } else {
+        return None;
+    };
+    match conn_err {
+        quinn::ConnectionError::ApplicationClosed(close) => Some((
+            close.error_code,
+            String::from_utf8_lossy(&close.reason).into_owned(),
+        )),
+        _ => None,
+    }
+}
+
 /// A verified stream of data coming from the provider
 ///
 /// We guarantee that the data is correct by incrementally verifying a hash
-#[repr(transparent)]
+///
+/// If only some ranges of the blob were requested, the stream only yields the bytes within
+/// these ranges, concatenated.
 #[derive(Debug)]
-pub struct DataStream(AsyncResponseDecoder<quinn::RecvStream>);
+pub struct DataStream {
+    decoder: AsyncResponseDecoder<HeaderReader>,
+    /// Which parts of the decoded data to yield, `None` if all of it is yielded.
+    trim: Option<Trim>,
+}
 
 impl DataStream {
-    fn new(inner: quinn::RecvStream, hash: Hash) -> Self {
-        let decoder =
-            AsyncResponseDecoder::new(hash.into(), RangeSet2::all(), IROH_BLOCK_SIZE, inner);
-        DataStream(decoder)
+    /// Creates a stream verifying the requested `ranges` of the blob.
+    ///
+    /// This reads the size header of the blob, since the ranges the provider encodes depend
+    /// on the size of the blob.
+    async fn new(mut inner: quinn::RecvStream, hash: Hash, ranges: &RangeSpec) -> Result<Self> {
+        let mut header = [0u8; 8];
+        inner.read_exact(&mut header).await?;
+        let size = u64::from_le_bytes(header);
+        let chunk_ranges = ranges.to_chunk_ranges(size);
+        let trim = (!ranges.is_all()).then(|| Trim::new(&chunk_ranges, ranges, size));
+        let decoder = AsyncResponseDecoder::new(
+            hash.into(),
+            chunk_ranges,
+            IROH_BLOCK_SIZE,
+            HeaderReader {
+                header,
+                pos: 0,
+                inner,
+            },
+        );
+        Ok(DataStream { decoder, trim })
     }
 
-    async fn read_size(&mut self) -> io::Result<u64> {
-        self.0.read_size().await
+    /// Reads the size of the blob from the stream.
+    ///
+    /// This does not consume any of the blob data, so it can be called before reading the
+    /// content.  This is the size of the entire blob, even if only some ranges were
+    /// requested.
+    pub async fn read_size(&mut self) -> io::Result<u64> {
+        self.decoder.read_size().await
     }
 
     fn into_inner(self) -> quinn::RecvStream {
-        self.0.into_inner()
+        self.decoder.into_inner().inner
     }
 }
 
-impl AsyncRead for DataStream {
+/// A reader which yields the already read size header before the rest of the stream.
+#[derive(Debug)]
+struct HeaderReader {
+    header: [u8; 8],
+    pos: usize,
+    inner: quinn::RecvStream,
+}
+
+impl AsyncRead for HeaderReader {
     fn poll_read(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut ReadBuf,
     ) -> std::task::Poll<std::io::Result<()>> {
-        std::pin::Pin::new(&mut self.0).poll_read(cx, buf)
+        if self.pos < self.header.len() {
+            let len = std::cmp::min(self.header.len() - self.pos, buf.remaining());
+            buf.put_slice(&self.header[self.pos..self.pos + len]);
+            self.pos += len;
+            return std::task::Poll::Ready(Ok(()));
+        }
+        std::pin::Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncRead for DataStream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let decoder = std::pin::Pin::new(&mut this.decoder);
+        match this.trim {
+            None => decoder.poll_read(cx, buf),
+            Some(ref mut trim) => trim.poll_read(decoder, cx, buf),
+        }
+    }
+}
+
+/// Converts a range of a [`RangeSet2`] into a [`Range`], ending unbounded ranges at `end`.
+fn bounded(range: RangeSetRange<&u64>, end: u64) -> Range<u64> {
+    match range {
+        RangeSetRange::Range(range) => *range.start..*range.end,
+        RangeSetRange::RangeFrom(range) => *range.start..end,
+    }
+}
+
+/// Trims the decoded blocks down to the requested byte ranges.
+///
+/// The provider sends entire blocks of [`IROH_BLOCK_SIZE`], so the decoded data contains
+/// more than was requested.  This keeps a list of alternating lengths of decoded data to
+/// skip and to yield.
+#[derive(Debug)]
+struct Trim {
+    /// Lengths to skip or yield, in order, starting with a length to skip.
+    segments: VecDeque<u64>,
+    /// Whether the first of the `segments` is to be skipped.
+    skip: bool,
+    scratch: Box<[u8]>,
+}
+
+impl Trim {
+    fn new(chunk_ranges: &RangeSetRef<ChunkNum>, ranges: &RangeSpec, size: u64) -> Self {
+        // The decoded data consists of all blocks intersecting with the chunk ranges.
+        let block_size = IROH_BLOCK_SIZE.bytes() as u64;
+        let mut blocks = RangeSet2::empty();
+        for range in chunk_ranges.iter() {
+            let (start, end) = match range {
+                RangeSetRange::Range(range) => (range.start.to_bytes().0, range.end.to_bytes().0),
+                RangeSetRange::RangeFrom(range) => (range.start.to_bytes().0, size),
+            };
+            let start = start / block_size * block_size;
+            let end = std::cmp::min((end + block_size - 1) / block_size * block_size, size);
+            blocks.union_with(&RangeSet2::from(start..end));
+        }
+        let wanted = ranges.to_byte_ranges(size);
+        let mut segments = VecDeque::new();
+        let mut skip = 0;
+        for block in blocks.iter() {
+            let block = bounded(block, size);
+            let mut pos = block.start;
+            for range in wanted
+                .intersection::<[u64; 2]>(&RangeSet2::from(block.clone()))
+                .iter()
+            {
+                let range = bounded(range, size);
+                segments.push_back(skip + range.start - pos);
+                segments.push_back(range.end - range.start);
+                skip = 0;
+                pos = range.end;
+            }
+            skip += block.end - pos;
+        }
+        segments.push_back(skip);
+        Self {
+            segments,
+            skip: true,
+            scratch: vec![0u8; 4096].into_boxed_slice(),
+        }
+    }
+
+    fn poll_read(
+        &mut self,
+        mut decoder: std::pin::Pin<&mut impl AsyncRead>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        while let Some(remaining) = self.segments.front_mut() {
+            if *remaining == 0 {
+                self.segments.pop_front();
+                self.skip = !self.skip;
+                continue;
+            }
+            let mut len = std::cmp::min(*remaining, self.scratch.len() as u64) as usize;
+            if !self.skip {
+                if buf.remaining() == 0 {
+                    break;
+                }
+                len = std::cmp::min(len, buf.remaining());
+            }
+            let mut scratch = ReadBuf::new(&mut self.scratch[..len]);
+            futures::ready!(decoder.as_mut().poll_read(cx, &mut scratch))?;
+            let read = scratch.filled().len();
+            if read == 0 {
+                return std::task::Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            *remaining -= read as u64;
+            if !self.skip {
+                buf.put_slice(&self.scratch[..read]);
+                return std::task::Poll::Ready(Ok(()));
+            }
+        }
+        std::task::Poll::Ready(Ok(()))
     }
 }
 
-/// Gets a collection and all its blobs using a [`Ticket`].
+/// Gets a blob or a collection and all its blobs using a [`Ticket`].
+///
+/// The ticket provides the address and authentication for the provider, the `request` is
+/// usually created from the ticket's hash using [`Request::new`].  The `keypair` identifies
+/// the client like [`Options::keypair`].
+///
+/// See [`run`] for how the callbacks are invoked.
+#[allow(clippy::too_many_arguments)]
 pub async fn run_ticket<A, B, C, FutA, FutB, FutC>(
     ticket: &Ticket,
+    request: Request,
     keylog: bool,
+    keypair: Option<Arc<Keypair>>,
     max_concurrent: u8,
     on_connected: A,
     on_collection: B,
     on_blob: C,
-) -> Result<Stats>
+) -> Result<Stats, GetError>
 where
     A: FnOnce() -> FutA,
     FutA: Future<Output = Result<()>>,
-    B: FnOnce(&Collection) -> FutB,
+    B: FnMut(&CollectionPage) -> FutB,
     FutB: Future<Output = Result<()>>,
     C: FnMut(Hash, DataStream, String) -> FutC,
     FutC: Future<Output = Result<DataStream>>,
 {
-    let span = debug_span!("get", hash=%ticket.hash());
+    let span = debug_span!("get", hash = %request.hash());
     async move {
         let start = Instant::now();
-        let connection = dial_ticket(ticket, keylog, max_concurrent.into()).await?;
+        if ticket.is_expired() {
+            return Err(GetError::Other(anyhow!("the ticket has expired")));
+        }
+        let connection = dial_ticket(ticket, keylog, keypair, max_concurrent.into()).await?;
         let span = debug_span!("connection", remote_addr=%connection.remote_address());
         run_connection(
             connection,
-            ticket.hash(),
-            ticket.token(),
+            request,
+            ticket_credential(ticket),
             start,
             on_connected,
             on_collection,
@@ -170,14 +477,170 @@ where
         )
         .instrument(span)
         .await
+        .map_err(GetError::from)
     }
     .instrument(span)
     .await
 }
 
+/// The credential to present for a ticket.
+///
+/// Tickets of a public provider have none, so [`Credential::Anonymous`] is presented.
+fn ticket_credential(ticket: &Ticket) -> Credential {
+    ticket
+        .credential()
+        .cloned()
+        .unwrap_or(Credential::Anonymous)
+}
+
+/// Asks a provider which of the `hashes` it has.
+///
+/// This is a lightweight alternative to starting a transfer, answered from the provider's
+/// database without sending any data.  Returns the [`Presence`] of every hash, in the order
+/// of `hashes`.
+pub async fn query(
+    hashes: Vec<Hash>,
+    credential: impl Into<Credential>,
+    opts: Options,
+) -> Result<Vec<(Hash, Presence)>, GetError> {
+    let span = debug_span!("query", count = hashes.len());
+    let credential = credential.into();
+    async move {
+        let connection = dial_peer(opts).await?;
+        let version = negotiated_version(&connection)?;
+        if version < QUERY_VERSION {
+            return Err(anyhow!(
+                "the provider does not support queries, it only speaks protocol version {version}"
+            )
+            .into());
+        }
+        let request = Req::Has(HasRequest {
+            hashes: hashes.clone(),
+        });
+        let (_writer, mut reader) =
+            send_request(&connection, version, credential, &request).await?;
+        let mut buffer = BytesMut::with_capacity(1024);
+        match read_response(&mut reader, &mut buffer).await? {
+            Res::Has { presence } if presence.len() == hashes.len() => {
+                Ok(hashes.into_iter().zip(presence).collect())
+            }
+            res => Err(anyhow!("unexpected response from provider: {res:?}").into()),
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Asks a provider which hash its tag named `tag` refers to.
+///
+/// Fails with [`ErrorCode::NotFound`] if the provider has no such tag.  See
+/// [`crate::provider::Database::set_tag`].
+pub async fn resolve(
+    tag: &str,
+    credential: impl Into<Credential>,
+    opts: Options,
+) -> Result<Hash, GetError> {
+    let span = debug_span!("resolve", tag);
+    let credential = credential.into();
+    async move {
+        let connection = dial_peer(opts).await?;
+        resolve_tag(&connection, credential, tag).await
+    }
+    .instrument(span)
+    .await
+}
+
+/// Asks the provider of a [`Ticket`] which hash the tag of the ticket refers to.
+///
+/// Fails if the ticket has no tag, see [`Ticket::tag`].  The other arguments are the same
+/// as for [`run_ticket`].
+pub async fn resolve_ticket(
+    ticket: &Ticket,
+    keylog: bool,
+    keypair: Option<Arc<Keypair>>,
+    max_concurrent: u8,
+) -> Result<Hash, GetError> {
+    let tag = ticket.tag().context("the ticket has no tag")?;
+    let span = debug_span!("resolve", tag);
+    async move {
+        if ticket.is_expired() {
+            return Err(GetError::Other(anyhow!("the ticket has expired")));
+        }
+        let connection = dial_ticket(ticket, keylog, keypair, max_concurrent.into()).await?;
+        resolve_tag(&connection, ticket_credential(ticket), tag).await
+    }
+    .instrument(span)
+    .await
+}
+
+async fn resolve_tag(
+    connection: &quinn::Connection,
+    credential: Credential,
+    tag: &str,
+) -> Result<Hash, GetError> {
+    let version = negotiated_version(connection)?;
+    if version < TAG_VERSION {
+        return Err(anyhow!(
+            "the provider does not support tags, it only speaks protocol version {version}"
+        )
+        .into());
+    }
+    let request = Req::Resolve(ResolveRequest {
+        tag: tag.to_string(),
+    });
+    let (_writer, mut reader) = send_request(connection, version, credential, &request).await?;
+    let mut buffer = BytesMut::with_capacity(1024);
+    match read_response(&mut reader, &mut buffer).await? {
+        Res::Resolved { hash } => Ok(hash),
+        res => Err(anyhow!("unexpected response from provider: {res:?}").into()),
+    }
+}
+
+/// Opens a stream to the provider and sends the handshake followed by the `request`.
+///
+/// The send stream is finished unless the request is followed by more data.
+pub(crate) async fn send_request(
+    connection: &quinn::Connection,
+    version: u64,
+    credential: Credential,
+    request: &Req,
+) -> Result<(quinn::SendStream, quinn::RecvStream)> {
+    let (mut writer, reader) = connection.open_bi().await?;
+
+    debug!("sending handshake");
+    let handshake = Handshake::new(version, credential);
+    write_lp(&mut writer, &handshake.to_bytes()?).await?;
+
+    debug!("sending request");
+    let data = postcard::to_stdvec(request)?;
+    write_lp(&mut writer, &data).await?;
+    if !matches!(request, Req::Push(_)) {
+        writer.finish().await?;
+    }
+    Ok((writer, reader))
+}
+
+/// Reads the next response from the provider.
+///
+/// A [`Res::Error`] is turned into a [`GetError::Provider`].
+pub(crate) async fn read_response(
+    reader: &mut quinn::RecvStream,
+    buffer: &mut BytesMut,
+) -> Result<Res> {
+    let data = read_lp(reader, buffer)
+        .await?
+        .context("provider closed stream")?;
+    let response: Response = postcard::from_bytes(&data)?;
+    match response.data {
+        Res::Error { code, message } => Err(GetError::provider(code, message).into()),
+        res => Ok(res),
+    }
+}
+
 async fn dial_ticket(
     ticket: &Ticket,
     keylog: bool,
+    keypair: Option<Arc<Keypair>>,
     max_concurrent: usize,
 ) -> Result<quinn::Connection> {
     // Sort the interfaces to make sure local ones are at the front of the list.
@@ -194,6 +657,7 @@ async fn dial_ticket(
                 addr,
                 peer_id: Some(ticket.peer()),
                 keylog,
+                keypair: keypair.clone(),
             };
             dial_peer(opts)
         })
@@ -229,32 +693,55 @@ fn is_same_subnet(addr: &SocketAddr, interfaces: &[Interface]) -> bool {
     false
 }
 
-/// Get a collection and all its blobs from a provider
+/// Get a blob or a collection and all its blobs from a provider
+///
+/// If the hash refers to a collection, `on_collection` is called for each [`CollectionPage`]
+/// once it is received, followed by `on_blob` for each blob of the page.  Small collections
+/// consist of a single page.  If the hash refers to a single blob, `on_collection` is not
+/// called and `on_blob` is called once with an empty name.
+///
+/// Entries referring to nested collections, see [`Blob::is_collection`], are received in
+/// place: `on_collection` is called for the pages of the nested collection, with
+/// [`CollectionPage::path`] set to the name of the entry, and the names passed to `on_blob`
+/// are prefixed with this path.
+///
+/// If the request only asks for some ranges of the data, see [`Request::with_ranges`], or
+/// marks some data as already present, see [`Request::with_have`], the [`DataStream`] passed
+/// to `on_blob` only yields the missing data in these ranges, as returned by
+/// [`Request::missing_ranges`].  `on_blob` is not called for blobs of a collection which
+/// are not missing any data, or which are not selected using [`Request::with_include`].
+///
+/// The `credential` is either the provider's [`AuthToken`](crate::protocol::AuthToken) or
+/// a [`Capability`](crate::protocol::Capability) for the requested hash.  If the provider
+/// rejects or fails the request, e.g. because of a wrong `credential` or because the data
+/// is not found, this returns [`GetError::Provider`] with the [`ErrorCode`] describing the
+/// failure.
 pub async fn run<A, B, C, FutA, FutB, FutC>(
-    hash: Hash,
-    auth_token: AuthToken,
+    request: Request,
+    credential: impl Into<Credential>,
     opts: Options,
     on_connected: A,
     on_collection: B,
     on_blob: C,
-) -> Result<Stats>
+) -> Result<Stats, GetError>
 where
     A: FnOnce() -> FutA,
     FutA: Future<Output = Result<()>>,
-    B: FnOnce(&Collection) -> FutB,
+    B: FnMut(&CollectionPage) -> FutB,
     FutB: Future<Output = Result<()>>,
     C: FnMut(Hash, DataStream, String) -> FutC,
     FutC: Future<Output = Result<DataStream>>,
 {
-    let span = debug_span!("get", %hash);
+    let span = debug_span!("get", hash = %request.hash());
+    let credential = credential.into();
     async move {
         let now = Instant::now();
         let connection = dial_peer(opts).await?;
         let span = debug_span!("connection", remote_addr=%connection.remote_address());
         run_connection(
             connection,
-            hash,
-            auth_token,
+            request,
+            credential,
             now,
             on_connected,
             on_collection,
@@ -262,53 +749,64 @@ where
         )
         .instrument(span)
         .await
+        .map_err(GetError::from)
     }
     .instrument(span)
     .await
 }
 
-/// Gets a collection and all its blobs from a provider on the established connection.
-async fn run_connection<A, B, C, FutA, FutB, FutC>(
+/// Gets a blob or a collection and all its blobs from a provider on the established
+/// connection.
+///
+/// Uses the protocol version negotiated for the connection.  Protocol version 1 only
+/// supports requesting the data in full.
+pub(crate) async fn run_connection<A, B, C, FutA, FutB, FutC>(
     connection: quinn::Connection,
-    hash: Hash,
-    auth_token: AuthToken,
+    request: Request,
+    credential: Credential,
     start_time: Instant,
     on_connected: A,
-    on_collection: B,
+    mut on_collection: B,
     mut on_blob: C,
 ) -> Result<Stats>
 where
     A: FnOnce() -> FutA,
     FutA: Future<Output = Result<()>>,
-    B: FnOnce(&Collection) -> FutB,
+    B: FnMut(&CollectionPage) -> FutB,
     FutB: Future<Output = Result<()>>,
     C: FnMut(Hash, DataStream, String) -> FutC,
     FutC: Future<Output = Result<DataStream>>,
 {
+    let version = negotiated_version(&connection)?;
+    debug!(version, "negotiated protocol version");
+    let hash = request.hash();
+    if version == 1 {
+        anyhow::ensure!(
+            request == Request::new(hash),
+            "the provider only supports protocol version 1, which can not request partial data"
+        );
+    }
+
     let (mut writer, mut reader) = connection.open_bi().await?;
 
     on_connected().await?;
 
-    let mut out_buffer = BytesMut::zeroed(std::cmp::max(
-        Request::POSTCARD_MAX_SIZE,
-        Handshake::POSTCARD_MAX_SIZE,
-    ));
-
     // 1. Send Handshake
     {
         debug!("sending handshake");
-        let handshake = Handshake::new(auth_token);
-        let used = postcard::to_slice(&handshake, &mut out_buffer)?;
-        write_lp(&mut writer, used).await?;
+        let handshake = Handshake::new(version, credential);
+        write_lp(&mut writer, &handshake.to_bytes()?).await?;
     }
 
     // 2. Send Request
     {
         debug!("sending request");
-        let req = Request { hash };
-
-        let used = postcard::to_slice(&req, &mut out_buffer)?;
-        write_lp(&mut writer, used).await?;
+        let data = match version {
+            1 => postcard::to_stdvec(&RequestV1 { hash })?,
+            2 => postcard::to_stdvec(&request)?,
+            _ => postcard::to_stdvec(&Req::Get(request.clone()))?,
+        };
+        write_lp(&mut writer, &data).await?;
     }
     writer.finish().await?;
     drop(writer);
@@ -327,20 +825,75 @@ where
                 match response.data {
                     // server is sending over a collection of blobs
                     Res::FoundCollection { total_blobs_size } => {
-                        data_len = total_blobs_size;
-
-                        // read entire collection data into buffer
-                        let data = read_bao_encoded(&mut reader, hash).await?;
+                        let mut remaining_size = total_blobs_size;
+                        // The collections being received, the innermost nested one last.
+                        let mut stack = vec![CollectionReceiver::new(
+                            hash,
+                            String::new(),
+                            total_blobs_size,
+                        )];
+                        loop {
+                            let nested = stack.len() > 1;
+                            let current = match stack.last_mut() {
+                                Some(current) => current,
+                                None => break,
+                            };
+                            let (index, blob) = match current.next_blob() {
+                                Some(entry) => entry,
+                                None if current.page < current.num_pages => {
+                                    // read and decode the next page of the collection
+                                    let (page, page_reader) =
+                                        current.read_page(version, reader).await?;
+                                    reader = page_reader;
+                                    on_collection(&page).await?;
+                                    current.add_blobs(page.blobs);
+                                    continue;
+                                }
+                                None => {
+                                    if let Some(current) = stack.pop() {
+                                        current.finish()?;
+                                    }
+                                    continue;
+                                }
+                            };
+                            if !nested && !request.includes(index, &blob.name) {
+                                continue;
+                            }
+                            let name = format!("{}{}", current.path, blob.name);
 
-                        // decode the collection
-                        let collection = Collection::from_bytes(&data)?;
-                        on_collection(&collection).await?;
+                            if blob.is_collection() {
+                                // the nested collection is sent like the requested one
+                                match read_response(&mut reader, &mut in_buffer).await? {
+                                    Res::FoundCollection { total_blobs_size } => {
+                                        stack.push(CollectionReceiver::new(
+                                            blob.hash,
+                                            format!("{name}/"),
+                                            total_blobs_size,
+                                        ));
+                                    }
+                                    Res::NotFound => {
+                                        return Err(GetError::provider(
+                                            ErrorCode::NotFound,
+                                            format!("collection {} not found", blob.hash),
+                                        )
+                                        .into());
+                                    }
+                                    _ => bail!(
+                                        "Unexpected message from provider. Ending transfer early."
+                                    ),
+                                }
+                                continue;
+                            }
 
-                        // expect to get blob data in the order they appear in the collection
-                        let mut remaining_size = total_blobs_size;
-                        for blob in collection.into_inner() {
+                            // expect to get blob data in the order they appear in the collection
+                            let ranges = request.missing_ranges(&blob.hash);
+                            if ranges.is_empty() {
+                                // the provider skips blobs we already have
+                                continue;
+                            }
                             let mut blob_reader =
-                                handle_blob_response(blob.hash, reader, &mut in_buffer).await?;
+                                handle_blob_response(blob.hash, &ranges, reader, &mut in_buffer)
+                                    .await?;
 
                             let size = blob_reader.read_size().await?;
                             anyhow::ensure!(
@@ -348,26 +901,40 @@ where
                                 "downloaded more than {total_blobs_size}"
                             );
                             remaining_size -= size;
-                            let mut blob_reader =
-                                on_blob(blob.hash, blob_reader, blob.name).await?;
-
-                            if blob_reader.read_exact(&mut [0u8; 1]).await.is_ok() {
-                                bail!("`on_blob` callback did not fully read the blob content")
-                            }
-                            reader = blob_reader.into_inner();
+                            data_len += ranges.selected_len(size);
+                            reader =
+                                process_blob(&mut on_blob, blob.hash, blob_reader, name).await?;
                         }
                     }
 
-                    // unexpected message
-                    Res::Found { .. } => {
-                        // we should only receive `Res::FoundCollection` or `Res::NotFound` from the
-                        // provider at this point in the exchange
-                        bail!("Unexpected message from provider. Ending transfer early.");
+                    // server is sending over a single blob
+                    Res::Found => {
+                        let ranges = request.missing_ranges(&hash);
+                        let mut blob_reader = DataStream::new(reader, hash, &ranges).await?;
+                        let size = blob_reader.read_size().await?;
+                        data_len = ranges.selected_len(size);
+                        reader =
+                            process_blob(&mut on_blob, hash, blob_reader, String::new()).await?;
                     }
 
                     // data associated with the hash is not found
                     Res::NotFound => {
-                        Err(anyhow!("data not found"))?;
+                        return Err(
+                            GetError::provider(ErrorCode::NotFound, "data not found").into()
+                        );
+                    }
+
+                    // the provider failed the request
+                    Res::Error { code, message } => {
+                        return Err(GetError::provider(code, message).into());
+                    }
+
+                    // unexpected message
+                    Res::PushAccepted
+                    | Res::PushCompleted
+                    | Res::Has { .. }
+                    | Res::Resolved { .. } => {
+                        bail!("Unexpected message from provider. Ending transfer early.");
                     }
                 }
 
@@ -391,12 +958,127 @@ where
     }
 }
 
+/// A collection which is being received by [`run_connection`].
+struct CollectionReceiver {
+    hash: Hash,
+    /// The path of the collection, see [`CollectionPage::path`].
+    path: String,
+    total_blobs_size: u64,
+    decoder: CollectionDecoder,
+    /// The next page to read.
+    page: u64,
+    num_pages: u64,
+    /// The number of blobs read so far.
+    num_read: u64,
+    /// Blobs which were read, but not handled yet.
+    pending: VecDeque<Blob>,
+}
+
+impl CollectionReceiver {
+    fn new(hash: Hash, path: String, total_blobs_size: u64) -> Self {
+        Self {
+            hash,
+            path,
+            total_blobs_size,
+            decoder: CollectionDecoder::default(),
+            page: 0,
+            num_pages: 1,
+            num_read: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Reads and decodes the next page of the collection.
+    ///
+    /// Returns the page and the underlying stream so the blobs can be read from it.
+    async fn read_page(
+        &mut self,
+        version: u64,
+        reader: quinn::RecvStream,
+    ) -> Result<(CollectionPage, quinn::RecvStream)> {
+        let ranges = collection_page(version, self.page);
+        let mut page_reader =
+            AsyncResponseDecoder::new(self.hash.into(), ranges, IROH_BLOCK_SIZE, reader);
+        let size = page_reader.read_size().await?;
+        if self.page == 0 {
+            self.num_pages = collection_page_count(version, size);
+        }
+        let mut data = Vec::new();
+        page_reader.read_to_end(&mut data).await?;
+        self.decoder.extend(&data);
+        let mut blobs = Vec::new();
+        while let Some(blob) = self.decoder.next_blob()? {
+            blobs.push(blob);
+        }
+        let mut metadata = Vec::new();
+        while let Some(entry) = self.decoder.next_metadata()? {
+            metadata.push(entry);
+        }
+        let page = CollectionPage {
+            hash: self.hash,
+            path: self.path.clone(),
+            blobs,
+            offset: self.num_read,
+            total_entries: self
+                .decoder
+                .num_blobs()
+                .context("collection page is too small")?,
+            total_blobs_size: self.total_blobs_size,
+            metadata,
+        };
+        self.page += 1;
+        Ok((page, page_reader.into_inner()))
+    }
+
+    /// Adds the blobs of a page which was read, to be returned by [`Self::next_blob`].
+    fn add_blobs(&mut self, blobs: Vec<Blob>) {
+        self.num_read += blobs.len() as u64;
+        self.pending.extend(blobs);
+    }
+
+    /// Returns the next blob which was read and its index in the collection.
+    fn next_blob(&mut self) -> Option<(u64, Blob)> {
+        let index = self.num_read - self.pending.len() as u64;
+        self.pending.pop_front().map(|blob| (index, blob))
+    }
+
+    /// Checks that the entire collection was read.
+    fn finish(self) -> Result<()> {
+        anyhow::ensure!(
+            self.decoder.finish()? == self.total_blobs_size,
+            "collection size does not match the announced size"
+        );
+        Ok(())
+    }
+}
+
+/// Passes the blob to the `on_blob` callback and makes sure it was fully read.
+///
+/// Returns the underlying stream so the next response can be read from it.
+async fn process_blob<C, FutC>(
+    on_blob: &mut C,
+    hash: Hash,
+    reader: DataStream,
+    name: String,
+) -> Result<quinn::RecvStream>
+where
+    C: FnMut(Hash, DataStream, String) -> FutC,
+    FutC: Future<Output = Result<DataStream>>,
+{
+    let mut reader = on_blob(hash, reader, name).await?;
+    if reader.read_exact(&mut [0u8; 1]).await.is_ok() {
+        bail!("`on_blob` callback did not fully read the blob content")
+    }
+    Ok(reader.into_inner())
+}
+
 /// Read next response, and if `Res::Found`, reads the next blob of data off the reader.
 ///
 /// Returns an `AsyncReader`
 /// The `AsyncReader` can be used to read the content.
 async fn handle_blob_response(
     hash: Hash,
+    ranges: &RangeSpec,
     mut reader: quinn::RecvStream,
     buffer: &mut BytesMut,
 ) -> Result<DataStream> {
@@ -405,15 +1087,24 @@ async fn handle_blob_response(
             let response: Response = postcard::from_bytes(&response_buffer)?;
             match response.data {
                 // unexpected message
-                Res::FoundCollection { .. } => Err(anyhow!(
+                Res::FoundCollection { .. }
+                | Res::PushAccepted
+                | Res::PushCompleted
+                | Res::Has { .. }
+                | Res::Resolved { .. } => Err(anyhow!(
                     "Unexpected message from provider. Ending transfer early."
                 ))?,
                 // blob data not found
-                Res::NotFound => Err(anyhow!("data for {} not found", hash))?,
+                Res::NotFound => Err(GetError::provider(
+                    ErrorCode::NotFound,
+                    format!("data for {hash} not found"),
+                ))?,
+                // the provider failed the request
+                Res::Error { code, message } => Err(GetError::provider(code, message))?,
                 // next blob in collection will be sent over
                 Res::Found => {
                     assert!(buffer.is_empty());
-                    let decoder = DataStream::new(reader, hash);
+                    let decoder = DataStream::new(reader, hash, ranges).await?;
                     Ok(decoder)
                 }
             }
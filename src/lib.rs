@@ -9,6 +9,7 @@ pub mod net;
 pub mod progress;
 pub mod protocol;
 pub mod provider;
+pub mod push;
 pub mod rpc_protocol;
 
 mod subnet;
@@ -16,7 +17,7 @@ mod tls;
 mod util;
 
 pub use tls::{Keypair, PeerId, PeerIdError, PublicKey, SecretKey, Signature};
-pub use util::Hash;
+pub use util::{path_from_name, DecodeError, Hash};
 
 use bao_tree::BlockSize;
 
@@ -31,7 +32,7 @@ mod tests {
         net::{Ipv4Addr, SocketAddr},
         path::{Path, PathBuf},
         sync::{atomic::AtomicUsize, Arc},
-        time::Duration,
+        time::{Duration, SystemTime},
     };
 
     use anyhow::{anyhow, Context, Result};
@@ -41,10 +42,14 @@ mod tests {
     use tokio::{fs, sync::broadcast};
     use tracing_subscriber::{prelude::*, EnvFilter};
 
-    use crate::protocol::AuthToken;
-    use crate::provider::{create_collection, Event, Provider};
+    use crate::protocol::{
+        AuthToken, Capability, Credential, ErrorCode, Include, Presence, RangeSpec, Request,
+    };
+    use crate::provider::{create_collection, Database, Event, PeerAuth, Provider, Ticket};
+    use crate::rpc_protocol::{IdRequest, TokenAddRequest, TokenListRequest, TokenRevokeRequest};
     use crate::tls::PeerId;
     use crate::util::Hash;
+    use futures::{StreamExt, TryStreamExt};
 
     use super::*;
 
@@ -146,11 +151,12 @@ mod tests {
                 addr,
                 peer_id: Some(peer_id),
                 keylog: true,
+                keypair: None,
             };
             let content = &content;
             let name = &name;
             get::run(
-                hash,
+                Request::new(hash),
                 token,
                 opts,
                 || async { Ok(()) },
@@ -174,7 +180,7 @@ mod tests {
         for _i in 0..3 {
             tasks.push(tokio::task::spawn(run_client(
                 hash,
-                provider.auth_token(),
+                provider.auth_token().unwrap(),
                 expect_hash.into(),
                 expect_name.clone(),
                 provider.local_address(),
@@ -269,18 +275,19 @@ mod tests {
             addr: dbg!(provider.local_address()),
             peer_id: Some(provider.peer_id()),
             keylog: true,
+            keypair: None,
         };
 
         let i = AtomicUsize::new(0);
         let expects = Arc::new(expects);
 
         get::run(
-            collection_hash,
-            provider.auth_token(),
+            Request::new(collection_hash),
+            provider.auth_token().unwrap(),
             opts,
             || async { Ok(()) },
             |collection| {
-                assert_eq!(collection.blobs().len(), num_blobs);
+                assert_eq!(collection.total_entries, num_blobs as u64);
                 async { Ok(()) }
             },
             |got_hash, mut reader, got_name| {
@@ -361,7 +368,7 @@ mod tests {
             .bind_addr("127.0.0.1:0".parse().unwrap())
             .spawn()
             .unwrap();
-        let auth_token = provider.auth_token();
+        let auth_token = provider.auth_token().unwrap();
         let provider_addr = provider.local_address();
 
         // This tasks closes the connection on the provider side as soon as the transfer
@@ -391,12 +398,13 @@ mod tests {
         });
 
         get::run(
-            hash,
+            Request::new(hash),
             auth_token,
             get::Options {
                 addr: provider_addr,
                 peer_id: None,
                 keylog: true,
+                keypair: None,
             },
             || async move { Ok(()) },
             |_collection| async move { Ok(()) },
@@ -434,18 +442,19 @@ mod tests {
         let provider = Provider::builder(db)
             .bind_addr("127.0.0.1:0".parse().unwrap())
             .spawn()?;
-        let auth_token = provider.auth_token();
+        let auth_token = provider.auth_token().unwrap();
         let provider_addr = provider.local_address();
 
         let timeout = tokio::time::timeout(
             std::time::Duration::from_secs(10),
             get::run(
-                hash,
+                Request::new(hash),
                 auth_token,
                 get::Options {
                     addr: provider_addr,
                     peer_id: None,
                     keylog: true,
+                    keypair: None,
                 },
                 || async move { Ok(()) },
                 |_collection| async move { Ok(()) },
@@ -466,6 +475,1111 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_get_blob() -> Result<()> {
+        let dir = testdir!();
+        let src0 = dir.join("src0");
+        let src1 = dir.join("src1");
+        fs::write(&src0, "hello world").await?;
+        fs::write(&src1, "hello there").await?;
+        let (db, _hash) = create_collection(vec![src0.into(), src1.into()]).await?;
+        let provider = Provider::builder(db)
+            .bind_addr("127.0.0.1:0".parse().unwrap())
+            .spawn()?;
+        let mut events = provider.subscribe();
+        let blob_hash = Hash::new(b"hello there");
+
+        let mut on_collection = false;
+        let mut blobs = Vec::new();
+        let stats = tokio::time::timeout(
+            Duration::from_secs(10),
+            get::run(
+                Request::new(blob_hash),
+                provider.auth_token().unwrap(),
+                get::Options {
+                    addr: provider.local_address(),
+                    peer_id: Some(provider.peer_id()),
+                    keylog: true,
+                    keypair: None,
+                },
+                || async move { Ok(()) },
+                |_collection| {
+                    on_collection = true;
+                    async move { Ok(()) }
+                },
+                |hash, mut stream, name| {
+                    blobs.push((hash, name));
+                    async move {
+                        let mut got = Vec::new();
+                        stream.read_to_end(&mut got).await?;
+                        assert_eq!(got, b"hello there");
+                        Ok(stream)
+                    }
+                },
+            ),
+        )
+        .await
+        .expect("timeout")?;
+
+        assert!(!on_collection);
+        assert_eq!(blobs, vec![(blob_hash, String::new())]);
+        assert_eq!(stats.data_len, 11);
+
+        let completed = tokio::time::timeout(Duration::from_secs(10), async move {
+            loop {
+                if let Event::TransferBlobCompleted { hash, index, .. } = events.recv().await? {
+                    break anyhow::Ok((hash, index));
+                }
+            }
+        })
+        .await
+        .expect("timeout")?;
+        assert_eq!(completed, (blob_hash, 0));
+        provider.shutdown();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blob_sources() -> Result<()> {
+        let dir = testdir!();
+        let src0 = dir.join("src0");
+        let src1 = dir.join("src1");
+        fs::write(&src0, "hello world").await?;
+        fs::write(&src1, "hello world").await?;
+        let (db, _hash) = create_collection(vec![src0.clone().into(), src1.clone().into()]).await?;
+        let provider = Provider::builder(db)
+            .bind_addr("127.0.0.1:0".parse().unwrap())
+            .spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let mut events = provider.subscribe();
+        let blob_hash = Hash::new(b"hello world");
+        let get_blob = || {
+            get::run(
+                Request::new(blob_hash),
+                provider.auth_token().unwrap(),
+                get::Options {
+                    addr: provider.local_address(),
+                    peer_id: Some(provider.peer_id()),
+                    keylog: true,
+                    keypair: None,
+                },
+                || async move { Ok(()) },
+                |_collection| async move { Ok(()) },
+                |_hash, mut stream, _name| async move {
+                    let mut got = Vec::new();
+                    stream.read_to_end(&mut got).await?;
+                    assert_eq!(got, b"hello world");
+                    Ok(stream)
+                },
+            )
+        };
+
+        // A changed file is skipped in favour of the other copy.
+        fs::write(&src0, "goodbye world").await?;
+        tokio::time::timeout(Duration::from_secs(10), get_blob())
+            .await
+            .expect("timeout")?;
+        let stale = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Event::BlobSourceStale { hash, path } = events.recv().await? {
+                    break anyhow::Ok((hash, path));
+                }
+            }
+        })
+        .await
+        .expect("timeout")?;
+        assert_eq!(stale, (blob_hash, src0));
+
+        // Without any unchanged copy the blob is not found.
+        fs::remove_file(&src1).await?;
+        let err = tokio::time::timeout(Duration::from_secs(10), get_blob())
+            .await
+            .expect("timeout")
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::NotFound));
+        Ok(())
+    }
+
+    /// Spawns a provider for a collection of a large random and a small blob.
+    ///
+    /// Returns the provider, the collection hash and the content of the large blob.
+    async fn spawn_large_small_provider(dir: &Path) -> Result<(Provider, Hash, Vec<u8>)> {
+        let mut large = vec![0u8; 100_000];
+        rand::thread_rng().fill_bytes(&mut large);
+        tokio::fs::write(dir.join("large"), &large).await?;
+        tokio::fs::write(dir.join("small"), b"hello world").await?;
+        let (db, collection_hash) =
+            create_collection(vec![dir.join("large").into(), dir.join("small").into()]).await?;
+        let provider = Provider::builder(db)
+            .bind_addr("127.0.0.1:0".parse().unwrap())
+            .spawn()?;
+        Ok((provider, collection_hash, large))
+    }
+
+    /// Runs the request and returns the names and data of the received blobs.
+    async fn get_blobs(
+        provider: &Provider,
+        request: Request,
+    ) -> Result<(Vec<(String, Vec<u8>)>, get::Stats)> {
+        let blobs = std::cell::RefCell::new(Vec::new());
+        let stats = tokio::time::timeout(
+            Duration::from_secs(10),
+            get::run(
+                request,
+                provider.auth_token().unwrap(),
+                get::Options {
+                    addr: provider.local_address(),
+                    peer_id: Some(provider.peer_id()),
+                    keylog: true,
+                    keypair: None,
+                },
+                || async move { Ok(()) },
+                |_collection| async move { Ok(()) },
+                |_hash, mut stream, name| {
+                    let blobs = &blobs;
+                    async move {
+                        let mut got = Vec::new();
+                        stream.read_to_end(&mut got).await?;
+                        blobs.borrow_mut().push((name, got));
+                        Ok(stream)
+                    }
+                },
+            ),
+        )
+        .await
+        .expect("timeout")?;
+        Ok((blobs.into_inner(), stats))
+    }
+
+    #[tokio::test]
+    async fn test_get_ranges() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let (provider, collection_hash, large) = spawn_large_small_provider(&dir).await?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+
+        // A single blob, with ranges in the middle of blocks and past the end.
+        let ranges = RangeSpec::from_ranges([1000..2000, 50_000..50_010, 99_990..200_000]);
+        let request = Request::new(Hash::from(blake3::hash(&large))).with_ranges(ranges);
+        let (blobs, stats) = get_blobs(&provider, request).await?;
+        let expected = [&large[1000..2000], &large[50_000..50_010], &large[99_990..]].concat();
+        assert_eq!(blobs, vec![(String::new(), expected)]);
+        assert_eq!(stats.data_len, 1020);
+
+        // A range entirely past the end of the blob.
+        let request = Request::new(Hash::new(b"hello world")).with_ranges(RangeSpec::from(20..));
+        let (blobs, stats) = get_blobs(&provider, request).await?;
+        assert_eq!(blobs, vec![(String::new(), Vec::new())]);
+        assert_eq!(stats.data_len, 0);
+
+        // The ranges apply to every blob in a collection.
+        let request = Request::new(collection_hash).with_ranges(RangeSpec::from(6..20_000));
+        let (blobs, stats) = get_blobs(&provider, request).await?;
+        assert_eq!(
+            blobs,
+            vec![
+                ("large".to_string(), large[6..20_000].to_vec()),
+                ("small".to_string(), b"world".to_vec()),
+            ]
+        );
+        assert_eq!(stats.data_len, 20_000 - 6 + 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_have() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let (provider, collection_hash, large) = spawn_large_small_provider(&dir).await?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let large_hash = Hash::from(blake3::hash(&large));
+        let small_hash = Hash::new(b"hello world");
+
+        // Blobs we have entirely are skipped, partial blobs only send the rest.
+        let request = Request::new(collection_hash)
+            .with_have(small_hash, RangeSpec::all())
+            .with_have(large_hash, RangeSpec::from(0..50_000))
+            .with_have(large_hash, RangeSpec::from(40_000..60_000));
+        let (blobs, stats) = get_blobs(&provider, request).await?;
+        assert_eq!(blobs, vec![("large".to_string(), large[60_000..].to_vec())]);
+        assert_eq!(stats.data_len, 40_000);
+
+        // Requesting a blob we already have only verifies the size.
+        let request = Request::new(small_hash).with_have(small_hash, RangeSpec::all());
+        let (blobs, stats) = get_blobs(&provider, request).await?;
+        assert_eq!(blobs, vec![(String::new(), Vec::new())]);
+        assert_eq!(stats.data_len, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_include() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let (provider, collection_hash, _large) = spawn_large_small_provider(&dir).await?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let mut events = provider.subscribe();
+
+        let request = Request::new(collection_hash).with_include(Include::Index(1));
+        let (blobs, _stats) = get_blobs(&provider, request).await?;
+        assert_eq!(blobs, vec![("small".to_string(), b"hello world".to_vec())]);
+
+        // The event still reports the position of the blob in the collection.
+        let completed = tokio::time::timeout(Duration::from_secs(10), async move {
+            let mut completed = Vec::new();
+            loop {
+                match events.recv().await? {
+                    Event::TransferBlobCompleted { hash, index, .. } => {
+                        completed.push((hash, index))
+                    }
+                    Event::TransferCollectionCompleted { .. } => break anyhow::Ok(completed),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("timeout")?;
+        assert_eq!(completed, vec![(Hash::new(b"hello world"), 1)]);
+
+        let request = Request::new(collection_hash)
+            .with_include(Include::Glob("l*".to_string()))
+            .with_include(Include::Name("small".to_string()));
+        let (blobs, _stats) = get_blobs(&provider, request).await?;
+        let names: Vec<_> = blobs.into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["large", "small"]);
+
+        let request = Request::new(collection_hash).with_include(Include::Glob("*.png".into()));
+        let (blobs, stats) = get_blobs(&provider, request).await?;
+        assert!(blobs.is_empty());
+        assert_eq!(stats.data_len, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_errors() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let (provider, collection_hash, _large) = spawn_large_small_provider(&dir).await?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let error_code = |err: anyhow::Error| {
+            err.downcast::<get::GetError>()
+                .expect("not a GetError")
+                .code()
+        };
+
+        let request = Request::new(Hash::new(b"missing"));
+        let err = get_blobs(&provider, request).await.unwrap_err();
+        assert_eq!(error_code(err), Some(ErrorCode::NotFound));
+
+        let run = |auth_token, shutdown| {
+            let provider = &provider;
+            get::run(
+                Request::new(collection_hash),
+                auth_token,
+                get::Options {
+                    addr: provider.local_address(),
+                    peer_id: Some(provider.peer_id()),
+                    keylog: true,
+                    keypair: None,
+                },
+                move || async move {
+                    if shutdown {
+                        provider.shutdown();
+                        provider.clone().await.unwrap();
+                    }
+                    Ok(())
+                },
+                |_collection| async move { Ok(()) },
+                |_hash, mut stream, _name| async move {
+                    io::copy(&mut stream, &mut io::sink()).await?;
+                    Ok(stream)
+                },
+            )
+        };
+
+        let err = run(AuthToken::generate(), false).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::BadToken));
+
+        // Shut down the provider once connected.
+        let err = run(provider.auth_token().unwrap(), true).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::ProviderTerminating));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_push() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let (source, collection_hash, large) = spawn_large_small_provider(&dir).await?;
+        let _source_guard = source.cancel_token().drop_guard();
+        let (db, _) =
+            create_collection(vec![dir.join("large").into(), dir.join("small").into()]).await?;
+
+        let push_dir = dir.join("pushed");
+        fs::create_dir(&push_dir).await?;
+        let client = Arc::new(Keypair::generate());
+        let provider = Provider::builder(Database::default())
+            .bind_addr("127.0.0.1:0".parse().unwrap())
+            .push_dir(push_dir.clone())
+            .allow_peer(PeerId::from(client.public()))
+            .spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let mut events = provider.subscribe();
+        let opts = |provider: &Provider| get::Options {
+            addr: provider.local_address(),
+            peer_id: Some(provider.peer_id()),
+            keylog: true,
+            keypair: None,
+        };
+
+        let stats = push::run(
+            &db,
+            collection_hash,
+            provider.auth_token().unwrap(),
+            opts(&provider),
+        )
+        .await?;
+        assert_eq!(stats.data_len, 100_000 + 11);
+        let received = tokio::time::timeout(Duration::from_secs(10), async move {
+            let mut received = Vec::new();
+            loop {
+                match events.recv().await? {
+                    Event::PushBlobReceived { index, size, .. } => received.push((index, size)),
+                    Event::PushCompleted { hash, .. } => break anyhow::Ok((hash, received)),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("timeout")?;
+        assert_eq!(received, (collection_hash, vec![(0, 100_000), (1, 11)]));
+        assert!(push_dir
+            .join(hex::encode(Hash::new(&large).as_ref()))
+            .is_file());
+
+        // The pushed collection can now be fetched from the provider.
+        let (blobs, _stats) = get_blobs(&provider, Request::new(collection_hash)).await?;
+        assert_eq!(
+            blobs,
+            vec![
+                ("large".to_string(), large.clone()),
+                ("small".to_string(), b"hello world".to_vec()),
+            ]
+        );
+
+        // Pushing again verifies the data but keeps the stored blobs.
+        push::run(
+            &db,
+            collection_hash,
+            provider.auth_token().unwrap(),
+            opts(&provider),
+        )
+        .await?;
+
+        let err = push::run(&db, collection_hash, AuthToken::generate(), opts(&provider))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::BadToken));
+
+        // An allowed client still needs the auth token to push.
+        let peer_opts = get::Options {
+            keypair: Some(client),
+            ..opts(&provider)
+        };
+        let err = push::run(
+            &db,
+            collection_hash,
+            AuthToken::generate(),
+            peer_opts.clone(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Forbidden));
+        push::run(
+            &db,
+            collection_hash,
+            provider.auth_token().unwrap(),
+            peer_opts,
+        )
+        .await?;
+
+        // Pushing is disabled by default.
+        let err = push::run(
+            &db,
+            collection_hash,
+            source.auth_token().unwrap(),
+            opts(&source),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Unsupported));
+
+        // A persistent database owns the pushed blobs, so removing them deletes the files.
+        let data_dir = dir.join("data");
+        let persistent = Database::open(&data_dir).await?;
+        let provider = Provider::builder(persistent.clone())
+            .bind_addr("127.0.0.1:0".parse().unwrap())
+            .push_dir(push_dir.clone())
+            .spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        push::run(
+            &db,
+            collection_hash,
+            provider.auth_token().unwrap(),
+            opts(&provider),
+        )
+        .await?;
+        let pushed = data_dir
+            .join("blobs")
+            .join(hex::encode(Hash::new(&large).as_ref()));
+        assert!(pushed.is_file());
+        assert!(persistent.remove(collection_hash).await?);
+        persistent.gc().await?;
+        assert!(!pushed.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let (provider, collection_hash, large) = spawn_large_small_provider(&dir).await?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let opts = get::Options {
+            addr: provider.local_address(),
+            peer_id: Some(provider.peer_id()),
+            keylog: true,
+            keypair: None,
+        };
+
+        let large_hash = Hash::new(&large);
+        let missing_hash = Hash::new(b"missing");
+        let hashes = vec![collection_hash, large_hash, missing_hash];
+        let res = get::query(hashes, provider.auth_token().unwrap(), opts.clone()).await?;
+        assert_eq!(res[0].0, collection_hash);
+        assert!(matches!(res[0].1, Presence::Collection { .. }));
+        assert_eq!(res[1], (large_hash, Presence::Blob { size: 100_000 }));
+        assert_eq!(res[2], (missing_hash, Presence::Missing));
+
+        let err = get::query(vec![large_hash], AuthToken::generate(), opts)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::BadToken));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_capability() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let (provider, collection_hash, large) = spawn_large_small_provider(&dir).await?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let opts = get::Options {
+            addr: provider.local_address(),
+            peer_id: Some(provider.peer_id()),
+            keylog: true,
+            keypair: None,
+        };
+        let get = |hash, credential: Credential| {
+            get::run(
+                Request::new(hash),
+                credential,
+                opts.clone(),
+                || async { Ok(()) },
+                |_collection| async { Ok(()) },
+                |_hash, mut stream, _name| async move {
+                    io::copy(&mut stream, &mut io::sink()).await?;
+                    Ok(stream)
+                },
+            )
+        };
+        let large_hash = Hash::new(&large);
+        let expires = SystemTime::now() + Duration::from_secs(60);
+
+        let capability = provider.capability(vec![collection_hash], expires, Some(2));
+        get(collection_hash, capability.clone().into()).await?;
+        // The blobs of the collection can not be requested directly.
+        let err = get(large_hash, capability.clone().into())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Forbidden));
+        // Both uses are used up.
+        let err = get(collection_hash, capability.into()).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::BadToken));
+
+        let capability = provider.capability(vec![collection_hash], expires, None);
+        get::query(vec![collection_hash], capability.clone(), opts.clone()).await?;
+        let err = get::query(vec![large_hash], capability, opts.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Forbidden));
+
+        let expired = SystemTime::now() - Duration::from_secs(1);
+        let capability = provider.capability(vec![collection_hash], expired, None);
+        let err = get(collection_hash, capability.into()).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::BadToken));
+
+        let other_key = Keypair::generate();
+        let capability = Capability::new(&other_key, vec![collection_hash], expires, None);
+        let err = get(collection_hash, capability.into()).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::BadToken));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auth_tokens() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let (provider, collection_hash, _large) = spawn_large_small_provider(&dir).await?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let opts = get::Options {
+            addr: provider.local_address(),
+            peer_id: Some(provider.peer_id()),
+            keylog: true,
+            keypair: None,
+        };
+        let get = |credential: Credential| {
+            get::run(
+                Request::new(collection_hash),
+                credential,
+                opts.clone(),
+                || async { Ok(()) },
+                |_collection| async { Ok(()) },
+                |_hash, mut stream, _name| async move {
+                    io::copy(&mut stream, &mut io::sink()).await?;
+                    Ok(stream)
+                },
+            )
+        };
+        // Collect the token names reported for the requests.
+        let mut provider_events = provider.subscribe();
+        let (tokens_tx, mut tokens) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                match provider_events.recv().await {
+                    Ok(Event::RequestReceived { token, .. }) => {
+                        tokens_tx.send(token).ok();
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        get(provider.auth_token().unwrap().into()).await?;
+        let token = tokens.recv().await.unwrap();
+        assert_eq!(token.as_deref(), Some(provider::DEFAULT_TOKEN_NAME));
+
+        // Tokens can be managed over RPC.
+        let controller = provider.controller();
+        let team_token = AuthToken::generate();
+        let response = controller
+            .rpc(TokenAddRequest {
+                name: "team".to_string(),
+                token: Some(team_token),
+            })
+            .await??;
+        assert_eq!(response.token, team_token);
+        let err = controller
+            .rpc(TokenAddRequest {
+                name: "team".to_string(),
+                token: None,
+            })
+            .await?;
+        assert!(err.is_err());
+        let names: Vec<_> = controller
+            .server_streaming(TokenListRequest)
+            .await?
+            .map(|item| item.map(|item| item.name))
+            .try_collect()
+            .await?;
+        assert_eq!(names, vec![provider::DEFAULT_TOKEN_NAME, "team"]);
+
+        get(team_token.into()).await?;
+        assert_eq!(tokens.recv().await.unwrap().as_deref(), Some("team"));
+        let expires = SystemTime::now() + Duration::from_secs(60);
+        let capability = provider.capability(vec![collection_hash], expires, None);
+        get(capability.into()).await?;
+        assert_eq!(tokens.recv().await.unwrap(), None);
+
+        controller
+            .rpc(TokenRevokeRequest {
+                name: "team".to_string(),
+            })
+            .await??;
+        let err = get(team_token.into()).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::BadToken));
+        assert!(!provider.revoke_auth_token("team"));
+        assert!(provider.revoke_auth_token(provider::DEFAULT_TOKEN_NAME));
+        assert!(provider.auth_tokens().is_empty());
+        assert_eq!(provider.auth_token(), None);
+        let response = controller.rpc(IdRequest).await?;
+        assert!(response.auth_token.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_allowed_peers() -> Result<()> {
+        setup_logging();
+        let readme = Path::new(env!("CARGO_MANIFEST_DIR")).join("README.md");
+        let (db, hash) = create_collection(vec![readme.into()]).await?;
+        let client = Arc::new(Keypair::generate());
+        let client_id = PeerId::from(client.public());
+        let get = |provider: &Provider, keypair: Option<Arc<Keypair>>, credential: Credential| {
+            let opts = get::Options {
+                addr: provider.local_address(),
+                peer_id: Some(provider.peer_id()),
+                keylog: true,
+                keypair,
+            };
+            get::run(
+                Request::new(hash),
+                credential,
+                opts,
+                || async { Ok(()) },
+                |_collection| async { Ok(()) },
+                |_hash, mut stream, _name| async move {
+                    io::copy(&mut stream, &mut io::sink()).await?;
+                    Ok(stream)
+                },
+            )
+        };
+
+        // An allowed client does not need a credential, others do.
+        let provider = Provider::builder(db.clone())
+            .bind_addr("127.0.0.1:0".parse().unwrap())
+            .allow_peer(client_id)
+            .spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let mut events = provider.subscribe();
+        get(&provider, Some(client.clone()), Credential::Anonymous).await?;
+        match events.recv().await? {
+            Event::ClientConnected { peer_id, .. } => assert_eq!(peer_id, client_id),
+            event => panic!("unexpected event {event:?}"),
+        }
+        get(
+            &provider,
+            Some(client.clone()),
+            AuthToken::generate().into(),
+        )
+        .await?;
+        let err = get(&provider, None, Credential::Anonymous)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Forbidden));
+        let err = get(&provider, None, AuthToken::generate().into())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::BadToken));
+        get(&provider, None, provider.auth_token().unwrap().into()).await?;
+
+        // Only allowed clients can connect, and they still need a valid token.
+        let provider = Provider::builder(db)
+            .bind_addr("127.0.0.1:0".parse().unwrap())
+            .allow_peer(client_id)
+            .peer_auth(PeerAuth::Required)
+            .spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let err = get(&provider, None, provider.auth_token().unwrap().into())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Forbidden));
+        let err = get(
+            &provider,
+            Some(client.clone()),
+            AuthToken::generate().into(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::BadToken));
+        get(
+            &provider,
+            Some(client),
+            provider.auth_token().unwrap().into(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_limits() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let path = dir.join("data");
+        let mut data = vec![0u8; 256 * 1024];
+        rand::thread_rng().fill_bytes(&mut data);
+        fs::write(&path, &data).await?;
+        let (db, hash) = create_collection(vec![path.into()]).await?;
+        let provider = Provider::builder(db)
+            .bind_addr("127.0.0.1:0".parse().unwrap())
+            .connection_rate_limit(512 * 1024)
+            .max_transfers_per_peer(1)
+            .spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let opts = get::Options {
+            addr: provider.local_address(),
+            peer_id: Some(provider.peer_id()),
+            keylog: true,
+            keypair: Some(Arc::new(Keypair::generate())),
+        };
+        let get = |opts: get::Options| {
+            get::run(
+                Request::new(hash),
+                provider.auth_token().unwrap(),
+                opts,
+                || async { Ok(()) },
+                |_collection| async { Ok(()) },
+                |_hash, mut stream, _name| async move {
+                    io::copy(&mut stream, &mut io::sink()).await?;
+                    Ok(stream)
+                },
+            )
+        };
+        let mut events = provider.subscribe();
+        let first = tokio::spawn(get(opts.clone()));
+        loop {
+            match events.recv().await? {
+                Event::TransferThrottled { token, .. } => {
+                    assert_eq!(token.as_deref(), Some(provider::DEFAULT_TOKEN_NAME));
+                    break;
+                }
+                Event::TransferCollectionCompleted { .. } => panic!("transfer not throttled"),
+                _ => {}
+            }
+        }
+
+        // The client already has a transfer in progress.
+        let err = get(opts.clone()).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::TooManyRequests));
+        loop {
+            if let Event::RequestRejected { .. } = events.recv().await? {
+                break;
+            }
+        }
+        // Other clients are not affected by this.
+        get(get::Options {
+            keypair: None,
+            ..opts.clone()
+        })
+        .await?;
+
+        first.await??;
+        get(opts).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nested_collection() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let mut sources = Vec::new();
+        for (name, content) in [
+            ("a/x.txt", "x"),
+            ("a/y.txt", "y"),
+            ("b/c/x.txt", "x"),
+            ("top.txt", "top"),
+        ] {
+            let path = dir.join(name.replace('/', "_"));
+            tokio::fs::write(&path, content).await?;
+            sources.push(provider::DataSource::with_name(path, name.to_string()));
+        }
+        let (db, collection_hash) = provider::create_nested_collection(sources.clone()).await?;
+        let collection = match db.get(&collection_hash) {
+            Some(provider::BlobOrCollection::Collection { data, .. }) => {
+                blobs::Collection::from_bytes(&data)?
+            }
+            _ => panic!("collection not found"),
+        };
+        let names: Vec<_> = collection.blobs().iter().map(|b| b.name()).collect();
+        assert_eq!(names, ["a", "b", "top.txt"]);
+        assert!(collection.blobs()[0].is_collection());
+        assert_eq!(collection.total_blobs_size(), 6);
+
+        // Unchanged directories result in the same nested collection.
+        sources.pop();
+        let (other_db, other_hash) = provider::create_nested_collection(sources).await?;
+        let other = match other_db.get(&other_hash) {
+            Some(provider::BlobOrCollection::Collection { data, .. }) => {
+                blobs::Collection::from_bytes(&data)?
+            }
+            _ => panic!("collection not found"),
+        };
+        assert_ne!(collection_hash, other_hash);
+        assert_eq!(collection.blobs()[0], other.blobs()[0]);
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let provider = provider::Provider::builder(db).bind_addr(addr).spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let mut events = provider.subscribe();
+        let connect = |version: u64| {
+            let addr = provider.local_address();
+            let peer_id = provider.peer_id();
+            async move {
+                let endpoint = get::make_client_endpoint(
+                    "127.0.0.1:0".parse().unwrap(),
+                    &Keypair::generate(),
+                    Some(peer_id),
+                    vec![protocol::alpn(version)],
+                    false,
+                )?;
+                anyhow::Ok(endpoint.connect(addr, "localhost")?.await?)
+            }
+        };
+        let get = |connection, request| async {
+            let pages = std::cell::RefCell::new(Vec::new());
+            let blobs = std::cell::RefCell::new(Vec::new());
+            get::run_connection(
+                connection,
+                request,
+                provider.auth_token().unwrap().into(),
+                std::time::Instant::now(),
+                || async move { Ok(()) },
+                |page| {
+                    pages.borrow_mut().push(page.path.clone());
+                    async move { Ok(()) }
+                },
+                |_hash, mut stream, name| {
+                    let blobs = &blobs;
+                    async move {
+                        let mut got = String::new();
+                        stream.read_to_string(&mut got).await?;
+                        blobs.borrow_mut().push((name, got));
+                        Ok(stream)
+                    }
+                },
+            )
+            .await?;
+            anyhow::Ok((pages.into_inner(), blobs.into_inner()))
+        };
+
+        let (pages, blobs) = get(connect(5).await?, Request::new(collection_hash)).await?;
+        assert_eq!(pages, ["", "a/", "b/", "b/c/"]);
+        let expected = [
+            ("a/x.txt", "x"),
+            ("a/y.txt", "y"),
+            ("b/c/x.txt", "x"),
+            ("top.txt", "top"),
+        ]
+        .map(|(name, content)| (name.to_string(), content.to_string()));
+        assert_eq!(blobs, expected);
+
+        // The events identify blobs of nested collections by the indices of their parents.
+        let completed = tokio::time::timeout(Duration::from_secs(10), async {
+            let mut completed = Vec::new();
+            loop {
+                match events.recv().await? {
+                    Event::TransferBlobCompleted { index, parents, .. } => {
+                        completed.push((parents, index))
+                    }
+                    Event::TransferCollectionCompleted { .. } => break anyhow::Ok(completed),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("timeout")?;
+        assert_eq!(
+            completed,
+            [(vec![0], 0), (vec![0], 1), (vec![1, 0], 0), (vec![], 2)]
+        );
+
+        // Selecting a nested collection gets all of its blobs.
+        let request = Request::new(collection_hash).with_include(Include::Name("b".to_string()));
+        let (pages, blobs) = get(connect(5).await?, request).await?;
+        assert_eq!(pages, ["", "b/", "b/c/"]);
+        assert_eq!(blobs, expected[2..3]);
+
+        // Older getters do not understand nested collections.
+        let err = get(connect(4).await?, Request::new(collection_hash))
+            .await
+            .unwrap_err();
+        let err = get::GetError::from(err);
+        assert_eq!(err.code(), Some(ErrorCode::Unsupported));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_large_collection() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let path = dir.join("data");
+        tokio::fs::write(&path, b"hello world").await?;
+
+        // Long names make the collection span multiple pages.
+        let num_blobs = 3000;
+        let name = |i: u64| format!("{}/{i}", "a".repeat(500));
+        let sources = (0..num_blobs)
+            .map(|i| provider::DataSource::with_name(path.clone(), name(i)))
+            .collect();
+        let (db, collection_hash) = provider::create_collection(sources).await?;
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let provider = provider::Provider::builder(db).bind_addr(addr).spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+
+        for (version, expected_pages) in [(3, 1), (4, 2)] {
+            let endpoint = get::make_client_endpoint(
+                "127.0.0.1:0".parse().unwrap(),
+                &Keypair::generate(),
+                Some(provider.peer_id()),
+                vec![protocol::alpn(version)],
+                false,
+            )?;
+            let connect = || async {
+                anyhow::Ok(
+                    endpoint
+                        .connect(provider.local_address(), "localhost")?
+                        .await?,
+                )
+            };
+
+            let pages = std::cell::RefCell::new(Vec::new());
+            let names = std::cell::RefCell::new(Vec::new());
+            get::run_connection(
+                connect().await?,
+                Request::new(collection_hash),
+                provider.auth_token().unwrap().into(),
+                std::time::Instant::now(),
+                || async move { Ok(()) },
+                |page| {
+                    assert_eq!(page.total_entries, num_blobs);
+                    assert_eq!(page.total_blobs_size, num_blobs * 11);
+                    pages
+                        .borrow_mut()
+                        .push((page.offset, page.blobs.len() as u64));
+                    async move { Ok(()) }
+                },
+                |_hash, mut stream, name| {
+                    let names = &names;
+                    async move {
+                        let mut got = Vec::new();
+                        stream.read_to_end(&mut got).await?;
+                        assert_eq!(got, b"hello world");
+                        names.borrow_mut().push(name);
+                        Ok(stream)
+                    }
+                },
+            )
+            .await?;
+            let pages = pages.into_inner();
+            assert_eq!(pages.len(), expected_pages, "version {version}");
+            let mut offset = 0;
+            for (page_offset, len) in pages {
+                assert_eq!(page_offset, offset);
+                offset += len;
+            }
+            assert_eq!(offset, num_blobs);
+            let mut expected: Vec<_> = (0..num_blobs).map(name).collect();
+            expected.sort();
+            assert_eq!(names.into_inner(), expected);
+
+            // Blobs from a later page can be requested on their own.
+            let names = std::cell::RefCell::new(Vec::new());
+            let request = Request::new(collection_hash).with_include(Include::Index(num_blobs - 1));
+            get::run_connection(
+                connect().await?,
+                request,
+                provider.auth_token().unwrap().into(),
+                std::time::Instant::now(),
+                || async move { Ok(()) },
+                |_page| async move { Ok(()) },
+                |_hash, mut stream, name| {
+                    let names = &names;
+                    async move {
+                        tokio::io::copy(&mut stream, &mut tokio::io::sink()).await?;
+                        names.borrow_mut().push(name);
+                        Ok(stream)
+                    }
+                },
+            )
+            .await?;
+            assert_eq!(names.into_inner(), vec![expected.pop().unwrap()]);
+        }
+        Ok(())
+    }
+
+    /// Runs getters offering different sets of protocol versions against the provider.
+    #[tokio::test]
+    async fn test_protocol_versions() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let (provider, collection_hash, large) = spawn_large_small_provider(&dir).await?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let expected = vec![
+            ("large".to_string(), large),
+            ("small".to_string(), b"hello world".to_vec()),
+        ];
+
+        let connect = |versions: &[u64]| {
+            let alpns = versions.iter().map(|v| protocol::alpn(*v)).collect();
+            let addr = provider.local_address();
+            let peer_id = provider.peer_id();
+            async move {
+                let endpoint = get::make_client_endpoint(
+                    "127.0.0.1:0".parse().unwrap(),
+                    &Keypair::generate(),
+                    Some(peer_id),
+                    alpns,
+                    false,
+                )?;
+                anyhow::Ok(endpoint.connect(addr, "localhost")?.await?)
+            }
+        };
+        let matrix: [(&[u64], u64); 9] = [
+            (&[1], 1),
+            (&[2], 2),
+            (&[3], 3),
+            (&[4], 4),
+            (&[5], 5),
+            (&[1, 2], 2),
+            (&[2, 1], 2),
+            (&[1, 2, 3], 3),
+            (&[1, 2, 3, 4, 5], 5),
+        ];
+        for (versions, expected_version) in matrix {
+            let connection = connect(versions).await?;
+            assert_eq!(protocol::negotiated_version(&connection)?, expected_version);
+            let blobs = std::cell::RefCell::new(Vec::new());
+            get::run_connection(
+                connection,
+                Request::new(collection_hash),
+                provider.auth_token().unwrap().into(),
+                std::time::Instant::now(),
+                || async move { Ok(()) },
+                |_collection| async move { Ok(()) },
+                |_hash, mut stream, name| {
+                    let blobs = &blobs;
+                    async move {
+                        let mut got = Vec::new();
+                        stream.read_to_end(&mut got).await?;
+                        blobs.borrow_mut().push((name, got));
+                        Ok(stream)
+                    }
+                },
+            )
+            .await?;
+            assert_eq!(blobs.into_inner(), expected, "versions {versions:?}");
+        }
+
+        // Versions the provider does not support are refused.
+        assert!(connect(&[protocol::VERSION + 1]).await.is_err());
+
+        // Protocol version 1 can only request entire blobs.
+        let connection = connect(&[1]).await?;
+        let request = Request::new(collection_hash).with_include(Include::Index(0));
+        let res = get::run_connection(
+            connection,
+            request,
+            provider.auth_token().unwrap().into(),
+            std::time::Instant::now(),
+            || async move { Ok(()) },
+            |_collection| async move { Ok(()) },
+            |_hash, stream, _name| async move { Ok(stream) },
+        )
+        .await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_ipv6() {
         let readme = Path::new(env!("CARGO_MANIFEST_DIR")).join("README.md");
@@ -481,18 +1595,19 @@ mod tests {
                 return;
             }
         };
-        let auth_token = provider.auth_token();
+        let auth_token = provider.auth_token().unwrap();
         let addr = provider.local_address();
         let peer_id = Some(provider.peer_id());
         tokio::time::timeout(
             Duration::from_secs(10),
             get::run(
-                hash,
+                Request::new(hash),
                 auth_token,
                 get::Options {
                     addr,
                     peer_id,
                     keylog: true,
+                    keypair: None,
                 },
                 || async move { Ok(()) },
                 |_collection| async move { Ok(()) },
@@ -524,7 +1639,9 @@ mod tests {
             Duration::from_secs(10),
             get::run_ticket(
                 &ticket,
+                Request::new(ticket.hash()),
                 true,
+                None,
                 16,
                 || {
                     on_connected = true;
@@ -550,4 +1667,126 @@ mod tests {
         assert!(on_collection);
         assert!(on_blob);
     }
+
+    #[tokio::test]
+    async fn test_public_ticket() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let path = dir.join("data");
+        fs::write(&path, b"hello world!").await?;
+        let readme = Path::new(env!("CARGO_MANIFEST_DIR")).join("README.md");
+        let (db, readme_hash) = create_collection(vec![readme.clone().into()]).await?;
+        let (db2, hash) = create_collection(vec![path.into()]).await?;
+        db.union_with(db2.to_inner()).unwrap();
+        let provider = Provider::builder(db)
+            .bind_addr("127.0.0.1:0".parse().unwrap())
+            .public(true)
+            .spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+
+        let ticket = provider.ticket_for_hashes(vec![readme_hash, hash])?;
+        assert_eq!(ticket.credential(), None);
+        assert_eq!(ticket.hashes(), &[readme_hash, hash]);
+        let readme_size = fs::metadata(&readme).await?.len();
+        assert_eq!(ticket.size(), Some(readme_size + 12));
+        assert_eq!(ticket.num_files(), Some(2));
+        let ticket: Ticket = ticket.to_string().parse()?;
+        for &hash in ticket.hashes() {
+            get::run_ticket(
+                &ticket,
+                Request::new(hash),
+                true,
+                None,
+                16,
+                || async { Ok(()) },
+                |_| async { Ok(()) },
+                |_hash, mut stream, _name| async move {
+                    io::copy(&mut stream, &mut io::sink()).await?;
+                    Ok(stream)
+                },
+            )
+            .await?;
+        }
+
+        // Public access does not allow pushing.
+        let err = push::run(&db2, hash, AuthToken::generate(), {
+            get::Options {
+                addr: provider.local_address(),
+                peer_id: Some(provider.peer_id()),
+                keylog: true,
+                keypair: None,
+            }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Forbidden));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tags() -> Result<()> {
+        setup_logging();
+        let dir: PathBuf = testdir!();
+        let path = dir.join("data");
+        fs::write(&path, b"hello world!").await?;
+        let readme = Path::new(env!("CARGO_MANIFEST_DIR")).join("README.md");
+        let (db, readme_hash) = create_collection(vec![readme.into()]).await?;
+        let (db2, hash) = create_collection(vec![path.into()]).await?;
+        db.union_with(db2.to_inner()).unwrap();
+        db.set_tag("nightly".to_string(), Some(readme_hash)).await?;
+        let provider = Provider::builder(db.clone())
+            .bind_addr("127.0.0.1:0".parse().unwrap())
+            .spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let opts = get::Options {
+            addr: provider.local_address(),
+            peer_id: Some(provider.peer_id()),
+            keylog: true,
+            keypair: None,
+        };
+
+        let resolved =
+            get::resolve("nightly", provider.auth_token().unwrap(), opts.clone()).await?;
+        assert_eq!(resolved, readme_hash);
+        let err = get::resolve("missing", provider.auth_token().unwrap(), opts.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::NotFound));
+        let expires = SystemTime::now() + Duration::from_secs(60);
+        let capability = provider.capability(vec![hash], expires, None);
+        let err = get::resolve("nightly", capability, opts).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Forbidden));
+
+        let ticket: Ticket = provider.ticket_for_tag("nightly")?.to_string().parse()?;
+        assert_eq!(ticket.tag(), Some("nightly"));
+        assert_eq!(ticket.hashes(), &[readme_hash]);
+        let resolved = get::resolve_ticket(&ticket, true, None, 16).await?;
+        assert_eq!(resolved, readme_hash);
+        // The capability of the ticket is for the tag, so it covers the data the tag is
+        // moved to, but no longer the data it referred to.
+        db.set_tag("nightly".to_string(), Some(hash)).await?;
+        let resolved = get::resolve_ticket(&ticket, true, None, 16).await?;
+        assert_eq!(resolved, hash);
+        let get_ticket = |hash| {
+            get::run_ticket(
+                &ticket,
+                Request::new(hash),
+                true,
+                None,
+                16,
+                || async { Ok(()) },
+                |_| async { Ok(()) },
+                |_hash, mut stream, _name| async move {
+                    let mut data = Vec::new();
+                    stream.read_to_end(&mut data).await?;
+                    assert_eq!(data, b"hello world!");
+                    Ok(stream)
+                },
+            )
+        };
+        get_ticket(hash).await?;
+        let err = get_ticket(readme_hash).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Forbidden));
+        Ok(())
+    }
 }
@@ -1,5 +1,8 @@
-use std::collections::{BTreeMap, HashMap};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr};
 
@@ -11,18 +14,20 @@ use indicatif::{
     HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState,
     ProgressStyle,
 };
-use iroh::protocol::AuthToken;
-use iroh::provider::{Database, Provider, Ticket};
+use iroh::blobs::{EntryKind, Metadata, Timestamp};
+use iroh::protocol::{AuthToken, Credential, Include, Presence, RangeSpec, Request};
+use iroh::provider::{Database, PeerAuth, Provider, Ticket};
 use iroh::rpc_protocol::*;
 use iroh::rpc_protocol::{
     ListRequest, ProvideRequest, ProviderRequest, ProviderResponse, ProviderService, VersionRequest,
 };
 use quic_rpc::transport::quinn::{QuinnConnection, QuinnServerEndpoint};
 use quic_rpc::{RpcClient, ServiceEndpoint};
+use tokio::io::AsyncWriteExt;
 use tracing_subscriber::{prelude::*, EnvFilter};
 mod main_util;
 
-use iroh::{get, provider, Hash, Keypair, PeerId};
+use iroh::{get, path_from_name, provider, Hash, Keypair, PeerId};
 use main_util::Blake3Cid;
 
 use crate::main_util::{iroh_data_root, pathbuf_from_name};
@@ -104,6 +109,33 @@ enum Commands {
         /// Optional rpc port, defaults to 4919. Set to 0 to disable RPC.
         #[clap(long, default_value_t = ProviderRpcPort::Enabled(DEFAULT_RPC_PORT))]
         rpc_port: ProviderRpcPort,
+        /// Create a nested collection for every directory. Getters need to support protocol version 5 or newer.
+        #[clap(long)]
+        nested: bool,
+        /// Record permissions, modification times, symlinks and directories.
+        #[clap(long)]
+        metadata: bool,
+        /// Copy the data into the iroh data directory, so it is still served if the original files change or are removed.
+        #[clap(long)]
+        copy: bool,
+        /// Give the client with this PeerId full access without an auth token. Can be given multiple times.
+        #[clap(long)]
+        allow_peer: Vec<PeerId>,
+        /// Only accept connections from the clients given by --allow-peer, which still need an auth token.
+        #[clap(long)]
+        require_allowed_peer: bool,
+        /// Allow anyone to fetch the data without an auth token. Tickets will not contain a credential.
+        #[clap(long)]
+        public: bool,
+        /// Tag the added data with this name instead of pinning it, so it can be fetched by the tag. The ticket refers to the tag.
+        #[clap(long)]
+        tag: Option<String>,
+        /// Watch the path and add it again after it changed, printing the new collection hash and ticket.
+        #[clap(long, requires = "path")]
+        watch: bool,
+        /// How long to wait for further changes before adding the path again, in milliseconds.
+        #[clap(long, default_value_t = 500, requires = "watch")]
+        debounce: u64,
     },
     /// List hashes
     #[clap(about = "List hashes")]
@@ -119,6 +151,20 @@ enum Commands {
         #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
         rpc_port: u16,
     },
+    /// Write a collection from the database of a running provider to a directory
+    #[clap(about = "Write a collection stored by the provider to a directory")]
+    Export {
+        /// The hash of the collection to export.
+        hash: Blake3Cid,
+        /// The directory to write the files to.
+        out: PathBuf,
+        /// Overwrite existing files.
+        #[clap(long, default_value_t = false)]
+        force: bool,
+        /// Optional rpc port, defaults to 4919
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
     /// Shutdown
     #[clap(about = "Shutdown provider")]
     Shutdown {
@@ -145,24 +191,52 @@ enum Commands {
         /// Optional rpc port, defaults to 4919
         #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
         rpc_port: u16,
+        /// Create a nested collection for every directory. Getters need to support protocol version 5 or newer.
+        #[clap(long)]
+        nested: bool,
+        /// Record permissions, modification times, symlinks and directories.
+        #[clap(long)]
+        metadata: bool,
+        /// Copy the data into the data directory of the provider, so it is still served if the original files change or are removed.
+        #[clap(long)]
+        copy: bool,
+        /// Tag the added data with this name instead of pinning it, moving the tag if it already exists. Data no longer tagged is removed by gc.
+        #[clap(long)]
+        tag: Option<String>,
     },
     /// Fetch some data by hash.
     #[clap(about = "Fetch the data from the hash")]
     Get {
         /// The root hash to retrieve.
-        hash: Blake3Cid,
+        #[clap(required_unless_present = "tag")]
+        hash: Option<Blake3Cid>,
+        /// Retrieve the data the provider's tag with this name refers to, instead of a hash.
+        #[clap(long, conflicts_with = "hash")]
+        tag: Option<String>,
         /// PeerId of the provider.
         #[clap(long, short)]
         peer: PeerId,
-        /// The authentication token to present to the server.
+        /// The authentication token or a capability token to present to the server. Not needed if the provider is public or allows the PeerId of --keypair.
+        #[clap(long)]
+        auth_token: Option<String>,
+        /// Path to the keypair identifying this client to the provider, created if it does not exist. Defaults to a new keypair for every connection.
         #[clap(long)]
-        auth_token: String,
+        keypair: Option<PathBuf>,
         /// Optional address of the provider, defaults to 127.0.0.1:4433.
         #[clap(long, short)]
         addr: Option<SocketAddr>,
         /// Optional path to a new directory in which to save the file(s). If none is specified writes the data to STDOUT.
         #[clap(long, short)]
         out: Option<PathBuf>,
+        /// Only fetch the given byte ranges of the data, e.g. `0..1024` or `1024..`. For a collection the ranges apply to every file.
+        #[clap(long)]
+        range: Option<RangeSpec>,
+        /// Only fetch the files of a collection matching this glob pattern, e.g. `images/*.png`. Can be given multiple times.
+        #[clap(long)]
+        include: Vec<String>,
+        /// Do not restore the permissions, modification times, symlinks and directories recorded in the collection.
+        #[clap(long)]
+        ignore_metadata: bool,
     },
     /// Fetches some data from a ticket,
     ///
@@ -173,12 +247,43 @@ enum Commands {
         about = "Fetch the data using a ticket for all provider information and authentication."
     )]
     GetTicket {
-        /// Optional path to a new directory in which to save the file(s). If none is specified writes the data to STDOUT.
+        /// Optional path to a new directory in which to save the file(s). If none is specified writes the data to STDOUT. Required for tickets with several hashes, which are each saved to a subdirectory named after the hash.
         #[clap(long, short)]
         out: Option<PathBuf>,
-        /// Ticket containing everything to retrieve a hash from provider.
+        /// Only fetch the given byte ranges of the data, e.g. `0..1024` or `1024..`. For a collection the ranges apply to every file.
+        #[clap(long)]
+        range: Option<RangeSpec>,
+        /// Only fetch the files of a collection matching this glob pattern, e.g. `images/*.png`. Can be given multiple times.
+        #[clap(long)]
+        include: Vec<String>,
+        /// Do not restore the permissions, modification times, symlinks and directories recorded in the collection.
+        #[clap(long)]
+        ignore_metadata: bool,
+        /// Path to the keypair identifying this client to the provider, created if it does not exist. Defaults to a new keypair for every connection.
+        #[clap(long)]
+        keypair: Option<PathBuf>,
+        /// Ticket containing everything to retrieve a hash from provider, either as printed by the provider or as an iroh://ticket/ URI.
         ticket: Ticket,
     },
+    /// Check which of the hashes a provider has, without fetching any data.
+    #[clap(about = "Check which hashes a provider has")]
+    Has {
+        /// The hashes to look up.
+        #[clap(required = true)]
+        hashes: Vec<Blake3Cid>,
+        /// PeerId of the provider.
+        #[clap(long, short)]
+        peer: PeerId,
+        /// The authentication token or a capability token to present to the server. Not needed if the provider is public or allows the PeerId of --keypair.
+        #[clap(long)]
+        auth_token: Option<String>,
+        /// Path to the keypair identifying this client to the provider, created if it does not exist. Defaults to a new keypair for every connection.
+        #[clap(long)]
+        keypair: Option<PathBuf>,
+        /// Optional address of the provider, defaults to 127.0.0.1:4433.
+        #[clap(long, short)]
+        addr: Option<SocketAddr>,
+    },
     /// List Provide Addresses
     #[clap(about = "List addresses")]
     Addresses {
@@ -186,6 +291,83 @@ enum Commands {
         #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
         rpc_port: u16,
     },
+    /// Manage the auth tokens of a running provider
+    #[clap(about = "Add, revoke and list auth tokens")]
+    Token {
+        #[clap(subcommand)]
+        command: TokenCommands,
+        /// Optional rpc port, defaults to 4919
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Remove a blob or collection from a running provider
+    #[clap(about = "Remove a blob or collection, the data it refers to is removed by gc")]
+    Remove {
+        /// The hash to remove and unpin.
+        hash: Blake3Cid,
+        /// Optional rpc port, defaults to 4919
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Remove everything which is not reachable from a pinned or tagged hash from a running provider
+    #[clap(about = "Remove all data which is not reachable from a pinned or tagged hash")]
+    Gc {
+        /// Optional rpc port, defaults to 4919
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Manage the tags of a running provider, names for hashes which are kept by gc
+    #[clap(about = "Set, delete and list tags")]
+    Tag {
+        #[clap(subcommand)]
+        command: TagCommands,
+        /// Optional rpc port, defaults to 4919
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum TokenCommands {
+    /// Add an auth token identified by a name, which is reported in the provider's events.
+    #[clap(about = "Add an auth token")]
+    Add {
+        /// The name of the token.
+        name: String,
+        /// The token to add, defaults to random generated.
+        #[clap(long)]
+        token: Option<String>,
+    },
+    /// Revoke an auth token, it can no longer be used for new requests.
+    #[clap(about = "Revoke an auth token")]
+    Revoke {
+        /// The name of the token.
+        name: String,
+    },
+    /// List the names and auth tokens
+    #[clap(about = "List auth tokens")]
+    List,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum TagCommands {
+    /// Set a tag to a hash of the database, moving it if it already exists.
+    #[clap(about = "Set a tag")]
+    Set {
+        /// The name of the tag.
+        name: String,
+        /// The hash the tag refers to.
+        hash: Blake3Cid,
+    },
+    /// Delete a tag, the data it refers to is removed by gc unless it is pinned.
+    #[clap(about = "Delete a tag")]
+    Delete {
+        /// The name of the tag.
+        name: String,
+    },
+    /// List the tags and the hashes they refer to
+    #[clap(about = "List tags")]
+    List,
 }
 
 // Note about writing to STDOUT vs STDERR
@@ -204,8 +386,13 @@ async fn make_rpc_client(
 ) -> anyhow::Result<RpcClient<ProviderService, QuinnConnection<ProviderResponse, ProviderRequest>>>
 {
     let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into();
-    let endpoint =
-        iroh::get::make_client_endpoint(bind_addr, None, vec![RPC_ALPN.to_vec()], false)?;
+    let endpoint = iroh::get::make_client_endpoint(
+        bind_addr,
+        &Keypair::generate(),
+        None,
+        vec![RPC_ALPN.to_vec()],
+        false,
+    )?;
     let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), rpc_port);
     let server_name = "localhost".to_string();
     let connection = QuinnConnection::new(endpoint, addr, server_name);
@@ -414,6 +601,15 @@ where
                 collection_hash = Some(hash);
                 break;
             }
+            ProvideProgress::Changed { .. } => {
+                anyhow::bail!("Got changes before the data was added");
+            }
+            ProvideProgress::ChangeFailed(e) => {
+                if let Some(mp) = mp.take() {
+                    mp.error();
+                }
+                anyhow::bail!("Error while adding data again: {}", e);
+            }
             ProvideProgress::Abort(e) => {
                 if let Some(mp) = mp.take() {
                     mp.error();
@@ -444,6 +640,14 @@ fn print_add_response(hash: Hash, entries: Vec<ProvideResponseEntry>) {
     println!("Collection: {}", Blake3Cid::new(hash));
 }
 
+fn import_mode(copy: bool) -> ImportMode {
+    if copy {
+        ImportMode::Copy
+    } else {
+        ImportMode::Reference
+    }
+}
+
 const PROGRESS_STYLE: &str =
     "{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})";
 
@@ -492,43 +696,93 @@ async fn main_impl() -> Result<()> {
     let r = match cli.command {
         Commands::Get {
             hash,
+            tag,
             peer,
             auth_token,
+            keypair,
             addr,
             out,
+            range,
+            include,
+            ignore_metadata,
         } => {
             let mut opts = get::Options {
                 peer_id: Some(peer),
                 keylog: cli.keylog,
+                keypair: get_client_keypair(keypair).await?,
                 ..Default::default()
             };
             if let Some(addr) = addr {
                 opts.addr = addr;
             }
-            let token = AuthToken::from_str(&auth_token)
-                .context("Wrong format for authentication token")?;
+            let token = parse_credential(auth_token)?;
+            let hash = match (hash, tag) {
+                (Some(hash), _) => *hash.as_hash(),
+                (None, Some(tag)) => get::resolve(&tag, token.clone(), opts.clone()).await?,
+                (None, None) => unreachable!("clap requires a hash or a tag"),
+            };
             let get = GetInteractive::Hash {
-                hash: *hash.as_hash(),
+                request: make_request(hash, range, include),
                 opts,
                 token,
             };
             tokio::select! {
                 biased;
-                res = get_interactive(get, out) => res,
+                res = get_interactive(get, out, ignore_metadata) => res,
                 _ = tokio::signal::ctrl_c() => {
                     println!("Ending transfer early...");
                     Ok(())
                 }
             }
         }
-        Commands::GetTicket { out, ticket } => {
-            let get = GetInteractive::Ticket {
-                ticket,
-                keylog: cli.keylog,
+        Commands::GetTicket {
+            out,
+            range,
+            include,
+            ignore_metadata,
+            keypair,
+            ticket,
+        } => {
+            let keypair = get_client_keypair(keypair).await?;
+            // Tickets can have several root hashes, each is fetched into its own directory
+            // below `out`.  A ticket with a tag is for the hash the tag currently refers to.
+            let gets = async {
+                let hashes = match ticket.tag() {
+                    Some(_) => vec![
+                        get::resolve_ticket(
+                            &ticket,
+                            cli.keylog,
+                            keypair.clone(),
+                            MAX_CONCURRENT_DIALS,
+                        )
+                        .await?,
+                    ],
+                    None => ticket.hashes().to_vec(),
+                };
+                let several = hashes.len() > 1;
+                anyhow::ensure!(
+                    !several || out.is_some(),
+                    "The ticket is for {} hashes, use --out to save them",
+                    hashes.len()
+                );
+                for hash in hashes {
+                    let get = GetInteractive::Ticket {
+                        request: make_request(hash, range.clone(), include.clone()),
+                        ticket: ticket.clone(),
+                        keylog: cli.keylog,
+                        keypair: keypair.clone(),
+                    };
+                    let out = match out {
+                        Some(ref out) if several => Some(out.join(hash.to_string())),
+                        _ => out.clone(),
+                    };
+                    get_interactive(get, out, ignore_metadata).await?;
+                }
+                Ok(())
             };
             tokio::select! {
                 biased;
-                res = get_interactive(get, out) => res,
+                res = gets => res,
                 _ = tokio::signal::ctrl_c() => {
                     println!("Ending transfer early...");
                     Ok(())
@@ -540,17 +794,19 @@ async fn main_impl() -> Result<()> {
             addr,
             auth_token,
             rpc_port,
+            nested,
+            metadata,
+            copy,
+            allow_peer,
+            require_allowed_peer,
+            public,
+            tag,
+            watch,
+            debounce,
         } => {
             let iroh_data_root = iroh_data_root()?;
-            let db = {
-                if iroh_data_root.is_dir() {
-                    // try to load db
-                    Database::load(&iroh_data_root).await?
-                } else {
-                    // directory does not exist, create an empty db
-                    Database::default()
-                }
-            };
+            // everything added to the db is written to disk right away
+            let db = Database::open(&iroh_data_root).await?;
             let key = Some(iroh_data_root.join("keypair"));
 
             let provider = provide(
@@ -560,6 +816,9 @@ async fn main_impl() -> Result<()> {
                 key,
                 cli.keylog,
                 rpc_port.into(),
+                allow_peer,
+                require_allowed_peer,
+                public,
             )
             .await?;
             let controller = provider.controller();
@@ -583,12 +842,61 @@ async fn main_impl() -> Result<()> {
                         // return the TempPath to keep it alive
                         (path_buf, Some(path))
                     };
+                    let label = match tmp_path {
+                        Some(_) => None,
+                        None => path
+                            .file_name()
+                            .map(|name| name.to_string_lossy().to_string()),
+                    };
+                    let make_ticket = |hash| {
+                        let mut ticket = provider.ticket(hash)?;
+                        if let Some(label) = &label {
+                            ticket = ticket.with_label(label.clone());
+                        }
+                        if let Some(tag) = &tag {
+                            ticket = ticket.with_tag(tag.clone());
+                        }
+                        anyhow::Ok(ticket)
+                    };
                     // tell the provider to add the data
-                    let stream = controller.server_streaming(ProvideRequest { path }).await?;
-                    let (hash, entries) = aggregate_add_response(stream).await?;
+                    let mut stream = controller
+                        .server_streaming(ProvideRequest {
+                            path: path.clone(),
+                            nested,
+                            metadata,
+                            import: import_mode(copy),
+                            tag: tag.clone(),
+                            watch: watch.then(|| Duration::from_millis(debounce)),
+                        })
+                        .await?;
+                    let (hash, entries) = aggregate_add_response(&mut stream).await?;
                     print_add_response(hash, entries);
-                    let ticket = provider.ticket(hash)?;
-                    println!("All-in-one ticket: {ticket}");
+                    println!("All-in-one ticket: {}", make_ticket(hash)?);
+                    // when watching, the provider adds the data again after every change
+                    while let Some(item) = stream.next().await {
+                        match item? {
+                            ProvideProgress::Changed { paths } => {
+                                println!();
+                                println!(
+                                    "{} paths changed, adding {} again...",
+                                    paths.len(),
+                                    path.display()
+                                );
+                                // a failed change is reported, the provider keeps watching
+                                match aggregate_add_response(&mut stream).await {
+                                    Ok((hash, entries)) => {
+                                        print_add_response(hash, entries);
+                                        println!("All-in-one ticket: {}", make_ticket(hash)?);
+                                    }
+                                    Err(e) => eprintln!("{e:#}"),
+                                }
+                            }
+                            ProvideProgress::Abort(e) => {
+                                anyhow::bail!("Error while watching {}: {}", path.display(), e);
+                            }
+                            item => anyhow::bail!("Unexpected progress while watching: {item:?}"),
+                        }
+                    }
                     anyhow::Ok(tmp_path)
                 })
             };
@@ -604,14 +912,14 @@ async fn main_impl() -> Result<()> {
                     res?;
                 }
             }
-            // persist the db to disk.
-            db.save(&iroh_data_root).await?;
-
             // the future holds a reference to the temp file, so we need to
-            // keep it for as long as the provider is running. The drop(fut)
-            // makes this explicit.
+            // keep it for as long as the provider is running. Awaiting the
+            // aborted future makes sure the import is gone before saving.
             fut.abort();
-            drop(fut);
+            fut.await.ok();
+
+            // compact the entries added since the db was last saved.
+            db.save(&iroh_data_root).await?;
             Ok(())
         }
         Commands::List { rpc_port } => {
@@ -663,6 +971,59 @@ async fn main_impl() -> Result<()> {
             }
             Ok(())
         }
+        Commands::Export {
+            hash,
+            out,
+            force,
+            rpc_port,
+        } => {
+            let client = make_rpc_client(rpc_port).await?;
+            // the provider may run in another directory
+            let out_dir = std::env::current_dir()?.join(out);
+            let hash = *hash.as_hash();
+            let mut state = ValidateProgressState::new();
+            let mut response = client
+                .server_streaming(ExportRequest {
+                    hash,
+                    out_dir,
+                    force,
+                })
+                .await?;
+            while let Some(item) = response.next().await {
+                match item? {
+                    ExportProgress::Starting { total } => {
+                        state.starting(total);
+                    }
+                    ExportProgress::Entry {
+                        id,
+                        hash,
+                        path,
+                        size,
+                    } => {
+                        state.add_entry(id, hash, Some(path), size);
+                    }
+                    ExportProgress::Progress { id, offset } => {
+                        state.progress(id, offset);
+                    }
+                    ExportProgress::Done { id, error } => {
+                        state.done(id, error);
+                    }
+                    ExportProgress::Abort(error) => {
+                        anyhow::bail!("Failed to export {}: {}", Blake3Cid(hash), error);
+                    }
+                    ExportProgress::AllDone => {
+                        break;
+                    }
+                }
+            }
+            anyhow::ensure!(
+                state.errors == 0,
+                "{} files of {} failed to export",
+                state.errors,
+                Blake3Cid(hash)
+            );
+            Ok(())
+        }
         Commands::Shutdown { force, rpc_port } => {
             let client = make_rpc_client(rpc_port).await?;
             client.rpc(ShutdownRequest { force }).await?;
@@ -674,20 +1035,131 @@ async fn main_impl() -> Result<()> {
 
             println!("Listening address: {}", response.listen_addr);
             println!("PeerID: {}", response.peer_id);
-            println!("Auth token: {}", response.auth_token);
+            match response.auth_token {
+                Some(auth_token) => println!("Auth token: {auth_token}"),
+                None => println!("Auth token: revoked"),
+            }
             Ok(())
         }
-        Commands::Add { path, rpc_port } => {
+        Commands::Add {
+            path,
+            rpc_port,
+            nested,
+            metadata,
+            copy,
+            tag,
+        } => {
             let client = make_rpc_client(rpc_port).await?;
             let absolute = path.canonicalize()?;
             println!("Adding {} as {}...", path.display(), absolute.display());
             let stream = client
-                .server_streaming(ProvideRequest { path: absolute })
+                .server_streaming(ProvideRequest {
+                    path: absolute,
+                    nested,
+                    metadata,
+                    import: import_mode(copy),
+                    tag,
+                    watch: None,
+                })
                 .await?;
             let (hash, entries) = aggregate_add_response(stream).await?;
             print_add_response(hash, entries);
             Ok(())
         }
+        Commands::Has {
+            hashes,
+            peer,
+            auth_token,
+            keypair,
+            addr,
+        } => {
+            let mut opts = get::Options {
+                peer_id: Some(peer),
+                keylog: cli.keylog,
+                keypair: get_client_keypair(keypair).await?,
+                ..Default::default()
+            };
+            if let Some(addr) = addr {
+                opts.addr = addr;
+            }
+            let token = parse_credential(auth_token)?;
+            let hashes = hashes.iter().map(|hash| *hash.as_hash()).collect();
+            for (hash, presence) in get::query(hashes, token, opts).await? {
+                match presence {
+                    Presence::Missing => println!("{} missing", Blake3Cid(hash)),
+                    Presence::Blob { size } => println!("{} blob {size}", Blake3Cid(hash)),
+                    Presence::Collection { size } => {
+                        println!("{} collection {size}", Blake3Cid(hash))
+                    }
+                }
+            }
+            Ok(())
+        }
+        Commands::Token { command, rpc_port } => {
+            let client = make_rpc_client(rpc_port).await?;
+            match command {
+                TokenCommands::Add { name, token } => {
+                    let token = token
+                        .map(|token| AuthToken::from_str(&token))
+                        .transpose()
+                        .context("Wrong format for authentication token")?;
+                    let response = client.rpc(TokenAddRequest { name, token }).await??;
+                    println!("Auth token: {}", response.token);
+                }
+                TokenCommands::Revoke { name } => {
+                    client.rpc(TokenRevokeRequest { name }).await??;
+                }
+                TokenCommands::List => {
+                    let mut response = client.server_streaming(TokenListRequest).await?;
+                    while let Some(item) = response.next().await {
+                        let item = item?;
+                        println!("{} {}", item.name, item.token);
+                    }
+                }
+            }
+            Ok(())
+        }
+        Commands::Remove { hash, rpc_port } => {
+            let client = make_rpc_client(rpc_port).await?;
+            let hash = *hash.as_hash();
+            let response = client.rpc(RemoveRequest { hash }).await??;
+            anyhow::ensure!(response.removed, "{} not found", Blake3Cid(hash));
+            Ok(())
+        }
+        Commands::Gc { rpc_port } => {
+            let client = make_rpc_client(rpc_port).await?;
+            let response = client.rpc(GcRequest).await??;
+            for hash in &response.removed {
+                println!("- {}", Blake3Cid(*hash));
+            }
+            println!("Removed {} entries", response.removed.len());
+            Ok(())
+        }
+        Commands::Tag { command, rpc_port } => {
+            let client = make_rpc_client(rpc_port).await?;
+            match command {
+                TagCommands::Set { name, hash } => {
+                    let hash = Some(*hash.as_hash());
+                    client.rpc(TagSetRequest { name, hash }).await??;
+                }
+                TagCommands::Delete { name } => {
+                    let request = TagSetRequest {
+                        name: name.clone(),
+                        hash: None,
+                    };
+                    let response = client.rpc(request).await??;
+                    anyhow::ensure!(response.previous.is_some(), "no tag named {name}");
+                }
+                TagCommands::List => {
+                    let mut response = client.server_streaming(TagListRequest).await?;
+                    while let Some(item) = response.next().await {
+                        let item = item?;
+                        println!("{} {}", item.name, Blake3Cid(item.hash));
+                    }
+                }
+            }
+            Ok(())
+        }
         Commands::Addresses { rpc_port } => {
             let client = make_rpc_client(rpc_port).await?;
             let response = client.rpc(AddrsRequest).await?;
@@ -704,6 +1176,7 @@ async fn main_impl() -> Result<()> {
     r
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn provide(
     db: Database,
     addr: Option<SocketAddr>,
@@ -711,10 +1184,21 @@ async fn provide(
     key: Option<PathBuf>,
     keylog: bool,
     rpc_port: Option<u16>,
+    allowed_peers: Vec<PeerId>,
+    require_allowed_peer: bool,
+    public: bool,
 ) -> Result<Provider> {
     let keypair = get_keypair(key).await?;
 
-    let mut builder = provider::Provider::builder(db).keylog(keylog);
+    let mut builder = provider::Provider::builder(db)
+        .keylog(keylog)
+        .public(public);
+    for peer_id in allowed_peers {
+        builder = builder.allow_peer(peer_id);
+    }
+    if require_allowed_peer {
+        builder = builder.peer_auth(PeerAuth::Required);
+    }
     if let Some(addr) = addr {
         builder = builder.bind_addr(addr);
     }
@@ -734,7 +1218,9 @@ async fn provide(
 
     println!("Listening address: {}", provider.local_address());
     println!("PeerID: {}", provider.peer_id());
-    println!("Auth token: {}", provider.auth_token());
+    if let Some(auth_token) = provider.auth_token() {
+        println!("Auth token: {auth_token}");
+    }
     println!();
     Ok(provider)
 }
@@ -782,30 +1268,410 @@ async fn get_keypair(key: Option<PathBuf>) -> Result<Keypair> {
     }
 }
 
+/// Loads the keypair identifying the client, if a path was given, and reports its PeerId.
+async fn get_client_keypair(path: Option<PathBuf>) -> Result<Option<Arc<Keypair>>> {
+    match path {
+        Some(path) => {
+            let keypair = get_keypair(Some(path)).await?;
+            progress!("Client PeerId: {}", PeerId::from(keypair.public()));
+            Ok(Some(Arc::new(keypair)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Parses the `--auth-token` option.
+///
+/// Without one no credential is sent, which is only accepted if the provider allows the
+/// client's PeerId or is public.
+fn parse_credential(auth_token: Option<String>) -> Result<Credential> {
+    match auth_token {
+        Some(auth_token) => {
+            Credential::from_str(&auth_token).context("Wrong format for authentication token")
+        }
+        None => Ok(Credential::Anonymous),
+    }
+}
+
 #[derive(Debug)]
 enum GetInteractive {
     Ticket {
         ticket: Ticket,
+        request: Request,
         keylog: bool,
+        keypair: Option<Arc<Keypair>>,
     },
     Hash {
-        hash: Hash,
+        request: Request,
         opts: get::Options,
-        token: AuthToken,
+        token: Credential,
     },
 }
 
+fn make_request(hash: Hash, range: Option<RangeSpec>, include: Vec<String>) -> Request {
+    let mut request = Request::new(hash).with_ranges(range.unwrap_or_default());
+    for pattern in include {
+        request = request.with_include(Include::Glob(pattern));
+    }
+    request
+}
+
 impl GetInteractive {
-    fn hash(&self) -> Hash {
+    fn request(&self) -> &Request {
+        match self {
+            GetInteractive::Ticket { request, .. } => request,
+            GetInteractive::Hash { request, .. } => request,
+        }
+    }
+
+    fn request_mut(&mut self) -> &mut Request {
         match self {
-            GetInteractive::Ticket { ticket, .. } => ticket.hash(),
-            GetInteractive::Hash { hash, .. } => *hash,
+            GetInteractive::Ticket { request, .. } => request,
+            GetInteractive::Hash { request, .. } => request,
+        }
+    }
+}
+
+/// Prefix of the temporary files written by `get --out`.
+///
+/// The rest of the file name is the hash of the blob, so an interrupted download can be
+/// resumed from the temporary file.
+const TEMP_FILE_PREFIX: &str = "iroh-tmp-";
+
+/// Name of the file listing the entries a `get --out` completed, so they can be skipped when
+/// the get is resumed.
+///
+/// Each line has the hash and the name of an entry, separated by a space.
+const COMPLETE_FILE_NAME: &str = "iroh-tmp-complete";
+
+fn temp_file_path(dir: &Path, hash: &Hash) -> PathBuf {
+    dir.join(format!("{TEMP_FILE_PREFIX}{hash}"))
+}
+
+/// Removes the temporary files of a `get --out` from `dir`.
+fn remove_temp_files(dir: &Path) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(TEMP_FILE_PREFIX)
+        {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Records that the entry `name` with `hash` was written to `dir`, see [`COMPLETE_FILE_NAME`].
+async fn record_complete(dir: &Path, hash: &Hash, name: &str) -> Result<()> {
+    if name.contains('\n') {
+        // Can not be listed, so it is fetched again when resuming.
+        return Ok(());
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(COMPLETE_FILE_NAME))
+        .await?;
+    file.write_all(format!("{hash} {name}\n").as_bytes())
+        .await?;
+    Ok(())
+}
+
+/// Data left in the output directory by a previous `get`.
+#[derive(Debug, Default)]
+struct ResumeState {
+    /// Complete files, by the hash of their content.
+    complete: HashMap<Hash, PathBuf>,
+    /// Temporary files of interrupted downloads, by hash, with the number of bytes present.
+    partial: HashMap<Hash, u64>,
+}
+
+impl ResumeState {
+    /// Scans the output directory for the files left by an interrupted get.
+    ///
+    /// Only the temporary files and the entries listed in the [`COMPLETE_FILE_NAME`] file
+    /// are considered, and the listed entries are hashed to check they are still complete.
+    fn scan(dir: &Path) -> Result<Self> {
+        let mut state = Self::default();
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(state),
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if let Some(hash) = file_name.strip_prefix(TEMP_FILE_PREFIX) {
+                if let (true, Ok(hash)) = (entry.file_type()?.is_file(), Hash::from_str(hash)) {
+                    state.partial.insert(hash, entry.metadata()?.len());
+                }
+            }
+        }
+        let complete = match std::fs::read_to_string(dir.join(COMPLETE_FILE_NAME)) {
+            Ok(complete) => complete,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        for line in complete.lines() {
+            let (hash, name) = match line.split_once(' ') {
+                Some((hash, name)) => (Hash::from_str(hash)?, name),
+                None => anyhow::bail!("invalid line in {COMPLETE_FILE_NAME}: {line}"),
+            };
+            let path = dir.join(pathbuf_from_name(name));
+            if path.is_file() && hash_file(&path)? == hash {
+                state.complete.insert(hash, path);
+            }
+        }
+        state
+            .partial
+            .retain(|hash, len| *len > 0 && !state.complete.contains_key(hash));
+        Ok(state)
+    }
+
+    /// Tells the provider about the data we already have.
+    fn apply(&self, mut request: Request) -> Request {
+        for hash in self.complete.keys() {
+            request = request.with_have(*hash, RangeSpec::all());
+        }
+        for (hash, len) in self.partial.iter() {
+            request = request.with_have(*hash, RangeSpec::from(0..*len));
+        }
+        request
+    }
+}
+
+fn hash_file(path: &Path) -> Result<Hash> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// Copies a file we already have to `target`, unless it is the same file.
+async fn copy_existing(source: &Path, target: &Path) -> Result<()> {
+    if source == target {
+        return Ok(());
+    }
+    if let Some(parent) = target.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Unable to create directory {}", parent.display()))?;
+    }
+    tokio::fs::copy(source, target)
+        .await
+        .with_context(|| format!("Failed to copy {}", source.display()))?;
+    Ok(())
+}
+
+/// Restores the metadata recorded in a collection for the entries below `out`.
+///
+/// Directories and symlinks are created.  The permissions and modification times are only
+/// applied to the files in `written`, the directories containing them and the created
+/// directories, so nothing this get did not write is changed.  Directories are updated last,
+/// deepest first, so creating their contents does not change their modification times again.
+///
+/// The metadata comes from the provider, so it must not affect anything outside of `out`.
+/// Names and symlink targets have to be relative paths without `..`, symlinks are never
+/// followed and only the permission bits are restored, never setuid, setgid or sticky.
+fn restore_metadata(
+    out: &Path,
+    mut metadata: Vec<Metadata>,
+    written: &HashSet<PathBuf>,
+) -> Result<()> {
+    std::fs::create_dir_all(out)
+        .with_context(|| format!("Unable to create directory {}", out.display()))?;
+    let out = out
+        .canonicalize()
+        .with_context(|| format!("Unable to resolve {}", out.display()))?;
+    metadata.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut touched: HashSet<&Path> = written.iter().flat_map(|path| path.ancestors()).collect();
+    let mut created = Vec::new();
+    for entry in metadata.iter() {
+        let name = path_from_name(&entry.name)
+            .with_context(|| format!("Invalid entry name {:?}", entry.name))?;
+        let path = out.join(&name);
+        match entry.kind {
+            EntryKind::File => {
+                if touched.contains(name.as_path()) {
+                    set_file_metadata(&out, &path, entry)?;
+                }
+            }
+            EntryKind::Directory => {
+                if path.symlink_metadata().is_err() {
+                    check_inside(&out, &path)?;
+                    std::fs::create_dir_all(&path).with_context(|| {
+                        format!("Unable to create directory {}", path.display())
+                    })?;
+                    created.push(name);
+                }
+            }
+            EntryKind::Symlink { ref target } => {
+                path_from_name(target).with_context(|| {
+                    format!("Invalid target {target:?} of symlink {}", path.display())
+                })?;
+                check_inside(&out, &path)?;
+                create_symlink(target, &path)?;
+            }
+        }
+    }
+    touched.extend(created.iter().map(PathBuf::as_path));
+    for entry in metadata.iter().rev() {
+        if entry.kind == EntryKind::Directory {
+            let name = path_from_name(&entry.name)?;
+            if touched.contains(name.as_path()) {
+                set_file_metadata(&out, &out.join(name), entry)?;
+            }
         }
     }
+    Ok(())
 }
 
-async fn get_interactive(get: GetInteractive, out: Option<PathBuf>) -> Result<()> {
-    progress!("Fetching: {}", Blake3Cid::new(get.hash()));
+/// Checks that the existing part of `path` does not lead outside of the canonical `out`.
+///
+/// Directories which do not exist yet are created below the deepest existing one, so this
+/// makes sure creating them does not follow a symlink out of `out`.
+fn check_inside(out: &Path, path: &Path) -> Result<()> {
+    let existing = path
+        .parent()
+        .into_iter()
+        .flat_map(Path::ancestors)
+        .find(|dir| dir.symlink_metadata().is_ok())
+        .unwrap_or(out);
+    let resolved = existing
+        .canonicalize()
+        .with_context(|| format!("Unable to resolve {}", existing.display()))?;
+    anyhow::ensure!(
+        resolved.starts_with(out),
+        "{} is outside of {}",
+        path.display(),
+        out.display()
+    );
+    Ok(())
+}
+
+/// Sets the permissions and modification time of `path`, if it exists and is no symlink.
+fn set_file_metadata(out: &Path, path: &Path, entry: &Metadata) -> Result<()> {
+    match path.symlink_metadata() {
+        Ok(meta) if !meta.file_type().is_symlink() => {}
+        _ => return Ok(()),
+    }
+    check_inside(out, path)?;
+    #[cfg(unix)]
+    if let Some(mode) = entry.mode {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))
+            .with_context(|| format!("Failed to set the permissions of {}", path.display()))?;
+    }
+    if let Some(mtime) = entry.mtime {
+        set_mtime(path, mtime).with_context(|| {
+            format!("Failed to set the modification time of {}", path.display())
+        })?;
+    }
+    Ok(())
+}
+
+/// Sets the access and modification time of `path`, without following a symlink.
+#[cfg(unix)]
+fn set_mtime(path: &Path, mtime: Timestamp) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let time = libc::timeval {
+        tv_sec: mtime.secs as libc::time_t,
+        tv_usec: (mtime.nanos / 1000) as libc::suseconds_t,
+    };
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let times = [time, time];
+    if unsafe { libc::lutimes(path.as_ptr(), times.as_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mtime(_path: &Path, _mtime: Timestamp) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Creates a symlink at `path` pointing to `target`, replacing an existing symlink.
+#[cfg(unix)]
+fn create_symlink(target: &str, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Unable to create directory {}", parent.display()))?;
+    }
+    if path
+        .symlink_metadata()
+        .map_or(false, |m| m.file_type().is_symlink())
+    {
+        std::fs::remove_file(path)?;
+    }
+    std::os::unix::fs::symlink(target, path)
+        .with_context(|| format!("Failed to create symlink {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &str, path: &Path) -> Result<()> {
+    tracing::warn!("skipping symlink {}, not supported", path.display());
+    Ok(())
+}
+
+/// Starts showing the download progress of `total_entries` files.
+///
+/// Without a `size` the length of the progress bar is increased as the files are received.
+fn start_download_progress(pb: &ProgressBar, total_entries: u64, size: Option<u64>) {
+    progress!("{} Downloading ...", style("[3/3]").bold().dim());
+    match size {
+        Some(size) => progress!(
+            "  {total_entries} file(s) with total transfer size {}",
+            HumanBytes(size)
+        ),
+        None => progress!("  selected file(s) of {total_entries}"),
+    }
+    pb.set_length(size.unwrap_or(0));
+    pb.reset();
+    pb.set_draw_target(ProgressDrawTarget::stderr());
+}
+
+async fn get_interactive(
+    mut get: GetInteractive,
+    out: Option<PathBuf>,
+    ignore_metadata: bool,
+) -> Result<()> {
+    progress!("Fetching: {}", Blake3Cid::new(get.request().hash()));
+
+    // Only resume when getting entire files, partial files are not a prefix otherwise.
+    let resume = get.request().ranges().is_all();
+    let state = match out {
+        Some(ref out) if resume => {
+            let out = out.clone();
+            let state = tokio::task::spawn_blocking(move || ResumeState::scan(&out)).await??;
+            if !state.complete.is_empty() || !state.partial.is_empty() {
+                progress!(
+                    "Resuming: found {} complete and {} partial file(s)",
+                    state.complete.len(),
+                    state.partial.len()
+                );
+            }
+            let request = get.request_mut();
+            *request = state.apply(request.clone());
+            state
+        }
+        _ => ResumeState::default(),
+    };
+    let state = RefCell::new(state);
+    let metadata = RefCell::new(Vec::new());
+    // The files written by this get, only their metadata is restored.
+    let written = RefCell::new(HashSet::new());
+    let request = get.request().clone();
+    let includes_all = request.includes_all();
 
     progress!("{} Connecting ...", style("[1/3]").bold().dim());
 
@@ -827,33 +1693,77 @@ async fn get_interactive(get: GetInteractive, out: Option<PathBuf>) -> Result<()
         progress!("{} Requesting ...", style("[2/3]").bold().dim());
         Ok(())
     };
-    let on_collection = |collection: &iroh::blobs::Collection| {
+    let on_collection = |page: &get::CollectionPage| {
         let pb = &pb;
-        let total_entries = collection.total_entries();
-        let size = collection.total_blobs_size();
+        let out = &out;
+        let state = &state;
+        let written = &written;
+        // Nested collections are part of the transfer started by the requested collection.
+        let nested = !page.path.is_empty();
+        // Files are only restored if they were fetched, directories and symlinks by name.
+        metadata.borrow_mut().extend(
+            page.metadata
+                .iter()
+                .filter(|entry| {
+                    nested || entry.kind == EntryKind::File || request.includes_name(&entry.name)
+                })
+                .map(|entry| Metadata {
+                    name: format!("{}{}", page.path, entry.name),
+                    ..entry.clone()
+                }),
+        );
+        let first_page = !nested && page.offset == 0;
+        let total_entries = page.total_entries;
+        // The total size is only known if the entire collection is requested.
+        let size = includes_all.then_some(page.total_blobs_size);
+        // The provider skips the files we already have, copy them to where they belong.
+        let existing: Vec<_> = page
+            .blobs
+            .iter()
+            .enumerate()
+            .filter(|(i, blob)| {
+                !blob.is_collection()
+                    && (nested || request.includes(page.offset + *i as u64, blob.name()))
+            })
+            .filter_map(|(_, blob)| {
+                let source = state.borrow().complete.get(&blob.hash())?.clone();
+                let name = format!("{}{}", page.path, blob.name());
+                Some((source, pathbuf_from_name(&name)))
+            })
+            .collect();
         async move {
-            progress!("{} Downloading ...", style("[3/3]").bold().dim());
-            progress!(
-                "  {total_entries} file(s) with total transfer size {}",
-                HumanBytes(size)
-            );
-            pb.set_length(size);
-            pb.reset();
-            pb.set_draw_target(ProgressDrawTarget::stderr());
-
+            if let Some(ref outpath) = out {
+                for (source, name) in existing {
+                    copy_existing(&source, &outpath.join(&name)).await?;
+                    written.borrow_mut().insert(name);
+                }
+            }
+            if first_page {
+                start_download_progress(pb, total_entries, size);
+            }
             Ok(())
         }
     };
 
-    let on_blob = |hash: Hash, mut reader, name: String| {
+    let on_blob = |hash: Hash, mut reader: get::DataStream, name: String| {
         let out = &out;
         let pb = &pb;
+        let state = &state;
+        let written = &written;
         async move {
-            let name = if name.is_empty() {
-                PathBuf::from(hash.to_string())
+            if pb.length().is_none() {
+                // A single blob was requested, so `on_collection` was not called.
+                let size = reader.read_size().await?;
+                start_download_progress(pb, 1, Some(size));
+            } else if !includes_all {
+                pb.inc_length(reader.read_size().await?);
+            }
+            let entry_name = if name.is_empty() {
+                hash.to_string()
             } else {
-                pathbuf_from_name(&name)
+                name
             };
+            let name = pathbuf_from_name(&entry_name);
             pb.set_message(format!("Receiving '{}'...", name.display()));
 
             // Wrap the reader to show progress.
@@ -863,34 +1773,65 @@ async fn get_interactive(get: GetInteractive, out: Option<PathBuf>) -> Result<()
                 tokio::fs::create_dir_all(outpath)
                     .await
                     .context("Unable to create directory {outpath}")?;
-                let dirpath = std::path::PathBuf::from(outpath);
-                let filepath = dirpath.join(name);
-
-                // Create temp file
-                let (temp_file, dup) = tokio::task::spawn_blocking(|| {
-                    let temp_file = tempfile::Builder::new()
-                        .prefix("iroh-tmp-")
-                        .tempfile_in(dirpath)
-                        .context("Failed to create temporary output file")?;
-                    let dup = temp_file.as_file().try_clone()?;
-                    Ok::<_, anyhow::Error>((temp_file, dup))
-                })
-                .await??;
+                let filepath = outpath.join(&name);
+                written.borrow_mut().insert(name);
+
+                let existing = state.borrow().complete.get(&hash).cloned();
+                if let Some(existing) = existing {
+                    // We already have all the data, nothing is sent for it.
+                    tokio::io::copy(&mut wrapped_reader, &mut tokio::io::sink()).await?;
+                    copy_existing(&existing, &filepath).await?;
+                    return Ok(reader);
+                }
 
-                let file = tokio::fs::File::from_std(dup);
+                // Write to the temp file, appending to the data from an interrupted get.
+                let temp_path = temp_file_path(outpath, &hash);
+                let offset = state.borrow().partial.get(&hash).copied().unwrap_or(0);
+                let file = if offset == 0 {
+                    tokio::fs::File::create(&temp_path).await
+                } else {
+                    tokio::fs::OpenOptions::new()
+                        .append(true)
+                        .open(&temp_path)
+                        .await
+                }
+                .context("Failed to create temporary output file")?;
+                anyhow::ensure!(
+                    file.metadata().await?.len() == offset,
+                    "temporary output file {} was modified",
+                    temp_path.display()
+                );
                 let mut file_buf = tokio::io::BufWriter::new(file);
                 tokio::io::copy(&mut wrapped_reader, &mut file_buf).await?;
 
+                if offset > 0 {
+                    // Only the resumed part was verified during the transfer.
+                    let path = temp_path.clone();
+                    let got = tokio::task::spawn_blocking(move || hash_file(&path)).await??;
+                    if got != hash {
+                        tokio::fs::remove_file(&temp_path).await?;
+                        anyhow::bail!(
+                            "Resumed data for '{}' is corrupt, removed the partial file",
+                            filepath.display()
+                        );
+                    }
+                }
+
                 // Rename temp file, to target name
-                let filepath2 = filepath.clone();
-                if let Some(parent) = filepath2.parent() {
+                if let Some(parent) = filepath.parent() {
                     tokio::fs::create_dir_all(parent)
                         .await
                         .context("Unable to create directory {parent}")?;
                 }
-                tokio::task::spawn_blocking(|| temp_file.persist(filepath2))
-                    .await?
+                tokio::fs::rename(&temp_path, &filepath)
+                    .await
                     .context("Failed to write output file")?;
+                if resume {
+                    record_complete(outpath, &hash, &entry_name).await?;
+                    let mut state = state.borrow_mut();
+                    state.partial.remove(&hash);
+                    state.complete.insert(hash, filepath);
+                }
             } else {
                 // Write to OUT_WRITER
                 let mut stdout = tokio::io::stdout();
@@ -900,24 +1841,55 @@ async fn get_interactive(get: GetInteractive, out: Option<PathBuf>) -> Result<()
             Ok(reader)
         }
     };
-    let stats = match get {
-        GetInteractive::Ticket { ticket, keylog } => {
+    let res = match get {
+        GetInteractive::Ticket {
+            ticket,
+            request,
+            keylog,
+            keypair,
+        } => {
             get::run_ticket(
                 &ticket,
+                request,
                 keylog,
+                keypair,
                 MAX_CONCURRENT_DIALS,
                 on_connected,
                 on_collection,
                 on_blob,
             )
-            .await?
+            .await
+        }
+        GetInteractive::Hash {
+            request,
+            opts,
+            token,
+        } => get::run(request, token, opts, on_connected, on_collection, on_blob).await,
+    };
+    let stats = match (res, &out) {
+        (Ok(stats), Some(out)) => {
+            // Nothing is left to resume.
+            remove_temp_files(out)?;
+            stats
         }
-        GetInteractive::Hash { hash, opts, token } => {
-            get::run(hash, token, opts, on_connected, on_collection, on_blob).await?
+        (Ok(stats), None) => stats,
+        (Err(err), Some(out)) => {
+            // A failed get, e.g. due to a lost connection, is resumed from the temporary
+            // files, unless it only got parts of the files.
+            if !resume {
+                remove_temp_files(out)?;
+            }
+            return Err(err.into());
         }
+        (Err(err), None) => return Err(err.into()),
     };
 
     pb.finish_and_clear();
+    let metadata = metadata.into_inner();
+    let written = written.into_inner();
+    if let Some(out) = out.filter(|_| !ignore_metadata && !metadata.is_empty()) {
+        tokio::task::spawn_blocking(move || restore_metadata(&out, metadata, &written)).await??;
+    }
     progress!(
         "Transferred {} in {}, {}/s",
         HumanBytes(stats.data_len),
@@ -927,3 +1899,90 @@ async fn get_interactive(get: GetInteractive, out: Option<PathBuf>) -> Result<()
 
     Ok(())
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use testdir::testdir;
+
+    use super::*;
+
+    fn entry(name: &str, kind: EntryKind, mode: u32) -> Metadata {
+        Metadata {
+            name: name.to_string(),
+            kind,
+            mode: Some(mode),
+            mtime: Some(Timestamp {
+                secs: -1000,
+                nanos: 0,
+            }),
+        }
+    }
+
+    fn mode(path: &Path) -> u32 {
+        path.symlink_metadata().unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn test_restore_metadata() -> Result<()> {
+        let dir = testdir!();
+        let victim = dir.join("victim");
+        std::fs::write(&victim, b"victim")?;
+        std::fs::set_permissions(&victim, std::fs::Permissions::from_mode(0o600))?;
+        let out = dir.join("out");
+        std::fs::create_dir_all(out.join("sub"))?;
+        std::fs::write(out.join("sub").join("file"), b"file")?;
+        std::fs::write(out.join("other"), b"other")?;
+        std::fs::set_permissions(out.join("other"), std::fs::Permissions::from_mode(0o600))?;
+        std::os::unix::fs::symlink(&dir, out.join("escape"))?;
+        let written = HashSet::from([PathBuf::from("sub/file"), PathBuf::from("escape/victim")]);
+
+        // Only the written file and its directory are changed, without the setuid bit.
+        let metadata = vec![
+            entry("sub/file", EntryKind::File, 0o4755),
+            entry("sub", EntryKind::Directory, 0o700),
+            entry("other", EntryKind::File, 0o777),
+            entry("empty", EntryKind::Directory, 0o750),
+        ];
+        restore_metadata(&out, metadata, &written)?;
+        assert_eq!(mode(&out.join("sub").join("file")), 0o755);
+        assert_eq!(mode(&out.join("sub")), 0o700);
+        assert_eq!(mode(&out.join("other")), 0o600);
+        assert_eq!(mode(&out.join("empty")), 0o750);
+        let expected = std::time::UNIX_EPOCH - Duration::from_secs(1000);
+        assert_eq!(
+            out.join("sub").join("file").metadata()?.modified()?,
+            expected
+        );
+
+        // Nothing outside of the output directory is changed.
+        for metadata in [
+            entry("escape/victim", EntryKind::File, 0o777),
+            entry("../victim", EntryKind::File, 0o777),
+            entry("escape/new", EntryKind::Directory, 0o777),
+            entry("/abs", EntryKind::Directory, 0o777),
+            entry("", EntryKind::Directory, 0o777),
+            entry(
+                "link",
+                EntryKind::Symlink {
+                    target: "../victim".to_string(),
+                },
+                0o777,
+            ),
+            entry(
+                "link",
+                EntryKind::Symlink {
+                    target: victim.to_string_lossy().to_string(),
+                },
+                0o777,
+            ),
+        ] {
+            assert!(restore_metadata(&out, vec![metadata], &written).is_err());
+        }
+        assert_eq!(mode(&victim), 0o600);
+        assert!(!dir.join("new").exists());
+        assert!(out.join("link").symlink_metadata().is_err());
+        Ok(())
+    }
+}
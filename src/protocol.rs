@@ -1,55 +1,579 @@
 //! Protocol for communication between provider and client.
-use std::fmt::Display;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
 use std::io;
+use std::ops::{Range, RangeFrom};
 use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, ensure, Context, Result};
 use bao_tree::io::tokio::AsyncResponseDecoder;
+use bao_tree::ChunkNum;
 use bytes::{Bytes, BytesMut};
 use postcard::experimental::max_size::MaxSize;
 use quinn::VarInt;
-use range_collections::RangeSet2;
+use range_collections::range_set::RangeSetRange;
+use range_collections::{RangeSet2, RangeSetRef};
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::{
     util::{self, Hash},
-    IROH_BLOCK_SIZE,
+    Keypair, PublicKey, Signature, IROH_BLOCK_SIZE,
 };
 
 /// Maximum message size is limited to 100MiB for now.
 pub(crate) const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 100;
 
 /// Protocol version
-pub const VERSION: u64 = 1;
+///
+/// This is the newest version, all versions down to [`MIN_VERSION`] are still supported.
+/// Each version is identified by its own ALPN and the provider picks the highest version
+/// offered by the getter.
+pub const VERSION: u64 = 7;
 
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, MaxSize)]
+/// Oldest protocol version which is still supported.
+pub const MIN_VERSION: u64 = 1;
+
+/// The ALPN of a protocol version is this prefix followed by the version number.
+const ALPN_PREFIX: &[u8] = b"n0/iroh/";
+
+/// The size of a blake3 chunk, the unit in which ranges are encoded.
+const CHUNK_SIZE: u64 = 1024;
+
+/// The first protocol version which transfers collections in pages.
+///
+/// Older versions send the entire collection before the data of its blobs, which means the
+/// getter has to hold the entire collection in memory.
+pub(crate) const PAGED_COLLECTION_VERSION: u64 = 4;
+
+/// The first protocol version which supports nested collections.
+///
+/// See [`crate::blobs::Blob::is_collection`].
+pub(crate) const NESTED_COLLECTION_VERSION: u64 = 5;
+
+/// The first protocol version which supports [`Capability`] tokens in the handshake.
+pub(crate) const CAPABILITY_VERSION: u64 = 6;
+
+/// The first protocol version which supports resolving tags, see [`ResolveRequest`].
+pub(crate) const TAG_VERSION: u64 = 7;
+
+/// The size of a collection page in chunks, i.e. 1 MiB.
+///
+/// This is a multiple of [`IROH_BLOCK_SIZE`] so pages never share a chunk group.
+const COLLECTION_PAGE_CHUNKS: u64 = 1024;
+
+/// The handshake starting every request.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
 pub(crate) struct Handshake {
     pub version: u64,
-    pub token: AuthToken,
+    pub credential: Credential,
+}
+
+/// The handshake as sent before [`CAPABILITY_VERSION`], which only supports an [`AuthToken`].
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, MaxSize)]
+struct HandshakeV1 {
+    version: u64,
+    token: AuthToken,
 }
 
 impl Handshake {
-    pub fn new(token: AuthToken) -> Self {
+    pub fn new(version: u64, credential: Credential) -> Self {
         Self {
-            version: VERSION,
-            token,
+            version,
+            credential,
+        }
+    }
+
+    /// Serialises the handshake as expected by the provider speaking protocol `version`.
+    ///
+    /// Fails if the credential is a [`Capability`] or [`Credential::Anonymous`] and the
+    /// provider does not support them.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if self.version >= CAPABILITY_VERSION {
+            return Ok(postcard::to_stdvec(self)?);
+        }
+        match self.credential {
+            Credential::Token(token) => Ok(postcard::to_stdvec(&HandshakeV1 {
+                version: self.version,
+                token,
+            })?),
+            Credential::Capability(_) => bail!(
+                "the provider does not support capability tokens, it only speaks protocol version {}",
+                self.version
+            ),
+            Credential::Anonymous => bail!(
+                "the provider requires an auth token, it only speaks protocol version {}",
+                self.version
+            ),
         }
     }
+
+    /// Deserialises a handshake sent using the negotiated protocol `version`.
+    pub fn from_bytes(version: u64, data: &[u8]) -> Result<Self> {
+        if version >= CAPABILITY_VERSION {
+            return Ok(postcard::from_bytes(data)?);
+        }
+        let handshake: HandshakeV1 = postcard::from_bytes(data)?;
+        Ok(Self::new(handshake.version, handshake.token.into()))
+    }
+}
+
+/// The ALPN identifying the given protocol version.
+pub(crate) fn alpn(version: u64) -> Vec<u8> {
+    [ALPN_PREFIX, version.to_string().as_bytes()].concat()
+}
+
+/// The ALPNs of all supported protocol versions, newest first.
+///
+/// The provider selects the first ALPN of its list which the getter also offers, so this
+/// order makes it choose the highest common version.
+pub(crate) fn supported_alpns() -> Vec<Vec<u8>> {
+    (MIN_VERSION..=VERSION).rev().map(alpn).collect()
+}
+
+/// Returns the protocol version which was negotiated for the connection.
+pub(crate) fn negotiated_version(connection: &quinn::Connection) -> Result<u64> {
+    let protocol = connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|data| data.protocol)
+        .context("no protocol negotiated")?;
+    let version = protocol
+        .strip_prefix(ALPN_PREFIX)
+        .and_then(|version| std::str::from_utf8(version).ok())
+        .and_then(|version| version.parse::<u64>().ok())
+        .with_context(|| {
+            format!(
+                "unknown protocol: {}",
+                String::from_utf8_lossy(&protocol).escape_debug()
+            )
+        })?;
+    ensure!(
+        (MIN_VERSION..=VERSION).contains(&version),
+        "unsupported protocol version {version}"
+    );
+    Ok(version)
 }
 
+/// A request as sent using protocol version 1.
+///
+/// Version 1 can only request an entire blob or collection.
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, MaxSize)]
-pub(crate) struct Request {
+pub(crate) struct RequestV1 {
     /// blake3 hash
     pub hash: Hash,
 }
 
+impl From<RequestV1> for Request {
+    fn from(request: RequestV1) -> Self {
+        Request::new(request.hash)
+    }
+}
+
+/// The request sent after the handshake, since protocol version 3.
+///
+/// Older versions can only send a [`Request`] to get data.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub(crate) enum Req {
+    /// Get a blob or a collection from the provider.
+    Get(Request),
+    /// Push a collection to the provider.
+    Push(PushRequest),
+    /// Ask the provider which hashes it has.
+    Has(HasRequest),
+    /// Ask the provider which hash a tag refers to.
+    Resolve(ResolveRequest),
+}
+
+/// A request to add a collection to the provider.
+///
+/// If the provider accepts the push it responds with [`Res::PushAccepted`], then the getter
+/// sends the bao encoded collection followed by the bao encoded data of every blob in the
+/// order of the collection.  Once all data is verified and stored the provider responds with
+/// [`Res::PushCompleted`].
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, MaxSize)]
+pub(crate) struct PushRequest {
+    /// The hash of the collection.
+    pub hash: Hash,
+}
+
+/// A request asking which of the hashes the provider has.
+///
+/// The provider responds with [`Res::Has`], containing the [`Presence`] of each hash in
+/// the same order.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub(crate) struct HasRequest {
+    /// The hashes to look up.
+    pub hashes: Vec<Hash>,
+}
+
+/// A request asking which hash a tag of the provider refers to.
+///
+/// The provider responds with [`Res::Resolved`], or with [`ErrorCode::NotFound`] if it has
+/// no such tag.  See [`crate::provider::Database::set_tag`].
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub(crate) struct ResolveRequest {
+    /// The name of the tag.
+    pub tag: String,
+}
+
+/// Whether a provider has the data for a hash, see [`crate::get::query`].
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Presence {
+    /// The provider does not have the hash.
+    Missing,
+    /// The hash is a blob.
+    Blob {
+        /// The size of the blob.
+        size: u64,
+    },
+    /// The hash is a collection.
+    Collection {
+        /// The size of the serialized collection, not including its blobs.
+        size: u64,
+    },
+}
+
+impl Presence {
+    /// Whether the provider has the hash.
+    pub fn is_present(&self) -> bool {
+        !matches!(self, Presence::Missing)
+    }
+
+    /// The size of the blob or serialized collection, if the provider has the hash.
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            Presence::Missing => None,
+            Presence::Blob { size } | Presence::Collection { size } => Some(*size),
+        }
+    }
+}
+
+/// A request for a blob or a collection.
+///
+/// By default the entire blob, or every blob of the collection, is requested.  Use
+/// [`Request::with_ranges`] to only request parts of the data and [`Request::with_have`]
+/// to skip data the getter already has, e.g. to resume an interrupted download.  For
+/// collections [`Request::with_include`] selects which blobs of the collection to send.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct Request {
+    /// blake3 hash
+    hash: Hash,
+    /// The ranges of the blob data to send.
+    ranges: RangeSpec,
+    /// The ranges of blobs which the getter already has and do not need to be sent.
+    have: HashMap<Hash, RangeSpec>,
+    /// The blobs of a collection to send, all of them if empty.
+    include: Vec<Include>,
+}
+
+/// Selects blobs of a requested collection, see [`Request::with_include`].
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub enum Include {
+    /// The blob at this position in the collection.
+    Index(u64),
+    /// The blob with exactly this name.
+    Name(String),
+    /// All blobs with names matching this glob pattern.
+    ///
+    /// Names are `/` separated paths.  `?` matches any single character and `*` any number
+    /// of characters, except for `/`.  `**` also matches `/`, e.g. `images/**/*.png`
+    /// matches all `.png` files below `images/`.
+    Glob(String),
+}
+
+impl Include {
+    /// Whether the blob at `index` in the collection, with `name`, is selected.
+    pub fn matches(&self, index: u64, name: &str) -> bool {
+        match self {
+            Include::Index(i) => *i == index,
+            Include::Name(n) => n == name,
+            Include::Glob(pattern) => util::glob_match(pattern, name),
+        }
+    }
+}
+
+impl Request {
+    /// Creates a request for the entire blob or collection identified by `hash`.
+    pub fn new(hash: Hash) -> Self {
+        Self {
+            hash,
+            ranges: RangeSpec::all(),
+            have: HashMap::new(),
+            include: Vec::new(),
+        }
+    }
+
+    /// Only requests the given ranges of the blob data.
+    ///
+    /// If the request is for a collection the collection itself is always sent in full
+    /// and the ranges are applied to each blob in the collection.
+    pub fn with_ranges(mut self, ranges: RangeSpec) -> Self {
+        self.ranges = ranges;
+        self
+    }
+
+    /// Tells the provider that the getter already has the given `ranges` of a blob.
+    ///
+    /// The provider will not send these ranges of the blob, whether it is requested
+    /// directly or is part of the requested collection.  Blobs of a collection which are
+    /// not missing any requested ranges are skipped entirely.  The collection itself is
+    /// always sent.
+    ///
+    /// Calling this multiple times for the same blob adds the ranges together.
+    pub fn with_have(mut self, hash: Hash, ranges: RangeSpec) -> Self {
+        self.have
+            .entry(hash)
+            .or_insert_with(RangeSpec::empty)
+            .0
+            .union_with(&ranges.0);
+        self
+    }
+
+    /// Only requests the blobs of the collection selected by `include`.
+    ///
+    /// Calling this multiple times sends the blobs selected by any of them, still in the
+    /// order of the collection.  The collection itself is always sent.  This has no effect
+    /// if a blob is requested.
+    ///
+    /// Only the entries of the requested collection are selected, a selected nested
+    /// collection is sent with all its blobs.  Nested collections are matched by the name of
+    /// their directory, see [`crate::blobs::Blob::name`].
+    pub fn with_include(mut self, include: Include) -> Self {
+        self.include.push(include);
+        self
+    }
+
+    /// The hash of the requested blob or collection.
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    /// The requested ranges of the blob data.
+    pub fn ranges(&self) -> &RangeSpec {
+        &self.ranges
+    }
+
+    /// Whether all blobs of the requested collection are requested.
+    pub fn includes_all(&self) -> bool {
+        self.include.is_empty()
+    }
+
+    /// Whether the blob at `index` in the requested collection, with `name`, is requested.
+    pub fn includes(&self, index: u64, name: &str) -> bool {
+        self.include.is_empty() || self.include.iter().any(|i| i.matches(index, name))
+    }
+
+    /// Whether an entry with `name` which is not a blob of the collection is requested.
+    ///
+    /// This is used for entries only described by the [`crate::blobs::Metadata`] of the
+    /// collection, e.g. directories.  They have no index, so only names and globs select them.
+    pub fn includes_name(&self, name: &str) -> bool {
+        self.include.is_empty()
+            || self.include.iter().any(|include| match include {
+                Include::Index(_) => false,
+                Include::Name(_) | Include::Glob(_) => include.matches(0, name),
+            })
+    }
+
+    /// The ranges of the blob with `hash` which will be sent.
+    ///
+    /// These are the requested ranges, minus the ranges the getter already has.
+    pub fn missing_ranges(&self, hash: &Hash) -> RangeSpec {
+        match self.have.get(hash) {
+            Some(have) => RangeSpec(self.ranges.0.difference(&have.0)),
+            None => self.ranges.clone(),
+        }
+    }
+}
+
+impl From<Hash> for Request {
+    fn from(hash: Hash) -> Self {
+        Self::new(hash)
+    }
+}
+
+/// A set of byte ranges of a blob.
+///
+/// The provider sends the bao encoded blocks covering these ranges, which allows the getter
+/// to verify just the requested data against the hash of the blob.  Ranges extending past
+/// the end of a blob are truncated to the blob size.
+///
+/// The printable representation is a comma separated list of ranges, e.g. `0..1024,4096..`,
+/// and can be parsed using [`FromStr`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<u64>", into = "Vec<u64>")]
+pub struct RangeSpec(RangeSet2<u64>);
+
+impl RangeSpec {
+    /// Selects the entire blob.
+    pub fn all() -> Self {
+        Self(RangeSet2::all())
+    }
+
+    /// Selects nothing.
+    pub fn empty() -> Self {
+        Self(RangeSet2::empty())
+    }
+
+    /// Creates a [`RangeSpec`] from a list of byte ranges.
+    ///
+    /// Overlapping and adjacent ranges are merged.
+    pub fn from_ranges(ranges: impl IntoIterator<Item = Range<u64>>) -> Self {
+        let mut res = RangeSet2::empty();
+        for range in ranges {
+            res.union_with(&RangeSet2::from(range));
+        }
+        Self(res)
+    }
+
+    /// Whether the entire blob is selected.
+    pub fn is_all(&self) -> bool {
+        self.0.is_all()
+    }
+
+    /// Whether nothing is selected.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the selected byte ranges of a blob of `size` bytes.
+    pub(crate) fn to_byte_ranges(&self, size: u64) -> RangeSet2<u64> {
+        self.0.intersection(&RangeSet2::from(0..size))
+    }
+
+    /// Returns the number of selected bytes of a blob of `size` bytes.
+    pub(crate) fn selected_len(&self, size: u64) -> u64 {
+        self.to_byte_ranges(size)
+            .iter()
+            .map(|range| match range {
+                RangeSetRange::Range(range) => range.end - range.start,
+                RangeSetRange::RangeFrom(range) => size - range.start,
+            })
+            .sum()
+    }
+
+    /// Returns the chunk ranges to encode for a blob of `size` bytes.
+    ///
+    /// Both provider and getter compute this from the blob size, the provider to encode the
+    /// data and the getter to decode it.  If no data is selected the last chunk is used, so
+    /// the getter can still verify the blob size.
+    pub(crate) fn to_chunk_ranges(&self, size: u64) -> RangeSet2<ChunkNum> {
+        if self.is_all() {
+            return RangeSet2::all();
+        }
+        let mut res = RangeSet2::empty();
+        for range in self.to_byte_ranges(size).iter() {
+            if let RangeSetRange::Range(range) = range {
+                let start = ChunkNum(range.start / CHUNK_SIZE);
+                let end = ChunkNum((range.end + CHUNK_SIZE - 1) / CHUNK_SIZE);
+                res.union_with(&RangeSet2::from(start..end));
+            }
+        }
+        if res.is_empty() {
+            if size == 0 {
+                return RangeSet2::all();
+            }
+            let last = ChunkNum((size - 1) / CHUNK_SIZE);
+            res = RangeSet2::from(last..last + 1);
+        }
+        res
+    }
+}
+
+impl Default for RangeSpec {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl From<Range<u64>> for RangeSpec {
+    fn from(range: Range<u64>) -> Self {
+        Self(RangeSet2::from(range))
+    }
+}
+
+impl From<RangeFrom<u64>> for RangeSpec {
+    fn from(range: RangeFrom<u64>) -> Self {
+        Self(RangeSet2::from(range))
+    }
+}
+
+impl TryFrom<Vec<u64>> for RangeSpec {
+    type Error = anyhow::Error;
+
+    fn try_from(boundaries: Vec<u64>) -> Result<Self> {
+        let ranges = RangeSetRef::new(&boundaries).context("invalid range boundaries")?;
+        let mut res = RangeSet2::empty();
+        res.union_with(ranges);
+        Ok(Self(res))
+    }
+}
+
+impl From<RangeSpec> for Vec<u64> {
+    fn from(spec: RangeSpec) -> Self {
+        spec.0.boundaries().to_vec()
+    }
+}
+
+/// Formats the ranges as a comma separated list, e.g. `0..1024,4096..`.
+impl Display for RangeSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, range) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            match range {
+                RangeSetRange::Range(range) => write!(f, "{}..{}", range.start, range.end)?,
+                RangeSetRange::RangeFrom(range) => write!(f, "{}..", range.start)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a comma separated list of ranges like `0..1024,4096..`.
+///
+/// The start of a range may be omitted and defaults to `0`, the end of a range may be
+/// omitted to select everything until the end of the blob.
+impl FromStr for RangeSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut res = RangeSet2::empty();
+        for part in s.split(',') {
+            let (start, end) = part
+                .trim()
+                .split_once("..")
+                .with_context(|| format!("invalid range {part:?}, expected start..end"))?;
+            let start = match start {
+                "" => 0,
+                start => start.parse().context("invalid range start")?,
+            };
+            let range = match end {
+                "" => RangeSet2::from(start..),
+                end => {
+                    let end: u64 = end.parse().context("invalid range end")?;
+                    ensure!(
+                        start < end,
+                        "invalid range {part:?}, start must be before end"
+                    );
+                    RangeSet2::from(start..end)
+                }
+            };
+            res.union_with(&range);
+        }
+        Ok(Self(res))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
 pub(crate) struct Response {
     pub data: Res,
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, MaxSize)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
 pub(crate) enum Res {
     NotFound,
     // If found, a stream of bao data is sent as next message.
@@ -57,10 +581,122 @@ pub(crate) enum Res {
     /// Indicates that the given hash referred to a collection of multiple blobs
     /// A stream of boa data that decodes to a `Collection` is sent as the next message,
     /// followed by `Res::Found` responses, send in the order indicated in the `Collection`.
+    ///
+    /// Since [`PAGED_COLLECTION_VERSION`] the collection is sent in pages, see
+    /// [`collection_page`].  Each page is followed by the blobs whose entries are complete
+    /// after decoding it, so the getter never needs the entire collection at once.  A
+    /// provider with a data directory maps its collections from files, so it only reads the
+    /// pages it sends.
+    ///
+    /// Since [`NESTED_COLLECTION_VERSION`] this is also sent in place of `Res::Found` for
+    /// entries which refer to a nested collection, followed by the nested collection in
+    /// the same way.
     FoundCollection {
         /// The size of the raw data we are planning to transfer
         total_blobs_size: u64,
     },
+    /// The provider failed to handle the request, no more data follows.
+    Error {
+        /// Machine readable reason for the failure.
+        code: ErrorCode,
+        /// Human readable description of the failure.
+        message: String,
+    },
+    /// The provider accepts a [`PushRequest`] and waits for the data.
+    PushAccepted,
+    /// All data of a [`PushRequest`] was verified and stored.
+    PushCompleted,
+    /// The answer to a [`HasRequest`].
+    Has {
+        /// The presence of each requested hash, in the order of the request.
+        presence: Vec<Presence>,
+    },
+    /// The answer to a [`ResolveRequest`].
+    Resolved {
+        /// The hash the tag refers to.
+        hash: Hash,
+    },
+}
+
+/// Stable error codes the provider sends back when it fails a request.
+///
+/// The numeric value of each code, see [`ErrorCode::as_u16`], is part of the wire protocol
+/// and never changes.  The same values are used as QUIC `error_code` when the provider
+/// closes a connection or resets a stream for one of these reasons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(from = "u16", into = "u16")]
+pub enum ErrorCode {
+    /// The provider is shutting down.
+    ProviderTerminating,
+    /// The getter speaks a protocol version the provider does not support.
+    VersionMismatch,
+    /// The credential in the handshake was not accepted, e.g. an expired [`Capability`].
+    BadToken,
+    /// The request could not be decoded or was otherwise malformed.
+    InvalidRequest,
+    /// The requested data is not available on the provider.
+    NotFound,
+    /// The provider failed while serving the request.
+    Internal,
+    /// The provider does not allow this kind of request.
+    Unsupported,
+    /// The credential in the handshake does not grant access to the requested data, or the
+    /// provider does not allow the client's [`PeerId`](crate::PeerId) to connect.
+    Forbidden,
+    /// The client already has as many transfers in progress as the provider allows.
+    TooManyRequests,
+    /// An error code not known to this version of iroh.
+    Unknown(u16),
+}
+
+impl ErrorCode {
+    /// The numeric value of this code as used on the wire.
+    pub fn as_u16(&self) -> u16 {
+        match self {
+            ErrorCode::ProviderTerminating => Closed::ProviderTerminating as u16,
+            ErrorCode::VersionMismatch => Closed::VersionMismatch as u16,
+            ErrorCode::BadToken => Closed::BadToken as u16,
+            ErrorCode::InvalidRequest => Closed::InvalidRequest as u16,
+            ErrorCode::NotFound => Closed::NotFound as u16,
+            ErrorCode::Internal => Closed::Internal as u16,
+            ErrorCode::Unsupported => Closed::Unsupported as u16,
+            ErrorCode::Forbidden => Closed::Forbidden as u16,
+            ErrorCode::TooManyRequests => Closed::TooManyRequests as u16,
+            ErrorCode::Unknown(code) => *code,
+        }
+    }
+}
+
+impl From<u16> for ErrorCode {
+    fn from(code: u16) -> Self {
+        match Closed::try_from(VarInt::from(code)).map(|closed| closed.error_code()) {
+            Ok(Some(code)) => code,
+            _ => ErrorCode::Unknown(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        code.as_u16()
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::ProviderTerminating => write!(f, "provider terminating"),
+            ErrorCode::VersionMismatch => write!(f, "version mismatch"),
+            ErrorCode::BadToken => write!(f, "bad token"),
+            ErrorCode::InvalidRequest => write!(f, "invalid request"),
+            ErrorCode::NotFound => write!(f, "not found"),
+            ErrorCode::Internal => write!(f, "internal error"),
+            ErrorCode::Unsupported => write!(f, "unsupported"),
+            ErrorCode::Forbidden => write!(f, "forbidden"),
+            ErrorCode::TooManyRequests => write!(f, "too many requests"),
+            ErrorCode::Unknown(code) => write!(f, "unknown error {code}"),
+        }
+    }
 }
 
 /// Write the given data to the provider sink, with a unsigned varint length prefix.
@@ -125,6 +761,36 @@ pub(crate) async fn read_bao_encoded<R: AsyncRead + Unpin>(
     Ok(decoded)
 }
 
+/// The number of pages in which a collection of `size` bytes is transferred.
+pub(crate) fn collection_page_count(version: u64, size: u64) -> u64 {
+    if version < PAGED_COLLECTION_VERSION {
+        return 1;
+    }
+    let chunks = (size + CHUNK_SIZE - 1) / CHUNK_SIZE;
+    ((chunks + COLLECTION_PAGE_CHUNKS - 1) / COLLECTION_PAGE_CHUNKS).max(1)
+}
+
+/// The chunk ranges of the given page of a collection.
+///
+/// Before [`PAGED_COLLECTION_VERSION`] the collection is sent as a single page.
+pub(crate) fn collection_page(version: u64, page: u64) -> RangeSet2<ChunkNum> {
+    if version < PAGED_COLLECTION_VERSION {
+        return RangeSet2::all();
+    }
+    let start = page * COLLECTION_PAGE_CHUNKS;
+    RangeSet2::from(ChunkNum(start)..ChunkNum(start + COLLECTION_PAGE_CHUNKS))
+}
+
+/// The byte range of the given page of a collection of `size` bytes.
+pub(crate) fn collection_page_bytes(version: u64, page: u64, size: u64) -> Range<u64> {
+    if version < PAGED_COLLECTION_VERSION {
+        return 0..size;
+    }
+    let page_size = COLLECTION_PAGE_CHUNKS * CHUNK_SIZE;
+    let start = (page * page_size).min(size);
+    start..(start + page_size).min(size)
+}
+
 /// A token used to authenticate a handshake.
 ///
 /// The token has a printable representation which can be serialised using [`Display`] and
@@ -143,10 +809,22 @@ impl AuthToken {
     }
 }
 
-/// Serialises the [`AuthToken`] to base64.
+impl AuthToken {
+    /// Serialises the [`AuthToken`] to an `iroh://token/` URI.
+    ///
+    /// [`FromStr`] is capable of deserialising this format.
+    pub fn to_uri(&self) -> String {
+        util::encode_uri(AUTH_TOKEN_URI_KIND, self.bytes)
+    }
+}
+
+/// The kind of [`AuthToken`] URIs.
+const AUTH_TOKEN_URI_KIND: &str = "token";
+
+/// Serialises the [`AuthToken`] to base32 with a checksum.
 impl Display for AuthToken {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", util::encode(self.bytes))
+        write!(f, "{}", util::encode_checked(self.bytes))
     }
 }
 
@@ -156,24 +834,246 @@ pub enum AuthTokenParseError {
     /// Invalid base64 encoding.
     #[error("invalid encoding: {0}")]
     Base64(#[from] base64::DecodeError),
+    /// Invalid base32 or URI encoding, e.g. a checksum mismatch.
+    #[error("invalid encoding: {0}")]
+    Encoding(#[from] util::DecodeError),
     /// Invalid length.
     #[error("invalid length: {0}")]
     Length(usize),
 }
 
-/// Deserialises the [`AuthToken`] from base64.
+/// Deserialises the [`AuthToken`] from its base32, URI or older base64 encoding.
 impl FromStr for AuthToken {
     type Err = AuthTokenParseError;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        let decoded = util::decode(s)?;
-        let bytes = decoded
-            .try_into()
-            .map_err(|v: Vec<u8>| AuthTokenParseError::Length(v.len()))?;
-        Ok(AuthToken { bytes })
+        util::decode_printable(s, AUTH_TOKEN_URI_KIND, |bytes| {
+            let bytes = bytes
+                .try_into()
+                .map_err(|_| AuthTokenParseError::Length(bytes.len()))?;
+            Ok(AuthToken { bytes })
+        })
+    }
+}
+
+/// The credential presented to the provider in the handshake of every request.
+///
+/// The printable representation of every credential can be parsed using [`FromStr`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Credential {
+    /// The [`AuthToken`] of the provider, which grants access to everything.
+    Token(AuthToken),
+    /// A [`Capability`] signed by the provider, which grants access to some hashes.
+    Capability(Capability),
+    /// No credential at all.
+    ///
+    /// This is only accepted by providers which allow the client's [`PeerId`](crate::PeerId)
+    /// or which are public.  It is printed as [`Credential::ANONYMOUS`].
+    Anonymous,
+}
+
+impl Credential {
+    /// The printable representation of [`Credential::Anonymous`].
+    pub const ANONYMOUS: &'static str = "anonymous";
+}
+
+impl From<AuthToken> for Credential {
+    fn from(token: AuthToken) -> Self {
+        Credential::Token(token)
     }
 }
 
+impl From<Capability> for Credential {
+    fn from(capability: Capability) -> Self {
+        Credential::Capability(capability)
+    }
+}
+
+/// Serialises an [`AuthToken`] to base32 with a checksum and a [`Capability`] to base64.
+impl Display for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credential::Token(token) => token.fmt(f),
+            Credential::Capability(capability) => capability.fmt(f),
+            Credential::Anonymous => f.write_str(Self::ANONYMOUS),
+        }
+    }
+}
+
+/// Deserialises either an [`AuthToken`] from its base32, URI or older base64 encoding, a
+/// [`Capability`] from base64, or [`Credential::ANONYMOUS`].
+impl FromStr for Credential {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == Self::ANONYMOUS {
+            return Ok(Credential::Anonymous);
+        }
+        match AuthToken::from_str(s) {
+            Ok(token) => Ok(Credential::Token(token)),
+            Err(AuthTokenParseError::Length(_)) => Ok(Credential::Capability(s.parse()?)),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// A token granting access to some hashes, signed by the provider's [`Keypair`].
+///
+/// Unlike the [`AuthToken`], which grants access to everything in the provider's database,
+/// a capability only allows to get the listed hashes, and only until it expires.  For a
+/// collection this includes all its blobs.  A capability can also list tags, which grants
+/// access to resolve them and to whatever hash they refer to at the time of the request.
+/// Optionally the number of requests which can be made using the capability is limited as
+/// well.
+///
+/// The provider only needs its [`Keypair`] to verify a capability, so capabilities can
+/// also be created while the provider is not running.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Capability {
+    /// The root hashes which can be requested.
+    hashes: Vec<Hash>,
+    /// The tags which can be resolved, and whose current hashes can be requested.
+    tags: Vec<String>,
+    /// The expiry time, in seconds since the UNIX epoch.
+    expires: u64,
+    /// The maximum number of requests.
+    max_uses: Option<u64>,
+    /// Random bytes identifying the capability, to count its uses.
+    nonce: [u8; 16],
+    /// The signature of all other fields by the provider's [`Keypair`].
+    signature: Signature,
+}
+
+impl Capability {
+    /// Creates a capability for `hashes` signed by the provider's `keypair`.
+    pub fn new(
+        keypair: &Keypair,
+        hashes: Vec<Hash>,
+        expires: SystemTime,
+        max_uses: Option<u64>,
+    ) -> Self {
+        Self::new_with_tags(keypair, hashes, Vec::new(), expires, max_uses)
+    }
+
+    /// Creates a capability for `hashes` and `tags` signed by the provider's `keypair`.
+    pub fn new_with_tags(
+        keypair: &Keypair,
+        hashes: Vec<Hash>,
+        tags: Vec<String>,
+        expires: SystemTime,
+        max_uses: Option<u64>,
+    ) -> Self {
+        let expires = expires
+            .duration_since(UNIX_EPOCH)
+            .map(|expires| expires.as_secs())
+            .unwrap_or_default();
+        let nonce = rand::random();
+        let signed = Self::signed_bytes(&hashes, &tags, expires, max_uses, nonce);
+        let signature = keypair.sign(&signed);
+        Self {
+            hashes,
+            tags,
+            expires,
+            max_uses,
+            nonce,
+            signature,
+        }
+    }
+
+    /// The root hashes this capability grants access to.
+    pub fn hashes(&self) -> &[Hash] {
+        &self.hashes
+    }
+
+    /// The tags this capability grants access to.
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Whether this capability grants access to `hash`.
+    ///
+    /// This does not include the hashes of its tags, which only the provider can resolve.
+    pub fn allows(&self, hash: &Hash) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    /// The time after which the capability is no longer accepted.
+    pub fn expires(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.expires)
+    }
+
+    /// The maximum number of requests which can be made using this capability.
+    pub fn max_uses(&self) -> Option<u64> {
+        self.max_uses
+    }
+
+    /// The random bytes identifying this capability.
+    pub(crate) fn id(&self) -> [u8; 16] {
+        self.nonce
+    }
+
+    /// Verifies the capability was signed by `key` and did not expire yet.
+    pub fn verify(&self, key: &PublicKey) -> Result<(), CapabilityError> {
+        let signed = Self::signed_bytes(
+            &self.hashes,
+            &self.tags,
+            self.expires,
+            self.max_uses,
+            self.nonce,
+        );
+        key.verify_strict(&signed, &self.signature)
+            .map_err(|_| CapabilityError::BadSignature)?;
+        if SystemTime::now() >= self.expires() {
+            return Err(CapabilityError::Expired);
+        }
+        Ok(())
+    }
+
+    /// The data which is signed, prefixed to separate it from other uses of the key.
+    fn signed_bytes(
+        hashes: &[Hash],
+        tags: &[String],
+        expires: u64,
+        max_uses: Option<u64>,
+        nonce: [u8; 16],
+    ) -> Vec<u8> {
+        postcard::to_stdvec(&("iroh-capability", hashes, tags, expires, max_uses, nonce))
+            .expect("postcard::to_stdvec is infallible")
+    }
+}
+
+/// Serialises the [`Capability`] to base64.
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes = postcard::to_stdvec(self).expect("postcard::to_stdvec is infallible");
+        write!(f, "{}", util::encode(bytes))
+    }
+}
+
+/// Deserialises the [`Capability`] from base64.
+impl FromStr for Capability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = util::decode(s)?;
+        Ok(postcard::from_bytes(&bytes)?)
+    }
+}
+
+/// Reasons for a [`Capability`] to be rejected by the provider.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilityError {
+    /// The capability was not signed by the provider.
+    #[error("capability has an invalid signature")]
+    BadSignature,
+    /// The capability expired.
+    #[error("capability expired")]
+    Expired,
+    /// All uses of the capability were used up.
+    #[error("capability has been used up")]
+    UsedUp,
+}
+
 /// Reasons to close connections or stop streams.
 ///
 /// A QUIC **connection** can be *closed* and a **stream** can request the other side to
@@ -199,6 +1099,26 @@ pub(crate) enum Closed {
     /// Only a single request is allowed on a stream, if more data is received after this a
     /// provider may send this error code in a STOP_STREAM frame.
     RequestReceived = 2,
+    /// The handshake used an unsupported protocol version, see [`ErrorCode::VersionMismatch`].
+    VersionMismatch = 3,
+    /// The handshake had a wrong credential, see [`ErrorCode::BadToken`].
+    BadToken = 4,
+    /// The request was malformed, see [`ErrorCode::InvalidRequest`].
+    InvalidRequest = 5,
+    /// The requested data was not found, see [`ErrorCode::NotFound`].
+    NotFound = 6,
+    /// The provider failed while serving the request, see [`ErrorCode::Internal`].
+    ///
+    /// Used to reset the stream when a transfer fails after data was already sent and a
+    /// [`Res::Error`] can no longer be written.
+    Internal = 7,
+    /// The request is not allowed, see [`ErrorCode::Unsupported`].
+    Unsupported = 8,
+    /// The request is not covered by the credential or the client is not allowed to
+    /// connect, see [`ErrorCode::Forbidden`].
+    Forbidden = 9,
+    /// The client has too many transfers in progress, see [`ErrorCode::TooManyRequests`].
+    TooManyRequests = 10,
 }
 
 impl Closed {
@@ -207,6 +1127,30 @@ impl Closed {
             Closed::StreamDropped => &b"stream dropped"[..],
             Closed::ProviderTerminating => &b"provider terminating"[..],
             Closed::RequestReceived => &b"request received"[..],
+            Closed::VersionMismatch => &b"version mismatch"[..],
+            Closed::BadToken => &b"bad token"[..],
+            Closed::InvalidRequest => &b"invalid request"[..],
+            Closed::NotFound => &b"not found"[..],
+            Closed::Internal => &b"internal error"[..],
+            Closed::Unsupported => &b"unsupported"[..],
+            Closed::Forbidden => &b"forbidden"[..],
+            Closed::TooManyRequests => &b"too many requests"[..],
+        }
+    }
+
+    /// The [`ErrorCode`] matching this reason, if it signals an error.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Closed::StreamDropped | Closed::RequestReceived => None,
+            Closed::ProviderTerminating => Some(ErrorCode::ProviderTerminating),
+            Closed::VersionMismatch => Some(ErrorCode::VersionMismatch),
+            Closed::BadToken => Some(ErrorCode::BadToken),
+            Closed::InvalidRequest => Some(ErrorCode::InvalidRequest),
+            Closed::NotFound => Some(ErrorCode::NotFound),
+            Closed::Internal => Some(ErrorCode::Internal),
+            Closed::Unsupported => Some(ErrorCode::Unsupported),
+            Closed::Forbidden => Some(ErrorCode::Forbidden),
+            Closed::TooManyRequests => Some(ErrorCode::TooManyRequests),
         }
     }
 }
@@ -230,6 +1174,14 @@ impl TryFrom<VarInt> for Closed {
             0 => Ok(Self::StreamDropped),
             1 => Ok(Self::ProviderTerminating),
             2 => Ok(Self::RequestReceived),
+            3 => Ok(Self::VersionMismatch),
+            4 => Ok(Self::BadToken),
+            5 => Ok(Self::InvalidRequest),
+            6 => Ok(Self::NotFound),
+            7 => Ok(Self::Internal),
+            8 => Ok(Self::Unsupported),
+            9 => Ok(Self::Forbidden),
+            10 => Ok(Self::TooManyRequests),
             val => Err(UnknownErrorCode(val)),
         }
     }
@@ -240,20 +1192,219 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_auth_token_base64() {
+    fn test_auth_token_encoding() {
         let token = AuthToken::generate();
         println!("token: {token}");
-        let base64 = token.to_string();
-        println!("token: {base64}");
-        let decoded = AuthToken::from_str(&base64).unwrap();
+        let encoded = token.to_string();
+        println!("token: {encoded}");
+        let decoded = AuthToken::from_str(&encoded).unwrap();
+        assert_eq!(decoded, token);
+
+        let decoded = AuthToken::from_str(&token.to_uri()).unwrap();
+        assert_eq!(decoded, token);
+        let decoded = AuthToken::from_str(&util::encode(token.bytes)).unwrap();
         assert_eq!(decoded, token);
 
         let err = AuthToken::from_str("not-base64").err().unwrap();
         println!("err {err:#}");
-        assert!(matches!(err, AuthTokenParseError::Base64(_)));
+        assert!(matches!(
+            err,
+            AuthTokenParseError::Encoding(util::DecodeError::Base32)
+        ));
 
         let err = AuthToken::from_str("abcd").err().unwrap();
         println!("err {err:#}");
         assert!(matches!(err, AuthTokenParseError::Length(3)));
     }
+
+    #[test]
+    fn test_capability() {
+        let keypair = Keypair::generate();
+        let hash = Hash::new(b"hello");
+        let expires = SystemTime::now() + Duration::from_secs(60);
+        let capability = Capability::new(&keypair, vec![hash], expires, Some(1));
+        capability.verify(&keypair.public()).unwrap();
+        assert!(capability.allows(&hash));
+        assert!(!capability.allows(&Hash::new(b"other")));
+
+        let decoded = Capability::from_str(&capability.to_string()).unwrap();
+        assert_eq!(decoded, capability);
+        let credential = Credential::from_str(&capability.to_string()).unwrap();
+        assert_eq!(credential, Credential::Capability(capability.clone()));
+        let token = AuthToken::generate();
+        let credential = Credential::from_str(&token.to_string()).unwrap();
+        assert_eq!(credential, Credential::Token(token));
+        let credential = Credential::from_str(&Credential::Anonymous.to_string()).unwrap();
+        assert_eq!(credential, Credential::Anonymous);
+
+        let other = Keypair::generate();
+        let err = capability.verify(&other.public()).unwrap_err();
+        assert_eq!(err, CapabilityError::BadSignature);
+
+        let mut tampered = capability.clone();
+        tampered.hashes.push(Hash::new(b"other"));
+        let err = tampered.verify(&keypair.public()).unwrap_err();
+        assert_eq!(err, CapabilityError::BadSignature);
+        let mut tampered = capability.clone();
+        tampered.tags.push("nightly".to_string());
+        let err = tampered.verify(&keypair.public()).unwrap_err();
+        assert_eq!(err, CapabilityError::BadSignature);
+
+        let expired = SystemTime::now() - Duration::from_secs(1);
+        let capability = Capability::new(&keypair, vec![hash], expired, None);
+        let err = capability.verify(&keypair.public()).unwrap_err();
+        assert_eq!(err, CapabilityError::Expired);
+    }
+
+    #[test]
+    fn test_handshake_versions() {
+        let token = AuthToken::generate();
+        let handshake = Handshake::new(CAPABILITY_VERSION - 1, token.into());
+        let bytes = handshake.to_bytes().unwrap();
+        assert_eq!(
+            postcard::from_bytes::<HandshakeV1>(&bytes).unwrap().token,
+            token
+        );
+        let decoded = Handshake::from_bytes(CAPABILITY_VERSION - 1, &bytes).unwrap();
+        assert_eq!(decoded, handshake);
+
+        let keypair = Keypair::generate();
+        let capability = Capability::new(&keypair, vec![], SystemTime::now(), None);
+        let handshake = Handshake::new(CAPABILITY_VERSION - 1, capability.clone().into());
+        assert!(handshake.to_bytes().is_err());
+        let handshake = Handshake::new(CAPABILITY_VERSION, capability.into());
+        let bytes = handshake.to_bytes().unwrap();
+        let decoded = Handshake::from_bytes(CAPABILITY_VERSION, &bytes).unwrap();
+        assert_eq!(decoded, handshake);
+
+        let handshake = Handshake::new(CAPABILITY_VERSION - 1, Credential::Anonymous);
+        assert!(handshake.to_bytes().is_err());
+        let handshake = Handshake::new(CAPABILITY_VERSION, Credential::Anonymous);
+        let bytes = handshake.to_bytes().unwrap();
+        let decoded = Handshake::from_bytes(CAPABILITY_VERSION, &bytes).unwrap();
+        assert_eq!(decoded, handshake);
+    }
+
+    /// Frames `message` the way it is sent on the wire.
+    async fn framed(message: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_lp(&mut out, message).await.unwrap();
+        out
+    }
+
+    /// The exact bytes a version 1 getter and provider exchange, which must never change.
+    #[tokio::test]
+    async fn test_v1_wire_format() {
+        let token = AuthToken {
+            bytes: std::array::from_fn(|i| i as u8),
+        };
+        let hash = Hash::from([0xab; 32]);
+
+        let mut expected = vec![33, 0, 0, 0, 0, 0, 0, 0, 1];
+        expected.extend(0u8..32);
+        let handshake = Handshake::new(1, token.into());
+        let bytes = handshake.to_bytes().unwrap();
+        assert_eq!(framed(&bytes).await, expected);
+        assert_eq!(Handshake::from_bytes(1, &bytes).unwrap(), handshake);
+
+        let mut expected = vec![33, 0, 0, 0, 0, 0, 0, 0, 32];
+        expected.extend([0xab; 32]);
+        let request = RequestV1 { hash };
+        let bytes = postcard::to_stdvec(&request).unwrap();
+        assert_eq!(framed(&bytes).await, expected);
+        assert_eq!(postcard::from_bytes::<RequestV1>(&bytes).unwrap(), request);
+
+        let responses = [
+            (Res::NotFound, vec![1, 0, 0, 0, 0, 0, 0, 0, 0]),
+            (Res::Found, vec![1, 0, 0, 0, 0, 0, 0, 0, 1]),
+            (
+                Res::FoundCollection {
+                    total_blobs_size: 300,
+                },
+                vec![3, 0, 0, 0, 0, 0, 0, 0, 2, 0xac, 0x02],
+            ),
+        ];
+        for (data, expected) in responses {
+            let response = Response { data };
+            let bytes = postcard::to_stdvec(&response).unwrap();
+            assert_eq!(framed(&bytes).await, expected);
+            assert_eq!(postcard::from_bytes::<Response>(&bytes).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn test_range_spec_parse() {
+        let spec = RangeSpec::from_str("0..1024, 4096..").unwrap();
+        assert_eq!(spec.to_string(), "0..1024,4096..");
+        assert_eq!(RangeSpec::from_str(&spec.to_string()).unwrap(), spec);
+        assert_eq!(
+            RangeSpec::from_str("0..10,5..20").unwrap(),
+            RangeSpec::from(0..20)
+        );
+        assert_eq!(RangeSpec::from_str("..10").unwrap(), RangeSpec::from(0..10));
+        assert!(RangeSpec::from_str("10..10").is_err());
+        assert!(RangeSpec::from_str("10").is_err());
+        assert!(RangeSpec::from_str("a..b").is_err());
+    }
+
+    #[test]
+    fn test_range_spec_serde() {
+        let spec = RangeSpec::from_ranges([10..20, 100..200]);
+        let bytes = postcard::to_stdvec(&spec).unwrap();
+        let decoded: RangeSpec = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, spec);
+
+        // boundaries must be strictly increasing
+        let bytes = postcard::to_stdvec(&vec![20u64, 10]).unwrap();
+        assert!(postcard::from_bytes::<RangeSpec>(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_range_spec_chunk_ranges() {
+        let size = 10 * 1024 + 10;
+        assert_eq!(RangeSpec::all().to_chunk_ranges(size), RangeSet2::all());
+        assert_eq!(
+            RangeSpec::from(1000..1030).to_chunk_ranges(size),
+            RangeSet2::from(ChunkNum(0)..ChunkNum(2))
+        );
+        assert_eq!(
+            RangeSpec::from(2048..).to_chunk_ranges(size),
+            RangeSet2::from(ChunkNum(2)..ChunkNum(11))
+        );
+        // ranges past the end still verify the last chunk
+        assert_eq!(
+            RangeSpec::from(size..size + 10).to_chunk_ranges(size),
+            RangeSet2::from(ChunkNum(10)..ChunkNum(11))
+        );
+        assert_eq!(RangeSpec::from(10..20).to_chunk_ranges(0), RangeSet2::all());
+    }
+
+    #[test]
+    fn test_alpn() {
+        // Version 1 getters and providers only know this ALPN.
+        assert_eq!(alpn(1), b"n0/iroh/1");
+        let alpns = supported_alpns();
+        assert_eq!(alpns.first(), Some(&alpn(VERSION)));
+        assert_eq!(alpns.last(), Some(&alpn(MIN_VERSION)));
+    }
+
+    #[test]
+    fn test_error_code() {
+        for code in 0..16u16 {
+            let error_code = ErrorCode::from(code);
+            assert_eq!(error_code.as_u16(), code);
+            if let Ok(closed) = Closed::try_from(VarInt::from(code)) {
+                assert_eq!(closed.error_code().unwrap_or(error_code), error_code);
+            }
+        }
+        assert_eq!(ErrorCode::from(4), ErrorCode::BadToken);
+        assert_eq!(ErrorCode::from(42), ErrorCode::Unknown(42));
+
+        let res = Res::Error {
+            code: ErrorCode::NotFound,
+            message: "gone".to_string(),
+        };
+        let bytes = postcard::to_stdvec(&res).unwrap();
+        assert_eq!(postcard::from_bytes::<Res>(&bytes).unwrap(), res);
+    }
 }
@@ -4,43 +4,57 @@
 //! blobs and treat this as a blob itself.  Then all blobs, including the "collection blob"
 //! are inserted in a hashmap.
 
-use std::collections::HashMap;
-use std::io::{BufReader, Cursor};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use anyhow::{bail, ensure, Context, Result};
-use bao_tree::outboard::PostOrderMemOutboard;
+use anyhow::{ensure, Context, Result};
+use bao_tree::outboard::{Outboard, PostOrderMemOutboard, PostOrderMemOutboardRef};
 use bytes::Bytes;
 use futures::{stream, StreamExt};
+use memmap2::{Mmap, MmapMut};
+use postcard::ser_flavors::Flavor;
 use tracing::{trace, trace_span};
 
-use crate::blobs::{Blob, Collection};
-use crate::protocol::MAX_MESSAGE_SIZE;
+use crate::blobs::{Blob, BlobKind, Collection, EntryKind, Metadata, Timestamp};
 use crate::rpc_protocol::ProvideProgress;
 use crate::util::{Progress, ProgressReader, ProgressReaderUpdate};
 use crate::{Hash, IROH_BLOCK_SIZE};
 
-use super::{BlobOrCollection, DataSource};
+use super::database::{format_hash, map_file, Import};
+use super::{merge_sources, BlobOrCollection, BlobSource, DataSource};
 
 /// Creates a collection blob and returns all blobs in a hashmap.
 ///
+/// If `nested` is true the blob names are treated as `/` separated paths and every
+/// directory becomes a nested collection, see [`Blob::is_collection`].
+///
+/// The `metadata` is added to the collection, or to the nested collection of its
+/// directory.
+///
+/// The `dirs` determine where the imported data is stored, see [`ImportDirs`].
+///
 /// Returns the hashmap with all blobs, including the created collection blob itself, as
-/// well as the [`Hash`] of the collection blob.
+/// well as the [`Hash`] of the collection blob.  [`ProvideProgress::AllDone`] is left to
+/// the caller, once the blobs are added to the database.
 pub(super) async fn create_collection(
     data_sources: Vec<DataSource>,
+    nested: bool,
+    metadata: Vec<Metadata>,
+    dirs: ImportDirs,
     progress: Progress<ProvideProgress>,
 ) -> Result<(HashMap<Hash, BlobOrCollection>, Hash)> {
-    let mut outboards = compute_all_outboards(data_sources, progress.clone()).await?;
+    let mut outboards = compute_all_outboards(data_sources, dirs.clone(), progress.clone()).await?;
 
     // TODO: Don't sort on async runtime?
     outboards.sort_by_key(|o| (o.name.clone(), o.hash));
 
     let mut map = HashMap::with_capacity(outboards.len() + 1);
     let mut blobs = Vec::with_capacity(outboards.len());
-    let mut total_blobs_size: u64 = 0;
 
     for BlobWithOutboard {
-        path,
+        source,
         name,
         size,
         hash,
@@ -48,40 +62,260 @@ pub(super) async fn create_collection(
     } in outboards
     {
         debug_assert!(outboard.len() >= 8, "outboard must at least contain size");
-        map.insert(
-            hash,
-            BlobOrCollection::Blob {
-                outboard,
-                path,
-                size,
-            },
-        );
-        total_blobs_size += size;
-        blobs.push(Blob { name, hash });
+        // Files with the same content are all recorded as sources of the blob.
+        match map.get_mut(&hash) {
+            Some(BlobOrCollection::Blob { sources, .. }) => {
+                merge_sources(sources, vec![source]);
+            }
+            _ => {
+                map.insert(
+                    hash,
+                    BlobOrCollection::Blob {
+                        outboard,
+                        sources: vec![source],
+                        size,
+                    },
+                );
+            }
+        }
+        blobs.push((name, hash, size));
     }
 
-    let collection = Collection::new(blobs, total_blobs_size)?;
-    let data = postcard::to_stdvec(&collection).context("collection blob encoding")?;
-    if data.len() > MAX_MESSAGE_SIZE {
-        bail!("Serialised collection exceeds {MAX_MESSAGE_SIZE}");
+    let hash = if nested {
+        let (hash, _) = add_nested_collection(blobs, metadata, &dirs, &mut map)?;
+        hash
+    } else {
+        let total_blobs_size = blobs.iter().map(|(_, _, size)| size).sum();
+        let blobs = blobs
+            .into_iter()
+            .map(|(name, hash, _)| Blob {
+                name,
+                hash,
+                kind: BlobKind::Blob,
+            })
+            .collect();
+        add_collection(blobs, total_blobs_size, metadata, &dirs, &mut map)?
+    };
+    Ok((map, hash))
+}
+
+/// Where [`create_collection`] stores the data it imports.
+#[derive(Debug, Clone, Default)]
+pub(super) struct ImportDirs {
+    /// Copy the files into this directory, named by their hash, and refer to the copies
+    /// instead of the original files.
+    pub blobs: Option<PathBuf>,
+    /// Write the outboards to files in this directory, named by their hash, instead of
+    /// keeping them on the heap.
+    pub outboards: Option<PathBuf>,
+    /// Write the serialised collections to files in this directory, named by their hash,
+    /// instead of keeping them on the heap.
+    pub collections: Option<PathBuf>,
+    /// The import the files are written for, which keeps them from being collected until
+    /// they are added to the database.
+    pub import: Option<Arc<Import>>,
+}
+
+impl ImportDirs {
+    /// Registers `hash` with the [`Import`], before a file named by it is written.
+    pub fn register(&self, hash: Hash) {
+        if let Some(import) = &self.import {
+            import.register(hash);
+        }
     }
-    let (outboard, hash) = bao_tree::outboard(&data, IROH_BLOCK_SIZE);
-    let hash = Hash::from(hash);
+}
+
+/// Creates a collection blob, adds it to the hashmap and returns its hash.
+fn add_collection(
+    blobs: Vec<Blob>,
+    total_blobs_size: u64,
+    metadata: Vec<Metadata>,
+    dirs: &ImportDirs,
+    map: &mut HashMap<Hash, BlobOrCollection>,
+) -> Result<Hash> {
+    let num_blobs = blobs.len() as u64;
+    let collection = Collection::new(blobs, total_blobs_size)?.with_metadata(metadata)?;
+    let (hash, outboard, data) = match dirs.collections.as_deref() {
+        Some(dir) => write_collection_file(&collection, dir, dirs)?,
+        None => {
+            let data = postcard::to_stdvec(&collection).context("collection blob encoding")?;
+            let (outboard, hash) = bao_tree::outboard(&data, IROH_BLOCK_SIZE);
+            (Hash::from(hash), Bytes::from(outboard), Bytes::from(data))
+        }
+    };
     map.insert(
         hash,
         BlobOrCollection::Collection {
-            outboard: Bytes::from(outboard),
-            data: Bytes::from(data.to_vec()),
+            outboard,
+            data,
+            num_blobs,
+            total_blobs_size,
         },
     );
-    progress.send(ProvideProgress::AllDone { hash }).await?;
-    Ok((map, hash))
+    Ok(hash)
+}
+
+/// Serialises the collection into a file in `dir`, named by its hash, and maps it into memory.
+///
+/// The serialised collection is written directly to the file and its outboard is computed
+/// from there, so it is never held on the heap.  Returns the hash, outboard and data.
+fn write_collection_file(
+    collection: &Collection,
+    dir: &Path,
+    dirs: &ImportDirs,
+) -> Result<(Hash, Bytes, Bytes)> {
+    let file = tempfile::Builder::new()
+        .prefix(".collection-")
+        .tempfile_in(dir)?;
+    let mut error = None;
+    let flavor = WriterFlavor {
+        writer: BufWriter::new(file.as_file()),
+        error: &mut error,
+    };
+    let res = postcard::serialize_with_flavor(collection, flavor);
+    if let Some(err) = error {
+        return Err(err).context("failed to write collection");
+    }
+    res.context("collection blob encoding")?.flush()?;
+    let size = file.as_file().metadata()?.len();
+    // This registers the hash with the import.
+    let (hash, outboard) = compute_outboard(file.path(), size, dirs, |_| {})?;
+    let path = dir.join(format_hash(&hash));
+    file.persist(&path)?;
+    Ok((hash, outboard, map_file(&path)?))
+}
+
+/// A postcard flavor which writes the serialised data to a writer.
+///
+/// postcard errors can not hold an [`io::Error`], so the first one is stored in `error`.
+struct WriterFlavor<'a, W> {
+    writer: W,
+    error: &'a mut Option<io::Error>,
+}
+
+impl<W: Write> Flavor for WriterFlavor<'_, W> {
+    type Output = W;
+
+    fn try_extend(&mut self, data: &[u8]) -> postcard::Result<()> {
+        self.writer.write_all(data).map_err(|err| {
+            *self.error = Some(err);
+            postcard::Error::SerializeBufferFull
+        })
+    }
+
+    fn try_push(&mut self, data: u8) -> postcard::Result<()> {
+        self.try_extend(&[data])
+    }
+
+    fn finalize(self) -> postcard::Result<W> {
+        Ok(self.writer)
+    }
+}
+
+/// A blob given as `(name, hash, size)`.
+type NamedBlob = (String, Hash, u64);
+
+/// Creates a collection for every directory in the names of the blobs.
+///
+/// The blobs are given as `(name, hash, size)`, where the names are `/` separated paths.
+/// Blobs and metadata inside a directory are added to the nested collection of that
+/// directory instead of directly to the returned collection.
+///
+/// Returns the hash of the created collection and the total size of all blobs in it.
+fn add_nested_collection(
+    blobs: Vec<NamedBlob>,
+    metadata: Vec<Metadata>,
+    import_dirs: &ImportDirs,
+    map: &mut HashMap<Hash, BlobOrCollection>,
+) -> Result<(Hash, u64)> {
+    let mut entries = Vec::new();
+    let mut entries_metadata = Vec::new();
+    let mut dirs: BTreeMap<String, (Vec<NamedBlob>, Vec<Metadata>)> = BTreeMap::new();
+    let mut total_blobs_size = 0;
+    for (name, hash, size) in blobs {
+        match name.split_once('/') {
+            Some((dir, rest)) => {
+                let (blobs, _) = dirs.entry(dir.to_string()).or_default();
+                blobs.push((rest.to_string(), hash, size));
+            }
+            None => {
+                total_blobs_size += size;
+                entries.push(Blob {
+                    name,
+                    hash,
+                    kind: BlobKind::Blob,
+                });
+            }
+        }
+    }
+    for mut entry in metadata {
+        match entry.name.split_once('/') {
+            Some((dir, rest)) => {
+                let dir = dir.to_string();
+                entry.name = rest.to_string();
+                let (_, metadata) = dirs.entry(dir).or_default();
+                metadata.push(entry);
+            }
+            None => entries_metadata.push(entry),
+        }
+    }
+    for (dir, (blobs, metadata)) in dirs {
+        let (hash, size) = add_nested_collection(blobs, metadata, import_dirs, map)?;
+        total_blobs_size += size;
+        entries.push(Blob {
+            name: dir,
+            hash,
+            kind: BlobKind::Collection,
+        });
+    }
+    let hash = add_collection(
+        entries,
+        total_blobs_size,
+        entries_metadata,
+        import_dirs,
+        map,
+    )?;
+    Ok((hash, total_blobs_size))
+}
+
+/// Reads the metadata of the file, directory or symlink at `path`.
+///
+/// Symlinks are not followed.
+pub(super) fn read_metadata(name: String, path: &Path) -> Result<Metadata> {
+    let meta = std::fs::symlink_metadata(path)
+        .with_context(|| format!("Failed to read metadata of {}", path.display()))?;
+    let kind = if meta.file_type().is_symlink() {
+        let target = std::fs::read_link(path)?;
+        let target = target
+            .to_str()
+            .with_context(|| format!("Symlink target of {} is not UTF-8", path.display()))?;
+        EntryKind::Symlink {
+            target: target.to_string(),
+        }
+    } else if meta.is_dir() {
+        EntryKind::Directory
+    } else {
+        EntryKind::File
+    };
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        Some(meta.permissions().mode() & 0o777)
+    };
+    #[cfg(not(unix))]
+    let mode = None;
+    Ok(Metadata {
+        name,
+        kind,
+        mode,
+        mtime: meta.modified().ok().map(Timestamp::from),
+    })
 }
 
 /// Outboard data for a blob.
 struct BlobWithOutboard {
-    /// The path of the file containing the original blob data.
-    path: PathBuf,
+    /// The file containing the original blob data.
+    source: BlobSource,
     /// The blob name.
     // TODO: This is not optional!  crate::blobs::Blob::name is String.
     name: String,
@@ -96,13 +330,17 @@ struct BlobWithOutboard {
 /// Computes all the outboards, using parallelism.
 async fn compute_all_outboards(
     data_sources: Vec<DataSource>,
+    dirs: ImportDirs,
     progress: Progress<ProvideProgress>,
 ) -> Result<Vec<BlobWithOutboard>> {
     let outboards: Vec<_> = stream::iter(data_sources)
         .enumerate()
         .map(|(id, data)| {
             let progress = progress.clone();
-            tokio::task::spawn_blocking(move || outboard_from_datasource(id as u64, data, progress))
+            let dirs = dirs.clone();
+            tokio::task::spawn_blocking(move || {
+                outboard_from_datasource(id as u64, data, &dirs, progress)
+            })
         })
         // Allow at most num_cpus tasks at a time, otherwise we might get too many open
         // files.
@@ -130,9 +368,17 @@ async fn compute_all_outboards(
 fn outboard_from_datasource(
     id: u64,
     data_source: DataSource,
+    dirs: &ImportDirs,
     progress: Progress<ProvideProgress>,
 ) -> Result<BlobWithOutboard> {
-    let file_meta = data_source.path().metadata().with_context(|| {
+    // Copy the data first, so the outboard is computed from the data the store owns.
+    let copy = dirs
+        .blobs
+        .as_deref()
+        .map(|dir| copy_to_store(data_source.path(), dir))
+        .transpose()?;
+    let path = copy.as_deref().unwrap_or_else(|| data_source.path());
+    let file_meta = path.metadata().with_context(|| {
         format!(
             "Failed to read file size from {}",
             data_source.path().display()
@@ -147,20 +393,43 @@ fn outboard_from_datasource(
     });
     let (hash, outboard) = {
         let progress = progress.clone();
-        compute_outboard(data_source.path(), size, move |offset| {
+        compute_outboard(path, size, dirs, move |offset| {
             progress.try_send(ProvideProgress::Progress { id, offset })
         })?
     };
+    let path = match (copy, dirs.blobs.as_deref()) {
+        (Some(copy), Some(dir)) => {
+            let path = dir.join(format_hash(&hash));
+            copy.persist(&path)?;
+            path
+        }
+        _ => data_source.path().to_path_buf(),
+    };
     progress.blocking_send(ProvideProgress::Done { id, hash });
     Ok(BlobWithOutboard {
-        path: data_source.path().to_path_buf(),
+        // The metadata is from before the outboard was computed, so changes while hashing
+        // are detected later.
+        source: BlobSource::with_metadata(path, &file_meta),
         name: data_source.name().to_string(),
         size,
         hash,
-        outboard: Bytes::from(outboard),
+        outboard,
     })
 }
 
+/// Copies the file at `path` to a temporary file in the `store` directory.
+///
+/// [`std::fs::copy`] lets the operating system clone the data instead, where the platform
+/// and filesystem support it, e.g. using reflinks.
+fn copy_to_store(path: &Path, store: &Path) -> Result<tempfile::TempPath> {
+    let copy = tempfile::Builder::new()
+        .prefix(".import-")
+        .tempfile_in(store)?
+        .into_temp_path();
+    std::fs::copy(path, &copy).with_context(|| format!("Failed to copy {}", path.display()))?;
+    Ok(copy)
+}
+
 /// Synchronously compute the outboard of a file, and return hash and outboard.
 ///
 /// It is assumed that the file is not modified while this is running.
@@ -170,11 +439,16 @@ fn outboard_from_datasource(
 ///
 /// If the size of the file is changed while this is running, an error will be
 /// returned.
-fn compute_outboard(
+///
+/// If the `dirs` have an outboards directory the outboard is written to a file in it, see
+/// [`compute_outboard_file`], otherwise it is kept in memory.  Either way the hash is
+/// registered with the import of the `dirs`.
+pub(super) fn compute_outboard(
     path: &Path,
     size: u64,
+    dirs: &ImportDirs,
     progress: impl Fn(u64) + Send + Sync + 'static,
-) -> anyhow::Result<(Hash, Vec<u8>)> {
+) -> anyhow::Result<(Hash, Bytes)> {
     ensure!(
         path.is_file(),
         "can only transfer blob data: {}",
@@ -183,16 +457,6 @@ fn compute_outboard(
     let span = trace_span!("outboard.compute", path = %path.display());
     let _guard = span.enter();
     let file = std::fs::File::open(path)?;
-    // compute outboard size so we can pre-allocate the buffer.
-    //
-    // outboard is ~1/16 of data size, so this will fail for really large files
-    // on really small devices. E.g. you want to transfer a 1TB file from a pi4 with 1gb ram.
-    //
-    // The way to solve this would be to have larger blocks than the blake3 chunk size of 1024.
-    // I think we really want to keep the outboard in memory for simplicity.
-    let outboard_size = usize::try_from(bao_tree::outboard_size(size, IROH_BLOCK_SIZE))
-        .context("outboard too large to fit in memory")?;
-    let mut outboard = Vec::with_capacity(outboard_size);
 
     // wrap the reader in a progress reader, so we can report progress.
     let reader = ProgressReader::new(file, |p| {
@@ -204,10 +468,79 @@ fn compute_outboard(
     // this reduces the number of io ops and also the number of progress reports
     let mut reader = BufReader::with_capacity(1024 * 1024, reader);
 
+    if let Some(dir) = dirs.outboards.as_deref() {
+        let (hash, outboard) = compute_outboard_file(&mut reader, size, dir, dirs)?;
+        trace!(%hash, "done");
+        return Ok((hash, outboard));
+    }
+
+    // compute outboard size so we can pre-allocate the buffer.
+    //
+    // outboard is ~1/16 of data size, so this will fail for really large files
+    // on really small devices. E.g. you want to transfer a 1TB file from a pi4 with 1gb ram.
+    // Databases with a data directory avoid this by storing outboards in files.
+    let outboard_size = usize::try_from(bao_tree::outboard_size(size, IROH_BLOCK_SIZE))
+        .context("outboard too large to fit in memory")?;
+    let mut outboard = Vec::with_capacity(outboard_size);
     let hash =
         bao_tree::io::sync::outboard_post_order(&mut reader, size, IROH_BLOCK_SIZE, &mut outboard)?;
     let ob = PostOrderMemOutboard::load(hash, Cursor::new(&outboard), IROH_BLOCK_SIZE)?.flip();
     trace!(%hash, "done");
+    dirs.register(hash.into());
+
+    Ok((hash.into(), Bytes::from(ob.into_inner())))
+}
 
-    Ok((hash.into(), ob.into_inner()))
+/// Computes the outboard into a file in `dir`, named by the hash, and maps it into memory.
+///
+/// bao-tree computes the outboard in post-order, which is flipped to the pre-order used for
+/// sending through a temporary file, so the outboard is never held on the heap.
+fn compute_outboard_file(
+    reader: &mut impl Read,
+    size: u64,
+    dir: &Path,
+    dirs: &ImportDirs,
+) -> Result<(Hash, Bytes)> {
+    let mut post_order_file = tempfile::Builder::new()
+        .prefix(".outboard-")
+        .tempfile_in(dir)?;
+    let hash = {
+        let mut writer = BufWriter::new(post_order_file.as_file_mut());
+        let hash =
+            bao_tree::io::sync::outboard_post_order(reader, size, IROH_BLOCK_SIZE, &mut writer)?;
+        writer.flush()?;
+        hash
+    };
+    // Safety: the temporary files are not accessed by anything but these mappings.
+    let post_order_data = unsafe { Mmap::map(post_order_file.as_file())? };
+    let post_order = PostOrderMemOutboardRef::load(hash, &post_order_data, IROH_BLOCK_SIZE)?;
+
+    // Both orders have the same length, the size prefix replaces the size suffix.
+    let pre_order_file = tempfile::Builder::new()
+        .prefix(".outboard-")
+        .tempfile_in(dir)?;
+    pre_order_file
+        .as_file()
+        .set_len(post_order_data.len() as u64)?;
+    let mut pre_order = unsafe { MmapMut::map_mut(pre_order_file.as_file())? };
+    pre_order[..8].copy_from_slice(&size.to_le_bytes());
+    let tree = post_order.tree();
+    for node in tree.post_order_nodes_iter() {
+        if let Some((l, r)) = post_order.load(node)? {
+            let offset = tree
+                .pre_order_offset(node)
+                .context("node missing from the outboard")?;
+            let offset = usize::try_from(offset)? * 64 + 8;
+            pre_order[offset..offset + 32].copy_from_slice(l.as_bytes());
+            pre_order[offset + 32..offset + 64].copy_from_slice(r.as_bytes());
+        }
+    }
+    pre_order.flush()?;
+    drop(pre_order);
+
+    let hash = Hash::from(hash);
+    dirs.register(hash);
+    let path = dir.join(format_hash(&hash));
+    pre_order_file.persist(&path)?;
+    Ok((hash, map_file(&path)?))
 }
@@ -1,29 +1,85 @@
-use super::BlobOrCollection;
+use super::{merge_sources, BlobOrCollection, BlobSource};
 use crate::{
-    rpc_protocol::ValidateProgress,
-    util::{validate_bao, BaoValidationError},
+    blobs::Collection,
+    rpc_protocol::{ExportProgress, ValidateProgress},
+    util::{path_from_name, validate_bao, validate_bao_copy, BaoValidationError},
     Hash,
 };
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use bytes::Bytes;
-use futures::StreamExt;
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::{BTreeSet, HashMap},
-    fmt, io,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
     path::{Path, PathBuf},
     result,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
 };
 use tokio::sync::mpsc;
 
 /// Database containing content-addressed data (blobs or collections).
+///
+/// A database opened with [`Database::open`] writes every entry to disk as it is added, so
+/// nothing is lost if the process exits without calling [`Database::save`].
+///
+/// The roots added to the database, e.g. the collection created for some provided files,
+/// are pinned, or given a name using [`Database::set_tag`].  [`Database::gc`] removes
+/// everything which is not reachable from a pin or a tag.
 #[derive(Debug, Clone, Default)]
-pub struct Database(Arc<RwLock<HashMap<Hash, BlobOrCollection>>>);
+pub struct Database {
+    map: Arc<RwLock<HashMap<Hash, BlobOrCollection>>>,
+    pins: Arc<RwLock<BTreeSet<Hash>>>,
+    tags: Arc<RwLock<BTreeMap<String, Hash>>>,
+    /// The journal new entries are written to, if the database is persistent.
+    journal: Option<Arc<Mutex<Journal>>>,
+    /// The imports in progress, whose data [`Database::gc`] must not remove.
+    imports: Arc<Mutex<Imports>>,
+}
 
+/// Pins every entry of the map which is not referenced by a collection.
 impl From<HashMap<Hash, BlobOrCollection>> for Database {
     fn from(map: HashMap<Hash, BlobOrCollection>) -> Self {
-        Self(Arc::new(RwLock::new(map)))
+        let pins = unreferenced_entries(&map);
+        Self {
+            map: Arc::new(RwLock::new(map)),
+            pins: Arc::new(RwLock::new(pins)),
+            tags: Default::default(),
+            journal: None,
+            imports: Default::default(),
+        }
+    }
+}
+
+/// The hashes of all children of a collection.
+fn collection_children(data: &[u8]) -> Result<impl Iterator<Item = Hash>> {
+    let collection = Collection::from_bytes(data)?;
+    let hashes = collection
+        .blobs()
+        .iter()
+        .map(|blob| blob.hash())
+        .collect::<Vec<_>>();
+    Ok(hashes.into_iter())
+}
+
+/// The entries which are not referenced by any collection in the map.
+///
+/// These are pinned for databases from before pins were recorded.
+fn unreferenced_entries(map: &HashMap<Hash, BlobOrCollection>) -> BTreeSet<Hash> {
+    let mut referenced = HashSet::new();
+    for entry in map.values() {
+        if let BlobOrCollection::Collection { data, .. } = entry {
+            if let Ok(children) = collection_children(data) {
+                referenced.extend(children);
+            }
+        }
     }
+    map.keys()
+        .filter(|hash| !referenced.contains(hash))
+        .copied()
+        .collect()
 }
 
 /// A snapshot of the database.
@@ -31,12 +87,17 @@ impl From<HashMap<Hash, BlobOrCollection>> for Database {
 /// `E` can be `Infallible` if we take a snapshot from an in memory database,
 /// or `io::Error` if we read a database from disk.
 pub(crate) struct Snapshot<E> {
-    /// list of paths we have, hash is the hash of the blob or collection
-    paths: Box<dyn Iterator<Item = (Hash, u64, Option<PathBuf>)>>,
+    /// list of entries we have, hash is the hash of the blob or collection, with the
+    /// sources of blobs
+    paths: Box<dyn Iterator<Item = (Hash, u64, Vec<BlobSource>)>>,
     /// map of hash to outboard, hash is the hash of the outboard and is unique
     outboards: Box<dyn Iterator<Item = result::Result<(Hash, Bytes), E>>>,
     /// map of hash to collection, hash is the hash of the collection and is unique
     collections: Box<dyn Iterator<Item = result::Result<(Hash, Bytes), E>>>,
+    /// the pinned hashes, `None` for a database from before pins were recorded
+    pins: Option<BTreeSet<Hash>>,
+    /// the tags, names for root hashes
+    tags: BTreeMap<String, Hash>,
 }
 
 impl<E> fmt::Debug for Snapshot<E> {
@@ -55,12 +116,17 @@ impl From<NoError> for io::Error {
     }
 }
 
+#[derive(Debug, Clone)]
 struct DataPaths {
-    #[allow(dead_code)]
     data_dir: PathBuf,
     outboards_dir: PathBuf,
     collections_dir: PathBuf,
+    blobs_dir: PathBuf,
     paths_file: PathBuf,
+    pins_file: PathBuf,
+    tags_file: PathBuf,
+    sources_file: PathBuf,
+    journal_file: PathBuf,
 }
 
 impl DataPaths {
@@ -68,15 +134,361 @@ impl DataPaths {
         Self {
             outboards_dir: data_dir.join("outboards"),
             collections_dir: data_dir.join("collections"),
+            blobs_dir: data_dir.join("blobs"),
             paths_file: data_dir.join("paths.bin"),
+            pins_file: data_dir.join("pins.bin"),
+            tags_file: data_dir.join("tags.bin"),
+            sources_file: data_dir.join("sources.bin"),
+            journal_file: data_dir.join("journal.bin"),
             data_dir,
         }
     }
+
+    fn create_dirs(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.data_dir)?;
+        fs::create_dir_all(&self.outboards_dir)?;
+        fs::create_dir_all(&self.collections_dir)?;
+        fs::create_dir_all(&self.blobs_dir)?;
+        Ok(())
+    }
+}
+
+/// A record of the journal.
+///
+/// The journal is replayed on top of the paths file when loading, and emptied whenever the
+/// paths file is rewritten by [`Database::save`].
+#[derive(Debug, Serialize, Deserialize)]
+enum JournalEntry {
+    /// An entry was added, with the same meaning as an item of the paths file.
+    Add {
+        hash: Hash,
+        size: u64,
+        path: Option<PathBuf>,
+    },
+    /// An entry was removed.
+    Remove { hash: Hash },
+    /// A hash was pinned.
+    Pin { hash: Hash },
+    /// A hash was unpinned.
+    Unpin { hash: Hash },
+    /// A tag was set, or deleted if `hash` is `None`.
+    Tag { name: String, hash: Option<Hash> },
+    /// The sources of a blob were set, replacing the path of its `Add` record.
+    Sources {
+        hash: Hash,
+        sources: Vec<BlobSource>,
+    },
+}
+
+/// The number of checksum bytes after each journal record.
+const RECORD_CHECKSUM_LEN: usize = 4;
+
+fn record_checksum(payload: &[u8]) -> [u8; RECORD_CHECKSUM_LEN] {
+    let mut sum = [0u8; RECORD_CHECKSUM_LEN];
+    sum.copy_from_slice(&blake3::hash(payload).as_bytes()[..RECORD_CHECKSUM_LEN]);
+    sum
+}
+
+/// Encodes a journal record: the length of the payload, the payload and its checksum.
+fn encode_record(entry: &JournalEntry) -> Vec<u8> {
+    let payload = postcard::to_stdvec(entry).expect("failed to serialize journal entry");
+    let mut record = Vec::with_capacity(4 + payload.len() + RECORD_CHECKSUM_LEN);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&payload);
+    record.extend_from_slice(&record_checksum(&payload));
+    record
+}
+
+/// Decodes the journal record at the start of `buf`, returning it and its length.
+///
+/// Returns `None` if the record is incomplete or corrupt, e.g. because the process
+/// crashed while writing it.
+fn decode_record(buf: &[u8]) -> Option<(JournalEntry, usize)> {
+    let len = u32::from_le_bytes(buf.get(..4)?.try_into().ok()?) as usize;
+    let end = 4 + len + RECORD_CHECKSUM_LEN;
+    let payload = buf.get(4..4 + len)?;
+    if buf.get(4 + len..end)? != record_checksum(payload) {
+        return None;
+    }
+    let entry = postcard::from_bytes(payload).ok()?;
+    Some((entry, end))
+}
+
+/// Reads the valid records of the journal, returning them and the length they take up.
+fn read_journal(path: &Path) -> io::Result<(Vec<JournalEntry>, u64)> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(err) => return Err(err),
+    };
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while let Some((entry, len)) = decode_record(&data[pos..]) {
+        entries.push(entry);
+        pos += len;
+    }
+    if pos < data.len() {
+        tracing::warn!(
+            "ignoring {} bytes of incomplete records at the end of {}",
+            data.len() - pos,
+            path.display()
+        );
+    }
+    Ok((entries, pos as u64))
+}
+
+/// Maps an outboard or collection file into memory, so it is not held on the heap.
+pub(super) fn map_file(path: &Path) -> io::Result<Bytes> {
+    let file = File::open(path)?;
+    // Safety: outboard and collection files are named by their hash and only ever replaced
+    // atomically, never modified in place, so the mapped data does not change.
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    Ok(Bytes::from_owner(mmap))
+}
+
+/// Writes `data` to `path` so that the file is either fully written or not changed at all.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+/// Removes a file, if it exists.
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Removes the content-addressed files in `dir` whose hash is not in `keep`.
+///
+/// Files which are not named by a hash, e.g. temporary files, are left alone.
+fn remove_orphans(dir: &Path, keep: &HashSet<Hash>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let hash = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| parse_hash(name).ok());
+        if let Some(hash) = hash {
+            if !keep.contains(&hash) {
+                tracing::debug!("removing orphaned {}", path.display());
+                remove_if_exists(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The first of the `sources` of a blob with `size` which did not change since it was
+/// added.
+fn current_source(sources: &[BlobSource], size: u64) -> Option<&BlobSource> {
+    sources.iter().find(|source| {
+        fs::metadata(&source.path)
+            .map(|metadata| source.is_current(size, &metadata))
+            .unwrap_or(false)
+    })
+}
+
+/// Writes the blob `hash` with the database `entry` to `path`, validating it while writing.
+///
+/// The data is written to a temporary file next to `path`, which is only moved into place
+/// once it is validated.  An existing file at `path` is only replaced if `force` is set.
+fn export_blob(
+    hash: Hash,
+    entry: Option<BlobOrCollection>,
+    path: &Path,
+    force: bool,
+    progress: impl Fn(u64),
+) -> anyhow::Result<()> {
+    let (outboard, sources, size) = match entry {
+        Some(BlobOrCollection::Blob {
+            outboard,
+            sources,
+            size,
+        }) => (outboard, sources, size),
+        _ => bail!("blob {hash} not found"),
+    };
+    // A changed source fails to validate, so it is only a last resort.
+    let source = current_source(&sources, size)
+        .or_else(|| sources.first())
+        .context("blob has no source")?;
+    let data = File::open(&source.path)
+        .with_context(|| format!("failed to open {}", source.path.display()))?;
+    let parent = path.parent().context("invalid path")?;
+    fs::create_dir_all(parent).with_context(|| format!("failed to create {}", parent.display()))?;
+    let target = tempfile::Builder::new()
+        .prefix(".export-")
+        .tempfile_in(parent)
+        .with_context(|| format!("failed to create a file in {}", parent.display()))?;
+    validate_bao_copy(hash, data, outboard, target.as_file(), progress)
+        .with_context(|| format!("failed to validate {}", source.path.display()))?;
+    target.as_file().sync_all()?;
+    // Dropping the temporary file on an error removes it again.
+    let res = if force {
+        target.persist(path)
+    } else {
+        target.persist_noclobber(path)
+    };
+    res.map_err(|err| err.error)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// Writes a content-addressed file, unless it already exists.
+fn write_if_missing(path: &Path, data: &[u8]) -> io::Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    write_atomic(path, data)
+}
+
+/// The imports in progress, see [`Database::start_import`].
+#[derive(Debug, Default)]
+struct Imports {
+    /// The number of imports in progress.
+    running: usize,
+    /// The hashes registered by the imports in progress, with how often they were registered.
+    hashes: HashMap<Hash, usize>,
+}
+
+/// An import in progress, which keeps [`Database::gc`] from removing the data it writes.
+///
+/// Data is added to the database only once the import is complete, so until then the files
+/// the import writes are not reachable from a pin or a tag.  Every hash is registered using
+/// [`Import::register`] before a file named by it is written, or before the import relies on
+/// an existing entry.  Dropping the import, once its entries are added, releases the hashes.
+#[derive(Debug)]
+pub(crate) struct Import {
+    imports: Arc<Mutex<Imports>>,
+    hashes: Mutex<Vec<Hash>>,
+}
+
+impl Import {
+    /// Keeps `hash` and the files named by it from being removed until the import is dropped.
+    pub fn register(&self, hash: Hash) {
+        *self.imports.lock().unwrap().hashes.entry(hash).or_default() += 1;
+        self.hashes.lock().unwrap().push(hash);
+    }
+}
+
+impl Drop for Import {
+    fn drop(&mut self) {
+        let mut imports = self.imports.lock().unwrap();
+        imports.running -= 1;
+        for hash in self.hashes.get_mut().unwrap().drain(..) {
+            if let Some(count) = imports.hashes.get_mut(&hash) {
+                *count -= 1;
+                if *count == 0 {
+                    imports.hashes.remove(&hash);
+                }
+            }
+        }
+    }
+}
+
+/// The on-disk log of the entries added to a persistent [`Database`].
+#[derive(Debug)]
+struct Journal {
+    paths: DataPaths,
+    file: File,
+}
+
+impl Journal {
+    /// Opens the journal in `paths`, dropping any incomplete record at its end.
+    fn open(paths: DataPaths) -> io::Result<Self> {
+        paths.create_dirs()?;
+        let (_, len) = read_journal(&paths.journal_file)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&paths.journal_file)?;
+        file.set_len(len)?;
+        Ok(Self { paths, file })
+    }
+
+    /// Writes the outboard and collection data of an entry and appends it to the journal.
+    ///
+    /// The record is only durable after [`Journal::sync`].
+    fn add(&mut self, hash: Hash, entry: &BlobOrCollection) -> io::Result<()> {
+        let name = format_hash(&hash);
+        let record = match entry {
+            BlobOrCollection::Blob {
+                outboard,
+                sources,
+                size,
+            } => {
+                write_if_missing(&self.paths.outboards_dir.join(&name), outboard)?;
+                self.append(&JournalEntry::Add {
+                    hash,
+                    size: *size,
+                    path: entry.blob_path().map(ToOwned::to_owned),
+                })?;
+                JournalEntry::Sources {
+                    hash,
+                    sources: sources.clone(),
+                }
+            }
+            BlobOrCollection::Collection { outboard, data, .. } => {
+                write_if_missing(&self.paths.outboards_dir.join(&name), outboard)?;
+                write_if_missing(&self.paths.collections_dir.join(&name), data)?;
+                JournalEntry::Add {
+                    hash,
+                    size: data.len() as u64,
+                    path: None,
+                }
+            }
+        };
+        self.append(&record)
+    }
+
+    /// Appends a record, which is only durable after [`Journal::sync`].
+    fn append(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        self.file.write_all(&encode_record(entry))
+    }
+
+    /// Removes the files of a removed entry.
+    ///
+    /// Blobs are only removed if they are copies owned by the database.
+    fn remove_files(&self, hash: Hash, entry: &BlobOrCollection) -> io::Result<()> {
+        let name = format_hash(&hash);
+        remove_if_exists(&self.paths.outboards_dir.join(&name))?;
+        match entry {
+            BlobOrCollection::Blob { sources, .. } => {
+                let copy = self.paths.blobs_dir.join(&name);
+                if sources.iter().any(|source| source.path == copy) {
+                    remove_if_exists(&copy)?;
+                }
+            }
+            BlobOrCollection::Collection { .. } => {
+                remove_if_exists(&self.paths.collections_dir.join(&name))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Empties the journal, once its entries are in the paths file.
+    fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()
+    }
 }
 
 /// Using base64 you have all those weird characters like + and /.
 /// So we use hex for file names.
-fn format_hash(hash: &Hash) -> String {
+pub(super) fn format_hash(hash: &Hash) -> String {
     hex::encode(hash.as_ref())
 }
 
@@ -89,25 +501,104 @@ fn parse_hash(hash: &str) -> Result<Hash> {
 
 impl Snapshot<io::Error> {
     /// Load a snapshot from disk.
+    ///
+    /// This includes the changes written to the journal since the paths file was written.
     pub fn load(data_dir: impl AsRef<Path>) -> anyhow::Result<Self> {
-        use std::fs;
         let DataPaths {
             outboards_dir,
             collections_dir,
             paths_file,
+            pins_file,
+            tags_file,
+            sources_file,
+            journal_file,
             ..
         } = DataPaths::new(data_dir.as_ref().to_path_buf());
-        let paths = fs::read(paths_file)?;
-        let paths = postcard::from_bytes::<Vec<(Hash, u64, Option<PathBuf>)>>(&paths)?;
-        let hashes = paths
-            .iter()
-            .map(|(hash, _, _)| *hash)
-            .collect::<BTreeSet<_>>();
+        let (mut paths, legacy) = match fs::read(paths_file) {
+            Ok(paths) => {
+                let paths = postcard::from_bytes::<Vec<(Hash, u64, Option<PathBuf>)>>(&paths)?;
+                let paths = paths
+                    .into_iter()
+                    .map(|(hash, size, path)| (hash, (size, path)))
+                    .collect::<HashMap<_, _>>();
+                (paths, true)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (HashMap::new(), false),
+            Err(err) => return Err(err.into()),
+        };
+        let mut pins = match fs::read(pins_file) {
+            Ok(pins) => Some(postcard::from_bytes::<BTreeSet<Hash>>(&pins)?),
+            // A paths file without a pins file is from before pins were recorded.
+            Err(err) if err.kind() == io::ErrorKind::NotFound && legacy => None,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Some(BTreeSet::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut tags = match fs::read(tags_file) {
+            Ok(tags) => postcard::from_bytes::<BTreeMap<String, Hash>>(&tags)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        // Without a sources file the path of the paths file is the only source.
+        let mut sources = match fs::read(sources_file) {
+            Ok(sources) => postcard::from_bytes::<HashMap<Hash, Vec<BlobSource>>>(&sources)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let (journal, _) = read_journal(&journal_file)?;
+        for entry in journal {
+            match entry {
+                JournalEntry::Add { hash, size, path } => {
+                    paths.insert(hash, (size, path));
+                    sources.remove(&hash);
+                }
+                JournalEntry::Remove { hash } => {
+                    paths.remove(&hash);
+                    sources.remove(&hash);
+                }
+                JournalEntry::Sources {
+                    hash,
+                    sources: blob_sources,
+                } => {
+                    sources.insert(hash, blob_sources);
+                }
+                JournalEntry::Pin { hash } => {
+                    pins.get_or_insert_with(BTreeSet::new).insert(hash);
+                }
+                JournalEntry::Unpin { hash } => {
+                    pins.get_or_insert_with(BTreeSet::new).remove(&hash);
+                }
+                JournalEntry::Tag {
+                    name,
+                    hash: Some(hash),
+                } => {
+                    tags.insert(name, hash);
+                }
+                JournalEntry::Tag { name, hash: None } => {
+                    tags.remove(&name);
+                }
+            }
+        }
+        let hashes = paths.keys().copied().collect::<BTreeSet<_>>();
+        let paths = paths.into_iter().map(move |(hash, (size, path))| {
+            let sources = match (sources.remove(&hash), path) {
+                (Some(sources), Some(_)) => sources,
+                (None, Some(path)) => vec![BlobSource::new(path)],
+                (_, None) => Vec::new(),
+            };
+            (hash, size, sources)
+        });
         let outboards = hashes.clone().into_iter().map(move |hash| {
             let path = outboards_dir.join(format_hash(&hash));
-            fs::read(path).map(|x| (hash, Bytes::from(x)))
+            map_file(&path).map(|x| (hash, x))
         });
-        let collections = fs::read_dir(collections_dir)?
+        let collections = match fs::read_dir(collections_dir) {
+            Ok(entries) => Some(entries),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        let collections = collections
+            .into_iter()
+            .flatten()
             .map(move |entry| {
                 let entry = entry?;
                 let path = entry.path();
@@ -143,15 +634,16 @@ impl Snapshot<io::Error> {
                     tracing::debug!("skipping unexpected hash: {:?}", hash);
                     return Ok(None);
                 }
-                // read the collection data and turn it into a Bytes
-                let collection = Bytes::from(fs::read(path)?);
+                let collection = map_file(&path)?;
                 io::Result::Ok(Some((hash, collection)))
             })
             .filter_map(|x| x.transpose());
         Ok(Self {
-            paths: Box::new(paths.into_iter()),
+            paths: Box::new(paths),
             outboards: Box::new(outboards),
             collections: Box::new(collections),
+            pins,
+            tags,
         })
     }
 }
@@ -161,31 +653,62 @@ where
     io::Error: From<E>,
 {
     /// Persist the snapshot to disk.
-    pub fn persist(self, data_dir: impl AsRef<Path>) -> io::Result<()> {
-        use std::fs;
+    ///
+    /// Outboards and collections are content-addressed, so only the missing ones are
+    /// written, and if `remove_orphaned` is set the ones of entries which are not in the
+    /// snapshot are removed.  The paths, sources, pins and tags files are replaced
+    /// atomically.
+    ///
+    /// The paths file has the first source of every blob, so older versions can still
+    /// read it.
+    pub fn persist(self, data_dir: impl AsRef<Path>, remove_orphaned: bool) -> io::Result<()> {
+        let data_paths = DataPaths::new(data_dir.as_ref().to_path_buf());
+        data_paths.create_dirs()?;
         let DataPaths {
             outboards_dir,
             collections_dir,
             paths_file,
+            pins_file,
+            tags_file,
+            sources_file,
             ..
-        } = DataPaths::new(data_dir.as_ref().to_path_buf());
-        fs::create_dir_all(&data_dir)?;
-        fs::create_dir_all(&outboards_dir)?;
-        fs::create_dir_all(&collections_dir)?;
+        } = data_paths;
         for item in self.outboards {
             let (hash, outboard) = item.map_err(Into::into)?;
-            let path = outboards_dir.join(format_hash(&hash));
-            fs::write(path, &outboard)?;
+            write_if_missing(&outboards_dir.join(format_hash(&hash)), &outboard)?;
         }
         for item in self.collections {
             let (hash, collection) = item.map_err(Into::into)?;
-            let path = collections_dir.join(format_hash(&hash));
-            fs::write(path, &collection)?;
+            write_if_missing(&collections_dir.join(format_hash(&hash)), &collection)?;
+        }
+        let tags_content = postcard::to_stdvec(&self.tags).expect("failed to serialize tags file");
+        write_atomic(&tags_file, &tags_content)?;
+        // The pins file is written first, a paths file without it is from an old version.
+        if let Some(pins) = self.pins {
+            let pins_content = postcard::to_stdvec(&pins).expect("failed to serialize pins file");
+            write_atomic(&pins_file, &pins_content)?;
         }
-        let mut paths = self.paths.collect::<Vec<_>>();
-        paths.sort_by_key(|(path, _, _)| *path);
+        let mut entries = self.paths.collect::<Vec<_>>();
+        entries.sort_by_key(|(hash, _, _)| *hash);
+        let sources = entries
+            .iter()
+            .filter(|(_, _, sources)| !sources.is_empty())
+            .map(|(hash, _, sources)| (*hash, sources))
+            .collect::<BTreeMap<_, _>>();
+        let sources_content =
+            postcard::to_stdvec(&sources).expect("failed to serialize sources file");
+        write_atomic(&sources_file, &sources_content)?;
+        let paths = entries
+            .iter()
+            .map(|(hash, size, sources)| (*hash, *size, sources.first().map(|s| &s.path)))
+            .collect::<Vec<_>>();
         let paths_content = postcard::to_stdvec(&paths).expect("failed to serialize paths file");
-        fs::write(paths_file, paths_content)?;
+        write_atomic(&paths_file, &paths_content)?;
+        if remove_orphaned {
+            let hashes = paths.iter().map(|(hash, _, _)| *hash).collect();
+            remove_orphans(&outboards_dir, &hashes)?;
+            remove_orphans(&collections_dir, &hashes)?;
+        }
         Ok(())
     }
 }
@@ -215,13 +738,76 @@ impl Database {
 
     fn save_internal(&self, dir: PathBuf) -> io::Result<()> {
         tracing::info!("Persisting database to {}...", dir.display());
-        let snapshot = self.snapshot();
-        snapshot.persist(dir)?;
+        match &self.journal {
+            Some(journal) if journal.lock().unwrap().paths.data_dir == dir => {
+                // Hold the lock so that no entry is added between the snapshot and clearing
+                // the journal.
+                let mut journal = journal.lock().unwrap();
+                // Running imports write files before adding their entries, so orphans are
+                // left alone while any import runs, like in gc.
+                let imports = self.imports.lock().unwrap();
+                self.snapshot().persist(&dir, imports.running == 0)?;
+                journal.clear()?;
+            }
+            _ => {
+                let imports = self.imports.lock().unwrap();
+                self.snapshot().persist(&dir, imports.running == 0)?
+            }
+        }
         tracing::info!("Database stored");
         io::Result::Ok(())
     }
 
+    fn open_internal(dir: PathBuf) -> anyhow::Result<Self> {
+        let journal = Journal::open(DataPaths::new(dir.clone()))?;
+        let legacy = journal.paths.paths_file.exists() && !journal.paths.pins_file.exists();
+        let mut db = Self::load_internal(dir.clone())?;
+        if legacy {
+            // Record the pins derived from the entries before anything else changes.
+            db.save_internal(dir)?;
+        }
+        db.journal = Some(Arc::new(Mutex::new(journal)));
+        Ok(db)
+    }
+
+    /// Open a persistent database in `dir`, creating it if it does not exist.
+    ///
+    /// Entries added to the database are written to `dir` immediately.  [`Database::save`]
+    /// to the same directory compacts the entries added since the last save.
+    pub async fn open(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let db = tokio::task::spawn_blocking(|| Self::open_internal(dir)).await??;
+        Ok(db)
+    }
+
+    /// The directory blobs are copied to by [`crate::rpc_protocol::ImportMode::Copy`].
+    ///
+    /// Only a database opened with [`Database::open`] has one.
+    pub(crate) fn blobs_dir(&self) -> Option<PathBuf> {
+        let journal = self.journal.as_ref()?.lock().unwrap();
+        Some(journal.paths.blobs_dir.clone())
+    }
+
+    /// The directory outboards are stored in, instead of on the heap.
+    ///
+    /// Only a database opened with [`Database::open`] has one.
+    pub(crate) fn outboards_dir(&self) -> Option<PathBuf> {
+        let journal = self.journal.as_ref()?.lock().unwrap();
+        Some(journal.paths.outboards_dir.clone())
+    }
+
+    /// The directory serialised collections are stored in, instead of on the heap.
+    ///
+    /// Only a database opened with [`Database::open`] has one.
+    pub(crate) fn collections_dir(&self) -> Option<PathBuf> {
+        let journal = self.journal.as_ref()?.lock().unwrap();
+        Some(journal.paths.collections_dir.clone())
+    }
+
     /// Load a database from disk.
+    ///
+    /// The returned database is in memory, entries added to it are only stored by
+    /// [`Database::save`].
     pub async fn load(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
         let dir = dir.as_ref().to_path_buf();
         let db = tokio::task::spawn_blocking(|| Self::load_internal(dir)).await??;
@@ -242,6 +828,8 @@ impl Database {
             outboards,
             collections,
             paths,
+            pins,
+            tags,
         } = snapshot;
         let outboards = outboards
             .collect::<result::Result<HashMap<_, _>, E>>()
@@ -250,13 +838,13 @@ impl Database {
             .collect::<result::Result<HashMap<_, _>, E>>()
             .map_err(Into::into)?;
         let mut db = HashMap::new();
-        for (hash, size, path) in paths {
-            if let (Some(path), Some(outboard)) = (path, outboards.get(&hash)) {
+        for (hash, size, sources) in paths {
+            if let (false, Some(outboard)) = (sources.is_empty(), outboards.get(&hash)) {
                 db.insert(
                     hash,
                     BlobOrCollection::Blob {
                         outboard: outboard.clone(),
-                        path,
+                        sources,
                         size,
                     },
                 );
@@ -264,17 +852,23 @@ impl Database {
         }
         for (hash, data) in collections {
             if let Some(outboard) = outboards.get(&hash) {
-                db.insert(
-                    hash,
-                    BlobOrCollection::Collection {
-                        outboard: outboard.clone(),
-                        data,
-                    },
-                );
+                match BlobOrCollection::collection(outboard.clone(), data) {
+                    Ok(entry) => {
+                        db.insert(hash, entry);
+                    }
+                    Err(err) => tracing::warn!("skipping invalid collection {}: {:#}", hash, err),
+                }
             }
         }
 
-        Ok(Self(Arc::new(RwLock::new(db))))
+        let pins = pins.unwrap_or_else(|| unreferenced_entries(&db));
+        Ok(Self {
+            map: Arc::new(RwLock::new(db)),
+            pins: Arc::new(RwLock::new(pins)),
+            tags: Arc::new(RwLock::new(tags)),
+            journal: None,
+            imports: Default::default(),
+        })
     }
 
     /// Validate the entire database, including collections.
@@ -283,7 +877,7 @@ impl Database {
     pub(crate) async fn validate(&self, tx: mpsc::Sender<ValidateProgress>) -> anyhow::Result<()> {
         // This makes a copy of the db, but since the outboards are Bytes, it's not expensive.
         let mut data = self
-            .0
+            .map
             .read()
             .unwrap()
             .clone()
@@ -298,8 +892,11 @@ impl Database {
             .enumerate()
             .map(|(id, (hash, boc))| {
                 let id = id as u64;
-                let path = if let BlobOrCollection::Blob { path, .. } = &boc {
-                    Some(path.clone())
+                // Validate the file the blob would be served from.
+                let path = if let BlobOrCollection::Blob { sources, size, .. } = &boc {
+                    current_source(sources, *size)
+                        .or_else(|| sources.first())
+                        .map(|source| source.path.clone())
                 } else {
                     None
                 };
@@ -323,7 +920,8 @@ impl Database {
                                 .ok();
                         };
                         let res = match boc {
-                            BlobOrCollection::Blob { outboard, path, .. } => {
+                            BlobOrCollection::Blob { outboard, .. } => {
+                                let path = path.unwrap_or_default();
                                 match std::fs::File::open(&path) {
                                     Ok(data) => {
                                         tracing::info!("validating {}", path.display());
@@ -334,7 +932,7 @@ impl Database {
                                     Err(cause) => Err(BaoValidationError::from(cause)),
                                 }
                             }
-                            BlobOrCollection::Collection { outboard, data } => {
+                            BlobOrCollection::Collection { outboard, data, .. } => {
                                 let data = std::io::Cursor::new(data);
                                 validate_bao(hash, data, outboard, progress)
                             }
@@ -359,9 +957,110 @@ impl Database {
         Ok(())
     }
 
+    /// Writes the collection `hash` to `out_dir`, validating every blob while it is written.
+    ///
+    /// Nested collections are written to subdirectories.  A file which fails to validate is
+    /// not written and its error reported in [`ExportProgress::Done`].  Existing files are
+    /// only overwritten if `force` is set.  The metadata recorded in the collections is not
+    /// restored.
+    pub(crate) async fn export(
+        &self,
+        hash: Hash,
+        out_dir: PathBuf,
+        force: bool,
+        tx: mpsc::Sender<ExportProgress>,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            out_dir.is_absolute(),
+            "the output directory must be an absolute path"
+        );
+        let mut files = Vec::new();
+        self.export_paths(hash, &out_dir, &mut files)?;
+        // The files are written concurrently, so no two of them may share a path.
+        let mut paths = HashSet::new();
+        for (_, path) in &files {
+            ensure!(paths.insert(path), "duplicate path {}", path.display());
+            ensure!(
+                force || !path.exists(),
+                "{} already exists, use force to overwrite it",
+                path.display()
+            );
+        }
+        tx.send(ExportProgress::Starting {
+            total: files.len() as u64,
+        })
+        .await?;
+        futures::stream::iter(files)
+            .enumerate()
+            .map(|(id, (hash, path))| {
+                let id = id as u64;
+                let entry = self.get(&hash);
+                let size = entry
+                    .as_ref()
+                    .map(BlobOrCollection::size)
+                    .unwrap_or_default();
+                let tx = tx.clone();
+                async move {
+                    tx.send(ExportProgress::Entry {
+                        id,
+                        hash,
+                        path: path.clone(),
+                        size,
+                    })
+                    .await?;
+                    let progress_tx = tx.clone();
+                    let error = tokio::task::spawn_blocking(move || {
+                        let progress = |offset| {
+                            progress_tx
+                                .try_send(ExportProgress::Progress { id, offset })
+                                .ok();
+                        };
+                        export_blob(hash, entry, &path, force, progress).err()
+                    })
+                    .await?;
+                    let error = error.map(|err| format!("{err:#}"));
+                    tx.send(ExportProgress::Done { id, error }).await?;
+                    anyhow::Ok(())
+                }
+            })
+            .buffer_unordered(num_cpus::get())
+            .try_for_each(|()| futures::future::ok(()))
+            .await?;
+        tx.send(ExportProgress::AllDone).await?;
+        Ok(())
+    }
+
+    /// Collects the blobs of the collection `hash`, with the paths below `dir` to write them to.
+    fn export_paths(
+        &self,
+        hash: Hash,
+        dir: &Path,
+        files: &mut Vec<(Hash, PathBuf)>,
+    ) -> anyhow::Result<()> {
+        let data = match self.get(&hash) {
+            Some(BlobOrCollection::Collection { outboard, data, .. }) => {
+                validate_bao(hash, io::Cursor::new(&data), outboard, |_| {})
+                    .with_context(|| format!("collection {hash} is corrupt"))?;
+                data
+            }
+            Some(BlobOrCollection::Blob { .. }) => bail!("{hash} is not a collection"),
+            None => bail!("collection {hash} not found"),
+        };
+        let collection = Collection::from_bytes(&data)?;
+        for blob in collection.blobs() {
+            let path = dir.join(path_from_name(blob.name())?);
+            if blob.is_collection() {
+                self.export_paths(blob.hash(), &path, files)?;
+            } else {
+                files.push((blob.hash(), path));
+            }
+        }
+        Ok(())
+    }
+
     /// take a snapshot of the database
     pub(crate) fn snapshot(&self) -> Snapshot<NoError> {
-        let this = self.0.read().unwrap();
+        let this = self.map.read().unwrap();
         let outboards = this
             .iter()
             .map(|(k, v)| match v {
@@ -381,8 +1080,8 @@ impl Database {
         let paths = this
             .iter()
             .map(|(k, v)| match v {
-                BlobOrCollection::Blob { path, size, .. } => (*k, *size, Some(path.clone())),
-                BlobOrCollection::Collection { data, .. } => (*k, data.len() as u64, None),
+                BlobOrCollection::Blob { sources, size, .. } => (*k, *size, sources.clone()),
+                BlobOrCollection::Collection { data, .. } => (*k, data.len() as u64, Vec::new()),
             })
             .collect::<Vec<_>>();
 
@@ -390,30 +1089,371 @@ impl Database {
             outboards: Box::new(outboards.into_iter().map(Ok)),
             collections: Box::new(collections.into_iter().map(Ok)),
             paths: Box::new(paths.into_iter()),
+            pins: Some(self.pins.read().unwrap().clone()),
+            tags: self.tags.read().unwrap().clone(),
         }
     }
 
+    /// Starts an import, which keeps the data it registers from being removed until it is
+    /// dropped, see [`Import`].
+    pub(crate) fn start_import(&self) -> Arc<Import> {
+        self.imports.lock().unwrap().running += 1;
+        Arc::new(Import {
+            imports: self.imports.clone(),
+            hashes: Default::default(),
+        })
+    }
+
     pub(crate) fn get(&self, key: &Hash) -> Option<BlobOrCollection> {
-        self.0.read().unwrap().get(key).cloned()
+        self.map.read().unwrap().get(key).cloned()
+    }
+
+    /// Adds the entries which are not in the database yet, without pinning anything.
+    #[cfg(test)]
+    pub(crate) fn union_with(&self, db: HashMap<Hash, BlobOrCollection>) -> io::Result<()> {
+        self.insert(db, None, None).map(drop)
+    }
+
+    /// Adds the entries which are not in the database yet and pins `pin`.
+    ///
+    /// Blobs which are already in the database gain any new source paths.  For a
+    /// persistent database the entries are durably written before they are added.  Returns
+    /// whether `pin` was not pinned before.
+    pub(crate) async fn extend_pinned(
+        &self,
+        db: HashMap<Hash, BlobOrCollection>,
+        pin: Hash,
+    ) -> io::Result<bool> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || this.insert(db, Some(pin), None)).await?
+    }
+
+    /// Adds the entries which are not in the database yet and sets the tag `name` to `hash`.
+    ///
+    /// Unlike [`Database::extend_pinned`] the data can be removed by [`Database::gc`] once
+    /// the tag is moved or deleted.
+    pub(crate) async fn extend_tagged(
+        &self,
+        db: HashMap<Hash, BlobOrCollection>,
+        name: String,
+        hash: Hash,
+    ) -> io::Result<()> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || this.insert(db, None, Some((name, hash))))
+            .await?
+            .map(drop)
+    }
+
+    /// Adds the entries of `db`, and pins `pin` or sets the tag `(name, hash)`.
+    ///
+    /// The root is set while still holding the journal lock, so [`Database::gc`] never sees
+    /// the new entries without it.  Returns whether `pin` was not pinned before.
+    fn insert(
+        &self,
+        db: HashMap<Hash, BlobOrCollection>,
+        pin: Option<Hash>,
+        tag: Option<(String, Hash)>,
+    ) -> io::Result<bool> {
+        // Holding the journal lock keeps other writers out while writing to disk.
+        let mut journal = self.journal.as_ref().map(|journal| journal.lock().unwrap());
+        // Blobs which are already in the database gain the new sources.
+        let mut new = Vec::new();
+        let mut merged = Vec::new();
+        {
+            let inner = self.map.read().unwrap();
+            for (k, v) in db {
+                match (inner.get(&k), v) {
+                    (None, v) => new.push((k, v)),
+                    (
+                        Some(existing @ BlobOrCollection::Blob { .. }),
+                        BlobOrCollection::Blob { sources, .. },
+                    ) => {
+                        let mut entry = existing.clone();
+                        if let BlobOrCollection::Blob {
+                            sources: existing, ..
+                        } = &mut entry
+                        {
+                            if merge_sources(existing, sources) {
+                                merged.push((k, entry));
+                            }
+                        }
+                    }
+                    (Some(_), _) => {}
+                }
+            }
+        }
+        let pin = pin.filter(|hash| !self.pins.read().unwrap().contains(hash));
+        if let Some(journal) = journal.as_mut() {
+            for (k, v) in &new {
+                journal.add(*k, v)?;
+            }
+            for (k, v) in &merged {
+                if let BlobOrCollection::Blob { sources, .. } = v {
+                    journal.append(&JournalEntry::Sources {
+                        hash: *k,
+                        sources: sources.clone(),
+                    })?;
+                }
+            }
+            if let Some(hash) = pin {
+                journal.append(&JournalEntry::Pin { hash })?;
+            }
+            journal.sync()?;
+        }
+        self.map
+            .write()
+            .unwrap()
+            .extend(new.into_iter().chain(merged));
+        self.pins.write().unwrap().extend(pin);
+        if let Some((name, hash)) = tag {
+            self.set_tag_locked(journal.as_deref_mut(), name, Some(hash))?;
+        }
+        Ok(pin.is_some())
+    }
+
+    /// Unpins `hash`, so [`Database::gc`] removes it unless it is tagged or still reachable.
+    ///
+    /// Returns `false` if it was not pinned.
+    pub(crate) async fn unpin(&self, hash: Hash) -> io::Result<bool> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut journal = this.journal.as_ref().map(|journal| journal.lock().unwrap());
+            if !this.pins.read().unwrap().contains(&hash) {
+                return Ok(false);
+            }
+            if let Some(journal) = journal.as_mut() {
+                journal.append(&JournalEntry::Unpin { hash })?;
+                journal.sync()?;
+            }
+            this.pins.write().unwrap().remove(&hash);
+            Ok(true)
+        })
+        .await?
     }
 
-    pub(crate) fn union_with(&self, db: HashMap<Hash, BlobOrCollection>) {
-        let mut inner = self.0.write().unwrap();
-        for (k, v) in db {
-            inner.entry(k).or_insert(v);
+    /// Unpins `hash`, deletes the tags referring to it and removes its entry.
+    ///
+    /// The entry is kept if it is still reachable from another pin or tag, or used by an
+    /// import in progress, and it fails if there is no pin or tag to remove either.  The
+    /// entries only reachable through the removed one are kept until [`Database::gc`].
+    /// Returns `false` if there was no such entry, pin or tag.
+    pub async fn remove(&self, hash: Hash) -> io::Result<bool> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || this.remove_internal(hash)).await?
+    }
+
+    fn remove_internal(&self, hash: Hash) -> io::Result<bool> {
+        let mut journal = self.journal.as_ref().map(|journal| journal.lock().unwrap());
+        let imports = self.imports.lock().unwrap();
+        let pinned = self.pins.read().unwrap().contains(&hash);
+        let tags = self
+            .tags
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, target)| **target == hash)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        let mut entry = self.get(&hash);
+        if !pinned && tags.is_empty() && entry.is_none() {
+            return Ok(false);
+        }
+        let roots = self.roots().into_iter().filter(|root| *root != hash);
+        if imports.hashes.contains_key(&hash) || self.reachable_from(roots).contains(&hash) {
+            if !pinned && tags.is_empty() {
+                let message = format!("{hash} is still in use by a pinned or tagged collection");
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+            entry = None;
+        }
+        if let Some(journal) = journal.as_mut() {
+            if pinned {
+                journal.append(&JournalEntry::Unpin { hash })?;
+            }
+            for name in &tags {
+                journal.append(&JournalEntry::Tag {
+                    name: name.clone(),
+                    hash: None,
+                })?;
+            }
+            if entry.is_some() {
+                journal.append(&JournalEntry::Remove { hash })?;
+            }
+            journal.sync()?;
         }
+        self.pins.write().unwrap().remove(&hash);
+        self.tags
+            .write()
+            .unwrap()
+            .retain(|name, _| !tags.contains(name));
+        if let Some(entry) = entry {
+            self.map.write().unwrap().remove(&hash);
+            if let Some(journal) = journal {
+                journal.remove_files(hash, &entry)?;
+            }
+        }
+        Ok(true)
     }
 
-    /// Iterate over all blobs in the database.
+    /// Removes all entries which are not reachable from a pin or a tag, returning their hashes.
+    ///
+    /// An entry is reachable if it is pinned, tagged or a child of a reachable collection.
+    /// Entries registered by an import in progress are kept as well.  For a persistent
+    /// database this also removes the files of the removed entries, and any orphaned outboard
+    /// and collection files unless an import is in progress.
+    pub async fn gc(&self) -> io::Result<Vec<Hash>> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || this.gc_internal()).await?
+    }
+
+    fn gc_internal(&self) -> io::Result<Vec<Hash>> {
+        let mut journal = self.journal.as_ref().map(|journal| journal.lock().unwrap());
+        // Holding the lock keeps imports from registering new hashes while collecting.
+        let imports = self.imports.lock().unwrap();
+        let mut live = self.reachable_from(self.roots());
+        live.extend(imports.hashes.keys().copied());
+        let dead = self
+            .map
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(hash, _)| !live.contains(hash))
+            .map(|(hash, entry)| (*hash, entry.clone()))
+            .collect::<Vec<_>>();
+        if let Some(journal) = journal.as_mut() {
+            for (hash, _) in &dead {
+                journal.append(&JournalEntry::Remove { hash: *hash })?;
+            }
+            journal.sync()?;
+        }
+        {
+            let mut map = self.map.write().unwrap();
+            for (hash, _) in &dead {
+                map.remove(hash);
+            }
+        }
+        if let Some(journal) = journal {
+            for (hash, entry) in &dead {
+                journal.remove_files(*hash, entry)?;
+            }
+            // Imports register their files before writing them, but leaving orphans alone
+            // while any import runs also covers files written before registering.
+            if imports.running == 0 {
+                remove_orphans(&journal.paths.outboards_dir, &live)?;
+                remove_orphans(&journal.paths.collections_dir, &live)?;
+            }
+        }
+        Ok(dead.into_iter().map(|(hash, _)| hash).collect())
+    }
+
+    /// The pinned and tagged hashes.
+    fn roots(&self) -> Vec<Hash> {
+        let mut roots = self
+            .pins
+            .read()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        roots.extend(self.tags.read().unwrap().values().copied());
+        roots
+    }
+
+    /// The hashes reachable from `roots`, through collections and nested collections.
+    fn reachable_from(&self, roots: impl IntoIterator<Item = Hash>) -> HashSet<Hash> {
+        let map = self.map.read().unwrap();
+        let mut reachable = HashSet::new();
+        let mut stack = roots.into_iter().collect::<Vec<_>>();
+        while let Some(hash) = stack.pop() {
+            if !reachable.insert(hash) {
+                continue;
+            }
+            if let Some(BlobOrCollection::Collection { data, .. }) = map.get(&hash) {
+                match collection_children(data) {
+                    Ok(children) => stack.extend(children),
+                    Err(err) => tracing::warn!("invalid collection {hash}: {err}"),
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Sets the tag `name` to `hash`, or deletes it if `hash` is `None`.
+    ///
+    /// Fails if `hash` is not in the database.  Tags keep the data they refer to from being
+    /// removed by [`Database::gc`].  Returns the hash the tag referred to before.
+    pub async fn set_tag(&self, name: String, hash: Option<Hash>) -> io::Result<Option<Hash>> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || this.set_tag_internal(name, hash)).await?
+    }
+
+    fn set_tag_internal(&self, name: String, hash: Option<Hash>) -> io::Result<Option<Hash>> {
+        let mut journal = self.journal.as_ref().map(|journal| journal.lock().unwrap());
+        self.set_tag_locked(journal.as_deref_mut(), name, hash)
+    }
+
+    /// Sets the tag `name` to `hash`, with the journal lock held by the caller.
+    fn set_tag_locked(
+        &self,
+        journal: Option<&mut Journal>,
+        name: String,
+        hash: Option<Hash>,
+    ) -> io::Result<Option<Hash>> {
+        if let Some(hash) = hash {
+            if !self.map.read().unwrap().contains_key(&hash) {
+                let message = format!("{hash} is not in the database");
+                return Err(io::Error::new(io::ErrorKind::NotFound, message));
+            }
+        }
+        let previous = self.resolve_tag(&name);
+        if previous == hash {
+            return Ok(previous);
+        }
+        if let Some(journal) = journal {
+            journal.append(&JournalEntry::Tag {
+                name: name.clone(),
+                hash,
+            })?;
+            journal.sync()?;
+        }
+        let mut tags = self.tags.write().unwrap();
+        match hash {
+            Some(hash) => tags.insert(name, hash),
+            None => tags.remove(&name),
+        };
+        Ok(previous)
+    }
+
+    /// The hash the tag `name` refers to, if it exists.
+    pub fn resolve_tag(&self, name: &str) -> Option<Hash> {
+        self.tags.read().unwrap().get(name).copied()
+    }
+
+    /// Iterate over all tags in the database, ordered by name.
+    pub fn tags(&self) -> impl Iterator<Item = (String, Hash)> + 'static {
+        let items = self
+            .tags
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, hash)| (name.clone(), *hash))
+            .collect::<Vec<_>>();
+        items.into_iter()
+    }
+
+    /// Iterate over all blobs in the database, once for every source of a blob.
     pub fn blobs(&self) -> impl Iterator<Item = (Hash, PathBuf, u64)> + 'static {
         let items = self
-            .0
+            .map
             .read()
             .unwrap()
             .iter()
-            .filter_map(|(k, v)| match v {
-                BlobOrCollection::Blob { path, size, .. } => Some((*k, path.clone(), *size)),
-                BlobOrCollection::Collection { .. } => None,
+            .flat_map(|(k, v)| match v {
+                BlobOrCollection::Blob { sources, size, .. } => sources
+                    .iter()
+                    .map(|source| (*k, source.path.clone(), *size))
+                    .collect(),
+                BlobOrCollection::Collection { .. } => Vec::new(),
             })
             .collect::<Vec<_>>();
         // todo: make this a proper lazy iterator at some point
@@ -423,6 +1463,6 @@ impl Database {
 
     #[cfg(test)]
     pub(crate) fn to_inner(&self) -> HashMap<Hash, BlobOrCollection> {
-        self.0.read().unwrap().clone()
+        self.map.read().unwrap().clone()
     }
 }
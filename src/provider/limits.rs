@@ -0,0 +1,338 @@
+//! Limits on the data a provider sends, see [`super::Builder::connection_rate_limit`] and
+//! the other limits of the [`super::Builder`].
+//!
+//! Rate limits are enforced by wrapping the stream the bao encoded data is written to in a
+//! [`ThrottledWriter`], which delays further writes once a limit is exceeded.
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::{ready, Future};
+use tokio::io::AsyncWrite;
+use tokio::time::Instant;
+
+use crate::tls::PeerId;
+
+/// How far a transfer may get ahead of its rate limits before it is delayed.
+///
+/// This avoids sleeping for tiny durations after every write.
+const BURST: Duration = Duration::from_millis(100);
+
+/// The limits configured on the [`super::Builder`], all rates are in bytes per second.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Limits {
+    pub connection_rate: Option<u64>,
+    pub ip_rate: Option<u64>,
+    pub peer_rate: Option<u64>,
+    pub token_rate: Option<u64>,
+    pub peer_transfers: Option<usize>,
+}
+
+/// Enforces the [`Limits`] across all connections of a provider.
+#[derive(Debug, Default)]
+pub(crate) struct Limiter {
+    limits: Limits,
+    ips: Mutex<HashMap<IpAddr, Weak<RateLimit>>>,
+    peers: Mutex<HashMap<PeerId, Weak<RateLimit>>>,
+    tokens: Mutex<HashMap<String, Weak<RateLimit>>>,
+    /// The number of transfers in progress for each peer.
+    transfers: Mutex<HashMap<PeerId, usize>>,
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// The limits of a new connection from `ip` by the client with `peer_id`.
+    pub fn connection(self: &Arc<Self>, ip: IpAddr, peer_id: PeerId) -> ConnectionLimits {
+        let rates = [
+            self.limits
+                .connection_rate
+                .map(|rate| Arc::new(RateLimit::new(rate))),
+            self.limits.ip_rate.map(|rate| shared(&self.ips, ip, rate)),
+            self.limits
+                .peer_rate
+                .map(|rate| shared(&self.peers, peer_id, rate)),
+        ];
+        ConnectionLimits {
+            limiter: self.clone(),
+            peer_id,
+            rates: rates.into_iter().flatten().collect(),
+        }
+    }
+}
+
+/// Returns the rate limit for `key`, creating it if no transfer is using it.
+fn shared<K: Eq + Hash>(
+    limits: &Mutex<HashMap<K, Weak<RateLimit>>>,
+    key: K,
+    rate: u64,
+) -> Arc<RateLimit> {
+    let mut limits = limits.lock().unwrap();
+    if let Some(limit) = limits.get(&key).and_then(Weak::upgrade) {
+        return limit;
+    }
+    limits.retain(|_, limit| limit.strong_count() > 0);
+    let limit = Arc::new(RateLimit::new(rate));
+    limits.insert(key, Arc::downgrade(&limit));
+    limit
+}
+
+/// The limits of a single connection.
+#[derive(Debug)]
+pub(crate) struct ConnectionLimits {
+    limiter: Arc<Limiter>,
+    peer_id: PeerId,
+    rates: Vec<Arc<RateLimit>>,
+}
+
+impl ConnectionLimits {
+    /// Starts a transfer using the auth token named `token`.
+    ///
+    /// Returns `None` if the client already has the maximum number of transfers in progress.
+    pub fn start_transfer(&self, token: Option<&str>) -> Option<Transfer> {
+        let limiter = &self.limiter;
+        if let Some(max) = limiter.limits.peer_transfers {
+            let mut transfers = limiter.transfers.lock().unwrap();
+            let count = transfers.entry(self.peer_id).or_default();
+            if *count >= max {
+                return None;
+            }
+            *count += 1;
+        }
+        let mut rates = self.rates.clone();
+        if let (Some(rate), Some(token)) = (limiter.limits.token_rate, token) {
+            rates.push(shared(&limiter.tokens, token.to_string(), rate));
+        }
+        Some(Transfer {
+            limiter: limiter.clone(),
+            peer_id: self.peer_id,
+            rates,
+            on_throttled: None,
+        })
+    }
+}
+
+/// A transfer in progress, counted until dropped.
+pub(crate) struct Transfer {
+    limiter: Arc<Limiter>,
+    peer_id: PeerId,
+    rates: Vec<Arc<RateLimit>>,
+    on_throttled: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl std::fmt::Debug for Transfer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Transfer")
+            .field("peer_id", &self.peer_id)
+            .field("rates", &self.rates)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Transfer {
+    /// Calls `f` the first time the transfer is delayed by a rate limit.
+    pub fn on_throttled(&mut self, f: impl FnOnce() + Send + Sync + 'static) {
+        self.on_throttled = Some(Box::new(f));
+    }
+
+    /// Wraps `writer` so that writing to it obeys the rate limits of this transfer.
+    pub fn writer<W>(&mut self, writer: W) -> ThrottledWriter<'_, W> {
+        ThrottledWriter {
+            inner: writer,
+            transfer: self,
+            sleep: None,
+        }
+    }
+
+    /// Accounts for `len` written bytes, returning how long to wait before writing more.
+    fn consume(&mut self, len: usize) -> Duration {
+        let delay = self
+            .rates
+            .iter()
+            .map(|rate| rate.consume(len))
+            .max()
+            .unwrap_or_default();
+        if !delay.is_zero() {
+            if let Some(f) = self.on_throttled.take() {
+                f();
+            }
+        }
+        delay
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        if self.limiter.limits.peer_transfers.is_some() {
+            let mut transfers = self.limiter.transfers.lock().unwrap();
+            if let Some(count) = transfers.get_mut(&self.peer_id) {
+                *count -= 1;
+                if *count == 0 {
+                    transfers.remove(&self.peer_id);
+                }
+            }
+        }
+    }
+}
+
+/// Allows writing `rate` bytes per second on average.
+#[derive(Debug)]
+pub(crate) struct RateLimit {
+    rate: u64,
+    /// When the bytes written so far are paid off.
+    next: Mutex<Instant>,
+}
+
+impl RateLimit {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Accounts for `len` written bytes, returning how long to wait before writing more.
+    fn consume(&self, len: usize) -> Duration {
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap();
+        let start = (*next).max(now);
+        *next = start + Duration::from_secs_f64(len as f64 / self.rate as f64);
+        next.saturating_duration_since(now).saturating_sub(BURST)
+    }
+}
+
+/// An [`AsyncWrite`] which delays writes to obey the rate limits of a [`Transfer`].
+pub(crate) struct ThrottledWriter<'a, W> {
+    inner: W,
+    transfer: &'a mut Transfer,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ThrottledWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let Some(sleep) = this.sleep.as_mut() {
+            ready!(sleep.as_mut().poll(cx));
+            this.sleep = None;
+        }
+        let len = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        let delay = this.transfer.consume(len);
+        if !delay.is_zero() {
+            this.sleep = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::Keypair;
+
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = RateLimit::new(1000);
+        // Writes within the burst are not delayed.
+        assert_eq!(limit.consume(50), Duration::ZERO);
+        let delay = limit.consume(1000);
+        assert!(delay > Duration::from_millis(900) && delay <= Duration::from_millis(950));
+    }
+
+    #[test]
+    fn test_peer_transfers() {
+        let limiter = Arc::new(Limiter::new(Limits {
+            peer_transfers: Some(1),
+            ..Default::default()
+        }));
+        let peer_id = PeerId::from(Keypair::generate().public());
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let limits = limiter.connection(ip, peer_id);
+        let transfer = limits.start_transfer(None).unwrap();
+        // Another connection of the same peer shares the limit.
+        let other = limiter.connection(ip, peer_id);
+        assert!(other.start_transfer(None).is_none());
+        drop(transfer);
+        assert!(other.start_transfer(None).is_some());
+        // Other peers are not affected.
+        let peer_id = PeerId::from(Keypair::generate().public());
+        let limits = limiter.connection(ip, peer_id);
+        let _transfer = limits.start_transfer(None).unwrap();
+        assert!(limits.start_transfer(None).is_none());
+    }
+
+    #[test]
+    fn test_shared_rates() {
+        let limiter = Arc::new(Limiter::new(Limits {
+            ip_rate: Some(1000),
+            token_rate: Some(1000),
+            ..Default::default()
+        }));
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let a = limiter.connection(ip, PeerId::from(Keypair::generate().public()));
+        let b = limiter.connection(ip, PeerId::from(Keypair::generate().public()));
+        let mut a = a.start_transfer(Some("default")).unwrap();
+        let mut b = b.start_transfer(None).unwrap();
+        let throttled = Arc::new(Mutex::new(false));
+        b.on_throttled({
+            let throttled = throttled.clone();
+            move || *throttled.lock().unwrap() = true
+        });
+        assert_eq!(a.rates.len(), 2);
+        assert_eq!(b.rates.len(), 1);
+        // Both connections come from the same address, so they share its rate.
+        assert!(a.consume(1000) > Duration::from_millis(800));
+        assert!(b.consume(100) > Duration::from_millis(900));
+        assert!(*throttled.lock().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttled_writer() {
+        use tokio::io::AsyncWriteExt;
+
+        let limiter = Arc::new(Limiter::new(Limits {
+            connection_rate: Some(512 * 1024),
+            ..Default::default()
+        }));
+        let limits = limiter.connection(
+            IpAddr::from([127, 0, 0, 1]),
+            PeerId::from(Keypair::generate().public()),
+        );
+        let mut transfer = limits.start_transfer(None).unwrap();
+        let start = Instant::now();
+        let mut writer = transfer.writer(tokio::io::sink());
+        for _ in 0..16 {
+            writer.write_all(&[0u8; 16 * 1024]).await.unwrap();
+        }
+        // Writing 256 KiB takes 500ms, less the burst and the last write, which is not
+        // delayed.  The timer rounds up to whole milliseconds.
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_micros(500_000 - 100_000 - 31_250)
+                && elapsed < Duration::from_millis(400),
+            "elapsed {elapsed:?}"
+        );
+    }
+}
@@ -8,28 +8,30 @@
 //!
 //! To shut down the provider, call [`Provider::shutdown`].
 use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::future::Future;
 use std::io::Cursor;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 use std::task::Poll;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use anyhow::{ensure, Context, Result};
-use bao_tree::io::sync::encode_ranges_validated;
+use anyhow::{Context, Result};
+use bao_tree::io::tokio::AsyncResponseDecoder;
 use bao_tree::outboard::PreOrderMemOutboardRef;
 use bytes::{Bytes, BytesMut};
 use futures::future::{BoxFuture, Shared};
-use futures::{FutureExt, Stream, StreamExt, TryFutureExt, TryStreamExt};
-use postcard::experimental::max_size::MaxSize;
+use futures::{FutureExt, Stream, TryFutureExt};
+use notify::Watcher;
 use quic_rpc::server::RpcChannel;
 use quic_rpc::transport::flume::FlumeConnection;
 use quic_rpc::transport::misc::DummyServerEndpoint;
 use quic_rpc::{RpcClient, RpcServer, ServiceConnection, ServiceEndpoint};
 use range_collections::RangeSet2;
-use tokio::io::{AsyncRead, AsyncWrite};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::{broadcast, mpsc};
 use tokio::task::JoinError;
 use tokio_util::sync::CancellationToken;
@@ -37,23 +39,30 @@ use tracing::{debug, debug_span, trace, warn};
 use tracing_futures::Instrument;
 use walkdir::WalkDir;
 
-use crate::blobs::Collection;
+use crate::blobs::{Collection, CollectionDecoder};
 use crate::net::find_local_addresses;
 use crate::protocol::{
-    read_lp, write_lp, AuthToken, Closed, Handshake, Request, Res, Response, VERSION,
+    collection_page, collection_page_bytes, collection_page_count, negotiated_version,
+    read_bao_encoded, read_lp, supported_alpns, write_lp, AuthToken, Capability, CapabilityError,
+    Closed, Credential, ErrorCode, Handshake, HasRequest, Presence, PushRequest, RangeSpec, Req,
+    Request, RequestV1, Res, ResolveRequest, Response, NESTED_COLLECTION_VERSION,
 };
 use crate::rpc_protocol::{
-    AddrsRequest, AddrsResponse, IdRequest, IdResponse, ListRequest, ListResponse, ProvideProgress,
-    ProvideRequest, ProviderRequest, ProviderResponse, ProviderService, ShutdownRequest,
+    AddrsRequest, AddrsResponse, ExportProgress, ExportRequest, GcRequest, GcResponse, IdRequest,
+    IdResponse, ImportMode, ListRequest, ListResponse, ProvideProgress, ProvideRequest,
+    ProviderRequest, ProviderResponse, ProviderService, RemoveRequest, RemoveResponse,
+    ShutdownRequest, TagListRequest, TagListResponse, TagSetRequest, TagSetResponse,
+    TokenAddRequest, TokenAddResponse, TokenListRequest, TokenListResponse, TokenRevokeRequest,
     ValidateProgress, ValidateRequest, VersionRequest, VersionResponse, WatchRequest,
     WatchResponse,
 };
-use crate::tls::{self, Keypair, PeerId};
-use crate::util::{canonicalize_path, Hash, Progress};
+use crate::tls::{self, Keypair, PeerId, PublicKey};
+use crate::util::{canonicalize_path, Hash, Progress, RpcResult};
 use crate::IROH_BLOCK_SIZE;
 
 mod collection;
 mod database;
+mod limits;
 mod ticket;
 
 pub use database::Database;
@@ -61,11 +70,19 @@ pub use database::Database;
 pub use database::Snapshot;
 pub use ticket::Ticket;
 
+use self::collection::ImportDirs;
+use self::database::format_hash;
+use self::limits::{ConnectionLimits, Limiter, Limits, Transfer};
+
 const MAX_CONNECTIONS: u32 = 1024;
 const MAX_STREAMS: u64 = 10;
 const HEALTH_POLL_WAIT: Duration = Duration::from_secs(1);
 /// Default bind address for the provider.
 pub const DEFAULT_BIND_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 4433);
+/// The name of the [`AuthToken`] given to [`Builder::auth_token`].
+pub const DEFAULT_TOKEN_NAME: &str = "default";
+/// How long the capability in a ticket created by [`Provider::ticket`] is valid.
+pub const DEFAULT_TICKET_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24 * 30);
 
 /// Builder for the [`Provider`].
 ///
@@ -82,6 +99,26 @@ pub struct Builder<E: ServiceEndpoint<ProviderService> = DummyServerEndpoint> {
     rpc_endpoint: E,
     db: Database,
     keylog: bool,
+    push_dir: Option<PathBuf>,
+    allowed_peers: Vec<PeerId>,
+    peer_auth: PeerAuth,
+    public: bool,
+    limits: Limits,
+}
+
+/// How the provider authorizes clients allowed by [`Builder::allow_peer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerAuth {
+    /// Allowed clients can get and query everything without a credential, see
+    /// [`Credential::Anonymous`].  Pushing still requires an [`AuthToken`].
+    ///
+    /// Other clients can still connect and need an [`AuthToken`] or a [`Capability`].
+    #[default]
+    Sufficient,
+    /// Only allowed clients can connect, and they still need a valid credential.
+    ///
+    /// Connections from other clients are closed with [`ErrorCode::Forbidden`].
+    Required,
 }
 
 /// A [`Database`] entry.
@@ -89,14 +126,16 @@ pub struct Builder<E: ServiceEndpoint<ProviderService> = DummyServerEndpoint> {
 pub(crate) enum BlobOrCollection {
     Blob {
         /// The bao outboard data.
+        ///
+        /// For a database with a data directory this is a memory-mapped file, see
+        /// [`Database::open`].
         outboard: Bytes,
-        /// Path to the original data, which must not change while in use.
+        /// The files containing the original data, in the order they were added.
         ///
-        /// Note that when adding multiple files with the same content, only one of them
-        /// will get added to the store. So the path is not that useful for information.  It
-        /// is just a place to look for the data correspoding to the hash and outboard.
-        // TODO: Change this to a list of paths.
-        path: PathBuf,
+        /// When adding multiple files with the same content, all of them are recorded.  The
+        /// data is served from the first one which did not change since it was added, see
+        /// [`BlobSource::open`].  This is never empty.
+        sources: Vec<BlobSource>,
         /// Size of the original data.
         size: u64,
     },
@@ -104,18 +143,40 @@ pub(crate) enum BlobOrCollection {
         /// The bao outboard data of the serialised [`Collection`].
         outboard: Bytes,
         /// The serialised [`Collection`].
+        ///
+        /// For a database with a data directory this is a memory-mapped file, so large
+        /// collections are not held on the heap and only the pages which are sent are read.
         data: Bytes,
+        /// The number of blobs in the collection, not counting nested collections.
+        num_blobs: u64,
+        /// The total size of the blobs, including those of nested collections.
+        ///
+        /// This is stored at the end of the serialised collection, so it is kept here to
+        /// avoid decoding the entire collection for every request.
+        total_blobs_size: u64,
     },
 }
 
 impl BlobOrCollection {
+    /// Creates a collection entry, decoding `data` once to find its size.
+    pub fn collection(outboard: Bytes, data: Bytes) -> Result<Self> {
+        let (num_blobs, total_blobs_size) = collection_info(&data)?;
+        Ok(BlobOrCollection::Collection {
+            outboard,
+            data,
+            num_blobs,
+            total_blobs_size,
+        })
+    }
+
     pub fn is_blob(&self) -> bool {
         matches!(self, BlobOrCollection::Blob { .. })
     }
 
+    /// The path of the first source of a blob.
     pub fn blob_path(&self) -> Option<&Path> {
         match self {
-            BlobOrCollection::Blob { path, .. } => Some(path),
+            BlobOrCollection::Blob { sources, .. } => sources.first().map(|s| s.path.as_path()),
             BlobOrCollection::Collection { .. } => None,
         }
     }
@@ -132,6 +193,88 @@ impl BlobOrCollection {
     }
 }
 
+/// A file containing the data of a blob, see [`BlobOrCollection::Blob`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct BlobSource {
+    /// The path of the file.
+    pub path: PathBuf,
+    /// The modification time of the file when it was added.
+    ///
+    /// This is `None` for blobs added before modification times were recorded, and on
+    /// platforms which do not support them.
+    pub mtime: Option<SystemTime>,
+}
+
+impl BlobSource {
+    /// A source for the file at `path`, which is not checked for changes.
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, mtime: None }
+    }
+
+    /// A source for the file at `path`, with the modification time in `metadata`.
+    pub fn with_metadata(path: PathBuf, metadata: &std::fs::Metadata) -> Self {
+        Self {
+            path,
+            mtime: metadata.modified().ok(),
+        }
+    }
+
+    /// Whether a file with `metadata` still has the data of a blob with `size`.
+    ///
+    /// This is the case if neither the size nor the modification time changed.
+    pub fn is_current(&self, size: u64, metadata: &std::fs::Metadata) -> bool {
+        metadata.len() == size && (self.mtime.is_none() || self.mtime == metadata.modified().ok())
+    }
+
+    /// Opens the first of `sources` which still has the data of a blob with `size`.
+    ///
+    /// Also returns the paths of the sources which were skipped because they are missing
+    /// or changed.
+    pub async fn open(
+        sources: &[BlobSource],
+        size: u64,
+    ) -> (Option<tokio::fs::File>, Vec<PathBuf>) {
+        let mut stale = Vec::new();
+        for source in sources {
+            let file = match tokio::fs::File::open(&source.path).await {
+                Ok(file) => file,
+                Err(err) => {
+                    debug!("can not open {}: {err}", source.path.display());
+                    stale.push(source.path.clone());
+                    continue;
+                }
+            };
+            match file.metadata().await {
+                Ok(metadata) if source.is_current(size, &metadata) => return (Some(file), stale),
+                _ => stale.push(source.path.clone()),
+            }
+        }
+        (None, stale)
+    }
+}
+
+/// Adds the `new` sources to the `sources` of a blob.
+///
+/// A new source for a path which is already a source replaces it, e.g. with a new
+/// modification time.  Returns whether anything changed.
+pub(crate) fn merge_sources(sources: &mut Vec<BlobSource>, new: Vec<BlobSource>) -> bool {
+    let mut changed = false;
+    for source in new {
+        match sources.iter_mut().find(|s| s.path == source.path) {
+            Some(existing) if *existing == source => {}
+            Some(existing) => {
+                *existing = source;
+                changed = true;
+            }
+            None => {
+                sources.push(source);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
 impl Builder {
     /// Creates a new builder for [`Provider`] using the given [`Database`].
     pub fn with_db(db: Database) -> Self {
@@ -142,6 +285,11 @@ impl Builder {
             rpc_endpoint: Default::default(),
             db,
             keylog: false,
+            push_dir: None,
+            allowed_peers: Vec::new(),
+            peer_auth: PeerAuth::default(),
+            public: false,
+            limits: Limits::default(),
         }
     }
 }
@@ -155,6 +303,11 @@ impl<E: ServiceEndpoint<ProviderService>> Builder<E> {
             auth_token: self.auth_token,
             db: self.db,
             keylog: self.keylog,
+            push_dir: self.push_dir,
+            allowed_peers: self.allowed_peers,
+            peer_auth: self.peer_auth,
+            public: self.public,
+            limits: self.limits,
             rpc_endpoint: value,
         }
     }
@@ -189,17 +342,98 @@ impl<E: ServiceEndpoint<ProviderService>> Builder<E> {
         self
     }
 
+    /// Accepts collections pushed by clients, see [`crate::push::run`].
+    ///
+    /// Pushed blobs are verified and stored as files in the `dir` directory, which must
+    /// exist.  A database opened with [`Database::open`] stores them in its data directory
+    /// instead, like copied blobs, so they are deleted when removed from it.  Clients need the [`AuthToken`] of the provider to push.  By default pushing
+    /// is not allowed.
+    pub fn push_dir(mut self, dir: PathBuf) -> Self {
+        self.push_dir = Some(dir);
+        self
+    }
+
+    /// Allows the client with this [`PeerId`], see [`PeerAuth`] for what this grants.
+    ///
+    /// Clients are identified by the keypair they connect with, see
+    /// [`crate::get::Options::keypair`].  Can be called multiple times to allow several
+    /// clients.
+    pub fn allow_peer(mut self, peer_id: PeerId) -> Self {
+        self.allowed_peers.push(peer_id);
+        self
+    }
+
+    /// Sets how clients allowed by [`Builder::allow_peer`] are authorized.
+    ///
+    /// Defaults to [`PeerAuth::Sufficient`].
+    pub fn peer_auth(mut self, peer_auth: PeerAuth) -> Self {
+        self.peer_auth = peer_auth;
+        self
+    }
+
+    /// Allows anyone to get and query the data, without a credential.
+    ///
+    /// Pushing still requires an [`AuthToken`].  Tickets created by [`Provider::ticket`]
+    /// for a public provider have no credential.  By default a credential is required.
+    pub fn public(mut self, public: bool) -> Self {
+        self.public = public;
+        self
+    }
+
+    /// Limits the data sent on each connection to `bytes_per_sec` bytes per second.
+    ///
+    /// Transfers which exceed a rate limit are slowed down and reported with
+    /// [`Event::TransferThrottled`].  By default the rate is unlimited.
+    pub fn connection_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.limits.connection_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Limits the data sent to each remote IP address to `bytes_per_sec` bytes per second.
+    ///
+    /// The limit is shared by all connections from the same address.
+    pub fn ip_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.limits.ip_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Limits the data sent to each client [`PeerId`] to `bytes_per_sec` bytes per second.
+    ///
+    /// The limit is shared by all connections of the same client.  Note that clients which
+    /// do not use a persistent keypair have a new [`PeerId`] for every connection.
+    pub fn peer_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.limits.peer_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Limits the data sent for each auth token to `bytes_per_sec` bytes per second.
+    ///
+    /// The limit is shared by all requests using a token of the same name, see
+    /// [`Provider::add_auth_token`].  Requests using a [`Capability`] are not limited by
+    /// this.
+    pub fn token_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.limits.token_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Limits the number of transfers each client [`PeerId`] can have in progress at once.
+    ///
+    /// Further requests are rejected with [`ErrorCode::TooManyRequests`] and reported with
+    /// [`Event::RequestRejected`].  By default the number is only limited by the maximum
+    /// number of streams per connection.
+    pub fn max_transfers_per_peer(mut self, max: usize) -> Self {
+        self.limits.peer_transfers = Some(max);
+        self
+    }
+
     /// Spawns the [`Provider`] in a tokio task.
     ///
     /// This will create the underlying network server and spawn a tokio task accepting
     /// connections.  The returned [`Provider`] can be used to control the task as well as
     /// get information about it.
     pub fn spawn(self) -> Result<Provider> {
-        let tls_server_config = tls::make_server_config(
-            &self.keypair,
-            vec![crate::tls::P2P_ALPN.to_vec()],
-            self.keylog,
-        )?;
+        let tls_server_config =
+            tls::make_server_config(&self.keypair, supported_alpns(), self.keylog)?;
         let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_server_config));
         let mut transport_config = quinn::TransportConfig::default();
         transport_config
@@ -217,11 +451,20 @@ impl<E: ServiceEndpoint<ProviderService>> Builder<E> {
         let cancel_token = CancellationToken::new();
         tracing::debug!("rpc listening on: {:?}", self.rpc_endpoint.local_addr());
         let (internal_rpc, controller) = quic_rpc::transport::flume::connection(1);
+        let auth = Arc::new(Auth::new(
+            self.auth_token,
+            self.keypair.public(),
+            self.allowed_peers,
+            self.peer_auth,
+            self.public,
+        ));
         let inner = Arc::new(ProviderInner {
             db: self.db,
             listen_addr,
             keypair: self.keypair,
-            auth_token: self.auth_token,
+            auth,
+            limiter: Arc::new(Limiter::new(self.limits)),
+            push_dir: self.push_dir,
             events,
             controller,
             cancel_token,
@@ -294,8 +537,10 @@ impl<E: ServiceEndpoint<ProviderService>> Builder<E> {
                 Some(connecting) = server.accept() => {
                     let db = handler.inner.db.clone();
                     let events = events.clone();
-                    let auth_token = handler.inner.auth_token;
-                    tokio::spawn(handle_connection(connecting, db, auth_token, events));
+                    let auth = handler.inner.auth.clone();
+                    let limiter = handler.inner.limiter.clone();
+                    let push_dir = handler.inner.push_dir.clone();
+                    tokio::spawn(handle_connection(connecting, db, auth, limiter, push_dir, events));
                 }
                 else => break,
             }
@@ -331,7 +576,9 @@ struct ProviderInner {
     db: Database,
     listen_addr: SocketAddr,
     keypair: Keypair,
-    auth_token: AuthToken,
+    auth: Arc<Auth>,
+    limiter: Arc<Limiter>,
+    push_dir: Option<PathBuf>,
     events: broadcast::Sender<Event>,
     cancel_token: CancellationToken,
     controller: FlumeConnection<ProviderResponse, ProviderRequest>,
@@ -344,6 +591,8 @@ pub enum Event {
     ClientConnected {
         /// An unique connection id.
         connection_id: u64,
+        /// The [`PeerId`] of the client, see [`crate::get::Options::keypair`].
+        peer_id: PeerId,
     },
     /// A request was received from a client.
     RequestReceived {
@@ -351,6 +600,8 @@ pub enum Event {
         connection_id: u64,
         /// An identifier uniquely identifying this transfer request.
         request_id: u64,
+        /// The name of the auth token used for the request, `None` if a capability was used.
+        token: Option<String>,
         /// The hash for which the client wants to receive data.
         hash: Hash,
     },
@@ -360,6 +611,8 @@ pub enum Event {
         connection_id: u64,
         /// An identifier uniquely identifying this transfer request.
         request_id: u64,
+        /// The name of the auth token used for the request, `None` if a capability was used.
+        token: Option<String>,
         /// The number of blobs in the collection.
         num_blobs: u64,
         /// The total blob size of the data.
@@ -371,26 +624,118 @@ pub enum Event {
         connection_id: u64,
         /// An identifier uniquely identifying this transfer request.
         request_id: u64,
+        /// The name of the auth token used for the request, `None` if a capability was used.
+        token: Option<String>,
     },
-    /// A blob in a collection was transferred.
+    /// A blob was transferred.
+    ///
+    /// This is emitted for every blob in a collection.  When a single blob was requested
+    /// directly this is the final event of the request.
     TransferBlobCompleted {
         /// An unique connection id.
         connection_id: u64,
         /// An identifier uniquely identifying this transfer request.
         request_id: u64,
+        /// The name of the auth token used for the request, `None` if a capability was used.
+        token: Option<String>,
         /// The hash of the blob
         hash: Hash,
-        /// The index of the blob in the collection.
+        /// The index of the blob in the collection containing it, `0` if the blob was
+        /// requested directly.
         index: u64,
+        /// The indices of the nested collections containing the blob, outermost first.
+        ///
+        /// The first is the index of an entry of the requested collection, each following
+        /// one an index in the previous nested collection.  This is empty for blobs of the
+        /// requested collection itself, so together with `index` this identifies the blob
+        /// even when a nested collection is shared by several entries.
+        parents: Vec<u64>,
         /// The size of the blob transferred.
         size: u64,
     },
+    /// A client started pushing a collection.
+    PushStarted {
+        /// An unique connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this push request.
+        request_id: u64,
+        /// The name of the auth token used for the request, `None` if a capability was used.
+        token: Option<String>,
+        /// The hash of the pushed collection.
+        hash: Hash,
+        /// The number of blobs in the collection.
+        num_blobs: u64,
+        /// The total blob size of the data.
+        total_blobs_size: u64,
+    },
+    /// A blob of a pushed collection was received and verified.
+    PushBlobReceived {
+        /// An unique connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this push request.
+        request_id: u64,
+        /// The name of the auth token used for the request, `None` if a capability was used.
+        token: Option<String>,
+        /// The hash of the blob.
+        hash: Hash,
+        /// The index of the blob in the collection.
+        index: u64,
+        /// The size of the blob.
+        size: u64,
+    },
+    /// A pushed collection was completely received and added to the database.
+    PushCompleted {
+        /// An unique connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this push request.
+        request_id: u64,
+        /// The name of the auth token used for the request, `None` if a capability was used.
+        token: Option<String>,
+        /// The hash of the pushed collection.
+        hash: Hash,
+    },
+    /// A transfer was slowed down because it exceeded a rate limit.
+    ///
+    /// This is emitted at most once per request, see [`Builder::connection_rate_limit`].
+    TransferThrottled {
+        /// An unique connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this transfer request.
+        request_id: u64,
+        /// The name of the auth token used for the request, `None` if a capability was used.
+        token: Option<String>,
+    },
+    /// A request was rejected because the client has too many transfers in progress.
+    ///
+    /// This is the final event of the request, see [`Builder::max_transfers_per_peer`].
+    RequestRejected {
+        /// An unique connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this transfer request.
+        request_id: u64,
+        /// The name of the auth token used for the request, `None` if a capability was used.
+        token: Option<String>,
+    },
     /// A request was aborted because the client disconnected.
     TransferAborted {
         /// The quic connection id.
         connection_id: u64,
         /// An identifier uniquely identifying this request.
         request_id: u64,
+        /// The name of the auth token used for the request.
+        ///
+        /// This is `None` if a capability was used or the request was not authenticated.
+        token: Option<String>,
+    },
+    /// A file a blob was added from is missing or was modified since.
+    ///
+    /// The blob is served from its other files, if it has any which did not change.  This
+    /// is emitted every time the provider tries to read the file.
+    BlobSourceStale {
+        /// The hash of the blob.
+        hash: Hash,
+        /// The path of the missing or modified file.
+        path: PathBuf,
     },
 }
 
@@ -423,9 +768,33 @@ impl Provider {
         self.inner.keypair.public().into()
     }
 
-    /// Returns the [`AuthToken`] needed to connect to the provider.
-    pub fn auth_token(&self) -> AuthToken {
-        self.inner.auth_token
+    /// Returns the [`AuthToken`] named [`DEFAULT_TOKEN_NAME`], needed to connect to the provider.
+    ///
+    /// This is the token given to [`Builder::auth_token`].  Once it is revoked using
+    /// [`Provider::revoke_auth_token`] this returns `None`.
+    pub fn auth_token(&self) -> Option<AuthToken> {
+        self.inner.auth.default_token()
+    }
+
+    /// Adds an [`AuthToken`] granting access to everything, identified by `name`.
+    ///
+    /// The name is reported in the [`Event`]s of the requests using the token.  Fails if the
+    /// name or the token is already used.  Tokens are only kept in memory, so they need to
+    /// be added again when the provider restarts.
+    pub fn add_auth_token(&self, name: String, token: AuthToken) -> Result<()> {
+        self.inner.auth.add_token(name, token)
+    }
+
+    /// Revokes the [`AuthToken`] identified by `name`, returning whether it existed.
+    ///
+    /// Requests which were already accepted are not interrupted.
+    pub fn revoke_auth_token(&self, name: &str) -> bool {
+        self.inner.auth.revoke_token(name)
+    }
+
+    /// Returns all [`AuthToken`]s with their names, sorted by name.
+    pub fn auth_tokens(&self) -> Vec<(String, AuthToken)> {
+        self.inner.auth.tokens()
     }
 
     /// Subscribe to [`Event`]s emitted from the provider, informing about connections and
@@ -441,13 +810,115 @@ impl Provider {
         RpcClient::new(self.inner.controller.clone())
     }
 
+    /// Creates a [`Capability`] granting access to `hashes` until `expires`.
+    ///
+    /// The capability is signed by the provider's [`Keypair`], so it is accepted by every
+    /// provider using the same keypair.  If `max_uses` is given, only this many requests can
+    /// be made using the capability.  Uses are counted in memory, so they start from zero
+    /// again when the provider restarts.
+    pub fn capability(
+        &self,
+        hashes: Vec<Hash>,
+        expires: SystemTime,
+        max_uses: Option<u64>,
+    ) -> Capability {
+        Capability::new(&self.inner.keypair, hashes, expires, max_uses)
+    }
+
     /// Return a single token containing everything needed to get a hash.
     ///
+    /// The ticket contains a [`Capability`] which only grants access to `hash` and expires
+    /// after [`DEFAULT_TICKET_LIFETIME`], so a leaked ticket does not expose anything else.
+    /// Use [`Provider::ticket_with_credential`] for other credentials.  If the provider is
+    /// [public](Builder::public) the ticket has no credential.
+    ///
     /// See [`Ticket`] for more details of how it can be used.
     pub fn ticket(&self, hash: Hash) -> Result<Ticket> {
-        // TODO: Verify that the hash exists in the db?
+        self.ticket_for_hashes(vec![hash])
+    }
+
+    /// Return a single token containing everything needed to get all of the `hashes`.
+    ///
+    /// Like [`Provider::ticket`] but for several root hashes, which are all covered by the
+    /// capability in the ticket.
+    pub fn ticket_for_hashes(&self, hashes: Vec<Hash>) -> Result<Ticket> {
+        if self.inner.auth.public {
+            return self.make_ticket(hashes, None);
+        }
+        let expires = SystemTime::now() + DEFAULT_TICKET_LIFETIME;
+        let capability = self.capability(hashes.clone(), expires, None);
+        self.make_ticket(hashes, Some(capability.into()))
+    }
+
+    /// Return a single token containing everything needed to get the data of a tag.
+    ///
+    /// The ticket is for the hash the tag currently refers to, see [`Ticket::tag`].  Its
+    /// [`Capability`] grants access to the tag rather than this hash, so the ticket can
+    /// still be used once the tag is moved.
+    pub fn ticket_for_tag(&self, tag: &str) -> Result<Ticket> {
+        let hash = self
+            .inner
+            .db
+            .resolve_tag(tag)
+            .with_context(|| format!("no tag named {tag}"))?;
+        let credential = if self.inner.auth.public {
+            None
+        } else {
+            let expires = SystemTime::now() + DEFAULT_TICKET_LIFETIME;
+            let capability = Capability::new_with_tags(
+                &self.inner.keypair,
+                Vec::new(),
+                vec![tag.to_string()],
+                expires,
+                None,
+            );
+            Some(capability.into())
+        };
+        Ok(self.make_ticket(vec![hash], credential)?.with_tag(tag))
+    }
+
+    /// Return a single token containing everything needed to get a hash using `credential`.
+    pub fn ticket_with_credential(
+        &self,
+        hash: Hash,
+        credential: impl Into<Credential>,
+    ) -> Result<Ticket> {
+        self.make_ticket(vec![hash], Some(credential.into()))
+    }
+
+    /// Creates a ticket, describing the size of the data if all `hashes` are in the database.
+    ///
+    /// The ticket expires with its credential if that is a [`Capability`].
+    fn make_ticket(&self, hashes: Vec<Hash>, credential: Option<Credential>) -> Result<Ticket> {
         let addrs = self.listen_addresses()?;
-        Ticket::new(hash, self.peer_id(), addrs, self.inner.auth_token)
+        let mut size = Some((0, 0));
+        for hash in &hashes {
+            let (hash_size, hash_files) = match self.inner.db.get(hash) {
+                Some(BlobOrCollection::Blob { size, .. }) => (size, 1),
+                Some(BlobOrCollection::Collection {
+                    num_blobs,
+                    total_blobs_size,
+                    ..
+                }) => (total_blobs_size, num_blobs),
+                None => {
+                    size = None;
+                    break;
+                }
+            };
+            size = size.map(|(size, files)| (size + hash_size, files + hash_files));
+        }
+        let expires = match &credential {
+            Some(Credential::Capability(capability)) => Some(capability.expires()),
+            _ => None,
+        };
+        let mut ticket = Ticket::new(hashes, self.peer_id(), addrs, credential)?;
+        if let Some((size, num_files)) = size {
+            ticket = ticket.with_size(size, num_files);
+        }
+        if let Some(expires) = expires {
+            ticket = ticket.with_expires(expires);
+        }
+        Ok(ticket)
     }
 
     /// Aborts the provider.
@@ -506,12 +977,29 @@ impl RpcHandler {
         tokio_stream::wrappers::ReceiverStream::new(rx)
     }
 
+    fn export(self, msg: ExportRequest) -> impl Stream<Item = ExportProgress> + Send + 'static {
+        let (tx, rx) = mpsc::channel(1);
+        let tx2 = tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = self
+                .inner
+                .db
+                .export(msg.hash, msg.out_dir, msg.force, tx)
+                .await
+            {
+                tx2.send(ExportProgress::Abort(e.into())).await.ok();
+            }
+        });
+        tokio_stream::wrappers::ReceiverStream::new(rx)
+    }
+
     fn provide(self, msg: ProvideRequest) -> impl Stream<Item = ProvideProgress> {
         let (tx, rx) = mpsc::channel(1);
         let tx2 = tx.clone();
         tokio::task::spawn(async move {
             if let Err(e) = self.provide0(msg, tx).await {
-                tx2.send(ProvideProgress::Abort(e.into())).await.unwrap();
+                // A watching client stops by dropping the stream, so it may be gone.
+                tx2.send(ProvideProgress::Abort(e.into())).await.ok();
             }
         });
         tokio_stream::wrappers::ReceiverStream::new(rx)
@@ -522,43 +1010,146 @@ impl RpcHandler {
         msg: ProvideRequest,
         progress: tokio::sync::mpsc::Sender<ProvideProgress>,
     ) -> anyhow::Result<()> {
-        let root = msg.path;
+        let client = progress.clone();
+        let progress = Progress::new(progress);
+        let (mut hash, mut pinned) = self.import(&msg, progress.clone()).await?;
+        let debounce = match msg.watch {
+            Some(debounce) => debounce,
+            None => return Ok(()),
+        };
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            tx.send(event).ok();
+        })?;
+        watcher.watch(&msg.path, notify::RecursiveMode::Recursive)?;
+        loop {
+            // The watcher is kept alive here, so only a dropped client ends the watch.
+            let event = tokio::select! {
+                event = rx.recv() => event,
+                _ = client.closed() => break,
+            };
+            let event = match event {
+                Some(event) => event,
+                None => break,
+            };
+            let mut paths = BTreeSet::new();
+            add_changed_paths(&mut paths, event);
+            // Wait for the changes to settle, e.g. until an editor finished saving.
+            while let Ok(Some(event)) = tokio::time::timeout(debounce, rx.recv()).await {
+                add_changed_paths(&mut paths, event);
+            }
+            if paths.is_empty() {
+                continue;
+            }
+            debug!("{} changed paths in {}", paths.len(), msg.path.display());
+            progress
+                .send(ProvideProgress::Changed {
+                    paths: paths.into_iter().collect(),
+                })
+                .await?;
+            let (new_hash, new_pin) = match self.import(&msg, progress.clone()).await {
+                Ok(res) => res,
+                Err(err) => {
+                    warn!("failed to add {} again: {:#}", msg.path.display(), err);
+                    progress
+                        .send(ProvideProgress::ChangeFailed(err.into()))
+                        .await?;
+                    continue;
+                }
+            };
+            // A tag was moved to the new hash, while a pin needs to be dropped explicitly,
+            // unless it was set independently of this watch.
+            if new_hash != hash {
+                if pinned {
+                    self.inner.db.unpin(hash).await?;
+                }
+                pinned = new_pin;
+                hash = new_hash;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the data of a [`ProvideRequest`] to the database.
+    ///
+    /// Returns its hash, and whether the hash was pinned by this call.
+    async fn import(
+        &self,
+        msg: &ProvideRequest,
+        progress: Progress<ProvideProgress>,
+    ) -> anyhow::Result<(Hash, bool)> {
+        let root = msg.path.clone();
         anyhow::ensure!(
             root.is_dir() || root.is_file(),
             "path must be either a Directory or a File"
         );
-        let data_sources = if root.is_dir() {
-            let files = futures::stream::iter(WalkDir::new(&root));
-            let data_sources = files.map_err(anyhow::Error::from).try_filter_map(|entry| {
-                let root = root.clone();
-                async move {
-                    if !entry.file_type().is_file() {
-                        // Skip symlinks. Directories are handled by WalkDir.
-                        return Ok(None);
-                    }
-                    let path = entry.into_path();
-                    let name = canonicalize_path(path.strip_prefix(&root)?)?;
-                    anyhow::Ok(Some(DataSource::NamedFile { name, path }))
+        let mut data_sources = Vec::new();
+        let mut metadata = Vec::new();
+        if root.is_dir() {
+            for entry in WalkDir::new(&root) {
+                let entry = entry?;
+                if entry.depth() == 0 {
+                    // The root itself is not part of the collection.
+                    continue;
                 }
-            });
-            let data_sources: Vec<anyhow::Result<DataSource>> =
-                data_sources.collect::<Vec<_>>().await;
-            data_sources
-                .into_iter()
-                .collect::<anyhow::Result<Vec<_>>>()?
+                let is_file = entry.file_type().is_file();
+                let path = entry.into_path();
+                let name = canonicalize_path(path.strip_prefix(&root)?)?;
+                if msg.metadata {
+                    metadata.push(collection::read_metadata(name.clone(), &path)?);
+                }
+                if is_file {
+                    data_sources.push(DataSource::NamedFile { name, path });
+                }
+                // Symlinks and directories are only recorded in the metadata, directories
+                // are handled by WalkDir.
+            }
         } else {
             // A single file, use the file name as the name of the blob.
-            vec![DataSource::NamedFile {
-                name: canonicalize_path(root.file_name().context("path must be a file")?)?,
-                path: root,
-            }]
-        };
+            let name = canonicalize_path(root.file_name().context("path must be a file")?)?;
+            if msg.metadata {
+                metadata.push(collection::read_metadata(name.clone(), &root)?);
+            }
+            data_sources.push(DataSource::NamedFile { name, path: root });
+        }
         // create the collection
         // todo: provide feedback for progress
-        let (db, _) = collection::create_collection(data_sources, Progress::new(progress)).await?;
-        self.inner.db.union_with(db);
+        let blobs = match msg.import {
+            ImportMode::Reference => None,
+            ImportMode::Copy => Some(
+                self.inner
+                    .db
+                    .blobs_dir()
+                    .context("the database has no directory to copy the data to")?,
+            ),
+        };
+        // Keeps gc from removing the imported data until it is added.
+        let import = self.inner.db.start_import();
+        let dirs = ImportDirs {
+            blobs,
+            outboards: self.inner.db.outboards_dir(),
+            collections: self.inner.db.collections_dir(),
+            import: Some(import.clone()),
+        };
+        let (db, hash) = collection::create_collection(
+            data_sources,
+            msg.nested,
+            metadata,
+            dirs,
+            progress.clone(),
+        )
+        .await?;
+        let pinned = match &msg.tag {
+            Some(tag) => {
+                self.inner.db.extend_tagged(db, tag.clone(), hash).await?;
+                false
+            }
+            None => self.inner.db.extend_pinned(db, hash).await?,
+        };
+        drop(import);
+        progress.send(ProvideProgress::AllDone { hash }).await?;
 
-        Ok(())
+        Ok((hash, pinned))
     }
     async fn version(self, _: VersionRequest) -> VersionResponse {
         VersionResponse {
@@ -568,11 +1159,61 @@ impl RpcHandler {
     async fn id(self, _: IdRequest) -> IdResponse {
         IdResponse {
             peer_id: Box::new(self.inner.keypair.public().into()),
-            auth_token: Box::new(self.inner.auth_token),
+            auth_token: self.inner.auth.default_token().map(Box::new),
             listen_addr: Box::new(self.inner.listen_addr),
             version: env!("CARGO_PKG_VERSION").to_string(),
         }
     }
+    async fn token_add(self, msg: TokenAddRequest) -> RpcResult<TokenAddResponse> {
+        let token = msg.token.unwrap_or_else(AuthToken::generate);
+        self.inner.auth.add_token(msg.name, token)?;
+        Ok(TokenAddResponse { token })
+    }
+    async fn remove(self, msg: RemoveRequest) -> RpcResult<RemoveResponse> {
+        let removed = self
+            .inner
+            .db
+            .remove(msg.hash)
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(RemoveResponse { removed })
+    }
+    async fn gc(self, _: GcRequest) -> RpcResult<GcResponse> {
+        let removed = self.inner.db.gc().await.map_err(anyhow::Error::from)?;
+        Ok(GcResponse { removed })
+    }
+    async fn tag_set(self, msg: TagSetRequest) -> RpcResult<TagSetResponse> {
+        let previous = self
+            .inner
+            .db
+            .set_tag(msg.name, msg.hash)
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(TagSetResponse { previous })
+    }
+    fn tag_list(self, _: TagListRequest) -> impl Stream<Item = TagListResponse> {
+        let items = self
+            .inner
+            .db
+            .tags()
+            .map(|(name, hash)| TagListResponse { name, hash });
+        futures::stream::iter(items)
+    }
+    async fn token_revoke(self, msg: TokenRevokeRequest) -> RpcResult<()> {
+        if !self.inner.auth.revoke_token(&msg.name) {
+            return Err(anyhow::anyhow!("no token named {}", msg.name).into());
+        }
+        Ok(())
+    }
+    fn token_list(self, _: TokenListRequest) -> impl Stream<Item = TokenListResponse> {
+        let items = self
+            .inner
+            .auth
+            .tokens()
+            .into_iter()
+            .map(|(name, token)| TokenListResponse { name, token });
+        futures::stream::iter(items)
+    }
     async fn addrs(self, _: AddrsRequest) -> AddrsResponse {
         AddrsResponse {
             addrs: find_local_addresses(self.inner.listen_addr).unwrap_or_default(),
@@ -601,6 +1242,21 @@ impl RpcHandler {
     }
 }
 
+/// Adds the paths changed by a file system `event` to `paths`.
+///
+/// Reading files, e.g. while they are added, is not a change.  An error, e.g. when a new
+/// directory can not be watched, is logged and its paths are treated as changed.
+fn add_changed_paths(paths: &mut BTreeSet<PathBuf>, event: notify::Result<notify::Event>) {
+    match event {
+        Ok(event) if !event.kind.is_access() => paths.extend(event.paths),
+        Ok(_) => {}
+        Err(err) => {
+            warn!("error while watching: {}", err);
+            paths.extend(err.paths);
+        }
+    }
+}
+
 fn handle_rpc_request<C: ServiceEndpoint<ProviderService>>(
     msg: ProviderRequest,
     chan: RpcChannel<ProviderService, C>,
@@ -620,10 +1276,27 @@ fn handle_rpc_request<C: ServiceEndpoint<ProviderService>>(
             Id(msg) => chan.rpc(msg, handler, RpcHandler::id).await,
             Addrs(msg) => chan.rpc(msg, handler, RpcHandler::addrs).await,
             Shutdown(msg) => chan.rpc(msg, handler, RpcHandler::shutdown).await,
+            TokenAdd(msg) => chan.rpc(msg, handler, RpcHandler::token_add).await,
+            TokenRevoke(msg) => chan.rpc(msg, handler, RpcHandler::token_revoke).await,
+            Remove(msg) => chan.rpc(msg, handler, RpcHandler::remove).await,
+            Gc(msg) => chan.rpc(msg, handler, RpcHandler::gc).await,
+            TagSet(msg) => chan.rpc(msg, handler, RpcHandler::tag_set).await,
+            TagList(msg) => {
+                chan.server_streaming(msg, handler, RpcHandler::tag_list)
+                    .await
+            }
+            TokenList(msg) => {
+                chan.server_streaming(msg, handler, RpcHandler::token_list)
+                    .await
+            }
             Validate(msg) => {
                 chan.server_streaming(msg, handler, RpcHandler::validate)
                     .await
             }
+            Export(msg) => {
+                chan.server_streaming(msg, handler, RpcHandler::export)
+                    .await
+            }
         }
     });
 }
@@ -631,7 +1304,9 @@ fn handle_rpc_request<C: ServiceEndpoint<ProviderService>>(
 async fn handle_connection(
     connecting: quinn::Connecting,
     db: Database,
-    auth_token: AuthToken,
+    auth: Arc<Auth>,
+    limiter: Arc<Limiter>,
+    push_dir: Option<PathBuf>,
     events: broadcast::Sender<Event>,
 ) {
     let remote_addr = connecting.remote_address();
@@ -643,17 +1318,60 @@ async fn handle_connection(
         }
     };
     let connection_id = connection.stable_id() as u64;
-    let span = debug_span!("connection", connection_id, %remote_addr);
+    let version = match negotiated_version(&connection) {
+        Ok(version) => version,
+        Err(err) => {
+            warn!(%remote_addr, "Error negotiating protocol: {err:#}");
+            let error_code = Closed::VersionMismatch;
+            connection.close(error_code.into(), error_code.reason());
+            return;
+        }
+    };
+    let peer_id = match tls::remote_peer_id(&connection) {
+        Ok(peer_id) => peer_id,
+        Err(err) => {
+            warn!(%remote_addr, "Error identifying client: {err:#}");
+            let error_code = Closed::Internal;
+            connection.close(error_code.into(), error_code.reason());
+            return;
+        }
+    };
+    if !auth.accepts(&peer_id) {
+        debug!(%remote_addr, %peer_id, "Rejecting client which is not allowed");
+        let error_code = Closed::Forbidden;
+        connection.close(error_code.into(), error_code.reason());
+        return;
+    }
+    let limits = Arc::new(limiter.connection(remote_addr.ip(), peer_id));
+    let span = debug_span!("connection", connection_id, %remote_addr, %peer_id, version);
     async move {
         while let Ok(stream) = connection.accept_bi().await {
             let span = debug_span!("stream", stream_id = %stream.0.id());
-            events.send(Event::ClientConnected { connection_id }).ok();
+            events
+                .send(Event::ClientConnected {
+                    connection_id,
+                    peer_id,
+                })
+                .ok();
             let db = db.clone();
+            let auth = auth.clone();
+            let limits = limits.clone();
+            let push_dir = push_dir.clone();
             let events = events.clone();
             tokio::spawn(
                 async move {
-                    if let Err(err) =
-                        handle_stream(db, auth_token, connection_id, stream, events).await
+                    if let Err(err) = handle_stream(
+                        db,
+                        &auth,
+                        &limits,
+                        push_dir,
+                        version,
+                        connection_id,
+                        peer_id,
+                        stream,
+                        events,
+                    )
+                    .await
                     {
                         warn!("error: {err:#?}",);
                     }
@@ -666,47 +1384,314 @@ async fn handle_connection(
     .await
 }
 
-/// Read and decode the handshake.
-///
-/// Will fail if there is an error while reading, there is a token mismatch, or no valid
-/// handshake was received.
-///
-/// When successful, the reader is still useable after this function and the buffer will be
-/// drained of any handshake data.
-async fn read_handshake<R: AsyncRead + Unpin>(
-    mut reader: R,
-    buffer: &mut BytesMut,
-    token: AuthToken,
-) -> Result<()> {
+/// Errors rejecting a request, reported to the getter using [`Res::Error`].
+#[derive(thiserror::Error, Debug)]
+enum RequestError {
+    #[error("expected version {0} but got {1}")]
+    VersionMismatch(u64, u64),
+    #[error("AuthToken mismatch")]
+    BadToken,
+    #[error("{0}")]
+    BadCapability(#[from] CapabilityError),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    Unsupported(&'static str),
+    #[error("{0:#}")]
+    Invalid(anyhow::Error),
+    #[error("{0:#}")]
+    Internal(anyhow::Error),
+}
+
+impl RequestError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            RequestError::VersionMismatch(..) => ErrorCode::VersionMismatch,
+            RequestError::BadToken | RequestError::BadCapability(_) => ErrorCode::BadToken,
+            RequestError::Forbidden(_) => ErrorCode::Forbidden,
+            RequestError::Unsupported(_) => ErrorCode::Unsupported,
+            RequestError::Invalid(_) => ErrorCode::InvalidRequest,
+            RequestError::Internal(_) => ErrorCode::Internal,
+        }
+    }
+
+    fn internal(err: impl Into<anyhow::Error>) -> Self {
+        RequestError::Internal(err.into())
+    }
+}
+
+impl From<anyhow::Error> for RequestError {
+    fn from(err: anyhow::Error) -> Self {
+        RequestError::Invalid(err)
+    }
+}
+
+/// Read and decode the handshake.
+///
+/// Will fail if there is an error while reading, the credential is not accepted, the
+/// handshake is not for the negotiated protocol `version`, or no valid handshake was
+/// received.  Returns what the credential or the client's `peer_id` grants access to.
+///
+/// When successful, the reader is still useable after this function and the buffer will be
+/// drained of any handshake data.
+async fn read_handshake<R: AsyncRead + Unpin>(
+    mut reader: R,
+    buffer: &mut BytesMut,
+    auth: &Auth,
+    peer_id: &PeerId,
+    version: u64,
+) -> Result<Access, RequestError> {
     let payload = read_lp(&mut reader, buffer)
         .await?
         .context("no valid handshake received")?;
-    let handshake: Handshake = postcard::from_bytes(&payload)?;
-    ensure!(
-        handshake.version == VERSION,
-        "expected version {} but got {}",
-        VERSION,
-        handshake.version
-    );
-    ensure!(handshake.token == token, "AuthToken mismatch");
-    Ok(())
+    let handshake =
+        Handshake::from_bytes(version, &payload).context("failed to decode handshake")?;
+    if handshake.version != version {
+        return Err(RequestError::VersionMismatch(version, handshake.version));
+    }
+    auth.check(peer_id, &handshake.credential)
+}
+
+/// Checks the [`PeerId`]s of clients and the [`Credential`]s presented in the handshakes of
+/// requests.
+#[derive(Debug)]
+struct Auth {
+    /// The named tokens granting access to everything.
+    tokens: RwLock<BTreeMap<String, AuthToken>>,
+    /// The key which signs accepted [`Capability`] tokens.
+    key: PublicKey,
+    /// How often capabilities with limited uses were used, and when they expire.
+    uses: Mutex<HashMap<[u8; 16], (u64, SystemTime)>>,
+    /// The clients allowed by [`Builder::allow_peer`].
+    peers: Vec<PeerId>,
+    /// What being one of the `peers` grants.
+    peer_auth: PeerAuth,
+    /// Whether getting and querying data is allowed without a credential.
+    public: bool,
+}
+
+impl Auth {
+    fn new(
+        token: AuthToken,
+        key: PublicKey,
+        peers: Vec<PeerId>,
+        peer_auth: PeerAuth,
+        public: bool,
+    ) -> Self {
+        let tokens = [(DEFAULT_TOKEN_NAME.to_string(), token)]
+            .into_iter()
+            .collect();
+        Self {
+            tokens: RwLock::new(tokens),
+            key,
+            uses: Default::default(),
+            peers,
+            peer_auth,
+            public,
+        }
+    }
+
+    /// Whether the client with `peer_id` may connect at all.
+    fn accepts(&self, peer_id: &PeerId) -> bool {
+        match self.peer_auth {
+            PeerAuth::Sufficient => true,
+            PeerAuth::Required => self.peers.contains(peer_id),
+        }
+    }
+
+    /// Adds a named token, failing if the name or the token is already used.
+    fn add_token(&self, name: String, token: AuthToken) -> Result<()> {
+        let mut tokens = self.tokens.write().unwrap();
+        anyhow::ensure!(
+            !tokens.contains_key(&name),
+            "a token named {name} already exists"
+        );
+        anyhow::ensure!(
+            !tokens.values().any(|t| *t == token),
+            "the token is already in use"
+        );
+        tokens.insert(name, token);
+        Ok(())
+    }
+
+    /// Removes the token named `name`, returning whether it existed.
+    fn revoke_token(&self, name: &str) -> bool {
+        self.tokens.write().unwrap().remove(name).is_some()
+    }
+
+    /// The token named [`DEFAULT_TOKEN_NAME`], unless it was revoked.
+    fn default_token(&self) -> Option<AuthToken> {
+        self.tokens.read().unwrap().get(DEFAULT_TOKEN_NAME).copied()
+    }
+
+    /// All named tokens, sorted by name.
+    fn tokens(&self) -> Vec<(String, AuthToken)> {
+        let tokens = self.tokens.read().unwrap();
+        tokens
+            .iter()
+            .map(|(name, token)| (name.clone(), *token))
+            .collect()
+    }
+
+    /// Checks the `credential` of the client with `peer_id`, counting a use if it is a
+    /// capability with limited uses.
+    ///
+    /// Allowed clients only need a credential if their [`PeerId`] does not suffice, or to
+    /// push using an [`AuthToken`].  If the provider is public, anything but a valid
+    /// [`AuthToken`] grants public access.
+    fn check(&self, peer_id: &PeerId, credential: &Credential) -> Result<Access, RequestError> {
+        let peer = self.peer_auth == PeerAuth::Sufficient && self.peers.contains(peer_id);
+        if peer && !matches!(credential, Credential::Token(_)) {
+            return Ok(Access::Peer);
+        }
+        match self.check_credential(credential) {
+            Ok(access @ Access::All { .. }) => Ok(access),
+            _ if peer => Ok(Access::Peer),
+            _ if self.public => Ok(Access::Public),
+            result => result,
+        }
+    }
+
+    fn check_credential(&self, credential: &Credential) -> Result<Access, RequestError> {
+        match credential {
+            Credential::Token(token) => {
+                let tokens = self.tokens.read().unwrap();
+                let (name, _) = tokens
+                    .iter()
+                    .find(|(_, t)| *t == token)
+                    .ok_or(RequestError::BadToken)?;
+                Ok(Access::All {
+                    token: name.clone(),
+                })
+            }
+            Credential::Capability(capability) => {
+                capability.verify(&self.key)?;
+                if let Some(max_uses) = capability.max_uses() {
+                    let mut uses = self.uses.lock().unwrap();
+                    let now = SystemTime::now();
+                    uses.retain(|_, (_, expires)| *expires > now);
+                    let (count, _) = uses
+                        .entry(capability.id())
+                        .or_insert((0, capability.expires()));
+                    if *count >= max_uses {
+                        return Err(CapabilityError::UsedUp.into());
+                    }
+                    *count += 1;
+                }
+                Ok(Access::Capability {
+                    hashes: capability.hashes().to_vec(),
+                    tags: capability.tags().to_vec(),
+                })
+            }
+            Credential::Anonymous => Err(RequestError::Forbidden(
+                "the provider requires a credential".to_string(),
+            )),
+        }
+    }
+}
+
+/// What the credential of a request grants access to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Access {
+    /// Everything, granted by the [`AuthToken`] with this name.
+    All { token: String },
+    /// Getting these root hashes and the current hashes of these tags, granted by a
+    /// [`Capability`].
+    Capability {
+        hashes: Vec<Hash>,
+        tags: Vec<String>,
+    },
+    /// Getting and querying everything, granted by the [`PeerId`] of the client.
+    Peer,
+    /// Getting and querying everything, granted to anyone by a public provider.
+    Public,
+}
+
+impl Access {
+    /// The name of the [`AuthToken`] which granted access.
+    fn token(&self) -> Option<&str> {
+        match self {
+            Access::All { token } => Some(token),
+            Access::Capability { .. } | Access::Peer | Access::Public => None,
+        }
+    }
+
+    /// Fails unless the `request` is allowed.
+    ///
+    /// Requesting a collection also grants access to its blobs, but they can not be
+    /// requested directly.  Only an [`AuthToken`] allows pushing.  Resolving a tag is
+    /// authorized once its hash is known, see [`handle_resolve`], and the tags of a
+    /// capability are resolved in `db` to the hashes they currently refer to.
+    fn authorize(&self, request: &Req, db: &Database) -> Result<(), RequestError> {
+        let (hashes, tags) = match self {
+            Access::All { .. } => return Ok(()),
+            Access::Peer | Access::Public => match request {
+                Req::Push(_) => {
+                    return Err(RequestError::Forbidden(
+                        "pushing requires an auth token".to_string(),
+                    ))
+                }
+                _ => return Ok(()),
+            },
+            Access::Capability { hashes, tags } => (hashes, tags),
+        };
+        let allowed = |hash: &Hash| {
+            hashes.contains(hash)
+                || tags
+                    .iter()
+                    .any(|tag| db.resolve_tag(tag).as_ref() == Some(hash))
+        };
+        let forbidden = match request {
+            Req::Get(request) => Some(request.hash()).filter(|hash| !allowed(hash)),
+            Req::Has(has) => has.hashes.iter().copied().find(|hash| !allowed(hash)),
+            Req::Resolve(_) => None,
+            Req::Push(_) => {
+                return Err(RequestError::Forbidden(
+                    "pushing requires an auth token".to_string(),
+                ))
+            }
+        };
+        match forbidden {
+            Some(hash) => Err(RequestError::Forbidden(format!(
+                "the capability does not grant access to {hash}"
+            ))),
+            None => Ok(()),
+        }
+    }
 }
 
 /// Read the request from the getter.
 ///
-/// Will fail if there is an error while reading, if the reader
-/// contains more data than the Request, or if no valid request is sent.
+/// Will fail if there is an error while reading, if the reader contains more data than the
+/// request, or if no valid request is sent.  Only a [`Req::Push`] is followed by more data.
 ///
-/// When successful, the buffer is empty after this function call.
-async fn read_request(mut reader: quinn::RecvStream, buffer: &mut BytesMut) -> Result<Request> {
-    let payload = read_lp(&mut reader, buffer)
+/// Requests using protocol version 1 or 2 are converted to a [`Req::Get`].
+///
+/// When successful, the buffer is empty after this function call.  For a [`Req::Push`]
+/// the reader is positioned at the start of the pushed data.
+async fn read_request(
+    reader: &mut quinn::RecvStream,
+    buffer: &mut BytesMut,
+    version: u64,
+) -> Result<Req, RequestError> {
+    let payload = read_lp(&mut *reader, buffer)
         .await?
         .context("No request received")?;
-    let request: Request = postcard::from_bytes(&payload)?;
-    ensure!(
-        reader.read_chunk(8, false).await?.is_none(),
-        "Extra data past request"
-    );
+    let request = match version {
+        1 => postcard::from_bytes::<RequestV1>(&payload).map(|r| Req::Get(r.into())),
+        2 => postcard::from_bytes::<Request>(&payload).map(Req::Get),
+        _ => postcard::from_bytes::<Req>(&payload),
+    }
+    .context("failed to decode request")?;
+    if !matches!(request, Req::Push(_)) {
+        let extra = reader
+            .read_chunk(8, false)
+            .await
+            .context("failed to read request")?;
+        if extra.is_some() {
+            return Err(anyhow::anyhow!("Extra data past request").into());
+        }
+    }
     Ok(request)
 }
 
@@ -715,74 +1700,167 @@ async fn read_request(mut reader: quinn::RecvStream, buffer: &mut BytesMut) -> R
 /// First, it transfers the collection data & its associated outboard encoding data. Then it sequentially transfers each individual blob data & its associated outboard
 /// encoding data.
 ///
+/// Since [`crate::protocol::PAGED_COLLECTION_VERSION`] the collection is sent in pages, each
+/// followed by the blobs whose entries it completes.  The collection is decoded
+/// incrementally, so it is never held in memory more than once.
+///
+/// Entries referring to a nested collection are sent like the requested collection, with a
+/// [`Res::FoundCollection`] response followed by the pages and blobs of the nested
+/// collection.  This needs [`NESTED_COLLECTION_VERSION`], older getters receive an
+/// [`ErrorCode::Unsupported`] error instead.
+///
 /// Will fail if there is an error writing to the getter or reading from
 /// the database.
 ///
 /// If a blob from the collection cannot be found in the database, the transfer will gracefully
 /// close the writer, and return with `Ok(SentStatus::NotFound)`.
 ///
+/// Only the blobs selected by [`Request::includes`] are sent, and of those only the missing
+/// ranges, see [`Request::missing_ranges`].  Blobs without any missing ranges are skipped.
+/// The collection itself is always sent in full.  The selection only applies to the entries
+/// of the requested collection, selected nested collections are sent with all their blobs.
+///
 /// If the transfer does _not_ end in error, the buffer will be empty and the writer is gracefully closed.
 #[allow(clippy::too_many_arguments)]
 async fn transfer_collection(
-    hash: Hash,
+    request: &Request,
+    // The negotiated protocol version.
+    version: u64,
     // Database from which to fetch blobs.
     db: &Database,
     // Quinn stream.
-    mut writer: quinn::SendStream,
+    writer: &mut quinn::SendStream,
     // Buffer used when writing to writer.
     buffer: &mut BytesMut,
+    // The limits the data is sent with.
+    transfer: &mut Transfer,
     // The bao outboard encoded data.
     outboard: &Bytes,
     // The actual blob data.
     data: &Bytes,
+    // The number of blobs in the collection.
+    num_blobs: u64,
+    // The total size of the blobs.
+    total_blobs_size: u64,
     events: broadcast::Sender<Event>,
     connection_id: u64,
     request_id: u64,
+    token: &Option<String>,
 ) -> Result<SentStatus> {
-    // We only respond to requests for collections, not individual blobs
-    let encoded_size: usize = bao_tree::encoded_size(data.len() as u64, IROH_BLOCK_SIZE)
-        .try_into()
-        .unwrap();
-    let mut encoded = Vec::with_capacity(encoded_size);
-    let outboard = PreOrderMemOutboardRef::new(hash.into(), IROH_BLOCK_SIZE, outboard);
-    encode_ranges_validated(Cursor::new(data), outboard, &RangeSet2::all(), &mut encoded)?;
-
-    let c: Collection = postcard::from_bytes(data)?;
-
     let _ = events.send(Event::TransferCollectionStarted {
         connection_id,
         request_id,
-        num_blobs: c.blobs().len() as u64,
-        total_blobs_size: c.total_blobs_size(),
+        token: token.clone(),
+        num_blobs,
+        total_blobs_size,
     });
 
     // TODO: we should check if the blobs referenced in this container
     // actually exist in this provider before returning `FoundCollection`
     write_response(
-        &mut writer,
+        &mut *writer,
         buffer,
-        Res::FoundCollection {
-            total_blobs_size: c.total_blobs_size(),
-        },
+        Res::FoundCollection { total_blobs_size },
     )
     .await?;
 
-    writer.write_all(&encoded).await?;
-    for (i, blob) in c.blobs().iter().enumerate() {
-        trace!("writing blob {}/{}", i, c.blobs().len());
+    // The collections being sent, the innermost nested collection last.
+    let mut stack = vec![CollectionTransfer::new(
+        request.hash(),
+        outboard.clone(),
+        data.clone(),
+        version,
+    )];
+    loop {
+        let nested = stack.len() > 1;
+        let current = match stack.last_mut() {
+            Some(current) => current,
+            None => break,
+        };
+        let blob = match current.decoder.next_blob()? {
+            Some(blob) => blob,
+            None if current.page < current.num_pages => {
+                current.send_page(version, writer, transfer).await?;
+                continue;
+            }
+            None => {
+                if let Some(current) = stack.pop() {
+                    current.decoder.finish()?;
+                }
+                continue;
+            }
+        };
+        let index = current.index;
+        let num_blobs = current.num_blobs;
+        current.index += 1;
+        trace!("writing blob {}/{}", index, num_blobs);
         tokio::task::yield_now().await;
-        let (status, writer1, size) = send_blob(db.clone(), blob.hash, writer, buffer).await?;
-        writer = writer1;
+        if !nested && !request.includes(index, &blob.name) {
+            continue;
+        }
+
+        if blob.is_collection() {
+            if version < NESTED_COLLECTION_VERSION {
+                let message = format!("{} is a nested collection", blob.name);
+                write_error(writer, buffer, version, ErrorCode::Unsupported, message).await;
+                return Ok(SentStatus::Unsupported);
+            }
+            match db.get(&blob.hash) {
+                Some(BlobOrCollection::Collection {
+                    outboard,
+                    data,
+                    total_blobs_size,
+                    ..
+                }) => {
+                    write_response(
+                        &mut *writer,
+                        buffer,
+                        Res::FoundCollection { total_blobs_size },
+                    )
+                    .await?;
+                    stack.push(CollectionTransfer::new(blob.hash, outboard, data, version));
+                }
+                _ => {
+                    write_response(&mut *writer, buffer, Res::NotFound).await?;
+                    writer.finish().await?;
+                    return Ok(SentStatus::NotFound);
+                }
+            }
+            continue;
+        }
+
+        let ranges = request.missing_ranges(&blob.hash);
+        if ranges.is_empty() {
+            trace!("skipping blob {}/{}, the getter has it", index, num_blobs);
+            continue;
+        }
+        let (status, size) = send_blob(
+            db.clone(),
+            blob.hash,
+            &ranges,
+            writer,
+            buffer,
+            transfer,
+            &events,
+        )
+        .await?;
         if SentStatus::NotFound == status {
             writer.finish().await?;
             return Ok(status);
         }
 
+        // The collections containing the current one have moved past its entry.
+        let parents = stack[..stack.len() - 1]
+            .iter()
+            .map(|parent| parent.index - 1)
+            .collect();
         let _ = events.send(Event::TransferBlobCompleted {
             connection_id,
             request_id,
+            token: token.clone(),
             hash: blob.hash,
-            index: i as u64,
+            index,
+            parents,
             size,
         });
     }
@@ -791,17 +1869,139 @@ async fn transfer_collection(
     Ok(SentStatus::Sent)
 }
 
-fn notify_transfer_aborted(events: broadcast::Sender<Event>, connection_id: u64, request_id: u64) {
+/// A collection which is being sent by [`transfer_collection`].
+struct CollectionTransfer {
+    hash: Hash,
+    outboard: Bytes,
+    data: Bytes,
+    decoder: CollectionDecoder,
+    /// The next page to send.
+    page: u64,
+    num_pages: u64,
+    /// The number of blobs in the collection, once decoded.
+    num_blobs: u64,
+    /// The index of the next blob.
+    index: u64,
+}
+
+impl CollectionTransfer {
+    fn new(hash: Hash, outboard: Bytes, data: Bytes, version: u64) -> Self {
+        let num_pages = collection_page_count(version, data.len() as u64);
+        Self {
+            hash,
+            outboard,
+            data,
+            decoder: CollectionDecoder::default(),
+            page: 0,
+            num_pages,
+            num_blobs: 0,
+            index: 0,
+        }
+    }
+
+    /// Sends the next page of the collection and adds it to the decoder.
+    async fn send_page(
+        &mut self,
+        version: u64,
+        writer: &mut quinn::SendStream,
+        transfer: &mut Transfer,
+    ) -> Result<()> {
+        let outboard =
+            PreOrderMemOutboardRef::new(self.hash.into(), IROH_BLOCK_SIZE, &self.outboard);
+        let ranges = collection_page(version, self.page);
+        bao_tree::io::tokio::encode_ranges_validated(
+            Cursor::new(self.data.as_ref()),
+            outboard,
+            &ranges,
+            transfer.writer(&mut *writer),
+        )
+        .await?;
+        let bytes = collection_page_bytes(version, self.page, self.data.len() as u64);
+        self.decoder
+            .extend(&self.data[bytes.start as usize..bytes.end as usize]);
+        self.num_blobs = self.decoder.num_blobs().unwrap_or_default();
+        self.page += 1;
+        Ok(())
+    }
+}
+
+/// Reads the number of blobs and their total size from a serialised collection.
+///
+/// This decodes the collection in pieces, so the blobs are never all in memory at once.
+fn collection_info(data: &[u8]) -> Result<(u64, u64)> {
+    let mut decoder = CollectionDecoder::default();
+    for piece in data.chunks(64 * 1024) {
+        decoder.extend(piece);
+        while decoder.next_blob()?.is_some() {}
+    }
+    let num_blobs = decoder.num_blobs().context("empty Collection data")?;
+    Ok((num_blobs, decoder.finish()?))
+}
+
+/// Transfers a single blob which was requested directly.
+///
+/// Only the missing ranges of the blob are sent, see [`Request::missing_ranges`].
+///
+/// Will fail if there is an error writing to the getter or reading from the database.
+///
+/// If the transfer does _not_ end in error, the buffer will be empty and the writer is gracefully closed.
+#[allow(clippy::too_many_arguments)]
+async fn transfer_blob(
+    request: &Request,
+    // Database from which to fetch the blob.
+    db: &Database,
+    // Quinn stream.
+    writer: &mut quinn::SendStream,
+    // Buffer used when writing to writer.
+    buffer: &mut BytesMut,
+    // The limits the data is sent with.
+    transfer: &mut Transfer,
+    events: broadcast::Sender<Event>,
+    connection_id: u64,
+    request_id: u64,
+    token: &Option<String>,
+) -> Result<SentStatus> {
+    let hash = request.hash();
+    let ranges = request.missing_ranges(&hash);
+    let (status, size) =
+        send_blob(db.clone(), hash, &ranges, writer, buffer, transfer, &events).await?;
+    writer.finish().await?;
+    if status == SentStatus::Sent {
+        let _ = events.send(Event::TransferBlobCompleted {
+            connection_id,
+            request_id,
+            token: token.clone(),
+            hash,
+            index: 0,
+            parents: Vec::new(),
+            size,
+        });
+    }
+    Ok(status)
+}
+
+fn notify_transfer_aborted(
+    events: broadcast::Sender<Event>,
+    connection_id: u64,
+    request_id: u64,
+    token: &Option<String>,
+) {
     let _ = events.send(Event::TransferAborted {
         connection_id,
         request_id,
+        token: token.clone(),
     });
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_stream(
     db: Database,
-    token: AuthToken,
+    auth: &Auth,
+    limits: &ConnectionLimits,
+    push_dir: Option<PathBuf>,
+    version: u64,
     connection_id: u64,
+    peer_id: PeerId,
     (mut writer, mut reader): (quinn::SendStream, quinn::RecvStream),
     events: broadcast::Sender<Event>,
 ) -> Result<()> {
@@ -814,36 +2014,115 @@ async fn handle_stream(
 
     // 1. Read Handshake
     debug!("reading handshake");
-    if let Err(e) = read_handshake(&mut reader, &mut in_buffer, token).await {
-        notify_transfer_aborted(events, connection_id, request_id);
-        return Err(e);
-    }
+    let access = match read_handshake(&mut reader, &mut in_buffer, auth, &peer_id, version).await {
+        Ok(access) => access,
+        Err(e) => {
+            notify_transfer_aborted(events, connection_id, request_id, &None);
+            write_error(
+                &mut writer,
+                &mut out_buffer,
+                version,
+                e.code(),
+                e.to_string(),
+            )
+            .await;
+            return Err(e.into());
+        }
+    };
+
+    let token = access.token().map(ToString::to_string);
 
     // 2. Decode the request.
     debug!("reading request");
-    let request = match read_request(reader, &mut in_buffer).await {
-        Ok(r) => r,
+    let request = read_request(&mut reader, &mut in_buffer, version)
+        .await
+        .and_then(|request| access.authorize(&request, &db).map(|_| request));
+    let request = match request {
+        Ok(Req::Get(r)) => r,
+        Ok(Req::Push(push)) => {
+            let res = handle_push(
+                push,
+                &db,
+                push_dir,
+                version,
+                (writer, reader),
+                &events,
+                connection_id,
+                request_id,
+                &token,
+            )
+            .await;
+            if res.is_err() {
+                notify_transfer_aborted(events, connection_id, request_id, &token);
+            }
+            return res;
+        }
+        Ok(Req::Has(has)) => {
+            debug!(count = has.hashes.len(), "received has request");
+            return handle_has(has, &db, writer, &mut out_buffer).await;
+        }
+        Ok(Req::Resolve(resolve)) => {
+            debug!(tag = %resolve.tag, "received resolve request");
+            return handle_resolve(resolve, &db, &access, version, writer, &mut out_buffer).await;
+        }
         Err(e) => {
-            notify_transfer_aborted(events, connection_id, request_id);
-            return Err(e);
+            notify_transfer_aborted(events, connection_id, request_id, &token);
+            write_error(
+                &mut writer,
+                &mut out_buffer,
+                version,
+                e.code(),
+                e.to_string(),
+            )
+            .await;
+            return Err(e.into());
         }
     };
+    drop(reader);
 
-    let hash = request.hash;
+    let hash = request.hash();
     debug!(%hash, "received request");
     let _ = events.send(Event::RequestReceived {
         connection_id,
         hash,
         request_id,
+        token: token.clone(),
     });
 
-    // 4. Attempt to find hash
-    let (outboard, data) = match db.get(&hash) {
-        // We only respond to requests for collections, not individual blobs
-        Some(BlobOrCollection::Collection { outboard, data }) => (outboard, data),
-        _ => {
+    // 4. Check the limits of the client
+    let mut transfer = match limits.start_transfer(token.as_deref()) {
+        Some(transfer) => transfer,
+        None => {
+            debug!("too many transfers in progress");
+            let _ = events.send(Event::RequestRejected {
+                connection_id,
+                request_id,
+                token: token.clone(),
+            });
+            let message = "too many transfers in progress".to_string();
+            let code = ErrorCode::TooManyRequests;
+            write_error(&mut writer, &mut out_buffer, version, code, message).await;
+            return Ok(());
+        }
+    };
+    transfer.on_throttled({
+        let events = events.clone();
+        let token = token.clone();
+        move || {
+            let _ = events.send(Event::TransferThrottled {
+                connection_id,
+                request_id,
+                token,
+            });
+        }
+    });
+
+    // 5. Attempt to find hash
+    let entry = match db.get(&hash) {
+        Some(entry) => entry,
+        None => {
             debug!("not found");
-            notify_transfer_aborted(events, connection_id, request_id);
+            notify_transfer_aborted(events, connection_id, request_id, &token);
             write_response(&mut writer, &mut out_buffer, Res::NotFound).await?;
             writer.finish().await?;
 
@@ -851,31 +2130,68 @@ async fn handle_stream(
         }
     };
 
-    // 5. Transfer data!
-    match transfer_collection(
-        hash,
-        &db,
-        writer,
-        &mut out_buffer,
-        &outboard,
-        &data,
-        events.clone(),
-        connection_id,
-        request_id,
-    )
-    .await
-    {
-        Ok(SentStatus::Sent) => {
-            let _ = events.send(Event::TransferCollectionCompleted {
+    // 6. Transfer data!
+    let entry_is_collection = !entry.is_blob();
+    let res = match entry {
+        BlobOrCollection::Collection {
+            outboard,
+            data,
+            num_blobs,
+            total_blobs_size,
+        } => {
+            transfer_collection(
+                &request,
+                version,
+                &db,
+                &mut writer,
+                &mut out_buffer,
+                &mut transfer,
+                &outboard,
+                &data,
+                num_blobs,
+                total_blobs_size,
+                events.clone(),
                 connection_id,
                 request_id,
-            });
+                &token,
+            )
+            .await
+        }
+        BlobOrCollection::Blob { .. } => {
+            transfer_blob(
+                &request,
+                &db,
+                &mut writer,
+                &mut out_buffer,
+                &mut transfer,
+                events.clone(),
+                connection_id,
+                request_id,
+                &token,
+            )
+            .await
+        }
+    };
+
+    match res {
+        Ok(SentStatus::Sent) => {
+            // A single blob transfer is completed with Event::TransferBlobCompleted
+            if entry_is_collection {
+                let _ = events.send(Event::TransferCollectionCompleted {
+                    connection_id,
+                    request_id,
+                    token: token.clone(),
+                });
+            }
         }
-        Ok(SentStatus::NotFound) => {
-            notify_transfer_aborted(events, connection_id, request_id);
+        Ok(SentStatus::NotFound | SentStatus::Unsupported) => {
+            notify_transfer_aborted(events, connection_id, request_id, &token);
         }
         Err(e) => {
-            notify_transfer_aborted(events, connection_id, request_id);
+            notify_transfer_aborted(events, connection_id, request_id, &token);
+            // Data may already have been sent, so we can not send a response anymore.
+            let code = Closed::Internal;
+            writer.reset(code.into()).ok();
             return Err(e);
         }
     }
@@ -884,41 +2200,304 @@ async fn handle_stream(
     Ok(())
 }
 
+/// Answers which of the requested hashes are in the database.
+async fn handle_has(
+    request: HasRequest,
+    db: &Database,
+    mut writer: quinn::SendStream,
+    buffer: &mut BytesMut,
+) -> Result<()> {
+    let presence = request
+        .hashes
+        .iter()
+        .map(|hash| match db.get(hash) {
+            Some(BlobOrCollection::Blob { size, .. }) => Presence::Blob { size },
+            Some(BlobOrCollection::Collection { data, .. }) => Presence::Collection {
+                size: data.len() as u64,
+            },
+            None => Presence::Missing,
+        })
+        .collect();
+    write_response(&mut writer, buffer, Res::Has { presence }).await?;
+    writer.finish().await?;
+    Ok(())
+}
+
+/// Answers which hash a tag refers to.
+///
+/// Resolving a tag is only allowed if getting the hash it refers to is.
+async fn handle_resolve(
+    request: ResolveRequest,
+    db: &Database,
+    access: &Access,
+    version: u64,
+    mut writer: quinn::SendStream,
+    buffer: &mut BytesMut,
+) -> Result<()> {
+    let hash = match db.resolve_tag(&request.tag) {
+        Some(hash) => hash,
+        None => {
+            let message = format!("no tag named {}", request.tag);
+            write_error(&mut writer, buffer, version, ErrorCode::NotFound, message).await;
+            return Ok(());
+        }
+    };
+    if let Err(e) = access.authorize(&Req::Get(Request::new(hash)), db) {
+        write_error(&mut writer, buffer, version, e.code(), e.to_string()).await;
+        return Ok(());
+    }
+    write_response(&mut writer, buffer, Res::Resolved { hash }).await?;
+    writer.finish().await?;
+    Ok(())
+}
+
+/// Receives a pushed collection and adds it to the database.
+///
+/// Will fail if pushing is not enabled, if the data does not match the hashes, or if there
+/// is an error storing the data.  The getter is told about failures using [`Res::Error`].
+#[allow(clippy::too_many_arguments)]
+async fn handle_push(
+    request: PushRequest,
+    db: &Database,
+    push_dir: Option<PathBuf>,
+    version: u64,
+    (mut writer, mut reader): (quinn::SendStream, quinn::RecvStream),
+    events: &broadcast::Sender<Event>,
+    connection_id: u64,
+    request_id: u64,
+    token: &Option<String>,
+) -> Result<()> {
+    let mut buffer = BytesMut::with_capacity(1024);
+    let hash = request.hash;
+    debug!(%hash, "received push request");
+    let res = match push_dir {
+        Some(dir) => {
+            write_response(&mut writer, &mut buffer, Res::PushAccepted).await?;
+            receive_collection(
+                hash,
+                db,
+                &dir,
+                &mut reader,
+                events,
+                connection_id,
+                request_id,
+                token,
+            )
+            .await
+        }
+        None => Err(RequestError::Unsupported("pushing is not enabled")),
+    };
+    if let Err(e) = res {
+        write_error(&mut writer, &mut buffer, version, e.code(), e.to_string()).await;
+        return Err(e.into());
+    }
+    write_response(&mut writer, &mut buffer, Res::PushCompleted).await?;
+    writer.finish().await?;
+    let _ = events.send(Event::PushCompleted {
+        connection_id,
+        request_id,
+        token: token.clone(),
+        hash,
+    });
+    Ok(())
+}
+
+/// Receives and verifies a pushed collection and all its blobs.
+///
+/// The blobs are stored in `dir`, or with the copies of a persistent database so removing
+/// them deletes the files.  Once everything is received the collection and its blobs are
+/// added to the database.
+#[allow(clippy::too_many_arguments)]
+async fn receive_collection(
+    hash: Hash,
+    db: &Database,
+    dir: &Path,
+    reader: &mut quinn::RecvStream,
+    events: &broadcast::Sender<Event>,
+    connection_id: u64,
+    request_id: u64,
+    token: &Option<String>,
+) -> Result<(), RequestError> {
+    let data = read_bao_encoded(&mut *reader, hash).await?;
+    let collection = Collection::from_bytes(&data)?;
+    if collection.blobs().iter().any(|blob| blob.is_collection()) {
+        return Err(RequestError::Unsupported("pushing nested collections"));
+    }
+    let _ = events.send(Event::PushStarted {
+        connection_id,
+        request_id,
+        token: token.clone(),
+        hash,
+        num_blobs: collection.total_entries(),
+        total_blobs_size: collection.total_blobs_size(),
+    });
+
+    // Keeps gc from removing the received blobs, or the existing ones which are not
+    // received again, until the collection is added.
+    let dirs = ImportDirs {
+        outboards: db.outboards_dir(),
+        import: Some(db.start_import()),
+        ..Default::default()
+    };
+    let dir = db.blobs_dir().unwrap_or_else(|| dir.to_path_buf());
+    let mut entries = HashMap::with_capacity(collection.blobs().len() + 1);
+    for (i, blob) in collection.blobs().iter().enumerate() {
+        let (entry, size) = receive_blob(blob.hash, db, &dir, &dirs, &mut *reader).await?;
+        if let Some(entry) = entry {
+            entries.insert(blob.hash, entry);
+        }
+        let _ = events.send(Event::PushBlobReceived {
+            connection_id,
+            request_id,
+            token: token.clone(),
+            hash: blob.hash,
+            index: i as u64,
+            size,
+        });
+    }
+    let extra = reader
+        .read_chunk(8, false)
+        .await
+        .context("failed to read pushed data")?;
+    if extra.is_some() {
+        return Err(anyhow::anyhow!("Extra data past pushed collection").into());
+    }
+
+    let (outboard, _) = bao_tree::outboard(&data, IROH_BLOCK_SIZE);
+    let entry = BlobOrCollection::collection(Bytes::from(outboard), Bytes::from(data))
+        .map_err(RequestError::internal)?;
+    entries.insert(hash, entry);
+    db.extend_pinned(entries, hash)
+        .await
+        .map_err(RequestError::internal)?;
+    Ok(())
+}
+
+/// Receives and verifies a single pushed blob, storing it in `dir`.
+///
+/// Returns the new database entry and the size of the blob.  If the database already has
+/// the blob the data is verified but not stored and no entry is returned.
+async fn receive_blob(
+    hash: Hash,
+    db: &Database,
+    dir: &Path,
+    dirs: &ImportDirs,
+    reader: &mut quinn::RecvStream,
+) -> Result<(Option<BlobOrCollection>, u64), RequestError> {
+    let mut decoder =
+        AsyncResponseDecoder::new(hash.into(), RangeSet2::all(), IROH_BLOCK_SIZE, reader);
+    let mut buf = vec![0u8; 64 * 1024];
+    dirs.register(hash);
+    if db.get(&hash).is_some() {
+        let mut size = 0;
+        loop {
+            let n = decoder.read(&mut buf).await.context("invalid blob data")?;
+            if n == 0 {
+                break;
+            }
+            size += n as u64;
+        }
+        return Ok((None, size));
+    }
+
+    let (file, temp_path) = tempfile::Builder::new()
+        .prefix(".push-")
+        .tempfile_in(dir)
+        .map_err(RequestError::internal)?
+        .into_parts();
+    let mut file = tokio::fs::File::from_std(file);
+    let mut size = 0;
+    loop {
+        let n = decoder.read(&mut buf).await.context("invalid blob data")?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buf[..n])
+            .await
+            .map_err(RequestError::internal)?;
+        size += n as u64;
+    }
+    file.flush().await.map_err(RequestError::internal)?;
+    drop(file);
+
+    let path = dir.join(format_hash(&hash));
+    let (outboard_hash, outboard) = {
+        let temp_path = temp_path.to_path_buf();
+        let dirs = dirs.clone();
+        tokio::task::spawn_blocking(move || {
+            collection::compute_outboard(&temp_path, size, &dirs, |_| {})
+        })
+        .await
+        .map_err(RequestError::internal)?
+        .map_err(RequestError::internal)?
+    };
+    if outboard_hash != hash {
+        return Err(RequestError::internal(anyhow::anyhow!(
+            "stored data for {hash} changed"
+        )));
+    }
+    temp_path.persist(&path).map_err(RequestError::internal)?;
+    let metadata = path.metadata().map_err(RequestError::internal)?;
+    let entry = BlobOrCollection::Blob {
+        outboard,
+        sources: vec![BlobSource::with_metadata(path, &metadata)],
+        size,
+    };
+    Ok((Some(entry), size))
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 enum SentStatus {
     Sent,
     NotFound,
+    Unsupported,
 }
 
-async fn send_blob<W: AsyncWrite + Unpin + Send + 'static>(
+/// Sends the requested `ranges` of a blob, obeying the rate limits of the `transfer`.
+///
+/// Returns the size of the entire blob.
+async fn send_blob(
     db: Database,
     name: Hash,
-    mut writer: W,
+    ranges: &RangeSpec,
+    writer: &mut quinn::SendStream,
     buffer: &mut BytesMut,
-) -> Result<(SentStatus, W, u64)> {
-    match db.get(&name) {
+    transfer: &mut Transfer,
+    events: &broadcast::Sender<Event>,
+) -> Result<(SentStatus, u64)> {
+    let (outboard, file, size) = match db.get(&name) {
         Some(BlobOrCollection::Blob {
             outboard,
-            path,
+            sources,
             size,
         }) => {
-            write_response(&mut writer, buffer, Res::Found).await?;
+            let (file, stale) = BlobSource::open(&sources, size).await;
+            for path in stale {
+                warn!(hash = %name, "stale blob source {}", path.display());
+                let _ = events.send(Event::BlobSourceStale { hash: name, path });
+            }
+            (outboard, file, size)
+        }
+        _ => (Bytes::new(), None, 0),
+    };
+    match file {
+        Some(file_reader) => {
+            write_response(&mut *writer, buffer, Res::Found).await?;
 
             let outboard = PreOrderMemOutboardRef::new(name.into(), IROH_BLOCK_SIZE, &outboard);
-            let file_reader = tokio::fs::File::open(&path).await?;
             bao_tree::io::tokio::encode_ranges_validated(
                 file_reader,
                 outboard,
-                &RangeSet2::all(),
-                &mut writer,
+                &ranges.to_chunk_ranges(size),
+                transfer.writer(&mut *writer),
             )
             .await?;
 
-            Ok((SentStatus::Sent, writer, size))
+            Ok((SentStatus::Sent, size))
         }
         _ => {
-            write_response(&mut writer, buffer, Res::NotFound).await?;
-            Ok((SentStatus::NotFound, writer, 0))
+            write_response(&mut *writer, buffer, Res::NotFound).await?;
+            Ok((SentStatus::NotFound, 0))
         }
     }
 }
@@ -1000,7 +2579,33 @@ impl From<&std::path::Path> for DataSource {
 /// Creates a database of blobs (stored in outboard storage) and Collections, stored in memory.
 /// Returns a the hash of the collection created by the given list of DataSources
 pub async fn create_collection(data_sources: Vec<DataSource>) -> Result<(Database, Hash)> {
-    let (db, hash) = collection::create_collection(data_sources, Progress::none()).await?;
+    let (db, hash) = collection::create_collection(
+        data_sources,
+        false,
+        Vec::new(),
+        ImportDirs::default(),
+        Progress::none(),
+    )
+    .await?;
+    Ok((Database::from(db), hash))
+}
+
+/// Creates a database like [`create_collection`], but with a nested collection for every
+/// directory in the names of the DataSources.
+///
+/// Names are `/` separated paths, e.g. `dir/file.txt` is added as `file.txt` to the nested
+/// collection for `dir`.  Directories with the same content result in the same nested
+/// collection, so they are shared between collections.  Getting nested collections needs a
+/// getter which speaks at least protocol version 5.
+pub async fn create_nested_collection(data_sources: Vec<DataSource>) -> Result<(Database, Hash)> {
+    let (db, hash) = collection::create_collection(
+        data_sources,
+        true,
+        Vec::new(),
+        ImportDirs::default(),
+        Progress::none(),
+    )
+    .await?;
     Ok((Database::from(db), hash))
 }
 
@@ -1012,8 +2617,9 @@ async fn write_response<W: AsyncWrite + Unpin>(
     let response = Response { data: res };
 
     // TODO: do not transfer blob data as part of the responses
-    if buffer.len() < Response::POSTCARD_MAX_SIZE {
-        buffer.resize(Response::POSTCARD_MAX_SIZE, 0u8);
+    let size = postcard::experimental::serialized_size(&response)?;
+    if buffer.len() < size {
+        buffer.resize(size, 0u8);
     }
     let used = postcard::to_slice(&response, buffer)?;
 
@@ -1023,6 +2629,31 @@ async fn write_response<W: AsyncWrite + Unpin>(
     Ok(())
 }
 
+/// Tells the getter why its request failed and closes the stream.
+///
+/// Protocol version 1 has no [`Res::Error`], for it the stream is reset with the error code
+/// instead.
+///
+/// This is best effort, the getter might already be gone.
+async fn write_error(
+    writer: &mut quinn::SendStream,
+    buffer: &mut BytesMut,
+    version: u64,
+    code: ErrorCode,
+    message: String,
+) {
+    if version == 1 {
+        writer.reset(code.as_u16().into()).ok();
+        return;
+    }
+    let res = Res::Error { code, message };
+    if let Err(err) = write_response(&mut *writer, buffer, res).await {
+        debug!("failed to send error response: {err:#}");
+        return;
+    }
+    writer.finish().await.ok();
+}
+
 /// Create a [`quinn::ServerConfig`] with the given keypair and limits.
 pub fn make_server_config(
     keypair: &Keypair,
@@ -1045,6 +2676,7 @@ pub fn make_server_config(
 
 #[cfg(test)]
 mod tests {
+    use futures::StreamExt;
     use proptest::prelude::*;
     use std::collections::HashMap;
     use std::net::Ipv4Addr;
@@ -1052,7 +2684,7 @@ mod tests {
     use std::str::FromStr;
     use testdir::testdir;
 
-    use crate::blobs::Blob;
+    use crate::blobs::{Blob, BlobKind};
     use crate::provider::database::Snapshot;
 
     use super::*;
@@ -1081,13 +2713,14 @@ mod tests {
                 cblobs.push(Blob {
                     name: hash.to_string(),
                     hash,
+                    kind: BlobKind::Blob,
                 });
                 map.insert(
                     hash,
                     BlobOrCollection::Blob {
                         outboard,
                         size,
-                        path,
+                        sources: vec![BlobSource::new(path)],
                     },
                 );
             }
@@ -1098,11 +2731,9 @@ mod tests {
                 let (outboard, hash) = bao_tree::outboard(&data, IROH_BLOCK_SIZE);
                 let outboard = Bytes::from(outboard);
                 let hash = Hash::from(hash);
-                map.insert(hash, BlobOrCollection::Collection { outboard, data });
+                map.insert(hash, BlobOrCollection::collection(outboard, data).unwrap());
             }
-            let db = Database::default();
-            db.union_with(map);
-            db
+            Database::from(map)
         })
     }
 
@@ -1118,7 +2749,7 @@ mod tests {
         fn database_persistence_roundtrip(db in db(10, 1024 * 64)) {
             let dir = tempfile::tempdir().unwrap();
             let snapshot = db.snapshot();
-            snapshot.persist(&dir).unwrap();
+            snapshot.persist(&dir, true).unwrap();
             let snapshot2 = Snapshot::load(&dir).unwrap();
             let db2 = Database::from_snapshot(snapshot2).unwrap();
             let db = db.to_inner();
@@ -1127,6 +2758,44 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_database_journal() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let data_dir = dir.join("data");
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, b"foo").await?;
+        let bar = dir.join("bar");
+        tokio::fs::write(&bar, b"bar").await?;
+        let (foo_db, foo_hash) = create_collection(vec![foo.into()]).await?;
+        let (bar_db, bar_hash) = create_collection(vec![bar.into()]).await?;
+
+        // Entries are on disk without saving the database.
+        let db = Database::open(&data_dir).await?;
+        db.extend_pinned(foo_db.to_inner(), foo_hash).await?;
+        drop(db);
+        let db = Database::open(&data_dir).await?;
+        assert_eq!(db.to_inner(), foo_db.to_inner());
+
+        // Saving compacts the journal into the paths file.
+        db.save(&data_dir).await?;
+        assert_eq!(std::fs::metadata(data_dir.join("journal.bin"))?.len(), 0);
+        db.extend_pinned(bar_db.to_inner(), bar_hash).await?;
+        drop(db);
+
+        // An incomplete record at the end of the journal is ignored.
+        let mut journal = std::fs::OpenOptions::new()
+            .append(true)
+            .open(data_dir.join("journal.bin"))?;
+        std::io::Write::write_all(&mut journal, &[42, 0, 0, 0, 1, 2])?;
+        drop(journal);
+        let db = Database::open(&data_dir).await?;
+        let mut expected = foo_db.to_inner();
+        expected.extend(bar_db.to_inner());
+        assert_eq!(db.to_inner(), expected);
+        assert_eq!(Database::load(&data_dir).await?.to_inner(), expected);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_create_collection() -> Result<()> {
         let dir: PathBuf = testdir!();
@@ -1141,6 +2810,7 @@ mod tests {
         expect_blobs.push(Blob {
             name: "foo".to_string(),
             hash,
+            kind: BlobKind::Blob,
         });
 
         // DataSource::NamedFile
@@ -1150,6 +2820,7 @@ mod tests {
         expect_blobs.push(Blob {
             name: "bat".to_string(),
             hash,
+            kind: BlobKind::Blob,
         });
 
         // DataSource::NamedFile, empty string name
@@ -1159,6 +2830,7 @@ mod tests {
         expect_blobs.push(Blob {
             name: "".to_string(),
             hash,
+            kind: BlobKind::Blob,
         });
 
         let expect_collection = Collection::new(expect_blobs, 0).unwrap();
@@ -1179,6 +2851,483 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_database_gc() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let data_dir = dir.join("data");
+        let shared = dir.join("shared");
+        tokio::fs::write(&shared, b"shared").await?;
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, b"foo").await?;
+        let bar = dir.join("bar");
+        tokio::fs::write(&bar, b"bar").await?;
+        let (foo_db, foo_hash) = create_collection(vec![foo.into(), shared.clone().into()]).await?;
+        let (bar_db, bar_hash) = create_collection(vec![bar.into(), shared.into()]).await?;
+        let foo_blob = Hash::from(blake3::hash(b"foo"));
+        let shared_blob = Hash::from(blake3::hash(b"shared"));
+
+        let db = Database::open(&data_dir).await?;
+        db.extend_pinned(foo_db.to_inner(), foo_hash).await?;
+        db.extend_pinned(bar_db.to_inner(), bar_hash).await?;
+        assert!(db.gc().await?.is_empty());
+
+        // Removing a collection keeps its blobs until they are collected.
+        assert!(db.remove(foo_hash).await?);
+        assert!(!db.remove(foo_hash).await?);
+        assert!(db.get(&foo_hash).is_none());
+        assert!(db.get(&foo_blob).is_some());
+        assert_eq!(db.gc().await?, vec![foo_blob]);
+        // Blobs still in a pinned collection are not removed.
+        let err = db.remove(shared_blob).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(db.get(&shared_blob).is_some());
+        let outboard = |hash: Hash| data_dir.join("outboards").join(hex::encode(hash));
+        assert!(!outboard(foo_hash).exists());
+        assert!(!outboard(foo_blob).exists());
+        assert!(!data_dir
+            .join("collections")
+            .join(hex::encode(foo_hash))
+            .exists());
+        assert!(outboard(shared_blob).exists());
+        drop(db);
+
+        let db = Database::open(&data_dir).await?;
+        assert_eq!(db.to_inner().len(), 3);
+        assert!(db.get(&foo_blob).is_none());
+
+        // Without a pins file all entries which are not in a collection are pinned.
+        db.save(&data_dir).await?;
+        drop(db);
+        std::fs::remove_file(data_dir.join("pins.bin"))?;
+        let db = Database::open(&data_dir).await?;
+        assert!(db.gc().await?.is_empty());
+        assert_eq!(db.to_inner(), bar_db.to_inner());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_gc_import() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let data_dir = dir.join("data");
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, b"foo").await?;
+        let db = Database::open(&data_dir).await?;
+        let outboards = db.outboards_dir().unwrap();
+        let orphan = outboards.join(hex::encode(Hash::new(b"orphan")));
+        tokio::fs::write(&orphan, b"orphan").await?;
+
+        // The files of an import in progress are kept by gc and save, and so are orphans.
+        let import = db.start_import();
+        let dirs = ImportDirs {
+            outboards: Some(outboards.clone()),
+            collections: db.collections_dir(),
+            import: Some(import.clone()),
+            ..Default::default()
+        };
+        let (map, hash) = collection::create_collection(
+            vec![foo.into()],
+            false,
+            Vec::new(),
+            dirs,
+            Progress::none(),
+        )
+        .await?;
+        assert!(db.gc().await?.is_empty());
+        db.save(&data_dir).await?;
+        for hash in map.keys() {
+            assert!(outboards.join(hex::encode(hash)).exists());
+        }
+        assert!(orphan.exists());
+
+        db.extend_pinned(map.clone(), hash).await?;
+        drop(import);
+        assert!(db.gc().await?.is_empty());
+        for hash in map.keys() {
+            assert!(outboards.join(hex::encode(hash)).exists());
+        }
+        assert!(!orphan.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_tags() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let data_dir = dir.join("data");
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, b"foo").await?;
+        let (foo_db, foo_hash) = create_collection(vec![foo.into()]).await?;
+        let missing = Hash::from(blake3::hash(b"missing"));
+
+        let db = Database::open(&data_dir).await?;
+        db.extend_tagged(foo_db.to_inner(), "nightly".to_string(), foo_hash)
+            .await?;
+        let previous = db.set_tag("nightly".to_string(), Some(foo_hash)).await?;
+        assert_eq!(previous, Some(foo_hash));
+        let err = db
+            .set_tag("other".to_string(), Some(missing))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        // Tagged data is kept by gc, even though nothing is pinned.
+        assert!(db.gc().await?.is_empty());
+        drop(db);
+
+        // Tags are journaled, and kept when the journal is compacted.
+        let db = Database::open(&data_dir).await?;
+        assert_eq!(db.resolve_tag("nightly"), Some(foo_hash));
+        db.save(&data_dir).await?;
+        drop(db);
+        let db = Database::open(&data_dir).await?;
+        assert_eq!(
+            db.tags().collect::<Vec<_>>(),
+            vec![("nightly".to_string(), foo_hash)]
+        );
+
+        // Removing a hash deletes its tags.
+        db.set_tag("latest".to_string(), Some(foo_hash)).await?;
+        assert!(db.remove(foo_hash).await?);
+        assert_eq!(db.tags().count(), 0);
+        assert_eq!(db.gc().await?.len(), 1);
+        drop(db);
+        let db = Database::open(&data_dir).await?;
+        assert_eq!(db.tags().count(), 0);
+        assert!(db.to_inner().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_sources() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let data_dir = dir.join("data");
+        let foo = dir.join("foo");
+        let bar = dir.join("bar");
+        tokio::fs::write(&foo, b"same").await?;
+        tokio::fs::write(&bar, b"same").await?;
+        let (foo_db, foo_hash) = create_collection(vec![foo.clone().into()]).await?;
+        let (bar_db, bar_hash) = create_collection(vec![bar.clone().into()]).await?;
+        let blob_hash = Hash::from(blake3::hash(b"same"));
+        let sources = |db: &Database| {
+            let mut paths = db
+                .blobs()
+                .map(|(hash, path, _)| {
+                    assert_eq!(hash, blob_hash);
+                    path
+                })
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+
+        // Adding the same content again records the other path.
+        let db = Database::open(&data_dir).await?;
+        db.extend_pinned(foo_db.to_inner(), foo_hash).await?;
+        db.extend_pinned(bar_db.to_inner(), bar_hash).await?;
+        assert_eq!(sources(&db), vec![bar.clone(), foo.clone()]);
+        drop(db);
+
+        // The sources are journaled, and kept when the journal is compacted.
+        let db = Database::open(&data_dir).await?;
+        assert_eq!(sources(&db), vec![bar.clone(), foo.clone()]);
+        db.save(&data_dir).await?;
+        drop(db);
+        let db = Database::open(&data_dir).await?;
+        assert_eq!(sources(&db), vec![bar, foo]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_outboard_file() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        for size in [0, 1, 1024, 16 * 1024 + 1, 1024 * 1024 + 7] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let path = dir.join(format!("data-{size}"));
+            std::fs::write(&path, &data)?;
+            let size = size as u64;
+            let (hash, outboard) =
+                collection::compute_outboard(&path, size, &ImportDirs::default(), |_| {})?;
+            let dirs = ImportDirs {
+                outboards: Some(dir.clone()),
+                ..Default::default()
+            };
+            let (file_hash, file_outboard) =
+                collection::compute_outboard(&path, size, &dirs, |_| {})?;
+            assert_eq!(file_hash, hash);
+            assert_eq!(file_outboard, outboard);
+            let stored = std::fs::read(dir.join(hex::encode(hash)))?;
+            assert_eq!(stored, outboard);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_collection_file() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let sources = ["a/foo", "a/bar", "baz"]
+            .into_iter()
+            .map(|name| {
+                let path = dir.join(name.replace('/', "-"));
+                std::fs::write(&path, name)?;
+                Ok(DataSource::with_name(path, name.to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (map, hash) = collection::create_collection(
+            sources.clone(),
+            true,
+            Vec::new(),
+            ImportDirs::default(),
+            Progress::none(),
+        )
+        .await?;
+        let collections = dir.join("collections");
+        std::fs::create_dir_all(&collections)?;
+        let dirs = ImportDirs {
+            collections: Some(collections.clone()),
+            ..Default::default()
+        };
+        let (file_map, file_hash) =
+            collection::create_collection(sources, true, Vec::new(), dirs, Progress::none())
+                .await?;
+        assert_eq!(file_hash, hash);
+        assert_eq!(file_map, map);
+        match &map[&hash] {
+            BlobOrCollection::Collection {
+                data,
+                num_blobs,
+                total_blobs_size,
+                ..
+            } => {
+                assert_eq!((*num_blobs, *total_blobs_size), (2, 13));
+                let stored = std::fs::read(collections.join(hex::encode(hash)))?;
+                assert_eq!(&stored, data);
+            }
+            entry => panic!("expected a collection, found {entry:?}"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_provide_copy() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, b"foo").await?;
+        let db = Database::open(dir.join("data")).await?;
+        let provider = Provider::builder(db.clone())
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+
+        let request = |import| ProvideRequest {
+            path: foo.clone(),
+            nested: false,
+            metadata: false,
+            import,
+            tag: None,
+            watch: None,
+        };
+        let mut stream = provider
+            .controller()
+            .server_streaming(request(ImportMode::Copy))
+            .await?;
+        let mut hash = None;
+        while let Some(progress) = stream.next().await {
+            match progress? {
+                ProvideProgress::Done { hash: h, .. } => hash = Some(h),
+                ProvideProgress::Abort(err) => return Err(err.into()),
+                _ => {}
+            }
+        }
+        let hash = hash.context("no blob added")?;
+
+        // The database owns a copy, so changing the original does not affect it.
+        tokio::fs::write(&foo, b"changed").await?;
+        let path = match db.get(&hash) {
+            Some(BlobOrCollection::Blob { sources, .. }) => sources[0].path.clone(),
+            entry => panic!("expected a blob, found {entry:?}"),
+        };
+        assert_eq!(path, dir.join("data").join("blobs").join(hex::encode(hash)));
+        assert_eq!(tokio::fs::read(&path).await?, b"foo");
+
+        // Copying needs a data directory.
+        let provider = Provider::builder(Database::default())
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let mut stream = provider
+            .controller()
+            .server_streaming(request(ImportMode::Copy))
+            .await?;
+        let mut aborted = false;
+        while let Some(progress) = stream.next().await {
+            aborted |= matches!(progress?, ProvideProgress::Abort(_));
+        }
+        assert!(aborted);
+        Ok(())
+    }
+
+    /// Reads the progress of adding the data, returning its hash and the changed paths.
+    async fn next_root<S, E>(stream: &mut S) -> Result<(Hash, Vec<PathBuf>)>
+    where
+        S: Stream<Item = std::result::Result<ProvideProgress, E>> + Unpin,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let mut changed = Vec::new();
+        loop {
+            match stream.next().await.context("stream ended")?? {
+                ProvideProgress::Changed { paths } => changed.extend(paths),
+                ProvideProgress::AllDone { hash } => return Ok((hash, changed)),
+                ProvideProgress::Abort(err) => return Err(err.into()),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_provide_watch() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let root = dir.join("root");
+        tokio::fs::create_dir_all(&root).await?;
+        tokio::fs::write(root.join("foo"), b"foo").await?;
+        let db = Database::default();
+        let provider = Provider::builder(db.clone())
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let request = |watch| ProvideRequest {
+            path: root.clone(),
+            nested: false,
+            metadata: false,
+            import: ImportMode::Reference,
+            tag: None,
+            watch,
+        };
+
+        // Pin the data independently of the watch.
+        let mut stream = provider
+            .controller()
+            .server_streaming(request(None))
+            .await?;
+        let (pinned, _) = next_root(&mut stream).await?;
+
+        let mut stream = provider
+            .controller()
+            .server_streaming(request(Some(Duration::from_millis(100))))
+            .await?;
+        let (first, changed) = next_root(&mut stream).await?;
+        assert!(changed.is_empty());
+        assert_eq!(first, pinned);
+
+        tokio::fs::write(root.join("bar"), b"bar").await?;
+        let (second, changed) =
+            tokio::time::timeout(Duration::from_secs(10), next_root(&mut stream))
+                .await
+                .expect("timeout")?;
+        assert!(changed.contains(&root.join("bar")));
+        assert_ne!(second, first);
+        let collection = match db.get(&second) {
+            Some(BlobOrCollection::Collection { data, .. }) => Collection::from_bytes(&data)?,
+            entry => panic!("expected a collection, found {entry:?}"),
+        };
+        assert_eq!(collection.blobs().len(), 2);
+        // The watch did not create the pin of the first collection, so it is kept.
+        assert!(db.gc().await?.is_empty());
+
+        tokio::fs::write(root.join("baz"), b"baz").await?;
+        let (third, _) = tokio::time::timeout(Duration::from_secs(10), next_root(&mut stream))
+            .await
+            .expect("timeout")?;
+        assert_ne!(third, second);
+        // The second collection was pinned by the watch and is no longer pinned.
+        assert_eq!(db.gc().await?, vec![second]);
+
+        // Dropping the stream stops the watch, so the third collection stays pinned.
+        drop(stream);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        tokio::fs::write(root.join("qux"), b"qux").await?;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(db.gc().await?.is_empty());
+        assert!(db.get(&third).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let root = dir.join("root");
+        tokio::fs::create_dir_all(root.join("sub")).await?;
+        tokio::fs::write(root.join("foo"), b"foo").await?;
+        tokio::fs::write(root.join("sub").join("bar"), b"bar").await?;
+        let provider = Provider::builder(Database::default())
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .spawn()?;
+        let _drop_guard = provider.cancel_token().drop_guard();
+        let controller = provider.controller();
+        let mut stream = controller
+            .server_streaming(ProvideRequest {
+                path: root.clone(),
+                nested: true,
+                metadata: false,
+                import: ImportMode::Reference,
+                tag: None,
+                watch: None,
+            })
+            .await?;
+        let (hash, _) = next_root(&mut stream).await?;
+
+        let controller = &controller;
+        let export = |out_dir: PathBuf, force| async move {
+            let mut stream = controller
+                .server_streaming(ExportRequest {
+                    hash,
+                    out_dir,
+                    force,
+                })
+                .await?;
+            let mut errors = Vec::new();
+            while let Some(progress) = stream.next().await {
+                match progress? {
+                    ExportProgress::Done {
+                        error: Some(error), ..
+                    } => errors.push(error),
+                    ExportProgress::Abort(err) => return Err(err.into()),
+                    _ => {}
+                }
+            }
+            anyhow::Ok(errors)
+        };
+        let out = dir.join("out");
+        assert!(export(out.clone(), false).await?.is_empty());
+        assert_eq!(tokio::fs::read(out.join("foo")).await?, b"foo");
+        assert_eq!(tokio::fs::read(out.join("sub").join("bar")).await?, b"bar");
+
+        // Existing files are only overwritten with force.
+        tokio::fs::write(out.join("foo"), b"mine").await?;
+        assert!(export(out.clone(), false).await.is_err());
+        assert_eq!(tokio::fs::read(out.join("foo")).await?, b"mine");
+        assert!(export(out.clone(), true).await?.is_empty());
+        assert_eq!(tokio::fs::read(out.join("foo")).await?, b"foo");
+
+        // A changed file fails to validate and is not exported.
+        tokio::fs::write(root.join("foo"), b"FOO").await?;
+        let out = dir.join("changed");
+        assert_eq!(export(out.clone(), false).await?.len(), 1);
+        assert!(!out.join("foo").exists());
+        assert_eq!(tokio::fs::read(out.join("sub").join("bar")).await?, b"bar");
+
+        // A file which fails to validate does not replace an existing one, even with force.
+        tokio::fs::write(out.join("foo"), b"mine").await?;
+        assert_eq!(export(out.clone(), true).await?.len(), 1);
+        assert_eq!(tokio::fs::read(out.join("foo")).await?, b"mine");
+        // No temporary files are left behind.
+        let mut entries = tokio::fs::read_dir(&out).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            assert!(!entry.file_name().to_string_lossy().starts_with(".export-"));
+        }
+
+        // The output directory must not depend on the working directory of the provider.
+        assert!(export(PathBuf::from("relative"), false).await.is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_ticket_multiple_addrs() {
         let readme = Path::new(env!("CARGO_MANIFEST_DIR")).join("README.md");
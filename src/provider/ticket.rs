@@ -1,70 +1,261 @@
 //! The ticket type for the provider.
 //!
 //! This is in it's own module to enforce the invariant that you can not construct a ticket
-//! with an empty address list.
+//! with an empty address list or without a hash.
+//!
+//! # Encoding
+//!
+//! A ticket is encoded as a version byte followed by the postcard encoding of the version's
+//! format.  Version 1 has a list of optional fields, each a tag followed by its postcard
+//! encoded value.  Fields with unknown tags are skipped, so new optional fields can be added
+//! without breaking older parsers.
+//!
+//! Tickets created before the version byte was introduced start with the postcard encoding
+//! of their hash, i.e. its length `32`, which is why that version number is never used.
 
 use std::fmt::{self, Display};
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{ensure, Result};
-use serde::{Deserialize, Serialize};
+use anyhow::{bail, ensure, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::protocol::AuthToken;
+use crate::protocol::{AuthToken, Credential};
 use crate::util;
 use crate::{Hash, PeerId};
 
-/// A token containing everything to get a file from the provider.
+/// The version byte of the current ticket format.
+const TICKET_VERSION: u8 = 1;
+
+/// The first byte of tickets from before the version byte was introduced.
+///
+/// This is the postcard length prefix of their [`Hash`].
+const UNVERSIONED: u8 = 32;
+
+/// The tags of the optional fields of a version 1 ticket.
+const LABEL: u16 = 0;
+const SIZE: u16 = 1;
+const NUM_FILES: u16 = 2;
+const EXPIRES: u16 = 3;
+const TAG: u16 = 4;
+
+/// The kind of ticket URIs, see [`Ticket::to_uri`].
+const TICKET_URI_KIND: &str = "ticket";
 
 /// A token containing everything to get a file from the provider.
 ///
 /// It is a single item which can be easily serialized and deserialized.  The [`Display`]
-/// and [`FromStr`] implementations serialize to base64.
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// and [`FromStr`] implementations serialize to base32 with a checksum, so mistyped tickets
+/// are detected.  [`Ticket::to_uri`] gives the same as an `iroh://ticket/` URI.
+///
+/// Besides the provider's address, a ticket has one or more root hashes and usually a
+/// [`Credential`].  Tickets for a public provider have no credential, see
+/// [`crate::provider::Builder::public`].  It can also describe the data with a label, its
+/// size and number of files, and carry an expiry time.
+///
+/// A ticket can also name a tag of the provider, see [`Ticket::tag`], to always get the
+/// data the tag currently refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Ticket {
-    /// The hash to retrieve.
-    hash: Hash,
+    /// The root hashes to retrieve.
+    ///
+    /// This will never be empty.
+    hashes: Vec<Hash>,
     /// The peer ID identifying the provider.
     peer: PeerId,
     /// The socket addresses the provider is listening on.
     ///
     /// This will never be empty.
     addrs: Vec<SocketAddr>,
-    /// The authentication token with permission to retrieve the hash.
+    /// The credential with permission to retrieve the hashes.
+    credential: Option<Credential>,
+    /// A human readable description of the data.
+    label: Option<String>,
+    /// The total size of the data.
+    size: Option<u64>,
+    /// The number of files of the data.
+    num_files: Option<u64>,
+    /// When the ticket expires, in seconds since the unix epoch.
+    expires: Option<u64>,
+    /// The tag of the provider referring to the data.
+    tag: Option<String>,
+}
+
+/// The format of version 1 tickets.
+#[derive(Debug, Serialize, Deserialize)]
+struct TicketV1 {
+    hashes: Vec<Hash>,
+    peer: PeerId,
+    addrs: Vec<SocketAddr>,
+    credential: Option<Credential>,
+    /// The optional fields, the tag and postcard encoded value of each.
+    fields: Vec<(u16, Vec<u8>)>,
+}
+
+/// The unversioned format with a [`Credential`], which can still be parsed.
+#[derive(Debug, Deserialize)]
+struct TicketUnversioned {
+    hash: Hash,
+    peer: PeerId,
+    addrs: Vec<SocketAddr>,
+    credential: Credential,
+}
+
+/// The unversioned format before [`Credential`] was introduced, which can still be parsed.
+#[derive(Debug, Deserialize)]
+struct TicketV0 {
+    hash: Hash,
+    peer: PeerId,
+    addrs: Vec<SocketAddr>,
     token: AuthToken,
 }
 
 impl Ticket {
     pub(super) fn new(
-        hash: Hash,
+        hashes: Vec<Hash>,
         peer: PeerId,
         addrs: Vec<SocketAddr>,
-        token: AuthToken,
+        credential: Option<Credential>,
     ) -> Result<Self> {
+        ensure!(!hashes.is_empty(), "hashes list can not be empty");
         ensure!(!addrs.is_empty(), "addrs list can not be empty");
         Ok(Self {
-            hash,
+            hashes,
             peer,
             addrs,
-            token,
+            credential,
+            label: None,
+            size: None,
+            num_files: None,
+            expires: None,
+            tag: None,
         })
     }
 
+    /// Sets a human readable description of the data.
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Sets when the ticket expires.
+    ///
+    /// This is informational, the provider only enforces the expiry of a
+    /// [`Capability`](crate::protocol::Capability).
+    pub fn with_expires(mut self, expires: SystemTime) -> Self {
+        let secs = expires
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.expires = Some(secs);
+        self
+    }
+
+    /// Sets the tag of the provider which refers to the data.
+    pub fn with_tag(mut self, tag: impl Into<String>) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    /// Sets the total size and the number of files of the data.
+    pub(super) fn with_size(mut self, size: u64, num_files: u64) -> Self {
+        self.size = Some(size);
+        self.num_files = Some(num_files);
+        self
+    }
+
     /// Deserializes from bytes.
+    ///
+    /// Tickets created by older versions, which have no version byte, are accepted as
+    /// well.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        let slf: Ticket = postcard::from_bytes(bytes)?;
+        let slf = match bytes.first() {
+            Some(&TICKET_VERSION) => Self::from_v1(postcard::from_bytes(&bytes[1..])?)?,
+            Some(&UNVERSIONED) => Self::from_unversioned(bytes)?,
+            Some(version) => bail!("unsupported ticket version {version}"),
+            None => bail!("empty ticket"),
+        };
+        ensure!(!slf.hashes.is_empty(), "Invalid hash list in ticket");
         ensure!(!slf.addrs.is_empty(), "Invalid address list in ticket");
         Ok(slf)
     }
 
+    fn from_v1(ticket: TicketV1) -> Result<Self> {
+        let mut slf = Self::new(ticket.hashes, ticket.peer, ticket.addrs, ticket.credential)?;
+        for (tag, value) in ticket.fields {
+            match tag {
+                LABEL => slf.label = Some(decode_field(tag, &value)?),
+                SIZE => slf.size = Some(decode_field(tag, &value)?),
+                NUM_FILES => slf.num_files = Some(decode_field(tag, &value)?),
+                EXPIRES => slf.expires = Some(decode_field(tag, &value)?),
+                TAG => slf.tag = Some(decode_field(tag, &value)?),
+                _ => {}
+            }
+        }
+        Ok(slf)
+    }
+
+    fn from_unversioned(bytes: &[u8]) -> Result<Self> {
+        let (hash, peer, addrs, credential) = match postcard::from_bytes::<TicketUnversioned>(bytes)
+        {
+            Ok(t) => (t.hash, t.peer, t.addrs, t.credential),
+            Err(err) => match postcard::from_bytes::<TicketV0>(bytes) {
+                Ok(t) => (t.hash, t.peer, t.addrs, t.token.into()),
+                Err(_) => return Err(err.into()),
+            },
+        };
+        Self::new(vec![hash], peer, addrs, Some(credential))
+    }
+
+    /// Serializes to an `iroh://ticket/` URI, which [`FromStr`] also accepts.
+    pub fn to_uri(&self) -> String {
+        util::encode_uri(TICKET_URI_KIND, self.to_bytes())
+    }
+
     /// Serializes to bytes.
     pub fn to_bytes(&self) -> Vec<u8> {
-        postcard::to_stdvec(self).expect("postcard::to_stdvec is infallible")
+        let mut fields = Vec::new();
+        if let Some(label) = &self.label {
+            fields.push((LABEL, encode_field(label)));
+        }
+        if let Some(size) = self.size {
+            fields.push((SIZE, encode_field(&size)));
+        }
+        if let Some(num_files) = self.num_files {
+            fields.push((NUM_FILES, encode_field(&num_files)));
+        }
+        if let Some(expires) = self.expires {
+            fields.push((EXPIRES, encode_field(&expires)));
+        }
+        if let Some(tag) = &self.tag {
+            fields.push((TAG, encode_field(tag)));
+        }
+        let ticket = TicketV1 {
+            hashes: self.hashes.clone(),
+            peer: self.peer,
+            addrs: self.addrs.clone(),
+            credential: self.credential.clone(),
+            fields,
+        };
+        let mut bytes = vec![TICKET_VERSION];
+        bytes.extend(postcard::to_stdvec(&ticket).expect("postcard::to_stdvec is infallible"));
+        bytes
     }
 
     /// The hash of the item this ticket can retrieve.
+    ///
+    /// This is the first of the [`Ticket::hashes`].
     pub fn hash(&self) -> Hash {
-        self.hash
+        self.hashes[0]
+    }
+
+    /// The root hashes of all items this ticket can retrieve.
+    ///
+    /// This is guaranteed to be non-empty.
+    pub fn hashes(&self) -> &[Hash] {
+        &self.hashes
     }
 
     /// The [`PeerId`] of the provider for this ticket.
@@ -79,55 +270,240 @@ impl Ticket {
         &self.addrs
     }
 
-    /// The authentication token for this ticket.
-    pub fn token(&self) -> AuthToken {
-        self.token
+    /// The credential for this ticket.
+    ///
+    /// Tickets created by [`crate::provider::Provider::ticket`] contain a
+    /// [`Capability`](crate::protocol::Capability) for the hashes of the ticket only, those
+    /// with a [tag](Ticket::tag) one for the tag.  Tickets of a public provider have no
+    /// credential.
+    pub fn credential(&self) -> Option<&Credential> {
+        self.credential.as_ref()
+    }
+
+    /// The human readable description of the data, if any.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// The total size of the data, if known.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// The number of files of the data, if known.
+    ///
+    /// For a collection this is the number of its entries.
+    pub fn num_files(&self) -> Option<u64> {
+        self.num_files
+    }
+
+    /// When the ticket expires, if it does.
+    pub fn expires(&self) -> Option<SystemTime> {
+        self.expires
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
     }
+
+    /// The tag of the provider which refers to the data, if any.
+    ///
+    /// The tag can be moved to new data after the ticket was created, use
+    /// [`crate::get::resolve_ticket`] to find the hash it currently refers to.  The
+    /// [`Capability`](crate::protocol::Capability) of tickets created by
+    /// [`crate::provider::Provider::ticket_for_tag`] grants access to the tag, so they can
+    /// get whatever data the tag refers to.
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    /// Whether the ticket has expired.
+    pub fn is_expired(&self) -> bool {
+        self.expires()
+            .map(|expires| expires <= SystemTime::now())
+            .unwrap_or(false)
+    }
+}
+
+fn encode_field<T: Serialize>(value: &T) -> Vec<u8> {
+    postcard::to_stdvec(value).expect("postcard::to_stdvec is infallible")
+}
+
+fn decode_field<T: DeserializeOwned>(tag: u16, value: &[u8]) -> Result<T> {
+    postcard::from_bytes(value).with_context(|| format!("invalid ticket field {tag}"))
 }
 
-/// Serializes to base64.
+/// Serializes to base32 with a checksum.
 impl Display for Ticket {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let encoded = self.to_bytes();
-        write!(f, "{}", util::encode(encoded))
+        write!(f, "{}", util::encode_checked(encoded))
     }
 }
 
-/// Deserializes from base64.
+/// Deserializes from checked base32, an `iroh://ticket/` URI or the base64 of older versions.
 impl FromStr for Ticket {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let bytes = util::decode(s)?;
-        let slf = Self::from_bytes(&bytes)?;
-        Ok(slf)
+        util::decode_printable(s, TICKET_URI_KIND, Self::from_bytes)
+    }
+}
+
+/// Serializes using [`Ticket::to_bytes`].
+impl Serialize for Ticket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.to_bytes())
+    }
+}
+
+/// Deserializes using [`Ticket::from_bytes`].
+impl<'de> Deserialize<'de> for Ticket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(TicketVisitor)
+    }
+}
+
+struct TicketVisitor;
+
+impl<'de> serde::de::Visitor<'de> for TicketVisitor {
+    type Value = Ticket;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an encoded ticket")
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Ticket::from_bytes(bytes).map_err(E::custom)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::protocol::Capability;
     use crate::tls::Keypair;
 
     use super::*;
 
     #[test]
-    fn test_ticket_base64_roundtrip() {
+    fn test_ticket_roundtrip() {
         let hash = blake3::hash(b"hi there");
         let hash = Hash::from(hash);
         let peer = PeerId::from(Keypair::generate().public());
         let addr = SocketAddr::from_str("127.0.0.1:1234").unwrap();
         let token = AuthToken::generate();
-        let ticket = Ticket {
-            hash,
-            peer,
-            addrs: vec![addr],
-            token,
-        };
-        let base64 = ticket.to_string();
-        println!("Ticket: {base64}");
-        println!("{} bytes", base64.len());
+        let ticket = Ticket::new(vec![hash], peer, vec![addr], Some(token.into())).unwrap();
+        let encoded = ticket.to_string();
+        println!("Ticket: {encoded}");
+        println!("{} bytes", encoded.len());
 
-        let ticket2: Ticket = base64.parse().unwrap();
+        let ticket2: Ticket = encoded.parse().unwrap();
         assert_eq!(ticket2, ticket);
     }
+
+    #[test]
+    fn test_ticket_printable() {
+        let hash = Hash::from(blake3::hash(b"hi there"));
+        let peer = PeerId::from(Keypair::generate().public());
+        let addr = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+        let token = AuthToken::generate();
+        let ticket = Ticket::new(vec![hash], peer, vec![addr], Some(token.into())).unwrap();
+
+        let uri = ticket.to_uri();
+        assert!(uri.starts_with("iroh://ticket/"));
+        assert_eq!(uri.parse::<Ticket>().unwrap(), ticket);
+        let encoded = ticket.to_string();
+        assert_eq!(encoded.to_uppercase().parse::<Ticket>().unwrap(), ticket);
+        let legacy = util::encode(ticket.to_bytes());
+        assert_eq!(legacy.parse::<Ticket>().unwrap(), ticket);
+
+        let mut typo = encoded.into_bytes();
+        typo[5] = if typo[5] == b'a' { b'b' } else { b'a' };
+        let err = String::from_utf8(typo)
+            .unwrap()
+            .parse::<Ticket>()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "checksum mismatch");
+    }
+
+    #[test]
+    fn test_ticket_capability() {
+        let hash = Hash::from(blake3::hash(b"hi there"));
+        let keypair = Keypair::generate();
+        let peer = PeerId::from(keypair.public());
+        let addr = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+        let expires = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        let capability = Capability::new(&keypair, vec![hash], expires, Some(3));
+        let ticket = Ticket::new(vec![hash], peer, vec![addr], Some(capability.into())).unwrap();
+
+        let ticket2: Ticket = ticket.to_string().parse().unwrap();
+        assert_eq!(ticket2, ticket);
+        match ticket2.credential() {
+            Some(Credential::Capability(capability)) => {
+                assert_eq!(capability.hashes(), &[hash]);
+                capability.verify(&keypair.public()).unwrap();
+            }
+            credential => panic!("unexpected credential {credential:?}"),
+        }
+    }
+
+    #[test]
+    fn test_ticket_fields() {
+        let hashes = vec![
+            Hash::from(blake3::hash(b"hi there")),
+            Hash::from(blake3::hash(b"hello")),
+        ];
+        let peer = PeerId::from(Keypair::generate().public());
+        let addr = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+        let expires = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let ticket = Ticket::new(hashes.clone(), peer, vec![addr], None)
+            .unwrap()
+            .with_label("holiday photos")
+            .with_size(1024, 3)
+            .with_expires(expires)
+            .with_tag("nightly");
+
+        let ticket2: Ticket = ticket.to_string().parse().unwrap();
+        assert_eq!(ticket2, ticket);
+        assert_eq!(ticket2.hashes(), &hashes[..]);
+        assert_eq!(ticket2.credential(), None);
+        assert_eq!(ticket2.label(), Some("holiday photos"));
+        assert_eq!(ticket2.size(), Some(1024));
+        assert_eq!(ticket2.num_files(), Some(3));
+        assert_eq!(ticket2.expires(), Some(expires));
+        assert_eq!(ticket2.tag(), Some("nightly"));
+        assert!(ticket2.is_expired());
+
+        // Fields added by newer versions are ignored.
+        let mut ticket = postcard::from_bytes::<TicketV1>(&ticket.to_bytes()[1..]).unwrap();
+        ticket.fields.push((1000, vec![1, 2, 3]));
+        let mut bytes = vec![TICKET_VERSION];
+        bytes.extend(postcard::to_stdvec(&ticket).unwrap());
+        assert_eq!(Ticket::from_bytes(&bytes).unwrap(), ticket2);
+    }
+
+    #[test]
+    fn test_ticket_unversioned() {
+        let hash = Hash::from(blake3::hash(b"hi there"));
+        let peer = PeerId::from(Keypair::generate().public());
+        let addr = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+        let token = AuthToken::generate();
+        let credential = Credential::from(token);
+        let bytes = postcard::to_stdvec(&(hash, peer, vec![addr], &credential)).unwrap();
+
+        let ticket = Ticket::from_bytes(&bytes).unwrap();
+        assert_eq!(ticket.hashes(), &[hash]);
+        assert_eq!(ticket.credential(), Some(&credential));
+        assert_eq!(ticket.label(), None);
+    }
+
+    #[test]
+    fn test_ticket_v0() {
+        let hash = Hash::from(blake3::hash(b"hi there"));
+        let peer = PeerId::from(Keypair::generate().public());
+        let addr = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+        let token = AuthToken::generate();
+        let bytes = postcard::to_stdvec(&(hash, peer, vec![addr], token)).unwrap();
+
+        let ticket = Ticket::from_bytes(&bytes).unwrap();
+        assert_eq!(ticket.hash(), hash);
+        assert_eq!(ticket.credential(), Some(&Credential::Token(token)));
+    }
 }
@@ -0,0 +1,159 @@
+//! The client side API to push data to a provider
+//!
+//! The main entry point is [`run`].  It uploads a collection and all its blobs from a local
+//! [`Database`] to a provider which accepts pushes, see
+//! [`crate::provider::Builder::push_dir`].
+
+use std::io::Cursor;
+use std::time::Instant;
+
+use anyhow::{bail, ensure, Context, Result};
+use bao_tree::io::sync::encode_ranges_validated;
+use bao_tree::outboard::PreOrderMemOutboardRef;
+use bytes::{Bytes, BytesMut};
+use range_collections::RangeSet2;
+use tracing::{debug, debug_span, warn};
+use tracing_futures::Instrument;
+
+use crate::blobs::Collection;
+use crate::get::{self, read_response, GetError, Options, Stats};
+use crate::protocol::{negotiated_version, AuthToken, PushRequest, Req, Res};
+use crate::provider::{BlobOrCollection, BlobSource, Database};
+use crate::{Hash, IROH_BLOCK_SIZE};
+
+/// The first protocol version which supports pushing.
+const PUSH_VERSION: u64 = 3;
+
+/// Pushes a collection and all its blobs to a provider.
+///
+/// The collection and its blobs are read from `db`, e.g. as created by
+/// [`crate::provider::create_collection`].  The provider verifies all data and adds it to its
+/// database, after which the collection can be fetched from it like any other.
+///
+/// Nested collections can not be pushed.
+///
+/// If the provider rejects the push, e.g. because of a wrong `auth_token` or because it does
+/// not accept pushes, this returns [`GetError::Provider`].
+pub async fn run(
+    db: &Database,
+    hash: Hash,
+    auth_token: AuthToken,
+    opts: Options,
+) -> Result<Stats, GetError> {
+    let span = debug_span!("push", %hash);
+    async move {
+        let start = Instant::now();
+        let connection = get::dial_peer(opts).await?;
+        let span = debug_span!("connection", remote_addr=%connection.remote_address());
+        run_connection(connection, db, hash, auth_token, start)
+            .instrument(span)
+            .await
+            .map_err(GetError::from)
+    }
+    .instrument(span)
+    .await
+}
+
+/// Pushes a collection and all its blobs to a provider on the established connection.
+async fn run_connection(
+    connection: quinn::Connection,
+    db: &Database,
+    hash: Hash,
+    auth_token: AuthToken,
+    start_time: Instant,
+) -> Result<Stats> {
+    let version = negotiated_version(&connection)?;
+    ensure!(
+        version >= PUSH_VERSION,
+        "the provider does not support pushing, it only speaks protocol version {version}"
+    );
+    let (outboard, data) = match db.get(&hash) {
+        Some(BlobOrCollection::Collection { outboard, data, .. }) => (outboard, data),
+        Some(BlobOrCollection::Blob { .. }) => bail!("{hash} is not a collection"),
+        None => bail!("collection {hash} not found"),
+    };
+    let collection = Collection::from_bytes(&data)?;
+    ensure!(
+        !collection.blobs().iter().any(|blob| blob.is_collection()),
+        "pushing nested collections is not supported"
+    );
+
+    // 1. Send handshake and request
+    let request = Req::Push(PushRequest { hash });
+    let (mut writer, mut reader) =
+        get::send_request(&connection, version, auth_token.into(), &request).await?;
+
+    // 2. Wait for the provider to accept the push
+    let mut buffer = BytesMut::with_capacity(1024);
+    match read_response(&mut reader, &mut buffer).await? {
+        Res::PushAccepted => {}
+        res => bail!("unexpected response from provider: {res:?}"),
+    }
+
+    // 3. Send the data
+    debug!("sending data");
+    let sent = send_collection(&mut writer, db, hash, &collection, &outboard, &data).await;
+    if sent.is_err() {
+        // Make sure the provider does not wait for more data.
+        writer.reset(0u8.into()).ok();
+    }
+
+    // 4. Read the final response, this also tells why the provider stopped reading.
+    let res = read_response(&mut reader, &mut buffer).await;
+    let data_len = match (sent, res) {
+        (Ok(data_len), Ok(Res::PushCompleted)) => data_len,
+        (_, Err(err)) if err.is::<GetError>() => return Err(err),
+        (Err(err), _) | (Ok(_), Err(err)) => return Err(err),
+        (Ok(_), Ok(res)) => bail!("unexpected response from provider: {res:?}"),
+    };
+    Ok(Stats {
+        data_len,
+        elapsed: start_time.elapsed(),
+    })
+}
+
+/// Sends the bao encoded collection followed by the bao encoded data of all its blobs.
+///
+/// Returns the total size of the blobs.
+async fn send_collection(
+    writer: &mut quinn::SendStream,
+    db: &Database,
+    hash: Hash,
+    collection: &Collection,
+    outboard: &Bytes,
+    data: &Bytes,
+) -> Result<u64> {
+    let mut encoded = Vec::new();
+    let outboard = PreOrderMemOutboardRef::new(hash.into(), IROH_BLOCK_SIZE, outboard);
+    encode_ranges_validated(Cursor::new(data), outboard, &RangeSet2::all(), &mut encoded)?;
+    writer.write_all(&encoded).await?;
+
+    let mut data_len = 0;
+    for blob in collection.blobs() {
+        let hash = blob.hash();
+        let (outboard, sources, size) = match db.get(&hash) {
+            Some(BlobOrCollection::Blob {
+                outboard,
+                sources,
+                size,
+            }) => (outboard, sources, size),
+            _ => bail!("blob {hash} of the collection not found"),
+        };
+        let (file, stale) = BlobSource::open(&sources, size).await;
+        for path in stale {
+            warn!(%hash, "stale blob source {}", path.display());
+        }
+        let file = file.with_context(|| format!("no unchanged file of blob {hash} found"))?;
+        let outboard = PreOrderMemOutboardRef::new(hash.into(), IROH_BLOCK_SIZE, &outboard);
+        bao_tree::io::tokio::encode_ranges_validated(
+            file,
+            outboard,
+            &RangeSet2::all(),
+            &mut *writer,
+        )
+        .await?;
+        data_len += size;
+    }
+    writer.finish().await?;
+    Ok(data_len)
+}
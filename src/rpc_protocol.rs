@@ -1,7 +1,11 @@
 #![allow(missing_docs)]
-use std::{net::SocketAddr, path::PathBuf};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
-use crate::{protocol::AuthToken, util::RpcError, Hash, PeerId};
+use crate::{
+    protocol::AuthToken,
+    util::{RpcError, RpcResult},
+    Hash, PeerId,
+};
 use derive_more::{From, TryInto};
 use quic_rpc::{
     message::{Msg, RpcMsg, ServerStreaming, ServerStreamingMsg},
@@ -12,6 +16,35 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProvideRequest {
     pub path: PathBuf,
+    /// Whether to create a nested collection for every directory.
+    pub nested: bool,
+    /// Whether to record the metadata of files, directories and symlinks.
+    pub metadata: bool,
+    /// How the provider stores the data.
+    pub import: ImportMode,
+    /// The tag to set to the hash of the added data, which is pinned if this is `None`.
+    pub tag: Option<String>,
+    /// Keep watching `path` and add it again after it changed.
+    ///
+    /// The data is added again once there were no further changes for this long, see
+    /// [`ProvideProgress::Changed`].  The stream of progress updates ends when the client
+    /// drops it.
+    pub watch: Option<Duration>,
+}
+
+/// How the provider stores the data added by a [`ProvideRequest`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImportMode {
+    /// Serve the files from their original location.
+    ///
+    /// The files must not change while the provider serves them.
+    #[default]
+    Reference,
+    /// Copy the files into the data directory of the database, so it owns the data.
+    ///
+    /// The operating system may clone the files instead, e.g. using reflinks.  This needs a
+    /// database opened with [`crate::provider::Database::open`].
+    Copy,
 }
 
 /// Progress updates for the provide operation
@@ -25,6 +58,17 @@ pub enum ProvideProgress {
     Done { id: u64, hash: Hash },
     /// We are done with the whole operation
     AllDone { hash: Hash },
+    /// The watched `paths` changed and the data is added again, see
+    /// [`ProvideRequest::watch`].
+    ///
+    /// The same updates as for the first addition follow, ending with
+    /// [`ProvideProgress::AllDone`] with the new hash.
+    Changed { paths: Vec<PathBuf> },
+    /// Adding the data again after [`ProvideProgress::Changed`] failed.
+    ///
+    /// This replaces [`ProvideProgress::AllDone`], the provider keeps watching and adds the
+    /// data again after the next change.
+    ChangeFailed(RpcError),
     /// We got an error and need to abort
     Abort(RpcError),
 }
@@ -121,6 +165,163 @@ impl RpcMsg<ProviderService> for AddrsRequest {
     type Response = AddrsResponse;
 }
 
+/// Adds a named auth token to the provider.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenAddRequest {
+    /// The name identifying the token.
+    pub name: String,
+    /// The token to add, a new one is generated if this is `None`.
+    pub token: Option<AuthToken>,
+}
+
+impl RpcMsg<ProviderService> for TokenAddRequest {
+    type Response = RpcResult<TokenAddResponse>;
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenAddResponse {
+    /// The added token.
+    pub token: AuthToken,
+}
+
+/// Revokes the auth token with the given name.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenRevokeRequest {
+    pub name: String,
+}
+
+impl RpcMsg<ProviderService> for TokenRevokeRequest {
+    type Response = RpcResult<()>;
+}
+
+/// Lists the names and auth tokens of the provider.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenListRequest;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenListResponse {
+    pub name: String,
+    pub token: AuthToken,
+}
+
+impl Msg<ProviderService> for TokenListRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for TokenListRequest {
+    type Response = TokenListResponse;
+}
+
+/// Removes an entry from the provider's database and unpins it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoveRequest {
+    pub hash: Hash,
+}
+
+impl RpcMsg<ProviderService> for RemoveRequest {
+    type Response = RpcResult<RemoveResponse>;
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoveResponse {
+    /// Whether the database had an entry or pin for the hash.
+    pub removed: bool,
+}
+
+/// Removes all entries of the provider's database which are not reachable from a pin.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GcRequest;
+
+impl RpcMsg<ProviderService> for GcRequest {
+    type Response = RpcResult<GcResponse>;
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GcResponse {
+    /// The hashes of the removed entries.
+    pub removed: Vec<Hash>,
+}
+
+/// Sets a tag of the provider's database to a hash, or deletes it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagSetRequest {
+    /// The name of the tag.
+    pub name: String,
+    /// The hash the tag refers to, `None` deletes the tag.
+    pub hash: Option<Hash>,
+}
+
+impl RpcMsg<ProviderService> for TagSetRequest {
+    type Response = RpcResult<TagSetResponse>;
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagSetResponse {
+    /// The hash the tag referred to before.
+    pub previous: Option<Hash>,
+}
+
+/// Lists the tags of the provider's database.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagListRequest;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagListResponse {
+    pub name: String,
+    pub hash: Hash,
+}
+
+impl Msg<ProviderService> for TagListRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for TagListRequest {
+    type Response = TagListResponse;
+}
+
+/// Writes the collection `hash` from the database of the provider to `out_dir`.
+///
+/// Nested collections become subdirectories.  Every blob is validated against its
+/// outboard while it is written.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportRequest {
+    pub hash: Hash,
+    /// The directory to write the files to, an absolute path on the provider's machine.
+    pub out_dir: PathBuf,
+    /// Whether to overwrite existing files, otherwise the export is aborted if any exist.
+    pub force: bool,
+}
+
+/// Progress updates for the export operation, like [`ValidateProgress`]
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExportProgress {
+    /// started exporting `total` blobs
+    Starting { total: u64 },
+    /// We started writing blob `hash` to `path`
+    Entry {
+        id: u64,
+        hash: Hash,
+        path: PathBuf,
+        size: u64,
+    },
+    /// We wrote `offset` bytes of `id`
+    Progress { id: u64, offset: u64 },
+    /// We are done with `id`, the file was not written if there is an error
+    Done { id: u64, error: Option<String> },
+    /// We are done with the whole operation
+    AllDone,
+    /// We got an error and need to abort
+    Abort(RpcError),
+}
+
+impl Msg<ProviderService> for ExportRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for ExportRequest {
+    type Response = ExportProgress;
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WatchResponse {
     pub version: String,
@@ -129,7 +330,8 @@ pub struct WatchResponse {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct IdResponse {
     pub peer_id: Box<PeerId>,
-    pub auth_token: Box<AuthToken>,
+    /// The default auth token, `None` if it was revoked.
+    pub auth_token: Option<Box<AuthToken>>,
     pub listen_addr: Box<SocketAddr>,
     pub version: String,
 }
@@ -167,6 +369,14 @@ pub enum ProviderRequest {
     Addrs(AddrsRequest),
     Shutdown(ShutdownRequest),
     Validate(ValidateRequest),
+    TokenAdd(TokenAddRequest),
+    TokenRevoke(TokenRevokeRequest),
+    TokenList(TokenListRequest),
+    Remove(RemoveRequest),
+    Gc(GcRequest),
+    TagSet(TagSetRequest),
+    TagList(TagListRequest),
+    Export(ExportRequest),
 }
 
 /// Response enum
@@ -180,6 +390,14 @@ pub enum ProviderResponse {
     Addrs(AddrsResponse),
     Validate(ValidateProgress),
     Shutdown(()),
+    TokenAdd(RpcResult<TokenAddResponse>),
+    TokenRevoke(RpcResult<()>),
+    TokenList(TokenListResponse),
+    Remove(RpcResult<RemoveResponse>),
+    Gc(RpcResult<GcResponse>),
+    TagSet(RpcResult<TagSetResponse>),
+    TagList(TagListResponse),
+    Export(ExportProgress),
 }
 
 impl Service for ProviderService {
@@ -19,8 +19,6 @@ use ssh_key::LineEnding;
 
 use crate::util;
 
-pub(crate) const P2P_ALPN: [u8; 9] = *b"n0/iroh/1";
-
 /// A keypair.
 #[derive(Debug)]
 pub struct Keypair(ed25519_dalek::Keypair);
@@ -69,7 +67,7 @@ impl Keypair {
         }
     }
 
-    fn sign(&self, msg: &[u8]) -> Signature {
+    pub(crate) fn sign(&self, msg: &[u8]) -> Signature {
         use ed25519_dalek::Signer;
 
         self.0.sign(msg)
@@ -91,7 +89,8 @@ impl From<ed25519_dalek::Keypair> for Keypair {
 /// # `Display` and `FromStr`
 ///
 /// The [`PeerId`] implements both `Display` and `FromStr` which can be used to
-/// (de)serialise to human-readable and relatively safely transferrable strings.
+/// (de)serialise to human-readable and relatively safely transferrable strings.  See
+/// [`PeerId::to_uri`] for the URI form.
 #[derive(Clone, PartialEq, Eq, Copy, Serialize, Deserialize)]
 pub struct PeerId(PublicKey);
 
@@ -101,18 +100,36 @@ impl From<PublicKey> for PeerId {
     }
 }
 
+impl std::hash::Hash for PeerId {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.as_bytes().hash(state);
+    }
+}
+
 impl Debug for PeerId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "PeerId({})", util::encode(self.0.as_bytes()))
     }
 }
 
-/// Serialises the [`PeerId`] to base64.
+impl PeerId {
+    /// Serialises the [`PeerId`] to an `iroh://peer/` URI.
+    ///
+    /// [`FromStr`] is capable of deserialising this format.
+    pub fn to_uri(&self) -> String {
+        util::encode_uri(PEER_ID_URI_KIND, self.0.as_bytes())
+    }
+}
+
+/// The kind of [`PeerId`] URIs.
+const PEER_ID_URI_KIND: &str = "peer";
+
+/// Serialises the [`PeerId`] to base32 with a checksum.
 ///
 /// [`FromStr`] is capable of deserialising this format.
 impl Display for PeerId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", util::encode(self.0.as_bytes()))
+        write!(f, "{}", util::encode_checked(self.0.as_bytes()))
     }
 }
 
@@ -122,21 +139,25 @@ pub enum PeerIdError {
     /// Error when decoding the base64.
     #[error("encoding: {0}")]
     Base64(#[from] base64::DecodeError),
+    /// Error when decoding the base32 or URI, e.g. a checksum mismatch.
+    #[error("encoding: {0}")]
+    Encoding(#[from] util::DecodeError),
     /// Error when decoding the public key.
     #[error("key: {0}")]
     Key(#[from] ed25519_dalek::SignatureError),
 }
 
-/// Deserialises the [`PeerId`] from it's base64 encoding.
+/// Deserialises the [`PeerId`] from its base32, URI or older base64 encoding.
 ///
-/// [`Display`] is capable of serialising this format.
+/// [`Display`] and [`PeerId::to_uri`] are capable of serialising these formats.
 impl FromStr for PeerId {
     type Err = PeerIdError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let bytes = util::decode(s)?;
-        let key = PublicKey::from_bytes(&bytes)?;
-        Ok(PeerId(key))
+        util::decode_printable(s, PEER_ID_URI_KIND, |bytes| {
+            let key = PublicKey::from_bytes(bytes)?;
+            Ok(PeerId(key))
+        })
     }
 }
 
@@ -198,6 +219,21 @@ pub fn make_server_config(
     Ok(crypto)
 }
 
+/// Returns the [`PeerId`] of the remote side of an established `connection`.
+///
+/// This is taken from the certificate which was verified during the TLS handshake, so the
+/// remote side is known to own the keypair of the peer id.
+pub(crate) fn remote_peer_id(connection: &quinn::Connection) -> anyhow::Result<PeerId> {
+    let certificates = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok())
+        .ok_or_else(|| anyhow::anyhow!("no peer certificate"))?;
+    let certificate = certificates
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("no peer certificate"))?;
+    Ok(certificate::parse(certificate)?.peer_id())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -209,4 +245,19 @@ mod tests {
         let de = Keypair::try_from_openssh(&ser).unwrap();
         assert_eq!(kp.to_bytes(), de.to_bytes());
     }
+
+    #[test]
+    fn test_peer_id_printable() {
+        let peer_id = PeerId::from(Keypair::generate().public());
+        assert_eq!(peer_id.to_string().parse::<PeerId>().unwrap(), peer_id);
+        assert_eq!(peer_id.to_uri().parse::<PeerId>().unwrap(), peer_id);
+        let legacy = util::encode(peer_id.0.as_bytes());
+        assert_eq!(legacy.parse::<PeerId>().unwrap(), peer_id);
+        // A URI for a different kind of value is rejected.
+        let uri = peer_id.to_uri().replace("peer", "token");
+        assert!(matches!(
+            uri.parse::<PeerId>(),
+            Err(PeerIdError::Encoding(util::DecodeError::Kind("peer")))
+        ));
+    }
 }
@@ -10,7 +10,7 @@ use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     fmt::{self, Display},
     io::{self, Read, Seek, Write},
-    path::{Component, Path},
+    path::{Component, Path, PathBuf},
     result,
     str::FromStr,
 };
@@ -29,6 +29,101 @@ pub fn decode(buf: impl AsRef<str>) -> Result<Vec<u8>, base64::DecodeError> {
     general_purpose::URL_SAFE_NO_PAD.decode(buf.as_ref())
 }
 
+/// The scheme of the URI form of tickets, peer ids and auth tokens.
+///
+/// The URI is the scheme followed by the kind of the value, a slash and the value encoded
+/// using [`encode_checked`], e.g. `iroh://peer/<base32>`.
+pub const URI_SCHEME: &str = "iroh://";
+
+/// The number of checksum bytes appended by [`encode_checked`].
+const CHECKSUM_LEN: usize = 4;
+
+/// Encode the given buffer followed by a checksum into lowercase base32 without padding.
+pub fn encode_checked(buf: impl AsRef<[u8]>) -> String {
+    let buf = buf.as_ref();
+    let mut data = buf.to_vec();
+    data.extend_from_slice(&checksum(buf));
+    data_encoding::BASE32_NOPAD
+        .encode(&data)
+        .to_ascii_lowercase()
+}
+
+/// Decode the given buffer from [`encode_checked`], ignoring case, and verify its checksum.
+pub fn decode_checked(buf: impl AsRef<str>) -> Result<Vec<u8>, DecodeError> {
+    let encoded = buf.as_ref().to_ascii_uppercase();
+    let mut data = data_encoding::BASE32_NOPAD
+        .decode(encoded.as_bytes())
+        .map_err(|_| DecodeError::Base32)?;
+    if data.len() < CHECKSUM_LEN {
+        return Err(DecodeError::Base32);
+    }
+    let sum = data.split_off(data.len() - CHECKSUM_LEN);
+    if sum != checksum(&data) {
+        return Err(DecodeError::ChecksumMismatch);
+    }
+    Ok(data)
+}
+
+fn checksum(buf: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let hash = blake3::hash(buf);
+    let mut sum = [0u8; CHECKSUM_LEN];
+    sum.copy_from_slice(&hash.as_bytes()[..CHECKSUM_LEN]);
+    sum
+}
+
+/// Formats the given buffer as an [`URI_SCHEME`] URI for a `kind` of value.
+pub(crate) fn encode_uri(kind: &str, buf: impl AsRef<[u8]>) -> String {
+    format!("{URI_SCHEME}{kind}/{}", encode_checked(buf))
+}
+
+/// Decodes any printable form of a `kind` of value and parses it using `parse`.
+///
+/// The forms are the URI of [`encode_uri`], the checked base32 of [`encode_checked`] and
+/// the base64 of [`encode`] used by older versions.  Since base32 is also valid base64, the
+/// base64 form is only tried if the checksum does not match.
+pub(crate) fn decode_printable<T, E>(
+    s: &str,
+    kind: &'static str,
+    parse: impl Fn(&[u8]) -> Result<T, E>,
+) -> Result<T, E>
+where
+    E: From<DecodeError>,
+{
+    let scheme = s.get(..URI_SCHEME.len()).unwrap_or_default();
+    if scheme.eq_ignore_ascii_case(URI_SCHEME) {
+        let rest = &s[URI_SCHEME.len()..];
+        let encoded = match rest.split_once('/') {
+            Some((uri_kind, encoded)) if uri_kind.eq_ignore_ascii_case(kind) => encoded,
+            _ => return Err(DecodeError::Kind(kind).into()),
+        };
+        return parse(&decode_checked(encoded)?);
+    }
+    let err = match decode_checked(s) {
+        Ok(bytes) => return parse(&bytes),
+        Err(err) => err,
+    };
+    match decode(s).map(|bytes| parse(&bytes)) {
+        Ok(Ok(value)) => Ok(value),
+        // Not base32 at all, so the error parsing the base64 is more useful.
+        Ok(Err(parse_err)) if err == DecodeError::Base32 => Err(parse_err),
+        _ => Err(err.into()),
+    }
+}
+
+/// Error decoding the checked base32 or URI form of a value.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The encoding is neither valid base32 nor valid base64.
+    #[error("invalid encoding")]
+    Base32,
+    /// The checksum does not match, the value was likely mistyped.
+    #[error("checksum mismatch")]
+    ChecksumMismatch,
+    /// The URI is not for the expected kind of value.
+    #[error("expected an {URI_SCHEME}{0}/ URI")]
+    Kind(&'static str),
+}
+
 /// Hash type used throught.
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Hash)]
 pub struct Hash(blake3::Hash);
@@ -193,6 +288,46 @@ pub fn validate_bao<F: Fn(u64)>(
     Ok(())
 }
 
+/// Validate that the data matches the outboard, while copying it to `target`.
+///
+/// The data is written to the same offsets it is read from, so `target` has a complete
+/// copy once the validation succeeds.
+pub fn validate_bao_copy<F: Fn(u64)>(
+    hash: Hash,
+    data_reader: impl Read + Seek,
+    outboard: Bytes,
+    target: impl Write + Seek,
+    progress: F,
+) -> result::Result<(), BaoValidationError> {
+    let reader = CopyReader {
+        inner: data_reader,
+        target,
+    };
+    validate_bao(hash, reader, outboard, progress)
+}
+
+/// A reader which writes all data it reads to the same position of `target`.
+struct CopyReader<R, W> {
+    inner: R,
+    target: W,
+}
+
+impl<R: Read, W: Write> Read for CopyReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.target.write_all(&buf[..read])?;
+        Ok(read)
+    }
+}
+
+impl<R: Seek, W: Seek> Seek for CopyReader<R, W> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let pos = self.inner.seek(pos)?;
+        self.target.seek(io::SeekFrom::Start(pos))?;
+        Ok(pos)
+    }
+}
+
 /// little util that discards data but prints progress every 1MB
 struct DevNull<F>(u64, F);
 
@@ -239,6 +374,82 @@ pub fn canonicalize_path(path: impl AsRef<Path>) -> anyhow::Result<String> {
     Ok(parts.join("/"))
 }
 
+/// Converts the `/` separated name of a collection entry back to a relative path.
+///
+/// Fails for names with empty components, or components which would leave the directory
+/// the path is relative to, e.g. `..`.
+pub fn path_from_name(name: &str) -> anyhow::Result<PathBuf> {
+    let path = name.split('/').collect::<PathBuf>();
+    ensure!(
+        name.split('/').all(|part| !part.is_empty())
+            && path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_))),
+        "invalid name {name:?}, only relative paths without `..` are allowed"
+    );
+    Ok(path)
+}
+
+/// Matches a `/` separated name, as created by [`canonicalize_path`], against a glob pattern.
+///
+/// `?` matches any single character and `*` any number of characters, except for `/`.  `**`
+/// also matches `/`, so `a/**/b` matches `a/b` as well as `a/x/y/b`.
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Token {
+        Char(char),
+        Any,
+        Star,
+        DoubleStar,
+        /// `**/`, which can match nothing or anything ending in `/`.
+        Dirs,
+    }
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '?' => Token::Any,
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    Token::Dirs
+                } else {
+                    Token::DoubleStar
+                }
+            }
+            '*' => Token::Star,
+            c => Token::Char(c),
+        };
+        tokens.push(token);
+    }
+    let name: Vec<char> = name.chars().collect();
+
+    // `matches[j]` is whether the tokens after the current one match `name[j..]`, computed
+    // backwards from the end of the pattern.
+    let mut matches = vec![false; name.len() + 1];
+    matches[name.len()] = true;
+    for token in tokens.iter().rev() {
+        let next = std::mem::replace(&mut matches, vec![false; name.len() + 1]);
+        // whether some `/` at or after `j` is followed by a match, for `Token::Dirs`
+        let mut dir_match = false;
+        for j in (0..=name.len()).rev() {
+            let c = name.get(j).copied();
+            matches[j] = match token {
+                Token::Char(expected) => c == Some(*expected) && next[j + 1],
+                Token::Any => c.map_or(false, |c| c != '/') && next[j + 1],
+                Token::Star => next[j] || (c.map_or(false, |c| c != '/') && matches[j + 1]),
+                Token::DoubleStar => next[j] || (c.is_some() && matches[j + 1]),
+                Token::Dirs => {
+                    dir_match |= c == Some('/') && next[j + 1];
+                    next[j] || dir_match
+                }
+            };
+        }
+    }
+    matches[0]
+}
+
 pub struct ProgressReader<R, F: Fn(ProgressReaderUpdate)> {
     inner: R,
     offset: u64,
@@ -328,8 +539,85 @@ mod tests {
         assert_eq!(encoded.parse::<Hash>().unwrap(), hash);
     }
 
+    #[test]
+    fn test_encode_checked() {
+        let data = b"hello world";
+        let encoded = encode_checked(data);
+        assert_eq!(decode_checked(&encoded).unwrap(), data);
+        // Decoding ignores case.
+        assert_eq!(decode_checked(encoded.to_uppercase()).unwrap(), data);
+
+        // A single mistyped character is detected.
+        let mut typo = encoded.into_bytes();
+        typo[3] = if typo[3] == b'a' { b'b' } else { b'a' };
+        let typo = String::from_utf8(typo).unwrap();
+        assert_eq!(decode_checked(typo), Err(DecodeError::ChecksumMismatch));
+        assert_eq!(decode_checked("not base32!"), Err(DecodeError::Base32));
+    }
+
+    #[test]
+    fn test_decode_printable() {
+        let parse = |bytes: &[u8]| -> Result<Vec<u8>, DecodeError> { Ok(bytes.to_vec()) };
+        let data = b"hello world".to_vec();
+        let uri = encode_uri("test", &data);
+        assert!(uri.starts_with("iroh://test/"));
+        assert_eq!(decode_printable(&uri, "test", parse).unwrap(), data);
+        let upper = uri.to_uppercase();
+        assert_eq!(decode_printable(&upper, "test", parse).unwrap(), data);
+        assert_eq!(
+            decode_printable(&uri, "other", parse),
+            Err(DecodeError::Kind("other"))
+        );
+        let encoded = encode_checked(&data);
+        assert_eq!(decode_printable(&encoded, "test", parse).unwrap(), data);
+        // The base64 of older versions is still accepted.
+        assert_eq!(
+            decode_printable(&encode(&data), "test", parse).unwrap(),
+            data
+        );
+    }
+
     #[test]
     fn test_canonicalize_path() {
         assert_eq!(canonicalize_path("foo/bar").unwrap(), "foo/bar");
     }
+
+    #[test]
+    fn test_path_from_name() {
+        let path = path_from_name("foo/bar").unwrap();
+        assert_eq!(path, Path::new("foo").join("bar"));
+        assert_eq!(canonicalize_path(path).unwrap(), "foo/bar");
+        for name in [
+            "",
+            "/foo",
+            "foo//bar",
+            "foo/",
+            "../foo",
+            "foo/../bar",
+            "./foo",
+        ] {
+            assert!(path_from_name(name).is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("foo", "foo"));
+        assert!(!glob_match("foo", "foobar"));
+        assert!(glob_match("*.png", "a.png"));
+        assert!(!glob_match("*.png", "images/a.png"));
+        assert!(glob_match("images/*.png", "images/a.png"));
+        assert!(!glob_match("images/*.png", "images/a.jpg"));
+        assert!(glob_match("images/?.png", "images/a.png"));
+        assert!(!glob_match("images/?.png", "images/ab.png"));
+        assert!(glob_match("**.png", "images/a.png"));
+        assert!(glob_match("**/*.png", "a.png"));
+        assert!(glob_match("**/*.png", "images/2023/a.png"));
+        assert!(glob_match("images/**/a.png", "images/a.png"));
+        assert!(glob_match("images/**/a.png", "images/x/y/a.png"));
+        assert!(!glob_match("images/**/a.png", "images/xa.png"));
+        assert!(glob_match("images/**", "images/x/y/a.png"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+    }
 }
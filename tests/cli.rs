@@ -82,6 +82,8 @@ fn cli_provide_persistence() -> anyhow::Result<()> {
     std::fs::write(&foo_path, b"foo")?;
     let bar_path = dir.join("bar");
     std::fs::write(&bar_path, b"bar")?;
+    let baz_path = dir.join("baz");
+    std::fs::write(&baz_path, b"baz")?;
     // spawn iroh in provide mode
     let iroh_provide = |path| {
         Command::new(iroh_bin())
@@ -99,32 +101,110 @@ fn cli_provide_persistence() -> anyhow::Result<()> {
             .arg(path)
             .spawn()
     };
-    // provide for 1 sec, then stop with control-c
-    let provide_1sec = |path| {
+    // provide for 1 sec, then stop with `signal`
+    let provide_1sec = |path, signal| {
         let mut child = iroh_provide(path)?;
         // wait for the provider to start
         std::thread::sleep(std::time::Duration::from_secs(1));
-        // kill the provider via Control-C
-        signal::kill(Pid::from_raw(child.id() as i32), Signal::SIGINT).unwrap();
+        // kill the provider, e.g. via Control-C
+        signal::kill(Pid::from_raw(child.id() as i32), signal).unwrap();
         // wait for the provider to exit and make sure that it exited successfully
         let status = child.wait()?;
         // comment out to get debug output from the child process
         std::io::copy(&mut child.stderr.unwrap(), &mut std::io::stdout())?;
-        assert!(status.success());
+        assert_eq!(status.success(), signal == Signal::SIGINT);
         anyhow::Ok(())
     };
-    provide_1sec(&foo_path)?;
+    provide_1sec(&foo_path, Signal::SIGINT)?;
     // should have some data now
     let db = Database::load_test(iroh_data_dir.clone())?;
     let blobs = db.blobs().map(|x| x.1).collect::<Vec<_>>();
     assert_eq!(blobs, vec![foo_path.clone()]);
 
-    provide_1sec(&bar_path)?;
+    provide_1sec(&bar_path, Signal::SIGINT)?;
     // should have more data now
     let db = Database::load_test(&iroh_data_dir)?;
     let mut blobs = db.blobs().map(|x| x.1).collect::<Vec<_>>();
     blobs.sort();
-    assert_eq!(blobs, vec![bar_path, foo_path]);
+    assert_eq!(blobs, vec![bar_path.clone(), foo_path.clone()]);
+
+    // data is kept even if the provider does not exit cleanly
+    provide_1sec(&baz_path, Signal::SIGKILL)?;
+    let db = Database::load_test(&iroh_data_dir)?;
+    let mut blobs = db.blobs().map(|x| x.1).collect::<Vec<_>>();
+    blobs.sort();
+    assert_eq!(blobs, vec![bar_path, baz_path, foo_path]);
+
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "cli"))]
+#[test]
+fn cli_provide_metadata() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    use nix::sys::{stat::utimes, time::TimeVal};
+
+    let dir = testdir!();
+    let src = dir.join("src");
+    std::fs::create_dir_all(src.join("empty"))?;
+    std::fs::create_dir_all(src.join("sub"))?;
+    let script = src.join("sub").join("script");
+    make_rand_file(1000, &script)?;
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o750))?;
+    std::os::unix::fs::symlink("sub/script", src.join("link"))?;
+    let mtime = TimeVal::new(1_000_000_000, 0);
+    utimes(&script, &mtime, &mtime)?;
+    utimes(&src.join("sub"), &mtime, &mtime)?;
+
+    let provider = Command::new(iroh_bin())
+        .env("IROH_DATA_DIR", dir.join("iroh_data_dir"))
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .stdout(Stdio::piped())
+        .arg("provide")
+        .arg(&src)
+        .arg("--metadata")
+        .arg("--addr")
+        .arg(ADDR)
+        .arg("--rpc-port")
+        .arg("disabled")
+        .spawn()?;
+    let mut provider = ProvideProcess { child: provider };
+    let stdout = BufReader::new(provider.child.stdout.take().unwrap());
+    let ticket = match_provide_output(stdout, 1, Input::Path)?;
+
+    let get = |out: &Path, args: &[&str]| -> Result<()> {
+        let output = Command::new(iroh_bin())
+            .arg("get-ticket")
+            .arg(&ticket)
+            .arg("--out")
+            .arg(out)
+            .args(args)
+            .output()?;
+        assert!(output.status.success());
+        Ok(())
+    };
+
+    let out = dir.join("out");
+    get(&out, &[])?;
+    let script = out.join("sub").join("script");
+    let meta = std::fs::metadata(&script)?;
+    assert_eq!(meta.permissions().mode() & 0o7777, 0o750);
+    let expected = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    assert_eq!(meta.modified()?, expected);
+    assert_eq!(std::fs::metadata(out.join("sub"))?.modified()?, expected);
+    assert!(out.join("empty").is_dir());
+    assert_eq!(
+        std::fs::read_link(out.join("link"))?,
+        PathBuf::from("sub/script")
+    );
+
+    let out = dir.join("out-ignored");
+    get(&out, &["--ignore-metadata"])?;
+    assert!(out.join("sub").join("script").is_file());
+    assert!(!out.join("empty").exists());
+    assert!(out.join("link").symlink_metadata().is_err());
 
     Ok(())
 }